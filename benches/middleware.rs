@@ -0,0 +1,156 @@
+// Per request overhead of `RateLimiterManager::handle` with 1, 5 and 20 limiters against the mock store,
+// with the number of allocations per request, then the throughput of large uploads with and without a limiter
+// reading the body. Run with `cargo bench --bench middleware`, criterion compares every run with the last one.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::convert::Infallible;
+use std::hint::black_box;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use axum::body::{Body, Bytes};
+use axum::http::Response;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hyper::body::{Body as _, Frame};
+use rate_limiter::builder::RateLimiterBuilder;
+use rate_limiter::limiter::RateLimiterManager;
+use rate_limiter::settings::{DecisionLogging, PossibleStrategies};
+use rate_limiter::testing::{MockClock, MockStore, TestRequest};
+
+// Requests the allocations are counted over, after as many to warm up the manager
+const ALLOCATION_REQUESTS: u64 = 1_000;
+// 1 MiB uploads, received in chunks of 64 KiB like from a socket
+const UPLOAD_CHUNK: &[u8] = &[b'a'; 64 * 1024];
+const UPLOAD_CHUNKS: usize = 16;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Every limiter limits its own header, so each one costs a key and a store call. Buckets are large enough to never run out.
+fn manager(limiters: usize) -> RateLimiterManager {
+    let mut builder = RateLimiterBuilder::new().store(MockStore::new(MockClock::new()));
+    for index in 0..limiters {
+        builder = builder.limiter(PossibleStrategies::Header)
+            .log_decisions(DecisionLogging::Off)
+            .bucket_per_value(format!("X-Key-{}", index), 1_000_000_000, "1h");
+    }
+    builder.build().expect("Invalid benchmark limiters")
+}
+
+fn request(limiters: usize) -> TestRequest {
+    (0..limiters).fold(TestRequest::get("/api/orders"), |request, index| request.header(format!("X-Key-{}", index), "client"))
+}
+
+async fn run(manager: &RateLimiterManager, limiters: usize, requests: u64) {
+    for _ in 0..requests {
+        // Building the request is measured too, its headers are allocated like the ones of a real request
+        let request = request(limiters);
+        let addr = request.addr();
+        let response = manager.handle(request.into_request(), addr, |_| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }).await;
+        black_box(response.ok());
+    }
+}
+
+// Hands out the same chunk without copying it, so only the copies of the middleware are measured
+struct Upload(usize);
+
+impl hyper::body::Body for Upload {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.0 == 0 {
+            return Poll::Ready(None);
+        }
+        self.0 -= 1;
+        Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(UPLOAD_CHUNK)))))
+    }
+}
+
+fn upload_manager(strategy: PossibleStrategies) -> RateLimiterManager {
+    RateLimiterBuilder::new().store(MockStore::new(MockClock::new()))
+        .limiter(strategy)
+        .log_decisions(DecisionLogging::Off)
+        .bucket_per_value("X-Key-0", 1_000_000_000, "1h")
+        .build()
+        .expect("Invalid benchmark limiters")
+}
+
+// The upstream reads the whole upload, frame by frame like when it's written to a socket, before answering
+async fn run_uploads(manager: &RateLimiterManager, uploads: u64) {
+    for _ in 0..uploads {
+        let request = request(1);
+        let addr = request.addr();
+        let request = request.into_request().map(|_| Body::new(Upload(UPLOAD_CHUNKS)));
+        let response = manager.handle(request, addr, |request| async move {
+            let mut body = request.into_body();
+            while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+                black_box(frame.ok());
+            }
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }).await;
+        black_box(response.ok());
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Failed to build the Tokio runtime")
+}
+
+fn handle(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("handle");
+    group.throughput(Throughput::Elements(1));
+    for limiters in [1, 5, 20] {
+        let manager = runtime.block_on(async { manager(limiters) });
+
+        // criterion only measures time, the allocations are counted apart
+        runtime.block_on(run(&manager, limiters, ALLOCATION_REQUESTS));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        runtime.block_on(run(&manager, limiters, ALLOCATION_REQUESTS));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        println!("handle/{}: {} allocations/request", limiters, allocations / ALLOCATION_REQUESTS);
+
+        group.bench_with_input(BenchmarkId::from_parameter(limiters), &limiters, |b, &limiters| {
+            b.to_async(&runtime).iter(|| run(&manager, limiters, 1));
+        });
+    }
+    group.finish();
+}
+
+// Only the body strategy buffers the upload, others stream it to the upstream as it comes
+fn upload(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("upload");
+    group.throughput(Throughput::Bytes((UPLOAD_CHUNK.len() * UPLOAD_CHUNKS) as u64));
+    for (name, strategy) in [("header", PossibleStrategies::Header), ("body", PossibleStrategies::Body)] {
+        let manager = runtime.block_on(async { upload_manager(strategy) });
+        group.bench_function(name, |b| b.to_async(&runtime).iter(|| run_uploads(&manager, 1)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, handle, upload);
+criterion_main!(benches);
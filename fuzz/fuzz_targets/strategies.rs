@@ -0,0 +1,48 @@
+#![no_main]
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use axum::body::Bytes;
+use axum::http::{HeaderName, HeaderValue, Request};
+use libfuzzer_sys::fuzz_target;
+use rate_limiter::key::KeyBuilder;
+use rate_limiter::settings::KeySettings;
+use rate_limiter::strategy::{Bucket, BucketsPerValue, HeaderRateLimiterStrategy, RateLimiterChecker, RequestBodyRateLimiterStrategy, RequestQueryRateLimiterStrategy, SafeRequest, UrlRateLimiterStrategy};
+
+// Splits the input into header name, header value, path and body at 0xff bytes,
+// and checks that no strategy panics whatever the request looks like
+fuzz_target!(|data: &[u8]| {
+    let mut parts = data.split(|byte| *byte == 0xff);
+    let (name, value, path, body) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+
+    let mut builder = Request::builder();
+    if let Ok(path) = std::str::from_utf8(path) {
+        builder = builder.uri(path);
+    }
+    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name), HeaderValue::from_bytes(value)) {
+        builder = builder.header(name.clone(), value.clone()).header("authorization", value);
+    }
+    let Ok(request) = builder.body(()) else {
+        return;
+    };
+
+    let (parts, _) = request.into_parts();
+    let request = SafeRequest::new(parts, Bytes::copy_from_slice(body));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let global_bucket = Bucket::new(10, 60);
+    let buckets_per_value = BucketsPerValue::new([
+        (String::from_utf8_lossy(name).into_owned(), Bucket::new(5, 60)),
+        ("X-Api-Key".to_string(), Bucket::new(5, 60)),
+        ("id".to_string(), Bucket::new(5, 60)),
+    ]);
+    let key_builder = KeyBuilder::new(&KeySettings::default()).unwrap();
+
+    let _ = HeaderRateLimiterStrategy::new(Some("header".to_string()), HeaderName::from_static("authorization")).get_key(&request, addr, Some(&global_bucket), Some(&buckets_per_value), &key_builder);
+    let _ = UrlRateLimiterStrategy::default().get_key(&request, addr, Some(&global_bucket), Some(&buckets_per_value), &key_builder);
+    let _ = RequestQueryRateLimiterStrategy.get_key(&request, addr, Some(&global_bucket), Some(&buckets_per_value), &key_builder);
+    let _ = RequestBodyRateLimiterStrategy.get_key(&request, addr, Some(&global_bucket), Some(&buckets_per_value), &key_builder);
+});
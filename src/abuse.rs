@@ -0,0 +1,61 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+
+#[derive(Debug)]
+struct Denials {
+    window_started_at: Instant,
+    count: u32,
+}
+
+
+// Counts how often every client IP was denied within a fixed window, to tell abusive clients from ones that hit their limit once
+#[derive(Debug)]
+pub struct DenialCounter {
+    denials: Arc<DashMap<IpAddr, Denials>>,
+    window: Duration,
+}
+
+impl DenialCounter {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of old windows
+    pub fn new(window: Duration) -> Self {
+        let denials = Arc::new(DashMap::new());
+        tokio::spawn(remove_old_windows(Arc::downgrade(&denials), window));
+
+        Self {
+            denials,
+            window,
+        }
+    }
+
+    // Counts a denial and returns the number of denials of the client in the current window
+    pub fn record(&self, ip: IpAddr) -> u32 {
+        let now = Instant::now();
+        let mut denials = self.denials.entry(ip).or_insert_with(|| Denials { window_started_at: now, count: 0 });
+        if now.duration_since(denials.window_started_at) >= self.window {
+            denials.window_started_at = now;
+            denials.count = 0;
+        }
+        denials.count += 1;
+        denials.count
+    }
+
+    pub fn reset(&self, ip: IpAddr) {
+        self.denials.remove(&ip);
+    }
+}
+
+async fn remove_old_windows(denials: Weak<DashMap<IpAddr, Denials>>, window: Duration) {
+    let mut interval = tokio::time::interval(window);
+    loop {
+        interval.tick().await;
+
+        let Some(denials) = denials.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        denials.retain(|_, denials| now.duration_since(denials.window_started_at) < window);
+    }
+}
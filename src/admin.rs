@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use axum::routing::{get, post};
+use config::FileFormat;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower_service::Service;
+use crate::auth;
+use crate::connection_guard::ACCEPT_ERROR_PAUSE;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::maintenance::Maintenance;
+use crate::metrics;
+use crate::overrides::BucketOverride;
+use crate::server;
+use crate::snapshot::Snapshot;
+use crate::settings::{AdminRole, AdminSettings, AdminTlsSettings, Settings};
+use crate::strategy::SafeRequest;
+use crate::tenant::Tenant;
+
+// Clients slower than this to complete the handshake are disconnected, so they can't hold connections open
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+// Who may call the admin server: holders of one of the bearer tokens and, over TLS with a client CA, of a client certificate
+#[derive(Clone)]
+pub struct AdminAccess {
+    // Names and roles of the tokens by their hex SHA-256
+    tokens: Arc<HashMap<String, (String, AdminRole)>>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl AdminAccess {
+    pub fn new(settings: &AdminSettings) -> Result<Self, RateLimiterError> {
+        if let Some(name) = settings.roles.keys().find(|name| !settings.tokens.contains_key(*name)) {
+            return Err(RateLimiterError::config(format!("admin.roles names the unknown token {}", name)));
+        }
+        let tokens = settings.tokens.iter()
+            .map(|(name, hash)| {
+                let role = settings.roles.get(name).copied().unwrap_or_default();
+                Ok((auth::sha256_setting(hash, "admin token", name)?, (name.clone(), role)))
+            })
+            .collect::<Result<HashMap<_, _>, RateLimiterError>>()?;
+
+        Ok(Self {
+            tokens: Arc::new(tokens),
+            tls: settings.tls.as_ref().map(tls_acceptor).transpose()?,
+        })
+    }
+}
+
+fn tls_acceptor(settings: &AdminTlsSettings) -> Result<TlsAcceptor, RateLimiterError> {
+    let certificates = CertificateDer::pem_file_iter(&settings.cert_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| RateLimiterError::Tls(format!("Could not read the admin certificate {}: {}", settings.cert_path, e)))?;
+    let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+        .map_err(|e| RateLimiterError::Tls(format!("Could not read the admin key {}: {}", settings.key_path, e)))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| RateLimiterError::Tls(e.to_string()))?;
+    let builder = match &settings.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(ca_path).map_err(|e| RateLimiterError::Tls(format!("Could not read the admin client CA {}: {}", ca_path, e)))? {
+                let certificate = certificate.map_err(|e| RateLimiterError::Tls(format!("Invalid admin client CA {}: {}", ca_path, e)))?;
+                roots.add(certificate).map_err(|e| RateLimiterError::Tls(format!("Invalid admin client CA {}: {}", ca_path, e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| RateLimiterError::Tls(format!("Invalid admin client CA {}: {}", ca_path, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certificates, key)
+        .map_err(|e| RateLimiterError::Tls(format!("Invalid admin certificate {}: {}", settings.cert_path, e)))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+
+pub struct AdminServer {
+    access: AdminAccess,
+    state: Arc<AdminState>,
+}
+
+struct AdminState {
+    config: Settings,
+    limiter: Arc<RateLimiterManager>,
+    tenants: Arc<Vec<Tenant>>,
+    maintenance: Arc<Maintenance>,
+}
+
+impl AdminState {
+    // Limiter of the tenant, or the default one
+    fn limiter(&self, tenant: Option<&str>) -> Option<&Arc<RateLimiterManager>> {
+        match tenant {
+            Some(name) => self.tenants.iter().find(|tenant| tenant.name == name).map(|tenant| &tenant.limiter),
+            None => Some(&self.limiter),
+        }
+    }
+}
+
+impl AdminServer {
+    // `config` is the configuration the proxy was started with, exported as is on `/config`
+    pub fn new(access: AdminAccess, config: Settings, limiter: Arc<RateLimiterManager>, tenants: Arc<Vec<Tenant>>, maintenance: Arc<Maintenance>) -> Self {
+        Self {
+            access,
+            state: Arc::new(AdminState { config, limiter, tenants, maintenance }),
+        }
+    }
+
+    // `listener` is bound by the caller, so an address in use fails the startup of the proxy
+    pub async fn run(self, listener: TcpListener) -> Result<(), RateLimiterError> {
+        let app = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .route("/quota", get(quota_handler))
+            .route("/limits", get(limits_handler))
+            .route("/unique_clients", get(unique_clients_handler))
+            .route("/overrides", get(overrides_handler).post(set_override_handler).delete(remove_override_handler))
+            .route("/config", get(config_handler))
+            .route("/config/validate", post(validate_config_handler))
+            .route("/instances", get(instances_handler))
+            .route("/challenge", post(challenge_handler))
+            .route("/maintenance", get(maintenance_handler).post(set_maintenance_handler))
+            .route("/lockdown", post(set_lockdown_handler))
+            .route("/throttle", post(set_throttle_handler))
+            // Snapshots hold every counter, so they can be much larger than the default limit
+            .route("/snapshot", get(snapshot_handler).post(restore_snapshot_handler).layer(DefaultBodyLimit::disable()))
+            .with_state(self.state);
+        let app = match self.access.tokens.is_empty() {
+            true => app,
+            false => app.layer(middleware::from_fn_with_state(self.access.tokens.clone(), bearer_auth)),
+        };
+
+        match self.access.tls {
+            Some(acceptor) => serve_tls(listener, app, acceptor).await,
+            None => Ok(axum::serve(listener, app).await?),
+        }
+    }
+}
+
+// Least role allowed to call an endpoint, everything that doesn't change state is read-only
+fn required_role(method: &Method, path: &str) -> AdminRole {
+    match (method, path) {
+        (&Method::GET | &Method::HEAD, _) | (&Method::POST, "/config/validate") => AdminRole::ReadOnly,
+        (_, "/overrides" | "/snapshot") => AdminRole::Admin,
+        _ => AdminRole::Operator,
+    }
+}
+
+// Requests without one of the tokens get a 401 whatever the endpoint, ones with a token of a lesser role than the endpoint needs a 403
+async fn bearer_auth(State(tokens): State<Arc<HashMap<String, (String, AdminRole)>>>, request: Request<Body>, next: Next) -> Response {
+    let token = request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return unauthorized("missing_token");
+    };
+    let Some((name, role)) = tokens.get(&hex::encode(Sha256::digest(token.trim().as_bytes()))) else {
+        return unauthorized("invalid_token");
+    };
+
+    let required_role = required_role(request.method(), request.uri().path());
+    if *role < required_role {
+        println!("Admin token {} was denied {} {}", name, request.method(), request.uri().path());
+        metrics::increment_counter("rate_limiter_admin_auth_failures_total", &[("reason", "forbidden")]);
+        return (StatusCode::FORBIDDEN, format!("Needs the {} role", required_role.as_str())).into_response();
+    }
+    next.run(request).await
+}
+
+fn unauthorized(reason: &str) -> Response {
+    metrics::increment_counter("rate_limiter_admin_auth_failures_total", &[("reason", reason)]);
+    (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")], "Unauthorized").into_response()
+}
+
+// Serves `app` like axum::serve does, over TLS. Clients without a valid certificate never get past the handshake.
+async fn serve_tls(listener: TcpListener, app: Router, acceptor: TlsAcceptor) -> Result<(), RateLimiterError> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to accept an admin connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            },
+        };
+
+        let (app, acceptor) = (app.clone(), acceptor.clone());
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    metrics::increment_counter("rate_limiter_admin_auth_failures_total", &[("reason", "tls_handshake")]);
+                    return eprintln!("Admin TLS handshake with {} failed: {}", addr, e);
+                },
+                Err(_) => return,
+            };
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| app.clone().call(request.map(Body::new)));
+            // Clients closing their connection early are common, so connection errors aren't logged
+            let _ = auto::Builder::new(TokioExecutor::new()).serve_connection_with_upgrades(TokioIo::new(stream), service).await;
+        });
+    }
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render())
+}
+
+#[derive(Deserialize, Debug)]
+struct QuotaQuery {
+    name: String,
+    value: String,
+    tenant: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct QuotaResponse {
+    name: String,
+    value: String,
+    limit: u32,
+    used: u32,
+    remaining: u32,
+    resets_in: u64,
+}
+
+// Usage of a client in the current period of a quota, e.g. `/quota?name=monthly&value=<api key>`
+async fn quota_handler(State(state): State<Arc<AdminState>>, Query(query): Query<QuotaQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    match limiter.quota_usage(&query.name, &query.value).await {
+        Some(Ok(usage)) => Json(QuotaResponse {
+            limit: usage.limit,
+            used: (i64::from(usage.limit) - i64::from(usage.remaining)).clamp(0, i64::from(usage.limit)) as u32,
+            remaining: usage.remaining.max(0) as u32,
+            resets_in: usage.resets_in,
+            name: query.name,
+            value: query.value,
+        }).into_response(),
+        Some(Err(e)) => (StatusCode::SERVICE_UNAVAILABLE, format!("Could not read the quota: {}", e)).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Unknown quota {}", query.name)).into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LimitsQuery {
+    ip: IpAddr,
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default = "default_method")]
+    method: String,
+    // `Name: value`, e.g. `X-Api-Key: abc`
+    header: Option<String>,
+    tenant: Option<String>,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+// Budget a client has left in every limiter that would apply to the request, without charging it,
+// e.g. `/limits?ip=1.2.3.4&path=/api/orders&header=X-Api-Key:%20abc`
+async fn limits_handler(State(state): State<Arc<AdminState>>, Query(query): Query<LimitsQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    let mut request = Request::builder().method(query.method.as_str()).uri(query.path.as_str());
+    if let Some(header) = &query.header {
+        let Some((name, value)) = header.split_once(':') else {
+            return (StatusCode::BAD_REQUEST, format!("Invalid header {}, expected `Name: value`", header)).into_response();
+        };
+        request = request.header(name.trim(), value.trim());
+    }
+    let parts = match request.body(()) {
+        Ok(request) => request.into_parts().0,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)).into_response(),
+    };
+
+    let mut request = SafeRequest::new(parts, Default::default());
+    limiter.classify_ip(&mut request, query.ip);
+    Json(limiter.peek(&request, SocketAddr::new(query.ip, 0)).await).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct TenantQuery {
+    tenant: Option<String>,
+}
+
+// Distinct clients per route in the current window, e.g. `/unique_clients?tenant=acme`
+async fn unique_clients_handler(State(state): State<Arc<AdminState>>, Query(query): Query<TenantQuery>) -> Response {
+    match state.limiter(query.tenant.as_deref()) {
+        Some(limiter) => Json(limiter.unique_clients()).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response(),
+    }
+}
+
+// Overrides in effect, e.g. `/overrides?tenant=acme`
+async fn overrides_handler(State(state): State<Arc<AdminState>>, Query(query): Query<TenantQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    match limiter.overrides() {
+        Some(overrides) => Json(overrides.list()).into_response(),
+        None => (StatusCode::NOT_FOUND, "Overrides are not configured").into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OverrideRequest {
+    limiter: String,
+    value: String,
+    tokens_count: Option<u32>,
+    add_tokens_every: Option<u32>,
+    burst: Option<u32>,
+    multiplier: Option<u32>,
+    ttl_secs: u64,
+}
+
+// Sets the override of a value of a limiter, replacing the previous one,
+// e.g. `curl -d '{"limiter":"api-keys","value":"X-Api-Key:abc","multiplier":10,"ttl_secs":86400}' /overrides`
+async fn set_override_handler(State(state): State<Arc<AdminState>>, Query(query): Query<TenantQuery>, Json(request): Json<OverrideRequest>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+    let Some(overrides) = limiter.overrides() else {
+        return (StatusCode::NOT_FOUND, "Overrides are not configured").into_response();
+    };
+    let Some(key) = limiter.override_key(&request.limiter, &request.value) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown limiter {}", request.limiter)).into_response();
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default();
+    let bucket_override = BucketOverride {
+        limiter: request.limiter,
+        value: request.value,
+        tokens_count: request.tokens_count,
+        add_tokens_every: request.add_tokens_every,
+        burst: request.burst,
+        multiplier: request.multiplier,
+        expires_at: now.saturating_add(request.ttl_secs),
+    };
+    match overrides.set(key, bucket_override.clone()).await {
+        Ok(()) => {
+            limiter.restart_warm_up();
+            Json(bucket_override).into_response()
+        },
+        Err(e @ RateLimiterError::Config(_)) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Could not store the override: {}", e)).into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RemoveOverrideQuery {
+    limiter: String,
+    value: String,
+    tenant: Option<String>,
+}
+
+// Removes an override before it expires, e.g. `DELETE /overrides?limiter=api-keys&value=X-Api-Key:abc`
+async fn remove_override_handler(State(state): State<Arc<AdminState>>, Query(query): Query<RemoveOverrideQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+    let Some(overrides) = limiter.overrides() else {
+        return (StatusCode::NOT_FOUND, "Overrides are not configured").into_response();
+    };
+    let Some(key) = limiter.override_key(&query.limiter, &query.value) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown limiter {}", query.limiter)).into_response();
+    };
+
+    match overrides.remove(&key).await {
+        Ok(true) => {
+            limiter.restart_warm_up();
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Ok(false) => (StatusCode::NOT_FOUND, "No override for this value").into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Could not remove the override: {}", e)).into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ConfigFormat {
+    #[default]
+    Json,
+    Toml,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfigQuery {
+    #[serde(default)]
+    format: ConfigFormat,
+}
+
+// Active configuration with the defaults and environment overrides applied and the secrets redacted, e.g. `/config?format=toml`
+async fn config_handler(State(state): State<Arc<AdminState>>, Query(query): Query<ConfigQuery>) -> Response {
+    match query.format {
+        ConfigFormat::Json => Json(&state.config).into_response(),
+        ConfigFormat::Toml => match toml::to_string(&state.config) {
+            Ok(config) => ([(CONTENT_TYPE, "application/toml")], config).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not export the configuration: {}", e)).into_response(),
+        },
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ValidationResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    changes: Vec<ConfigChange>,
+}
+
+#[derive(Serialize, Debug)]
+struct ConfigChange {
+    // e.g. `rate_limiter.limiter[0].global_bucket.tokens_count`
+    path: String,
+    before: Value,
+    after: Value,
+}
+
+// Checks a candidate configuration like a startup would and lists what it changes, without applying it,
+// e.g. `curl --data-binary @Settings.toml '/config/validate?format=toml'`
+async fn validate_config_handler(State(state): State<Arc<AdminState>>, Query(query): Query<ConfigQuery>, body: String) -> Response {
+    let format = match query.format {
+        ConfigFormat::Json => FileFormat::Json,
+        ConfigFormat::Toml => FileFormat::Toml,
+    };
+    let candidate = match Settings::parse(&body, format) {
+        Ok(candidate) => candidate,
+        Err(e) => return invalid_config(e.to_string()),
+    };
+    let candidate = match tokio::task::spawn_blocking(move || server::validate(&candidate).map(|()| candidate)).await {
+        Ok(Ok(candidate)) => candidate,
+        Ok(Err(e)) => return invalid_config(e.to_string()),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not validate the configuration: {}", e)).into_response(),
+    };
+
+    // Both sides are compared with their secrets redacted, so changed secrets don't show up
+    let (before, after) = match (serde_json::to_value(&state.config), serde_json::to_value(&candidate)) {
+        (Ok(before), Ok(after)) => (before, after),
+        (Err(e), _) | (_, Err(e)) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Could not compare the configurations: {}", e)).into_response(),
+    };
+    let mut changes = Vec::new();
+    diff(String::new(), before, after, &mut changes);
+    Json(ValidationResponse { valid: true, error: None, changes }).into_response()
+}
+
+fn invalid_config(error: String) -> Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationResponse { valid: false, error: Some(error), changes: Vec::new() })).into_response()
+}
+
+// Tables are compared key by key and arrays item by item, down to the values that differ
+fn diff(path: String, before: Value, after: Value, changes: &mut Vec<ConfigChange>) {
+    match (before, after) {
+        (Value::Object(mut before), Value::Object(mut after)) => {
+            let mut keys = before.keys().chain(after.keys()).cloned().collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys.dedup();
+            for key in keys {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                diff(path, before.remove(&key).unwrap_or(Value::Null), after.remove(&key).unwrap_or(Value::Null), changes);
+            }
+        },
+        (Value::Array(before), Value::Array(after)) => {
+            let len = before.len().max(after.len());
+            let (mut before, mut after) = (before.into_iter(), after.into_iter());
+            for index in 0..len {
+                diff(format!("{}[{}]", path, index), before.next().unwrap_or(Value::Null), after.next().unwrap_or(Value::Null), changes);
+            }
+        },
+        (before, after) if before != after => changes.push(ConfigChange { path, before, after }),
+        _ => {},
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct InstancesResponse {
+    instance_id: String,
+    instances: Vec<String>,
+}
+
+// Live instances as of the last heartbeat of this one
+async fn instances_handler(State(state): State<Arc<AdminState>>) -> Response {
+    match state.limiter.cluster() {
+        Some(cluster) => Json(InstancesResponse {
+            instance_id: cluster.instance_id().to_string(),
+            instances: cluster.instances(),
+        }).into_response(),
+        None => (StatusCode::NOT_FOUND, "Cluster membership is not configured").into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ChallengeQuery {
+    token: String,
+    tenant: Option<String>,
+}
+
+// Called by the verification service once a client solved its challenge, e.g. `POST /challenge?token=<token>`
+async fn challenge_handler(State(state): State<Arc<AdminState>>, Query(query): Query<ChallengeQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    match limiter.challenge() {
+        Some(challenge) if challenge.solve(&query.token) => StatusCode::NO_CONTENT.into_response(),
+        Some(_) => (StatusCode::NOT_FOUND, "Unknown or expired challenge token").into_response(),
+        None => (StatusCode::NOT_FOUND, "Challenges are not configured").into_response(),
+    }
+}
+
+async fn maintenance_handler(State(state): State<Arc<AdminState>>) -> Response {
+    Json(state.maintenance.state()).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct MaintenanceQuery {
+    route: String,
+    enabled: bool,
+}
+
+// Puts a path prefix under maintenance or takes it out, e.g. `POST /maintenance?route=/api&enabled=true`
+async fn set_maintenance_handler(State(state): State<Arc<AdminState>>, Query(query): Query<MaintenanceQuery>) -> Response {
+    match state.maintenance.set_route(&query.route, query.enabled) {
+        Ok(()) => Json(state.maintenance.state()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct LockdownQuery {
+    enabled: bool,
+}
+
+// Blocks every client outside of the ip_whitelist, e.g. `POST /lockdown?enabled=true`
+async fn set_lockdown_handler(State(state): State<Arc<AdminState>>, Query(query): Query<LockdownQuery>) -> Response {
+    state.maintenance.set_lockdown(query.enabled);
+    Json(state.maintenance.state()).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct ThrottleQuery {
+    percent: u8,
+}
+
+// Rejects a share of the traffic of non-whitelisted clients at random, e.g. `POST /throttle?percent=30`, `percent=0` stops it
+async fn set_throttle_handler(State(state): State<Arc<AdminState>>, Query(query): Query<ThrottleQuery>) -> Response {
+    match state.maintenance.set_throttle(query.percent) {
+        Ok(()) => Json(state.maintenance.state()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+// Counters, bans and overrides of the limiter, e.g. `curl -o snapshot.json '/snapshot?tenant=acme'`
+async fn snapshot_handler(State(state): State<Arc<AdminState>>, Query(query): Query<TenantQuery>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    match limiter.snapshot().await {
+        Ok(snapshot) => Json(snapshot).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Could not export the snapshot: {}", e)).into_response(),
+    }
+}
+
+// Writes a snapshot back, e.g. `curl --data-binary @snapshot.json -H 'Content-Type: application/json' /snapshot`
+async fn restore_snapshot_handler(State(state): State<Arc<AdminState>>, Query(query): Query<TenantQuery>, Json(snapshot): Json<Snapshot>) -> Response {
+    let Some(limiter) = state.limiter(query.tenant.as_deref()) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown tenant {}", query.tenant.unwrap_or_default())).into_response();
+    };
+
+    match limiter.restore(&snapshot).await {
+        Ok(restored) => Json(restored).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Could not restore the snapshot: {}", e)).into_response(),
+    }
+}
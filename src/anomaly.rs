@@ -0,0 +1,230 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use axum::body::Body;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use dashmap::DashMap;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::AnomalyDetectionSettings;
+use crate::strategy::LimitKey;
+
+// Keys idle for this many intervals have a baseline close to 0 and are dropped
+const IDLE_INTERVALS: u64 = 10;
+const EVENTS_CAPACITY: usize = 1024;
+
+
+// A key whose requests in the current interval went over `factor` times its baseline
+#[derive(Serialize, Debug, Clone)]
+pub struct Anomaly {
+    pub limiter: String,
+    pub key: String,
+    pub requests: u64,
+    pub baseline: f64,
+    // Set when the key got the stricter bucket of the penalty
+    pub penalized_for_secs: Option<u64>,
+    // Unix time in seconds
+    pub at: u64,
+}
+
+#[derive(Debug)]
+struct KeyRate {
+    interval: u64,
+    requests: u64,
+    // EWMA of the requests per interval, None during the first interval of the key
+    baseline: Option<f64>,
+    is_flagged: bool,
+    penalized_until: Option<Instant>,
+}
+
+
+// Learns the usual request rate of every limiter key and flags keys that suddenly send far more, e.g. a leaked
+// API key or a scraper ramping up, before their buckets would notice. Rates are counted by this instance only.
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    interval_secs: u64,
+    smoothing: f64,
+    factor: f64,
+    min_requests: u64,
+    max_keys: usize,
+    // Divisor of the tokens and burst, and duration of the penalty
+    penalty: Option<(u32, Duration)>,
+    rates: DashMap<String, KeyRate>,
+    events: broadcast::Sender<Anomaly>,
+}
+
+impl AnomalyDetector {
+    // Must be called inside a tokio runtime, as it spawns the cleanup and the webhook
+    pub fn new(settings: &AnomalyDetectionSettings) -> Result<Arc<Self>, RateLimiterError> {
+        if settings.interval_secs == 0 || settings.max_keys == 0 {
+            return Err(RateLimiterError::config("anomaly_detection.interval_secs and max_keys must be greater than 0"));
+        }
+        if !(settings.smoothing > 0.0 && settings.smoothing <= 1.0) || settings.factor <= 1.0 {
+            return Err(RateLimiterError::config("anomaly_detection.smoothing must be in (0, 1] and factor greater than 1"));
+        }
+        let penalty = match &settings.penalty {
+            Some(penalty) if penalty.divisor < 2 || penalty.duration_secs == 0 => {
+                return Err(RateLimiterError::config("anomaly_detection.penalty needs a divisor of at least 2 and duration_secs greater than 0"));
+            },
+            Some(penalty) => Some((penalty.divisor, Duration::from_secs(penalty.duration_secs))),
+            None => None,
+        };
+        if let Some(url) = &settings.webhook_url && !url.starts_with("http://") {
+            return Err(RateLimiterError::config(format!("anomaly_detection.webhook_url {} must be an http:// URL", url)));
+        }
+
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let detector = Arc::new(Self {
+            interval_secs: settings.interval_secs,
+            smoothing: settings.smoothing,
+            factor: settings.factor,
+            min_requests: settings.min_requests,
+            max_keys: settings.max_keys,
+            penalty,
+            rates: DashMap::new(),
+            events,
+        });
+        tokio::spawn(remove_idle_keys(Arc::downgrade(&detector)));
+        if let Some(url) = &settings.webhook_url {
+            tokio::spawn(post_anomalies(detector.subscribe(), url.clone()));
+        }
+        Ok(detector)
+    }
+
+    // Anomalies flagged from now on. Receivers lagging more than 1024 events behind lose the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<Anomaly> {
+        self.events.subscribe()
+    }
+
+    // Counts a request of the key and flags it the first time in the interval it goes over its baseline
+    pub fn record(&self, limiter: &str, key: &str) {
+        if self.rates.len() >= self.max_keys && !self.rates.contains_key(key) {
+            return;
+        }
+
+        let interval = unix_now() / self.interval_secs;
+        let mut rate = self.rates.entry(key.to_string()).or_insert_with(|| KeyRate {
+            interval,
+            requests: 0,
+            baseline: None,
+            is_flagged: false,
+            penalized_until: None,
+        });
+        if rate.interval < interval {
+            self.close_interval(&mut rate, interval);
+        }
+        rate.requests += 1;
+
+        let Some(baseline) = rate.baseline else {
+            return;
+        };
+        if rate.is_flagged || rate.requests < self.min_requests || (rate.requests as f64) <= baseline * self.factor {
+            return;
+        }
+        rate.is_flagged = true;
+        if let Some((_, duration)) = self.penalty {
+            rate.penalized_until = Some(Instant::now() + duration);
+        }
+        let anomaly = Anomaly {
+            limiter: limiter.to_string(),
+            key: key.to_string(),
+            requests: rate.requests,
+            baseline,
+            penalized_for_secs: self.penalty.map(|(_, duration)| duration.as_secs()),
+            at: unix_now(),
+        };
+        drop(rate);
+
+        metrics::increment_counter("rate_limiter_anomalies_total", &[("limiter", limiter)]);
+        println!("Anomaly: limiter={} key={} requests={} baseline={:.1}", anomaly.limiter, anomaly.key, anomaly.requests, anomaly.baseline);
+        // Fails only when nobody subscribed
+        let _ = self.events.send(anomaly);
+    }
+
+    // Penalized keys are limited by a bucket of their own, `divisor` times smaller, until the penalty ends
+    pub fn penalize(&self, limit_key: &mut LimitKey) {
+        let Some((divisor, _)) = self.penalty else {
+            return;
+        };
+        let is_penalized = self.rates.get(&limit_key.key)
+            .and_then(|rate| rate.penalized_until)
+            .is_some_and(|penalized_until| penalized_until > Instant::now());
+        if is_penalized {
+            limit_key.key.push_str(":penalty");
+            limit_key.bucket.tokens_count = (limit_key.bucket.tokens_count / divisor).max(1);
+            limit_key.bucket.burst = limit_key.bucket.burst.map(|burst| (burst / divisor).max(1));
+        }
+    }
+
+    // Flagged intervals are left out of the baseline, so an attack doesn't become the norm. Intervals without
+    // requests lower it like intervals of 0 requests would.
+    fn close_interval(&self, rate: &mut KeyRate, interval: u64) {
+        if !rate.is_flagged {
+            rate.baseline = Some(match rate.baseline {
+                Some(baseline) => baseline + self.smoothing * (rate.requests as f64 - baseline),
+                None => rate.requests as f64,
+            });
+        }
+        let idle_intervals = (interval - rate.interval - 1).min(IDLE_INTERVALS) as i32;
+        rate.baseline = rate.baseline.map(|baseline| baseline * (1.0 - self.smoothing).powi(idle_intervals));
+        (rate.interval, rate.requests, rate.is_flagged) = (interval, 0, false);
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+async fn remove_idle_keys(detector: Weak<AnomalyDetector>) {
+    let Some(every) = detector.upgrade().map(|detector| Duration::from_secs(detector.interval_secs)) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(detector) = detector.upgrade() else {
+            return;
+        };
+        let (now, current) = (Instant::now(), unix_now() / detector.interval_secs);
+        detector.rates.retain(|_, rate| {
+            rate.interval + IDLE_INTERVALS > current || rate.penalized_until.is_some_and(|penalized_until| penalized_until > now)
+        });
+    }
+}
+
+// Posts every anomaly as JSON to the webhook, stops once the detector is dropped
+async fn post_anomalies(mut events: broadcast::Receiver<Anomaly>, url: String) {
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    loop {
+        let anomaly = match events.recv().await {
+            Ok(anomaly) => anomaly,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!("Skipped posting {} anomalies to {}", skipped, url);
+                continue;
+            },
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let request = match serde_json::to_string(&anomaly) {
+            Ok(payload) => Request::post(url.as_str()).header(CONTENT_TYPE, "application/json").body(Body::from(payload)),
+            Err(e) => {
+                println!("Failed to serialize an anomaly: {}", e);
+                continue;
+            },
+        };
+        let result = match request {
+            Ok(request) => client.request(request).await.map(|response| response.status()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(status) if status.is_success() => {},
+            Ok(status) => println!("Failed to post an anomaly to {}: answered {}", url, status),
+            Err(e) => println!("Failed to post an anomaly to {}: {}", url, e),
+        }
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::body::{to_bytes, Body};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::response::IntoResponse;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use deadpool_redis::redis;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use ring::hmac;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use url::form_urlencoded;
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::{AuthSettings, HmacAlgorithm, HmacAuthSettings, IntrospectionSettings, JwtAuthSettings, SignatureEncoding};
+use crate::strategy::Bucket;
+
+// JWKS are fetched again at most this often when a token names a key they don't have
+const MIN_JWKS_REFETCH: Duration = Duration::from_secs(10);
+const MAX_JWKS_BYTES: usize = 1 << 20;
+const MAX_INTROSPECTION_BYTES: usize = 1 << 16;
+// Expired results are dropped from the memory cache once it holds that many
+const MAX_CACHED_INTROSPECTIONS: usize = 100_000;
+
+
+// Who the request was authenticated as: the name of the API key, the user of basic auth or the identity claim of the JWT.
+// Inserted in the extensions of authenticated requests, where the identity strategy reads it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity(pub String);
+
+
+#[derive(Debug, Clone)]
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    // Uncompressed points
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+impl PublicKey {
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        let result = match (self, alg) {
+            (PublicKey::Rsa { n, e }, "RS256") => RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+            (PublicKey::Rsa { n, e }, "RS384") => RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA384, message, signature),
+            (PublicKey::Rsa { n, e }, "RS512") => RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA512, message, signature),
+            (PublicKey::P256(point), "ES256") => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point).verify(message, signature),
+            (PublicKey::P384(point), "ES384") => UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point).verify(message, signature),
+            (PublicKey::Ed25519(x), "EdDSA") => UnparsedPublicKey::new(&signature::ED25519, x).verify(message, signature),
+            _ => return false,
+        };
+        result.is_ok()
+    }
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+// Keys of unsupported types are skipped, a JWKS may hold keys meant for other services
+fn parse_jwks(bytes: &[u8]) -> Result<Vec<(Option<String>, PublicKey)>, String> {
+    let jwks: JwkSet = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+    let decode = |value: &Option<String>| value.as_deref().and_then(|value| URL_SAFE_NO_PAD.decode(value).ok());
+    Ok(jwks.keys.into_iter()
+        .filter_map(|jwk| {
+            let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+                ("RSA", _) => PublicKey::Rsa { n: decode(&jwk.n)?, e: decode(&jwk.e)? },
+                ("EC", Some("P-256")) => PublicKey::P256([vec![4], decode(&jwk.x)?, decode(&jwk.y)?].concat()),
+                ("EC", Some("P-384")) => PublicKey::P384([vec![4], decode(&jwk.x)?, decode(&jwk.y)?].concat()),
+                ("OKP", Some("Ed25519")) => PublicKey::Ed25519(decode(&jwk.x)?),
+                _ => return None,
+            };
+            Some((jwk.kid, key))
+        })
+        .collect())
+}
+
+
+#[derive(Debug)]
+enum JwksSource {
+    // http:// only
+    Url(String),
+    Path(String),
+}
+
+#[derive(Debug, Default)]
+struct JwksCache {
+    keys: Vec<(Option<String>, PublicKey)>,
+    fetched_at: Option<Instant>,
+}
+
+// Validates the signature and the registered claims of JWTs, with the keys of a JWKS fetched on first use and again every `refresh_secs`
+#[derive(Debug)]
+struct JwtValidator {
+    source: JwksSource,
+    refresh: Duration,
+    cache: RwLock<JwksCache>,
+    // Held while fetching, so concurrent requests don't all fetch the JWKS
+    fetching: tokio::sync::Mutex<()>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    identity_claim: String,
+    leeway_secs: u64,
+}
+
+impl JwtValidator {
+    fn new(settings: &JwtAuthSettings) -> Result<Self, RateLimiterError> {
+        let source = match (&settings.jwks_url, &settings.jwks_path) {
+            (Some(url), None) if url.starts_with("http://") => JwksSource::Url(url.clone()),
+            (Some(url), None) => return Err(RateLimiterError::config(format!("jwks_url {} must be an http:// URL, use jwks_path for other sources", url))),
+            (None, Some(path)) => JwksSource::Path(path.clone()),
+            _ => return Err(RateLimiterError::config("jwt auth needs either jwks_url or jwks_path")),
+        };
+        if settings.refresh_secs == 0 {
+            return Err(RateLimiterError::config("jwt refresh_secs must be greater than 0"));
+        }
+
+        Ok(Self {
+            source,
+            refresh: Duration::from_secs(settings.refresh_secs),
+            cache: RwLock::new(JwksCache::default()),
+            fetching: tokio::sync::Mutex::new(()),
+            issuer: settings.issuer.clone(),
+            audience: settings.audience.clone(),
+            identity_claim: settings.identity_claim.clone(),
+            leeway_secs: settings.leeway_secs,
+        })
+    }
+
+    async fn validate(&self, token: &str) -> Result<Identity, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err("malformed_token");
+        };
+        let decode_json = |part: &str| URL_SAFE_NO_PAD.decode(part).ok().and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok());
+        let (Some(header), Some(claims), Ok(signature)) = (decode_json(header), decode_json(payload), URL_SAFE_NO_PAD.decode(signature)) else {
+            return Err("malformed_token");
+        };
+        let alg = header.get("alg").and_then(Value::as_str).ok_or("malformed_token")?;
+        let kid = header.get("kid").and_then(Value::as_str);
+
+        let message = &token.as_bytes()[..token.rfind('.').unwrap_or_default()];
+        self.refresh_keys(kid).await;
+        let is_verified = self.cache.read().unwrap_or_else(|e| e.into_inner()).keys.iter()
+            .filter(|(key_id, _)| kid.is_none() || key_id.as_deref() == kid)
+            .any(|(_, key)| key.verify(alg, message, &signature));
+        if !is_verified {
+            return Err("invalid_signature");
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default();
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if exp + self.leeway_secs >= now => {},
+            _ => return Err("expired_token"),
+        }
+        if claims.get("nbf").and_then(Value::as_u64).is_some_and(|nbf| nbf > now + self.leeway_secs) {
+            return Err("expired_token");
+        }
+        if let Some(issuer) = &self.issuer && claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+            return Err("invalid_claims");
+        }
+        if let Some(audience) = &self.audience {
+            let has_audience = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !has_audience {
+                return Err("invalid_claims");
+            }
+        }
+
+        match claims.get(&self.identity_claim) {
+            Some(Value::String(identity)) => Ok(Identity(identity.clone())),
+            Some(Value::Number(identity)) => Ok(Identity(identity.to_string())),
+            _ => Err("invalid_claims"),
+        }
+    }
+
+    // Fetches the JWKS when it's stale, or when the token names a key it doesn't have and it wasn't fetched just before
+    async fn refresh_keys(&self, kid: Option<&str>) {
+        let is_stale = |cache: &JwksCache| match cache.fetched_at {
+            Some(fetched_at) => fetched_at.elapsed() >= self.refresh
+                || (fetched_at.elapsed() >= MIN_JWKS_REFETCH && kid.is_some() && !cache.keys.iter().any(|(key_id, _)| key_id.as_deref() == kid)),
+            None => true,
+        };
+        if !is_stale(&self.cache.read().unwrap_or_else(|e| e.into_inner())) {
+            return;
+        }
+
+        let _fetching = self.fetching.lock().await;
+        // Another request may have fetched it while this one waited
+        if !is_stale(&self.cache.read().unwrap_or_else(|e| e.into_inner())) {
+            return;
+        }
+        let keys = self.fetch().await;
+        let mut cache = self.cache.write().unwrap_or_else(|e| e.into_inner());
+        // On errors the last known keys are kept
+        match keys {
+            Ok(keys) => cache.keys = keys,
+            Err(e) => println!("Failed to fetch the JWKS: {}", e),
+        }
+        cache.fetched_at = Some(Instant::now());
+    }
+
+    async fn fetch(&self) -> Result<Vec<(Option<String>, PublicKey)>, String> {
+        let bytes = match &self.source {
+            JwksSource::Path(path) => tokio::fs::read(path).await.map_err(|e| format!("{}: {}", path, e))?.into(),
+            JwksSource::Url(url) => {
+                let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+                let request = Request::get(url.as_str()).body(Body::empty()).map_err(|e| e.to_string())?;
+                let response = client.request(request).await.map_err(|e| format!("{}: {}", url, e))?;
+                if !response.status().is_success() {
+                    return Err(format!("{} answered {}", url, response.status()));
+                }
+                to_bytes(Body::new(response.into_body()), MAX_JWKS_BYTES).await.map_err(|e| format!("{}: {}", url, e))?
+            },
+        };
+        parse_jwks(&bytes)
+    }
+}
+
+
+// Validates opaque tokens with RFC 7662 introspection. Results are cached by the SHA-256 of the token, under
+// `<prefix>:introspection:<hash>` in Redis with the redis backend so every instance shares them, in memory otherwise.
+#[derive(Debug)]
+struct Introspector {
+    endpoint: String,
+    authorization: Option<HeaderValue>,
+    identity_claim: String,
+    cache_secs: u64,
+    negative_cache_secs: u64,
+    key_prefix: String,
+    pool: Option<RedisPool>,
+    // Identity of active tokens, None for inactive ones, and the expiry of the result
+    cache: DashMap<String, (Option<String>, Instant)>,
+    client: Client<HttpConnector, Body>,
+}
+
+impl Introspector {
+    fn new(settings: &IntrospectionSettings, pool: Option<RedisPool>, key_prefix: &str) -> Result<Self, RateLimiterError> {
+        if !settings.endpoint.starts_with("http://") {
+            return Err(RateLimiterError::config(format!("introspection endpoint {} must be an http:// URL", settings.endpoint)));
+        }
+        let authorization = match (&settings.client_id, &settings.client_secret) {
+            (Some(client_id), Some(client_secret)) => Some(HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(format!("{}:{}", client_id, client_secret))))
+                .map_err(|_| RateLimiterError::config("Invalid introspection client_id or client_secret"))?),
+            (None, None) => None,
+            _ => return Err(RateLimiterError::config("introspection needs both client_id and client_secret, or neither")),
+        };
+
+        Ok(Self {
+            endpoint: settings.endpoint.clone(),
+            authorization,
+            identity_claim: settings.identity_claim.clone(),
+            cache_secs: settings.cache_secs,
+            negative_cache_secs: settings.negative_cache_secs,
+            key_prefix: format!("{}:introspection", key_prefix),
+            pool,
+            cache: DashMap::new(),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        })
+    }
+
+    async fn validate(&self, token: &str) -> Result<Identity, &'static str> {
+        let hash = sha256_hex(token.as_bytes());
+        let identity = match self.cached(&hash).await {
+            Some(identity) => identity,
+            None => {
+                let (identity, ttl_secs) = self.introspect(token).await.map_err(|e| {
+                    println!("Failed to introspect a token at {}: {}", self.endpoint, e);
+                    "introspection_failed"
+                })?;
+                self.cache(&hash, identity.as_deref(), ttl_secs).await;
+                identity
+            },
+        };
+        identity.map(Identity).ok_or("inactive_token")
+    }
+
+    // None when the token is not cached, Some(None) when it's cached as inactive
+    async fn cached(&self, hash: &str) -> Option<Option<String>> {
+        let Some(pool) = &self.pool else {
+            return self.cache.get(hash)
+                .filter(|entry| entry.1 > Instant::now())
+                .map(|entry| entry.0.clone());
+        };
+
+        let result = async {
+            let mut connection = pool.get().await?;
+            redis::cmd("GET").arg(format!("{}:{}", self.key_prefix, hash)).query_async::<Option<String>>(&mut connection).await
+                .map_err(RateLimiterError::from)
+        }.await;
+        // Inactive tokens are cached as an empty identity. Without the cache the token is introspected again.
+        match result {
+            Ok(identity) => identity.map(|identity| Some(identity).filter(|identity| !identity.is_empty())),
+            Err(e) => {
+                println!("Failed to read a cached introspection: {}", e);
+                None
+            },
+        }
+    }
+
+    async fn cache(&self, hash: &str, identity: Option<&str>, ttl_secs: u64) {
+        if ttl_secs == 0 {
+            return;
+        }
+        let Some(pool) = &self.pool else {
+            if self.cache.len() >= MAX_CACHED_INTROSPECTIONS {
+                let now = Instant::now();
+                self.cache.retain(|_, (_, expires_at)| *expires_at > now);
+            }
+            self.cache.insert(hash.to_string(), (identity.map(str::to_string), Instant::now() + Duration::from_secs(ttl_secs)));
+            return;
+        };
+
+        let result = async {
+            let mut connection = pool.get().await?;
+            redis::cmd("SET").arg(format!("{}:{}", self.key_prefix, hash)).arg(identity.unwrap_or_default()).arg("EX").arg(ttl_secs)
+                .query_async::<()>(&mut connection).await
+                .map_err(RateLimiterError::from)
+        }.await;
+        if let Err(e) = result {
+            println!("Failed to cache an introspection: {}", e);
+        }
+    }
+
+    // The identity of the token if it's active, and how long the result may be cached
+    async fn introspect(&self, token: &str) -> Result<(Option<String>, u64), String> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .append_pair("token_type_hint", "access_token")
+            .finish();
+        let mut request = Request::post(self.endpoint.as_str()).header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization.clone());
+        }
+        let request = request.body(Body::from(body)).map_err(|e| e.to_string())?;
+        let response = self.client.request(request).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("answered {}", response.status()));
+        }
+        let body = to_bytes(Body::new(response.into_body()), MAX_INTROSPECTION_BYTES).await.map_err(|e| e.to_string())?;
+        let claims: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default();
+        let identity = match claims.get(&self.identity_claim) {
+            Some(Value::String(identity)) if !identity.is_empty() => Some(identity.clone()),
+            Some(Value::Number(identity)) => Some(identity.to_string()),
+            _ => None,
+        };
+        let expires_in = claims.get("exp").and_then(Value::as_u64).map(|exp| exp.saturating_sub(now));
+        match (claims.get("active").and_then(Value::as_bool), identity) {
+            (Some(true), Some(identity)) if expires_in != Some(0) => Ok((Some(identity), expires_in.map_or(self.cache_secs, |secs| secs.min(self.cache_secs)))),
+            _ => Ok((None, self.negative_cache_secs)),
+        }
+    }
+}
+
+
+// Verifies requests signed with a secret shared with the client, e.g. webhooks. The signature covers the body,
+// preceded by the timestamp and a dot when a timestamp header is configured.
+#[derive(Debug)]
+struct HmacVerifier {
+    key_id_header: HeaderName,
+    signature_header: HeaderName,
+    signature_prefix: String,
+    encoding: SignatureEncoding,
+    timestamp_header: Option<HeaderName>,
+    max_age_secs: u64,
+    keys: HashMap<String, hmac::Key>,
+    // Invalid signatures a client IP may send before it's denied
+    failure_limit: Option<Bucket>,
+}
+
+impl HmacVerifier {
+    fn new(settings: &HmacAuthSettings) -> Result<Self, RateLimiterError> {
+        let header = |setting: &str, name: &str| HeaderName::try_from(name)
+            .map_err(|_| RateLimiterError::config(format!("Invalid hmac {} {:?}", setting, name)));
+        if settings.secrets.is_empty() {
+            return Err(RateLimiterError::config("hmac auth needs secrets"));
+        }
+        let algorithm = match settings.algorithm {
+            HmacAlgorithm::Sha256 => hmac::HMAC_SHA256,
+            HmacAlgorithm::Sha512 => hmac::HMAC_SHA512,
+        };
+
+        Ok(Self {
+            key_id_header: header("key_id_header", &settings.key_id_header)?,
+            signature_header: header("signature_header", &settings.signature_header)?,
+            signature_prefix: settings.signature_prefix.clone(),
+            encoding: settings.encoding,
+            timestamp_header: settings.timestamp_header.as_deref().map(|name| header("timestamp_header", name)).transpose()?,
+            max_age_secs: settings.max_age_secs,
+            keys: settings.secrets.iter()
+                .map(|(key_id, secret)| (key_id.clone(), hmac::Key::new(algorithm, secret.as_bytes())))
+                .collect(),
+            failure_limit: settings.failure_limit.as_ref().map(Bucket::from),
+        })
+    }
+
+    fn verify(&self, key_id: &str, headers: &HeaderMap, body: &[u8]) -> Result<Identity, &'static str> {
+        let key = self.keys.get(key_id).ok_or("unknown_key_id")?;
+        let signature = headers.get(&self.signature_header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(self.signature_prefix.as_str()))
+            .and_then(|value| match self.encoding {
+                SignatureEncoding::Hex => hex::decode(value.trim()).ok(),
+                SignatureEncoding::Base64 => STANDARD.decode(value.trim()).ok(),
+            })
+            .ok_or("invalid_hmac_signature")?;
+
+        let message = match &self.timestamp_header {
+            Some(header) => {
+                let timestamp = headers.get(header).and_then(|value| value.to_str().ok()).ok_or("stale_timestamp")?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default();
+                if timestamp.parse::<u64>().map_or(true, |at| at.abs_diff(now) > self.max_age_secs) {
+                    return Err("stale_timestamp");
+                }
+                [timestamp.as_bytes(), b".", body].concat()
+            },
+            None => body.to_vec(),
+        };
+        hmac::verify(key, &message, &signature).map_err(|_| "invalid_hmac_signature")?;
+        Ok(Identity(key_id.to_string()))
+    }
+}
+
+
+// Why a request was rejected, one of the reasons of the `rate_limiter_auth_failures_total` metric, and the response to answer
+#[derive(Debug)]
+pub struct Rejection {
+    pub reason: &'static str,
+    pub response: Response<Body>,
+}
+
+impl Rejection {
+    // Counted by the failure limit of HMAC routes
+    pub fn is_signature_failure(&self) -> bool {
+        matches!(self.reason, "invalid_hmac_signature" | "unknown_key_id")
+    }
+}
+
+
+#[derive(Debug)]
+struct AuthRoute {
+    path_prefix: String,
+    // Names of the API keys by the hex SHA-256 of the key
+    api_keys: Option<(HeaderName, HashMap<String, String>)>,
+    jwt: Option<JwtValidator>,
+    introspection: Option<Introspector>,
+    hmac: Option<HmacVerifier>,
+    // Hex SHA-256 of the passwords by user
+    basic: Option<(HashMap<String, String>, HeaderValue)>,
+}
+
+impl AuthRoute {
+    fn is_public(&self) -> bool {
+        self.api_keys.is_none() && self.jwt.is_none() && self.introspection.is_none() && self.hmac.is_none() && self.basic.is_none()
+    }
+
+    async fn authenticate(&self, headers: &HeaderMap, body: &[u8]) -> Result<Identity, &'static str> {
+        if let Some(hmac) = &self.hmac && let Some(key_id) = headers.get(&hmac.key_id_header) {
+            return hmac.verify(key_id.to_str().map_err(|_| "unknown_key_id")?, headers, body);
+        }
+        if let Some((header, keys)) = &self.api_keys && let Some(key) = headers.get(header) {
+            return keys.get(&sha256_hex(key.as_bytes())).map(|name| Identity(name.clone())).ok_or("invalid_api_key");
+        }
+
+        let authorization = headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+        if scheme.eq_ignore_ascii_case("bearer") {
+            // With both, tokens that look like JWTs are validated locally and the others are introspected
+            let token = credentials.trim();
+            match (&self.jwt, &self.introspection) {
+                (Some(jwt), None) => return jwt.validate(token).await,
+                (Some(jwt), Some(_)) if token.split('.').count() == 3 => return jwt.validate(token).await,
+                (_, Some(introspection)) => return introspection.validate(token).await,
+                (None, None) => {},
+            }
+        }
+        if let Some((users, _)) = &self.basic && scheme.eq_ignore_ascii_case("basic") {
+            let credentials = STANDARD.decode(credentials.trim()).ok().and_then(|credentials| String::from_utf8(credentials).ok());
+            let Some((user, password)) = credentials.as_deref().and_then(|credentials| credentials.split_once(':')) else {
+                return Err("invalid_credentials");
+            };
+            return match users.get(user) {
+                Some(hash) if *hash == sha256_hex(password.as_bytes()) => Ok(Identity(user.to_string())),
+                _ => Err("invalid_credentials"),
+            };
+        }
+        Err("missing_credentials")
+    }
+
+    fn reject(&self) -> Response<Body> {
+        let mut response = (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+        if let Some((_, realm)) = &self.basic {
+            response.headers_mut().append(WWW_AUTHENTICATE, realm.clone());
+        }
+        if self.jwt.is_some() || self.introspection.is_some() {
+            response.headers_mut().append(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+
+// Rejects requests without valid credentials on the routes it's configured for, following the settings of the longest matching route
+#[derive(Debug, Clone)]
+pub struct Auth {
+    routes: Arc<Vec<AuthRoute>>,
+}
+
+impl Auth {
+    // With a pool, introspection results are cached in Redis
+    pub fn new(settings: &[AuthSettings], pool: Option<RedisPool>, key_prefix: &str) -> Result<Self, RateLimiterError> {
+        let routes = settings.iter()
+            .map(|route| {
+                if !route.path_prefix.starts_with('/') {
+                    return Err(RateLimiterError::config(format!("auth path_prefix {} must start with /", route.path_prefix)));
+                }
+                let api_keys = route.api_key.as_ref()
+                    .map(|api_key| {
+                        let header = HeaderName::try_from(api_key.header.as_str())
+                            .map_err(|_| RateLimiterError::config(format!("Invalid api_key header {:?}", api_key.header)))?;
+                        let keys = api_key.keys.iter()
+                            .map(|(name, hash)| Ok((sha256_setting(hash, "API key", name)?, name.clone())))
+                            .collect::<Result<HashMap<_, _>, RateLimiterError>>()?;
+                        Ok::<_, RateLimiterError>((header, keys))
+                    })
+                    .transpose()?;
+                let basic = route.basic.as_ref()
+                    .map(|basic| {
+                        let users = basic.users.iter()
+                            .map(|(user, hash)| Ok((user.clone(), sha256_setting(hash, "password of user", user)?)))
+                            .collect::<Result<HashMap<_, _>, RateLimiterError>>()?;
+                        let realm = HeaderValue::from_str(&format!("Basic realm=\"{}\"", basic.realm))
+                            .map_err(|_| RateLimiterError::config(format!("Invalid basic auth realm {:?}", basic.realm)))?;
+                        Ok::<_, RateLimiterError>((users, realm))
+                    })
+                    .transpose()?;
+
+                Ok(AuthRoute {
+                    path_prefix: route.path_prefix.trim_end_matches('/').to_string(),
+                    api_keys,
+                    jwt: route.jwt.as_ref().map(JwtValidator::new).transpose()?,
+                    introspection: route.introspection.as_ref().map(|settings| Introspector::new(settings, pool.clone(), key_prefix)).transpose()?,
+                    hmac: route.hmac.as_ref().map(HmacVerifier::new).transpose()?,
+                    basic,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            routes: Arc::new(routes),
+        })
+    }
+
+    fn route(&self, path: &str) -> Option<&AuthRoute> {
+        self.routes.iter()
+            .filter(|route| path.strip_prefix(route.path_prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|route| route.path_prefix.len())
+            .filter(|route| !route.is_public())
+    }
+
+    // Requests of HMAC routes are authenticated with their body
+    pub fn needs_body(&self, path: &str) -> bool {
+        self.route(path).is_some_and(|route| route.hmac.is_some())
+    }
+
+    pub fn signature_failure_limit(&self, path: &str) -> Option<&Bucket> {
+        self.route(path).and_then(|route| route.hmac.as_ref()?.failure_limit.as_ref())
+    }
+
+    // The identity of the request, None on paths without authentication. `body` is only read on routes that need it.
+    pub async fn authenticate(&self, path: &str, headers: &HeaderMap, body: &[u8]) -> Result<Option<Identity>, Rejection> {
+        let Some(route) = self.route(path) else {
+            return Ok(None);
+        };
+
+        match route.authenticate(headers, body).await {
+            Ok(identity) => Ok(Some(identity)),
+            Err(reason) => {
+                metrics::increment_counter("rate_limiter_auth_failures_total", &[("reason", reason)]);
+                let response = match reason {
+                    "introspection_failed" => (StatusCode::SERVICE_UNAVAILABLE, "Authentication unavailable").into_response(),
+                    _ => route.reject(),
+                };
+                Err(Rejection { reason, response })
+            },
+        }
+    }
+}
+
+fn sha256_hex(value: &[u8]) -> String {
+    hex::encode(Sha256::digest(value))
+}
+
+// Secrets are configured as the hex SHA-256 of their value, so the configuration doesn't hold them
+pub(crate) fn sha256_setting(hash: &str, what: &str, name: &str) -> Result<String, RateLimiterError> {
+    match hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        true => Ok(hash.to_ascii_lowercase()),
+        false => Err(RateLimiterError::config(format!("The {} {} must be the hex SHA-256 of its value", what, name))),
+    }
+}
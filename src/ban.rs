@@ -0,0 +1,181 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use deadpool_redis::redis;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use crate::abuse::DenialCounter;
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::{BanActionSettings, BanSettings};
+
+
+#[derive(Serialize, Debug, Clone)]
+struct BanEvent {
+    ip: IpAddr,
+    duration_secs: u64,
+    // Unix time in seconds
+    at: u64,
+}
+
+// A ban as saved in snapshots
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredBan {
+    pub ip: IpAddr,
+    // Unix time in seconds
+    pub expires_at: u64,
+}
+
+#[derive(Debug)]
+enum BanAction {
+    File(String),
+    Command(String, Vec<String>),
+    RedisList(RedisPool, String),
+}
+
+impl BanAction {
+    async fn run(&self, event: &BanEvent) -> Result<(), RateLimiterError> {
+        match self {
+            BanAction::File(path) => {
+                let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+                let line = format!("{} banned {} for {}s\n", event.at, event.ip, event.duration_secs);
+                file.write_all(line.as_bytes()).await?;
+                Ok(file.flush().await?)
+            },
+            BanAction::Command(program, args) => {
+                let (ip, duration) = (event.ip.to_string(), event.duration_secs.to_string());
+                let args = args.iter().map(|arg| arg.replace("{ip}", &ip).replace("{duration}", &duration));
+                let status = Command::new(program).args(args).status().await?;
+                match status.success() {
+                    true => Ok(()),
+                    false => Err(RateLimiterError::Io(std::io::Error::other(format!("{} exited with {}", program, status)))),
+                }
+            },
+            BanAction::RedisList(pool, key) => {
+                let event = serde_json::to_string(event).map_err(std::io::Error::from)?;
+                let mut connection = pool.get().await?;
+                Ok(redis::cmd("LPUSH").arg(key).arg(event).query_async::<()>(&mut connection).await?)
+            },
+        }
+    }
+}
+
+
+// Bans client IPs denied at least `min_denials` times within the window: their requests get a 403 for `duration_secs`
+// without reaching the limiters. Every ban runs the actions, so external tooling like fail2ban or a cloud WAF can
+// block the client at a lower layer. Bans are kept by every proxy instance on its own.
+#[derive(Debug)]
+pub struct Bans {
+    denials: DenialCounter,
+    min_denials: u32,
+    duration: Duration,
+    banned_until: Arc<DashMap<IpAddr, Instant>>,
+    actions: Arc<Vec<BanAction>>,
+}
+
+impl Bans {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of old windows and bans
+    pub fn new(settings: &BanSettings, pool: Option<&RedisPool>) -> Result<Self, RateLimiterError> {
+        if settings.window_secs == 0 || settings.duration_secs == 0 || settings.min_denials == 0 {
+            return Err(RateLimiterError::config("ban.min_denials, window_secs and duration_secs must be greater than 0"));
+        }
+        let actions = settings.actions.iter()
+            .map(|action| match (action, pool) {
+                (BanActionSettings::File { path }, _) => Ok(BanAction::File(path.clone())),
+                (BanActionSettings::Command { program, args }, _) => Ok(BanAction::Command(program.clone(), args.clone())),
+                (BanActionSettings::RedisList { key }, Some(pool)) => Ok(BanAction::RedisList(pool.clone(), key.clone())),
+                (BanActionSettings::RedisList { .. }, None) => Err(RateLimiterError::config("The redis_list ban action requires the redis backend")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let duration = Duration::from_secs(settings.duration_secs);
+        let banned_until = Arc::new(DashMap::new());
+        tokio::spawn(remove_expired_bans(Arc::downgrade(&banned_until), duration));
+
+        Ok(Self {
+            denials: DenialCounter::new(Duration::from_secs(settings.window_secs)),
+            min_denials: settings.min_denials,
+            duration,
+            banned_until,
+            actions: Arc::new(actions),
+        })
+    }
+
+    // Time left until the ban of the client ends, None if it isn't banned
+    pub fn banned_for(&self, ip: IpAddr) -> Option<Duration> {
+        self.banned_until.get(&ip)
+            .map(|banned_until| banned_until.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+
+    // Counts a denial of the client and bans it once it was denied `min_denials` times in the window.
+    // The actions run in the background, so the response doesn't wait for them.
+    pub fn record_denial(&self, ip: IpAddr) {
+        if self.denials.record(ip) < self.min_denials || self.banned_for(ip).is_some() {
+            return;
+        }
+
+        self.banned_until.insert(ip, Instant::now() + self.duration);
+        self.denials.reset(ip);
+        metrics::increment_counter("rate_limiter_bans_total", &[]);
+        println!("Banned {} for {}s", ip, self.duration.as_secs());
+
+        let event = BanEvent {
+            ip,
+            duration_secs: self.duration.as_secs(),
+            at: unix_now(),
+        };
+        let actions = self.actions.clone();
+        tokio::spawn(async move {
+            for action in actions.iter() {
+                if let Err(e) = action.run(&event).await {
+                    println!("Failed to run a ban action for {}: {}", event.ip, e);
+                }
+            }
+        });
+    }
+
+    pub fn export(&self) -> Vec<StoredBan> {
+        let now_secs = unix_now();
+        self.banned_until.iter()
+            .filter_map(|entry| {
+                let left = self.banned_for(*entry.key())?;
+                Some(StoredBan { ip: *entry.key(), expires_at: now_secs + (left.as_millis() as u64).div_ceil(1000) })
+            })
+            .collect()
+    }
+
+    // Bans the IPs of a snapshot until it said, keeping longer bans in place. The actions already ran when they were banned.
+    // Returns the number of bans restored, expired ones are skipped.
+    pub fn import(&self, bans: &[StoredBan]) -> usize {
+        let now_secs = unix_now();
+        let mut imported = 0;
+        for ban in bans.iter().filter(|ban| ban.expires_at > now_secs) {
+            let banned_until = Instant::now() + Duration::from_secs(ban.expires_at - now_secs);
+            let mut entry = self.banned_until.entry(ban.ip).or_insert(banned_until);
+            *entry = (*entry).max(banned_until);
+            imported += 1;
+        }
+        imported
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+async fn remove_expired_bans(banned_until: Weak<DashMap<IpAddr, Instant>>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(banned_until) = banned_until.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        banned_until.retain(|_, banned_until| *banned_until > now);
+    }
+}
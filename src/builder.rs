@@ -0,0 +1,284 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use crate::clock::{Clock, SystemClock};
+use crate::error::RateLimiterError;
+use crate::layer::RateLimitLayer;
+use crate::limiter::RateLimiterManager;
+use crate::settings::{AuthSettings, BuckerPerValue, BucketSettings, CardinalitySettings, ConsumeMode, CorsSettings, DecisionLogging, default_fallback_header, DistinctLimitSettings, default_ipv4_prefix, default_ipv6_prefix, KeySettings, LimitScope, LimiterSettings, LocalCacheSettings, MemcachedSettings, MostRestrictive, OnStoreError, OverridesSettings, PriorityClassSettings, PathNormalizationSettings, PossibleBackends, PossibleStrategies, RateLimiterSettings, RedisSettings, SpikeArrestSettings, WafSettings};
+use crate::store::LimitStore;
+
+
+// Builds `RateLimiterSettings` in code, e.g.
+// `RateLimiterBuilder::new().backend(PossibleBackends::Memory).limiter(PossibleStrategies::IP).global_bucket(100, "1m").build()`.
+// Limiter options apply to the last added limiter. Errors are collected and returned by `build`,
+// which goes through the same validation as settings loaded from TOML.
+#[derive(Debug, Default)]
+pub struct RateLimiterBuilder {
+    settings: RateLimiterSettings,
+    // Replaces the configured backend
+    store: Option<Arc<dyn LimitStore>>,
+    // Replaces the system clock
+    clock: Option<Arc<dyn Clock>>,
+    error: Option<RateLimiterError>,
+}
+
+impl RateLimiterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn backend(mut self, backend: PossibleBackends) -> Self {
+        self.settings.backend = backend;
+        self
+    }
+
+    // Any `LimitStore`, e.g. `MockStore` of the `testing` feature, used instead of `backend`
+    pub fn store(mut self, store: Arc<dyn LimitStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    // Time of windows, quota periods and schedules, e.g. the `MockClock` shared with a `MockStore`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn max_clock_skew_ms(mut self, max_clock_skew_ms: u64) -> Self {
+        self.settings.max_clock_skew_ms = max_clock_skew_ms;
+        self
+    }
+
+    pub fn redis_addr(mut self, redis_addr: impl Into<String>) -> Self {
+        self.settings.redis_addr = Some(redis_addr.into());
+        self
+    }
+
+    pub fn redis(mut self, redis: RedisSettings) -> Self {
+        self.settings.redis = redis;
+        self
+    }
+
+    pub fn memcached(mut self, memcached: MemcachedSettings) -> Self {
+        self.settings.memcached = Some(memcached);
+        self
+    }
+
+    pub fn local_cache(mut self, local_cache: LocalCacheSettings) -> Self {
+        self.settings.local_cache = Some(local_cache);
+        self
+    }
+
+    pub fn keys(mut self, keys: KeySettings) -> Self {
+        self.settings.keys = keys;
+        self
+    }
+
+    pub fn most_restrictive(mut self, most_restrictive: MostRestrictive) -> Self {
+        self.settings.most_restrictive = most_restrictive;
+        self
+    }
+
+    pub fn consume(mut self, consume: ConsumeMode) -> Self {
+        self.settings.consume = consume;
+        self
+    }
+
+    pub fn well_known_endpoint(mut self, well_known_endpoint: bool) -> Self {
+        self.settings.well_known_endpoint = well_known_endpoint;
+        self
+    }
+
+    pub fn status_path(mut self, status_path: impl Into<String>) -> Self {
+        self.settings.status_path = Some(status_path.into());
+        self
+    }
+
+    // `allow`, `deny` or `bucket:<limiter name>`
+    pub fn default_action(mut self, default_action: impl Into<String>) -> Self {
+        self.settings.default_action = Some(default_action.into());
+        self
+    }
+
+    pub fn overrides(mut self, overrides: OverridesSettings) -> Self {
+        self.settings.overrides = Some(overrides);
+        self
+    }
+
+    pub fn priority_class(mut self, priority_class: PriorityClassSettings) -> Self {
+        self.settings.priority_classes.push(priority_class);
+        self
+    }
+
+    pub fn distinct_limit(mut self, distinct_limit: DistinctLimitSettings) -> Self {
+        self.settings.distinct_limits.push(distinct_limit);
+        self
+    }
+
+    pub fn auth_route(mut self, auth_route: AuthSettings) -> Self {
+        self.settings.auth_routes.push(auth_route);
+        self
+    }
+
+    pub fn cors_route(mut self, cors_route: CorsSettings) -> Self {
+        self.settings.cors_routes.push(cors_route);
+        self
+    }
+
+    pub fn waf_route(mut self, waf_route: WafSettings) -> Self {
+        self.settings.waf_routes.push(waf_route);
+        self
+    }
+
+    pub fn policy_header(mut self, policy_header: bool) -> Self {
+        self.settings.policy_header = policy_header;
+        self
+    }
+
+    pub fn whitelist_ip(mut self, ip: IpAddr) -> Self {
+        self.settings.ip_whitelist.insert(ip);
+        self
+    }
+
+    pub fn limiter(mut self, strategy: PossibleStrategies) -> Self {
+        self.settings.limiters_settings.push(LimiterSettings {
+            name: None,
+            strategy,
+            on_store_error: OnStoreError::default(),
+            scope: LimitScope::default(),
+            cardinality: None,
+            spike_arrest: None,
+            log_decisions: None,
+            global_bucket: None,
+            buckets_per_value: None,
+            default_bucket: None,
+            schedules: Vec::new(),
+            fallback_header: default_fallback_header(),
+            normalize_path: None,
+            query_params: Vec::new(),
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+        });
+        self
+    }
+
+    pub fn name(self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.with_last_limiter("name", |limiter| limiter.name = Some(name))
+    }
+
+    pub fn on_store_error(self, on_store_error: OnStoreError) -> Self {
+        self.with_last_limiter("on_store_error", |limiter| limiter.on_store_error = on_store_error)
+    }
+
+    pub fn scope(self, scope: LimitScope) -> Self {
+        self.with_last_limiter("scope", |limiter| limiter.scope = scope)
+    }
+
+    pub fn fallback_header(self, header: impl Into<String>) -> Self {
+        let header = header.into();
+        self.with_last_limiter("fallback_header", |limiter| limiter.fallback_header = header)
+    }
+
+    pub fn normalize_path(self, normalization: PathNormalizationSettings) -> Self {
+        self.with_last_limiter("normalize_path", |limiter| limiter.normalize_path = Some(normalization))
+    }
+
+    pub fn query_param(self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.with_last_limiter("query_param", |limiter| limiter.query_params.push(name))
+    }
+
+    pub fn ip_prefixes(self, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        self.with_last_limiter("ip_prefixes", |limiter| {
+            limiter.ipv4_prefix = ipv4_prefix;
+            limiter.ipv6_prefix = ipv6_prefix;
+        })
+    }
+
+    pub fn log_decisions(self, log_decisions: DecisionLogging) -> Self {
+        self.with_last_limiter("log_decisions", |limiter| limiter.log_decisions = Some(log_decisions))
+    }
+
+    pub fn cardinality(self, max_keys: usize, window: &str) -> Self {
+        self.with_duration(window, |builder, window_secs| builder.with_last_limiter("cardinality", |limiter| {
+            limiter.cardinality = Some(CardinalitySettings { max_keys, window_secs: window_secs as u64 });
+        }))
+    }
+
+    pub fn spike_arrest(self, requests: u32, window_ms: u32) -> Self {
+        self.with_last_limiter("spike_arrest", |limiter| limiter.spike_arrest = Some(SpikeArrestSettings { requests, window_ms }))
+    }
+
+    pub fn global_bucket(self, tokens_count: u32, add_tokens_every: &str) -> Self {
+        self.with_duration(add_tokens_every, |builder, add_tokens_every| builder.with_last_limiter("global_bucket", |limiter| {
+            limiter.global_bucket = Some(BucketSettings { tokens_count, add_tokens_every, burst: None, borrow: 0 });
+        }))
+    }
+
+    pub fn default_bucket(self, tokens_count: u32, add_tokens_every: &str) -> Self {
+        self.with_duration(add_tokens_every, |builder, add_tokens_every| builder.with_last_limiter("default_bucket", |limiter| {
+            limiter.default_bucket = Some(BucketSettings { tokens_count, add_tokens_every, burst: None, borrow: 0 });
+        }))
+    }
+
+    pub fn bucket_per_value(self, value: impl Into<String>, tokens_count: u32, add_tokens_every: &str) -> Self {
+        let value = value.into();
+        self.with_duration(add_tokens_every, |builder, add_tokens_every| builder.with_last_limiter("bucket_per_value", |limiter| {
+            limiter.buckets_per_value.get_or_insert_with(Vec::new).push(BuckerPerValue { value, tokens_count, add_tokens_every, burst: None, borrow: 0 });
+        }))
+    }
+
+    pub fn into_settings(self) -> Result<RateLimiterSettings, RateLimiterError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.settings),
+        }
+    }
+
+    // Must be called inside a tokio runtime, as some stores spawn background tasks
+    pub fn build(mut self) -> Result<RateLimiterManager, RateLimiterError> {
+        let (store, clock) = (self.store.take(), self.clock.take());
+        RateLimiterManager::with_clock(self.into_settings()?, store, clock.unwrap_or_else(|| Arc::new(SystemClock)))
+    }
+
+    pub fn layer(self) -> Result<RateLimitLayer, RateLimiterError> {
+        Ok(RateLimitLayer::from_manager(Arc::new(self.build()?)))
+    }
+
+    fn with_last_limiter(mut self, option: &str, update: impl FnOnce(&mut LimiterSettings)) -> Self {
+        match self.settings.limiters_settings.last_mut() {
+            Some(limiter) => update(limiter),
+            None => self.set_error(format!("{} must be set after adding a limiter", option)),
+        }
+        self
+    }
+
+    fn with_duration(mut self, duration: &str, update: impl FnOnce(Self, u32) -> Self) -> Self {
+        match parse_duration_secs(duration) {
+            Some(secs) => update(self, secs),
+            None => {
+                self.set_error(format!("Invalid duration {:?}, expected a number of seconds with an optional s, m, h or d suffix", duration));
+                self
+            },
+        }
+    }
+
+    // Only the first error is kept, later ones are usually caused by it
+    fn set_error(&mut self, message: String) {
+        self.error.get_or_insert_with(|| RateLimiterError::config(message));
+    }
+}
+
+fn parse_duration_secs(duration: &str) -> Option<u32> {
+    let duration = duration.trim();
+    let (value, multiplier) = match duration.char_indices().last()? {
+        (index, 's') => (&duration[..index], 1),
+        (index, 'm') => (&duration[..index], 60),
+        (index, 'h') => (&duration[..index], 60 * 60),
+        (index, 'd') => (&duration[..index], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+
+    value.parse::<u32>().ok()?.checked_mul(multiplier).filter(|secs| *secs > 0)
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use axum::http::HeaderName;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::hmac;
+use crate::error::RateLimiterError;
+use crate::settings::BypassSettings;
+
+pub const USAGE: &str = "Usage: rate_limiter bypass-token --key-id <id> --subject <name> [--ttl-secs <secs>]";
+
+
+// Short-lived tokens that let internal tools, e.g. CI runners, skip the rate limits without whitelisting their IPs.
+// A token is `<key id>.<expires at>.<subject>.<signature>`, the signature being the base64url HMAC-SHA256 of what
+// precedes it with the secret of the key ID. Secrets rotate by adding a key ID and removing the old one once its tokens expired.
+#[derive(Debug)]
+pub struct BypassTokens {
+    header: HeaderName,
+    max_ttl_secs: u64,
+    keys: HashMap<String, hmac::Key>,
+}
+
+impl BypassTokens {
+    pub fn new(settings: &BypassSettings) -> Result<Self, RateLimiterError> {
+        let header = HeaderName::try_from(settings.header.as_str())
+            .map_err(|_| RateLimiterError::config(format!("Invalid bypass.header {:?}", settings.header)))?;
+        if settings.secrets.is_empty() || settings.max_ttl_secs == 0 {
+            return Err(RateLimiterError::config("bypass needs secrets and a max_ttl_secs greater than 0"));
+        }
+        if let Some(key_id) = settings.secrets.keys().find(|key_id| !is_token_part(key_id) || key_id.contains('.')) {
+            return Err(RateLimiterError::config(format!("Invalid bypass key ID {:?}, key IDs are printable ASCII without dots", key_id)));
+        }
+
+        Ok(Self {
+            header,
+            max_ttl_secs: settings.max_ttl_secs,
+            keys: settings.secrets.iter()
+                .map(|(key_id, secret)| (key_id.clone(), hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())))
+                .collect(),
+        })
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    // The key ID and subject of a valid token, or the reason it was rejected. `now` is in seconds since the unix epoch.
+    pub fn verify<'a>(&self, token: &'a str, now: u64) -> Result<(&'a str, &'a str), &'static str> {
+        let (message, signature) = token.rsplit_once('.').ok_or("malformed")?;
+        let mut parts = message.splitn(3, '.');
+        let (Some(key_id), Some(expires_at), Some(subject)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("malformed");
+        };
+        let expires_at = expires_at.parse::<u64>().map_err(|_| "malformed")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed")?;
+
+        let key = self.keys.get(key_id).ok_or("unknown_key_id")?;
+        hmac::verify(key, message.as_bytes(), &signature).map_err(|_| "invalid_signature")?;
+        if expires_at <= now {
+            return Err("expired");
+        }
+        // Tokens are meant to be short-lived, ones valid for longer than allowed are refused even when signed
+        if expires_at - now > self.max_ttl_secs {
+            return Err("ttl_too_long");
+        }
+        Ok((key_id, subject))
+    }
+
+    // Signs a token of `subject` valid for `ttl_secs` from `now`
+    pub fn issue(&self, key_id: &str, subject: &str, ttl_secs: u64, now: u64) -> Result<String, RateLimiterError> {
+        let key = self.keys.get(key_id).ok_or_else(|| RateLimiterError::config(format!("Unknown bypass key ID {}", key_id)))?;
+        if !is_token_part(subject) {
+            return Err(RateLimiterError::config(format!("Invalid subject {:?}, subjects are printable ASCII", subject)));
+        }
+        if ttl_secs == 0 || ttl_secs > self.max_ttl_secs {
+            return Err(RateLimiterError::config(format!("--ttl-secs must be between 1 and bypass.max_ttl_secs ({})", self.max_ttl_secs)));
+        }
+
+        let message = format!("{}.{}.{}", key_id, now + ttl_secs, subject);
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(key, message.as_bytes()));
+        Ok(format!("{}.{}", message, signature))
+    }
+
+    pub fn max_ttl_secs(&self) -> u64 {
+        self.max_ttl_secs
+    }
+}
+
+// Header values and log lines stay readable
+fn is_token_part(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_graphic())
+}
+
+
+// Arguments of `rate_limiter bypass-token`, the TTL defaults to bypass.max_ttl_secs
+#[derive(Debug, Default)]
+pub struct BypassTokenArgs {
+    pub key_id: String,
+    pub subject: String,
+    pub ttl_secs: Option<u64>,
+}
+
+impl BypassTokenArgs {
+    pub fn parse(args: &[String]) -> Result<Self, RateLimiterError> {
+        let mut token_args = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args.next().cloned()
+                .ok_or_else(|| RateLimiterError::config(format!("Missing value of {}", arg)))?;
+            match arg.as_str() {
+                "--key-id" => token_args.key_id = value,
+                "--subject" => token_args.subject = value,
+                "--ttl-secs" => token_args.ttl_secs = Some(value.parse().map_err(|_| RateLimiterError::config(format!("Invalid --ttl-secs {}", value)))?),
+                _ => return Err(RateLimiterError::config(format!("Unknown argument {}", arg))),
+            }
+        }
+
+        if token_args.key_id.is_empty() || token_args.subject.is_empty() {
+            return Err(RateLimiterError::config("--key-id and --subject are required"));
+        }
+        Ok(token_args)
+    }
+}
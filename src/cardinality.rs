@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::clock::Clock;
+use crate::settings::CardinalitySettings;
+
+
+#[derive(Debug)]
+struct CardinalityWindow {
+    started_at_us: u64,
+    // Only hashes are kept, so memory stays bounded by `max_keys` whatever the size of the values
+    keys: HashSet<u64>,
+    is_overflowed: bool,
+}
+
+
+// Caps the number of distinct keys a limiter creates per window, so random header values or URLs can't fill the store.
+#[derive(Debug)]
+pub struct CardinalityGuard {
+    max_keys: usize,
+    window: Duration,
+    current: Mutex<CardinalityWindow>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CardinalityGuard {
+    pub fn new(settings: &CardinalitySettings, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            max_keys: settings.max_keys,
+            window: Duration::from_secs(settings.window_secs),
+            current: Mutex::new(CardinalityWindow {
+                started_at_us: clock.now_us(),
+                keys: HashSet::new(),
+                is_overflowed: false,
+            }),
+            clock,
+        }
+    }
+
+    // Returns whether the key can get its own bucket, and whether this is the first rejected key of the window
+    pub fn admit(&self, key: &str) -> (bool, bool) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let now_us = self.clock.now_us();
+        if Duration::from_micros(now_us.saturating_sub(current.started_at_us)) >= self.window {
+            current.started_at_us = now_us;
+            current.keys.clear();
+            current.is_overflowed = false;
+        }
+
+        if current.keys.contains(&hash) {
+            return (true, false);
+        }
+        if current.keys.len() < self.max_keys {
+            current.keys.insert(hash);
+            return (true, false);
+        }
+
+        (false, !std::mem::replace(&mut current.is_overflowed, true))
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use axum::http::header::LOCATION;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use crate::abuse::DenialCounter;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::ChallengeSettings;
+
+pub const CHALLENGE_TOKEN_HEADER: &str = "X-Challenge-Token";
+
+
+#[derive(Debug, Default)]
+struct ChallengeState {
+    // Token handed to the client and when it expires
+    pending: DashMap<IpAddr, (String, Instant)>,
+    // Clients that solved their challenge skip the rate limits until then
+    unblocked: DashMap<IpAddr, Instant>,
+}
+
+
+// Answers abusive clients with a challenge instead of a bare 429: a redirect to a verification page or a token header.
+// The verification service reports solved challenges to the admin server, which unblocks the client for a while.
+#[derive(Debug)]
+pub struct Challenge {
+    denials: DenialCounter,
+    state: Arc<ChallengeState>,
+    min_denials: u32,
+    redirect_url: Option<String>,
+    token_ttl: Duration,
+    unblock_for: Duration,
+    random: RandomState,
+    issued: AtomicU64,
+}
+
+impl Challenge {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of expired tokens
+    pub fn new(settings: &ChallengeSettings) -> Result<Self, RateLimiterError> {
+        if settings.window_secs == 0 || settings.token_ttl_secs == 0 {
+            return Err(RateLimiterError::config("challenge.window_secs and challenge.token_ttl_secs must be greater than 0"));
+        }
+        if let Some(redirect_url) = &settings.redirect_url && redirect_url.parse::<Uri>().is_err() {
+            return Err(RateLimiterError::config(format!("Invalid challenge.redirect_url {}", redirect_url)));
+        }
+
+        let state = Arc::new(ChallengeState::default());
+        let token_ttl = Duration::from_secs(settings.token_ttl_secs);
+        tokio::spawn(remove_expired(Arc::downgrade(&state), token_ttl));
+
+        Ok(Self {
+            denials: DenialCounter::new(Duration::from_secs(settings.window_secs)),
+            state,
+            min_denials: settings.min_denials,
+            redirect_url: settings.redirect_url.clone(),
+            token_ttl,
+            unblock_for: Duration::from_secs(settings.unblock_secs),
+            random: RandomState::new(),
+            issued: AtomicU64::new(0),
+        })
+    }
+
+    pub fn is_unblocked(&self, ip: IpAddr) -> bool {
+        self.state.unblocked.get(&ip).is_some_and(|until| *until > Instant::now())
+    }
+
+    // Counts a denial of the client and returns the challenge to answer with once it was denied `min_denials` times in the window
+    pub fn challenge(&self, ip: IpAddr) -> Option<Response> {
+        if self.denials.record(ip) < self.min_denials {
+            return None;
+        }
+
+        // A client keeps its token until it expires, so retries don't flood the pending tokens
+        let now = Instant::now();
+        let token = self.state.pending.entry(ip)
+            .and_modify(|(token, expires_at)| if *expires_at <= now {
+                *token = self.new_token(ip);
+                *expires_at = now + self.token_ttl;
+            })
+            .or_insert_with(|| (self.new_token(ip), now + self.token_ttl))
+            .0.clone();
+        metrics::increment_counter("rate_limiter_challenges_total", &[]);
+
+        Some(match &self.redirect_url {
+            Some(redirect_url) => {
+                let separator = if redirect_url.contains('?') { '&' } else { '?' };
+                (StatusCode::SEE_OTHER, [(LOCATION, format!("{}{}token={}", redirect_url, separator, token))]).into_response()
+            },
+            None => (StatusCode::TOO_MANY_REQUESTS, [(CHALLENGE_TOKEN_HEADER, token)], "Challenge required").into_response(),
+        })
+    }
+
+    // Unblocks the client the token was issued to, false if the token is unknown or expired
+    pub fn solve(&self, token: &str) -> bool {
+        let now = Instant::now();
+        let Some(ip) = self.state.pending.iter()
+            .find(|entry| entry.value().0 == token && entry.value().1 > now)
+            .map(|entry| *entry.key()) else {
+            return false;
+        };
+
+        self.state.pending.remove(&ip);
+        self.state.unblocked.insert(ip, now + self.unblock_for);
+        self.denials.reset(ip);
+        true
+    }
+
+    // 128 bits of keyed SipHash over a unique input, unpredictable without the random keys of `random`
+    fn new_token(&self, ip: IpAddr) -> String {
+        let issued = self.issued.fetch_add(1, Ordering::Relaxed);
+        let (high, low) = (self.random.hash_one((ip, issued, 0u8)), self.random.hash_one((ip, issued, 1u8)));
+        format!("{:016x}{:016x}", high, low)
+    }
+}
+
+async fn remove_expired(state: Weak<ChallengeState>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        state.pending.retain(|_, (_, expires_at)| *expires_at > now);
+        state.unblocked.retain(|_, until| *until > now);
+    }
+}
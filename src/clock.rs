@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Time as the limiting algorithms see it: windows, GCRA, quota periods and schedules all read it from a clock,
+// so tests can move it by hand with the `MockClock` of the testing feature.
+pub trait Clock: Debug + Send + Sync {
+    // Microseconds since the unix epoch
+    fn now_us(&self) -> u64;
+
+    fn now_secs(&self) -> u64 {
+        self.now_us() / 1_000_000
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_us(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_micros() as u64).unwrap_or_default()
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use deadpool_redis::redis;
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::settings::ClusterSettings;
+
+// Instances that missed this many heartbeats are no longer counted
+const MISSED_HEARTBEATS: u64 = 3;
+
+
+// Registers this instance in Redis and keeps track of the other live ones.
+// Instances announce themselves in a sorted set scored by their last heartbeat, as read from their own clock.
+#[derive(Debug)]
+pub struct Cluster {
+    key: String,
+    instance_id: String,
+    heartbeat: Duration,
+    // Heartbeats of instances whose clock is behind are kept that much longer
+    max_clock_skew_ms: u64,
+    // (instances << 32) | rank, packed in one atomic so both are always read together
+    position: AtomicU64,
+    instances: Mutex<Vec<String>>,
+}
+
+impl Cluster {
+    // Must be called inside a tokio runtime, as it spawns the heartbeat
+    pub fn new(settings: &ClusterSettings, pool: RedisPool, key_prefix: &str, max_clock_skew_ms: u64) -> Result<Arc<Self>, RateLimiterError> {
+        if settings.heartbeat_secs == 0 {
+            return Err(RateLimiterError::config("cluster.heartbeat_secs must be greater than 0"));
+        }
+        let instance_id = match &settings.instance_id {
+            Some(instance_id) if instance_id.is_empty() => return Err(RateLimiterError::config("cluster.instance_id can't be empty")),
+            Some(instance_id) => instance_id.clone(),
+            None => default_instance_id(),
+        };
+
+        // Until the first heartbeat this instance is alone
+        let cluster = Arc::new(Self {
+            key: format!("{}:instances", key_prefix),
+            instances: Mutex::new(vec![instance_id.clone()]),
+            instance_id,
+            heartbeat: Duration::from_secs(settings.heartbeat_secs),
+            max_clock_skew_ms,
+            position: AtomicU64::new(1 << 32),
+        });
+        tokio::spawn(send_heartbeats(Arc::downgrade(&cluster), pool));
+
+        Ok(cluster)
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    // Index of this instance among the live ones and the number of live instances
+    pub fn position(&self) -> (u32, u32) {
+        let position = self.position.load(Ordering::Relaxed);
+        (position as u32, (position >> 32) as u32)
+    }
+
+    pub fn instances(&self) -> Vec<String> {
+        self.instances.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    async fn heartbeat(&self, pool: &RedisPool) -> Result<(), RateLimiterError> {
+        let mut connection = pool.get().await?;
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or_default();
+        let expiry_ms = self.heartbeat.as_millis() as u64 * MISSED_HEARTBEATS;
+
+        let (mut instances,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .cmd("ZADD").arg(&self.key).arg(now_ms).arg(&self.instance_id).ignore()
+            .cmd("ZREMRANGEBYSCORE").arg(&self.key).arg("-inf").arg(now_ms.saturating_sub(expiry_ms + self.max_clock_skew_ms)).ignore()
+            .cmd("PEXPIRE").arg(&self.key).arg(expiry_ms + self.max_clock_skew_ms).ignore()
+            .cmd("ZRANGE").arg(&self.key).arg(0).arg(-1)
+            .query_async(&mut connection).await?;
+
+        // Members are sorted by score then id, only the ids give every instance the same order
+        instances.sort_unstable();
+        if let Some(rank) = instances.iter().position(|instance| instance == &self.instance_id) {
+            self.position.store(((instances.len() as u64) << 32) | rank as u64, Ordering::Relaxed);
+        }
+        *self.instances.lock().unwrap_or_else(|e| e.into_inner()) = instances;
+        Ok(())
+    }
+}
+
+pub fn default_instance_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "instance".to_string());
+    format!("{}-{}", host, std::process::id())
+}
+
+async fn send_heartbeats(cluster: Weak<Cluster>, pool: RedisPool) {
+    let Some(every) = cluster.upgrade().map(|cluster| cluster.heartbeat) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(cluster) = cluster.upgrade() else {
+            return;
+        };
+        // On errors the last known instances are kept, instances that can't reach Redis don't limit anything anyway
+        if let Err(e) = cluster.heartbeat(&pool).await {
+            println!("Failed to send the cluster heartbeat of instance {}: {}", cluster.instance_id, e);
+        }
+    }
+}
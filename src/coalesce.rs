@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use axum::body::{to_bytes, Body, Bytes, HttpBody};
+use axum::extract::{Request, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_LENGTH, HOST, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderName, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use tokio::sync::broadcast;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::CoalesceSettings;
+use crate::strategy::header_value;
+
+
+// Lets identical GETs wait for the one already in flight and answers them with its response, so the upstream sees it
+// once. Requests are identical when their host, URI and `vary` headers are. Responses the leader can't share, like
+// ones setting cookies or streamed without a Content-Length, make the waiting requests go to the upstream themselves.
+#[derive(Debug)]
+pub struct Coalescer {
+    charge_duplicates: bool,
+    vary: Vec<HeaderName>,
+    max_body_bytes: usize,
+    // Requests in flight by key, the waiting ones subscribe to the response of the leader
+    in_flight: DashMap<String, broadcast::Sender<Option<SharedResponse>>>,
+}
+
+#[derive(Clone, Debug)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    fn response(&self) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+// Removes the leader's entry if it's dropped before finishing, the waiting requests then go to the upstream themselves
+struct Leader<'a> {
+    coalescer: &'a Coalescer,
+    key: Option<String>,
+}
+
+impl Leader<'_> {
+    fn finish(mut self, response: Option<SharedResponse>) {
+        if let Some((_, sender)) = self.key.take().and_then(|key| self.coalescer.in_flight.remove(&key)) {
+            // Nobody waiting is fine
+            let _ = sender.send(response);
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            self.coalescer.in_flight.remove(key);
+        }
+    }
+}
+
+impl Coalescer {
+    pub fn new(settings: &CoalesceSettings) -> Result<Self, RateLimiterError> {
+        let vary = settings.vary.iter()
+            .map(|name| HeaderName::try_from(name.as_str()).map_err(|_| RateLimiterError::config(format!("Invalid coalesce.vary header {:?}", name))))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            charge_duplicates: settings.charge_duplicates,
+            vary,
+            max_body_bytes: settings.max_body_bytes,
+            in_flight: DashMap::new(),
+        })
+    }
+
+    // Duplicates go through the limiters before waiting when charged, so the layer must sit inside the rate limit layer
+    pub fn charges_duplicates(&self) -> bool {
+        self.charge_duplicates
+    }
+
+    // Only GETs without a body are coalesced
+    fn key(&self, request: &Request) -> Option<String> {
+        if request.method() != Method::GET || !request.body().is_end_stream() {
+            return None;
+        }
+        let mut key = format!("{}\n{}", request.headers().get(HOST).map(header_value).unwrap_or_default(), request.uri());
+        for name in &self.vary {
+            key.push('\n');
+            for value in request.headers().get_all(name) {
+                key.push_str(&header_value(value));
+                key.push(',');
+            }
+        }
+        Some(key)
+    }
+
+    fn is_shareable(&self, response: &Response) -> bool {
+        let headers = response.headers();
+        let content_length = headers.get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        let is_private = headers.get_all(CACHE_CONTROL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("private"));
+
+        content_length.is_some_and(|length| length <= self.max_body_bytes) && !headers.contains_key(SET_COOKIE) && !is_private
+    }
+
+    async fn lead(&self, key: String, request: Request, next: Next) -> Response {
+        let leader = Leader { coalescer: self, key: Some(key) };
+        let response = next.run(request).await;
+        if !self.is_shareable(&response) {
+            leader.finish(None);
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+        match to_bytes(body, self.max_body_bytes).await {
+            Ok(body) => {
+                leader.finish(Some(SharedResponse { status: parts.status, headers: parts.headers.clone(), body: body.clone() }));
+                Response::from_parts(parts, Body::from(body))
+            },
+            Err(e) => {
+                println!("Failed to read the response to share with coalesced requests: {}", e);
+                leader.finish(None);
+                StatusCode::BAD_GATEWAY.into_response()
+            },
+        }
+    }
+}
+
+pub async fn middleware(State(coalescer): State<Arc<Coalescer>>, request: Request, next: Next) -> Response {
+    let Some(key) = coalescer.key(&request) else {
+        return next.run(request).await;
+    };
+    let receiver = match coalescer.in_flight.entry(key.clone()) {
+        Entry::Occupied(entry) => Some(entry.get().subscribe()),
+        Entry::Vacant(entry) => {
+            entry.insert(broadcast::channel(1).0);
+            None
+        },
+    };
+    let Some(mut receiver) = receiver else {
+        return coalescer.lead(key, request, next).await;
+    };
+
+    match receiver.recv().await {
+        Ok(Some(response)) => {
+            metrics::increment_counter("rate_limiter_coalesced_total", &[]);
+            response.response()
+        },
+        _ => next.run(request).await,
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::ConcurrencyLimitSettings;
+
+
+// Caps the requests a listener handles at once, whatever their client or upstream, so the proxy degrades predictably
+// under extreme load. Requests above the cap wait their turn in a queue of bounded length, first come first served.
+// A request holds its slot until the response headers are sent.
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    slots: Semaphore,
+    max_queued: usize,
+    queued: AtomicUsize,
+    queue_timeout: Duration,
+    retry_after_secs: u64,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(settings: &ConcurrencyLimitSettings) -> Result<Self, RateLimiterError> {
+        if settings.max_in_flight == 0 {
+            return Err(RateLimiterError::config("concurrency_limit.max_in_flight must be greater than 0"));
+        }
+
+        Ok(Self {
+            slots: Semaphore::new(settings.max_in_flight),
+            max_queued: settings.max_queued,
+            queued: AtomicUsize::new(0),
+            queue_timeout: Duration::from_millis(settings.queue_timeout_ms),
+            retry_after_secs: settings.retry_after_secs,
+        })
+    }
+
+    // Errors name the reason the request didn't get a slot
+    async fn acquire(&self) -> Result<SemaphorePermit<'_>, &'static str> {
+        if let Ok(slot) = self.slots.try_acquire() {
+            return Ok(slot);
+        }
+        if self.queued.fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| (queued < self.max_queued).then_some(queued + 1)).is_err() {
+            return Err("queue_full");
+        }
+
+        let _queued = Queued(&self.queued);
+        match tokio::time::timeout(self.queue_timeout, self.slots.acquire()).await {
+            Ok(Ok(slot)) => Ok(slot),
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => Err("timeout"),
+        }
+    }
+
+    fn reject(&self, reason: &str) -> Response {
+        metrics::increment_counter("rate_limiter_concurrency_rejected_total", &[("reason", reason)]);
+        (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, self.retry_after_secs.to_string())], "Server overloaded").into_response()
+    }
+}
+
+
+// Leaves the queue when the request got its slot, timed out or its client went away
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Runs before everything else on the listener, so rejected requests are never charged
+pub async fn middleware(State(concurrency_limit): State<Arc<ConcurrencyLimit>>, request: Request<Body>, next: Next) -> Response {
+    match concurrency_limit.acquire().await {
+        Ok(_slot) => next.run(request).await,
+        Err(reason) => concurrency_limit.reject(reason),
+    }
+}
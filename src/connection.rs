@@ -0,0 +1,221 @@
+use std::time::Duration;
+use deadpool_redis::{sentinel, Connection, Manager, Pool, PoolConfig, PoolError, Runtime, Timeouts};
+use deadpool_redis::redis::{Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, Pipeline, RedisFuture, RedisResult, TlsCertificates, Value};
+use deadpool_redis::redis::aio::ConnectionLike;
+use crate::error::RateLimiterError;
+use crate::settings::{RateLimiterSettings, RedisPoolSettings, SentinelSettings};
+
+
+#[derive(Clone, Debug)]
+pub enum RedisPool {
+    Standalone(Pool),
+    Sentinel(sentinel::Pool),
+}
+
+impl RedisPool {
+    pub fn new(rate_limiter_settings: &RateLimiterSettings) -> Result<Self, RateLimiterError> {
+        match &rate_limiter_settings.redis.sentinel {
+            Some(sentinel_settings) => create_sentinel_pool(rate_limiter_settings, sentinel_settings).map(RedisPool::Sentinel),
+            None => create_redis_pool(rate_limiter_settings).map(RedisPool::Standalone),
+        }
+    }
+
+    pub async fn get(&self) -> Result<RedisConnection, PoolError> {
+        match self {
+            RedisPool::Standalone(pool) => Ok(RedisConnection::Standalone(pool.get().await?)),
+            RedisPool::Sentinel(pool) => Ok(RedisConnection::Sentinel(SentinelConnection {
+                connection: Some(pool.get().await?),
+                pool: pool.clone(),
+                is_stale: false,
+            })),
+        }
+    }
+
+    // Gets a connection and pings the server, so misconfigurations show up on startup instead of on every request
+    pub async fn check_connection(&self) -> Result<(), RateLimiterError> {
+        let mut connection = self.get().await
+            .map_err(|e| RateLimiterError::Redis(format!("Could not connect to Redis: {}", e)))?;
+
+        deadpool_redis::redis::cmd("PING").query_async::<()>(&mut connection).await
+            .map_err(|e| RateLimiterError::Redis(format!("Redis did not answer PING: {}", e)))
+    }
+}
+
+
+pub enum RedisConnection {
+    Standalone(Connection),
+    Sentinel(SentinelConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Standalone(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Sentinel(connection) => Box::pin(async move {
+                let result = connection.connection().req_packed_command(cmd).await;
+                connection.check_result(&result);
+                result
+            }),
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Standalone(connection) => connection.req_packed_commands(cmd, offset, count),
+            RedisConnection::Sentinel(connection) => Box::pin(async move {
+                let result = connection.connection().req_packed_commands(cmd, offset, count).await;
+                connection.check_result(&result);
+                result
+            }),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Standalone(connection) => connection.get_db(),
+            RedisConnection::Sentinel(connection) => connection.connection.as_ref().map_or(0, |c| c.get_db()),
+        }
+    }
+}
+
+
+pub struct SentinelConnection {
+    connection: Option<sentinel::Connection>,
+    pool: sentinel::Pool,
+    is_stale: bool,
+}
+
+impl SentinelConnection {
+    fn connection(&mut self) -> &mut sentinel::Connection {
+        self.connection.as_mut().expect("connection is only taken on drop")
+    }
+
+    fn check_result<T>(&mut self, result: &RedisResult<T>) {
+        // After a failover the old master either disappears or is demoted to a read-only replica
+        if let Err(e) = result && (e.kind() == ErrorKind::ReadOnly || e.is_unrecoverable_error()) {
+            self.is_stale = true;
+        }
+    }
+}
+
+impl Drop for SentinelConnection {
+    fn drop(&mut self) {
+        if !self.is_stale {
+            return;
+        }
+
+        println!("Redis master connection is stale, re-resolving master through sentinel");
+        // Detach the connection and drop idle ones, so new connections ask the sentinels for the current master
+        if let Some(connection) = self.connection.take() {
+            drop(sentinel::Connection::take(connection));
+        }
+        self.pool.retain(|_, _| false);
+    }
+}
+
+
+fn redis_connection_info(rate_limiter_settings: &RateLimiterSettings) -> Result<ConnectionInfo, RateLimiterError> {
+    let settings = &rate_limiter_settings.redis;
+    let mut connection_info = match (&settings.url, &rate_limiter_settings.redis_addr) {
+        (Some(url), _) => url.as_str().into_connection_info(),
+        (None, Some(addr)) => format!("redis://{}", addr).into_connection_info(),
+        (None, None) => return Err(RateLimiterError::config("Either redis_addr or redis.url must be defined")),
+    }.map_err(RateLimiterError::config)?;
+
+    // Structured settings take precedence over the values parsed from the URL
+    if let Some(username) = &settings.username {
+        connection_info.redis.username = Some(username.clone());
+    }
+    if let Some(password) = &settings.password {
+        connection_info.redis.password = Some(password.clone());
+    }
+    if let Some(db) = settings.db {
+        connection_info.redis.db = db;
+    }
+    if settings.tls && let ConnectionAddr::Tcp(host, port) = connection_info.addr {
+        connection_info.addr = ConnectionAddr::TcpTls { host, port, insecure: false, tls_params: None };
+    }
+
+    if let Some(ca_path) = &settings.ca_path {
+        if !matches!(connection_info.addr, ConnectionAddr::TcpTls { .. }) {
+            return Err(RateLimiterError::config("redis.ca_path requires a TLS connection (rediss:// or redis.tls = true)"));
+        }
+
+        let root_cert = std::fs::read(ca_path)
+            .map_err(|e| RateLimiterError::Tls(format!("Could not read redis.ca_path {}: {}", ca_path, e)))?;
+        let client = Client::build_with_tls(connection_info, TlsCertificates { client_tls: None, root_cert: Some(root_cert) })
+            .map_err(|e| RateLimiterError::Tls(e.to_string()))?;
+        connection_info = client.get_connection_info().clone();
+    }
+
+    Ok(connection_info)
+}
+
+fn pool_config(settings: &RedisPoolSettings) -> PoolConfig {
+    let mut config = PoolConfig::default();
+    if let Some(max_size) = settings.max_size {
+        config.max_size = max_size;
+    }
+    config.timeouts = Timeouts {
+        wait: settings.wait_timeout_ms.map(Duration::from_millis),
+        create: settings.create_timeout_ms.map(Duration::from_millis),
+        recycle: settings.recycle_timeout_ms.map(Duration::from_millis),
+    };
+    config
+}
+
+fn create_redis_pool(rate_limiter_settings: &RateLimiterSettings) -> Result<Pool, RateLimiterError> {
+    let manager = Manager::new(redis_connection_info(rate_limiter_settings)?).map_err(RateLimiterError::config)?;
+
+    Pool::builder(manager)
+        .config(pool_config(&rate_limiter_settings.redis.pool))
+        .runtime(Runtime::Tokio1)
+        .build()
+        .map_err(RateLimiterError::config)
+}
+
+fn create_sentinel_pool(rate_limiter_settings: &RateLimiterSettings, sentinel_settings: &SentinelSettings) -> Result<sentinel::Pool, RateLimiterError> {
+    let settings = &rate_limiter_settings.redis;
+    if settings.ca_path.is_some() {
+        return Err(RateLimiterError::config("redis.ca_path is not supported together with redis.sentinel"));
+    }
+    if sentinel_settings.addrs.is_empty() {
+        return Err(RateLimiterError::config("redis.sentinel.addrs must contain at least one sentinel address"));
+    }
+
+    let sentinels = sentinel_settings.addrs.iter()
+        .map(|addr| match addr.contains("://") {
+            true => addr.clone(),
+            false => format!("redis://{}", addr),
+        })
+        .collect::<Vec<_>>();
+
+    // Credentials, db and TLS of the [rate_limiter.redis] table apply to the resolved master
+    let mut redis_connection_info = deadpool_redis::RedisConnectionInfo::default();
+    if let Some(username) = &settings.username {
+        redis_connection_info.username = Some(username.clone());
+    }
+    if let Some(password) = &settings.password {
+        redis_connection_info.password = Some(password.clone());
+    }
+    if let Some(db) = settings.db {
+        redis_connection_info.db = db;
+    }
+    let node_connection_info = sentinel::SentinelNodeConnectionInfo {
+        tls_mode: settings.tls.then_some(sentinel::TlsMode::Secure),
+        redis_connection_info: Some(redis_connection_info),
+    };
+
+    let manager = sentinel::Manager::new(
+        sentinels,
+        sentinel_settings.master_name.clone(),
+        Some(node_connection_info),
+        sentinel::SentinelServerType::Master,
+    ).map_err(RateLimiterError::config)?;
+
+    sentinel::Pool::builder(manager)
+        .config(pool_config(&settings.pool))
+        .runtime(Runtime::Tokio1)
+        .build()
+        .map_err(RateLimiterError::config)
+}
@@ -0,0 +1,256 @@
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use axum::body::{Body, Bytes};
+use axum::extract::ConnectInfo;
+use axum::http::header::CONNECTION;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use dashmap::DashMap;
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Sleep;
+use tower_service::Service;
+use crate::clock::{Clock, SystemClock};
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::memory_budget::{self, MemoryBudgetExceeded};
+use crate::metrics;
+use crate::settings::{ConnectionLimitSettings, ReadTimeoutSettings};
+
+// Like axum::serve, accept errors such as running out of file descriptors are retried after a pause
+pub const ACCEPT_ERROR_PAUSE: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+
+// Protects the proxy itself from clients flooding it with connections, or holding them open by sending slowly.
+// Connections over the caps are closed as soon as they are accepted, whitelisted IPs are never capped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    max_per_ip_per_sec: Option<u32>,
+    max_concurrent_per_ip: Option<u32>,
+    header_timeout: Option<Duration>,
+    body_timeout: Option<Duration>,
+    limiter: Arc<RateLimiterManager>,
+    // Second of the window with the connections accepted in it, by client IP
+    accepted: DashMap<IpAddr, (u64, u32)>,
+    last_cleanup: AtomicU64,
+    open: DashMap<IpAddr, u32>,
+}
+
+impl ConnectionGuard {
+    // None when the listener is served without a guard
+    pub fn new(
+        connection_limit: Option<&ConnectionLimitSettings>,
+        read_timeouts: Option<&ReadTimeoutSettings>,
+        limiter: Arc<RateLimiterManager>,
+    ) -> Result<Option<Self>, RateLimiterError> {
+        if connection_limit.is_none() && read_timeouts.is_none() {
+            return Ok(None);
+        }
+        let (max_per_ip_per_sec, max_concurrent_per_ip) = connection_limit
+            .map_or((None, None), |settings| (settings.max_per_ip_per_sec, settings.max_concurrent_per_ip));
+        if connection_limit.is_some() && max_per_ip_per_sec.is_none() && max_concurrent_per_ip.is_none() {
+            return Err(RateLimiterError::config("connection_limit needs max_per_ip_per_sec or max_concurrent_per_ip"));
+        }
+        if max_per_ip_per_sec == Some(0) || max_concurrent_per_ip == Some(0) {
+            return Err(RateLimiterError::config("max_per_ip_per_sec and max_concurrent_per_ip of connection_limit must be greater than 0"));
+        }
+        let (header_timeout, body_timeout) = read_timeouts.map_or((None, None), |settings| (settings.headers_ms, settings.body_ms));
+        if header_timeout == Some(0) || body_timeout == Some(0) {
+            return Err(RateLimiterError::config("headers_ms and body_ms of read_timeouts must be greater than 0"));
+        }
+
+        Ok(Some(Self {
+            max_per_ip_per_sec,
+            max_concurrent_per_ip,
+            header_timeout: header_timeout.map(Duration::from_millis),
+            body_timeout: body_timeout.map(Duration::from_millis),
+            limiter,
+            accepted: DashMap::new(),
+            last_cleanup: AtomicU64::new(0),
+            open: DashMap::new(),
+        }))
+    }
+
+    // The connection counts as open until the returned guard is dropped. Errors name the cap the connection is over.
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Option<OpenConnection>, &'static str> {
+        let ip = ip.to_canonical();
+        if self.limiter.is_whitelisted(&ip) {
+            return Ok(None);
+        }
+
+        if let Some(max_per_ip_per_sec) = self.max_per_ip_per_sec {
+            let now = SystemClock.now_secs();
+            // Counts of past seconds are dropped once a second, so a flood from many IPs doesn't keep them around
+            if self.last_cleanup.swap(now, Ordering::Relaxed) != now {
+                self.accepted.retain(|_, (second, _)| *second == now);
+            }
+            let mut accepted = self.accepted.entry(ip).or_insert((now, 0));
+            let (second, count) = accepted.value_mut();
+            if *second != now {
+                (*second, *count) = (now, 0);
+            }
+            *count += 1;
+            if *count > max_per_ip_per_sec {
+                return Err("rate");
+            }
+        }
+
+        let Some(max_concurrent_per_ip) = self.max_concurrent_per_ip else {
+            return Ok(None);
+        };
+        let mut open = self.open.entry(ip).or_insert(0);
+        if *open >= max_concurrent_per_ip {
+            return Err("concurrency");
+        }
+        *open += 1;
+        Ok(Some(OpenConnection { guard: self.clone(), ip }))
+    }
+}
+
+
+#[derive(Debug)]
+struct OpenConnection {
+    guard: Arc<ConnectionGuard>,
+    ip: IpAddr,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.guard.open.remove_if_mut(&self.ip, |_, open| {
+            *open -= 1;
+            *open == 0
+        });
+    }
+}
+
+
+// Serves `app` like axum::serve does, closing the connections the guard doesn't admit. With a header timeout
+// only HTTP/1 is served, as telling HTTP/2 apart would wait for the client without a timeout.
+pub async fn serve(listener: TcpListener, app: Router, guard: Arc<ConnectionGuard>) -> Result<(), std::io::Error> {
+    let listener_addr = listener.local_addr()?.to_string();
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to accept a connection on {}: {}", listener_addr, e);
+                tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
+            },
+        };
+        let open_connection = match guard.admit(addr.ip()) {
+            Ok(open_connection) => open_connection,
+            Err(reason) => {
+                metrics::increment_counter("rate_limiter_connections_rejected_total", &[("listener", &listener_addr), ("reason", reason)]);
+                continue;
+            },
+        };
+
+        let (app, header_timeout, body_timeout) = (app.clone(), guard.header_timeout, guard.body_timeout);
+        let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(addr));
+            let request = match body_timeout {
+                Some(body_timeout) => request.map(|body| Body::new(TimeoutBody::new(Body::new(body), body_timeout))),
+                None => request.map(Body::new),
+            };
+            app.clone().call(request)
+        });
+        tokio::spawn(async move {
+            let _open_connection = open_connection;
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            let (stream, timeout_answer) = match header_timeout {
+                Some(header_timeout) => {
+                    builder = builder.http1_only();
+                    builder.http1().timer(TokioTimer::new()).header_read_timeout(header_timeout);
+                    match with_answer_handle(stream) {
+                        Ok((stream, timeout_answer)) => (stream, Some(timeout_answer)),
+                        Err(e) => return eprintln!("Failed to set up the connection of {}: {}", addr, e),
+                    }
+                },
+                None => (stream, None),
+            };
+            // Clients closing their connection early are common, so other connection errors aren't logged
+            if let Err(e) = builder.serve_connection_with_upgrades(TokioIo::new(stream), service).await
+                && e.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout)
+                && let Some(Ok(mut timeout_answer)) = timeout_answer.map(TcpStream::from_std)
+            {
+                metrics::increment_counter("rate_limiter_read_timeouts_total", &[("part", "headers")]);
+                let _ = timeout_answer.write_all(REQUEST_TIMEOUT_RESPONSE).await;
+            }
+        });
+    }
+}
+
+// hyper closes connections whose headers timed out without an answer, so a second handle of the socket sends it
+fn with_answer_handle(stream: TcpStream) -> Result<(TcpStream, std::net::TcpStream), std::io::Error> {
+    let stream = stream.into_std()?;
+    let answer = stream.try_clone()?;
+    Ok((TcpStream::from_std(stream)?, answer))
+}
+
+
+#[derive(Debug, thiserror::Error)]
+#[error("Request body not received within the read timeout")]
+pub struct BodyTimeout;
+
+// Fails with `BodyTimeout` once the whole body wasn't received in time
+struct TimeoutBody {
+    inner: Body,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl TimeoutBody {
+    fn new(inner: Body, timeout: Duration) -> Self {
+        Self { inner, deadline: Box::pin(tokio::time::sleep(timeout)) }
+    }
+}
+
+impl hyper::body::Body for TimeoutBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+        self.deadline.as_mut().poll(cx).map(|_| Some(Err(axum::Error::new(BodyTimeout))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Answers requests whose body couldn't be read, with a 408 when it timed out and a 503 when it took the memory budget over
+pub fn body_error_response(e: &axum::Error) -> Response {
+    read_error_response(e).unwrap_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response())
+}
+
+// The 408 or 503 of a body that timed out or took the memory budget over anywhere in the sources of `e`,
+// such as an upstream request that failed while the body was streamed to it
+pub fn read_error_response(e: &(dyn std::error::Error + 'static)) -> Option<Response> {
+    let mut error = Some(e);
+    while let Some(source) = error {
+        if source.is::<BodyTimeout>() {
+            metrics::increment_counter("rate_limiter_read_timeouts_total", &[("part", "body")]);
+            return Some((StatusCode::REQUEST_TIMEOUT, [(CONNECTION, "close")], "Request timeout").into_response());
+        }
+        if let Some(exceeded) = source.downcast_ref::<MemoryBudgetExceeded>() {
+            return Some(memory_budget::exceeded_response(exceeded));
+        }
+        error = source.source();
+    }
+    None
+}
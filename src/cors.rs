@@ -0,0 +1,140 @@
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use axum::http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+use axum::response::IntoResponse;
+use crate::error::RateLimiterError;
+use crate::settings::CorsSettings;
+
+
+#[derive(Debug, Clone)]
+pub struct CorsRoute {
+    path_prefix: String,
+    // None allows any origin
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: HeaderValue,
+    // None echoes the headers the preflight asks for
+    allowed_headers: Option<HeaderValue>,
+    exposed_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age_secs: Option<u32>,
+    pub preflight_bypasses_limits: bool,
+}
+
+impl CorsRoute {
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        match &self.allowed_origins {
+            Some(origins) => origin.to_str().is_ok_and(|origin| origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))),
+            None => true,
+        }
+    }
+
+    // Answers the preflight of an allowed origin, the upstream never sees it
+    pub fn preflight(&self, origin: &HeaderValue, request_headers: &HeaderMap) -> Response<Body> {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        self.insert_origin(response.headers_mut(), origin);
+        let headers = response.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, self.allowed_methods.clone());
+        let allowed_headers = self.allowed_headers.clone().or_else(|| request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned());
+        if let Some(allowed_headers) = allowed_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        if let Some(max_age_secs) = self.max_age_secs {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age_secs));
+        }
+        response
+    }
+
+    // Replaces the CORS headers the upstream may have sent, the proxy owns CORS on the route
+    pub fn insert(&self, response: &mut Response<Body>, origin: &HeaderValue) {
+        let headers = response.headers_mut();
+        self.insert_origin(headers, origin);
+        if let Some(exposed_headers) = &self.exposed_headers {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers.clone());
+        }
+    }
+
+    fn insert_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        match self.allowed_origins {
+            Some(_) => headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone()),
+            None => headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*")),
+        };
+        if self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+
+// CORS of the routes it's configured for, following the settings of the longest matching route
+#[derive(Debug, Clone)]
+pub struct Cors {
+    routes: Vec<CorsRoute>,
+}
+
+impl Cors {
+    pub fn new(settings: &[CorsSettings]) -> Result<Self, RateLimiterError> {
+        let routes = settings.iter()
+            .map(|route| {
+                if !route.path_prefix.starts_with('/') {
+                    return Err(RateLimiterError::config(format!("cors path_prefix {} must start with /", route.path_prefix)));
+                }
+                if route.allowed_origins.is_empty() {
+                    return Err(RateLimiterError::config(format!("cors route {} needs allowed_origins", route.path_prefix)));
+                }
+                let any_origin = route.allowed_origins.iter().any(|origin| origin == "*");
+                if any_origin && route.allow_credentials {
+                    return Err(RateLimiterError::config(format!("cors route {} can't allow credentials from any origin", route.path_prefix)));
+                }
+                if let Some(method) = route.allowed_methods.iter().find(|method| Method::from_bytes(method.as_bytes()).is_err()) {
+                    return Err(RateLimiterError::config(format!("Invalid cors method {:?}", method)));
+                }
+                let any_header = route.allowed_headers.iter().any(|header| header == "*");
+                if let Some(header) = route.allowed_headers.iter().chain(&route.exposed_headers)
+                    .find(|header| *header != "*" && HeaderName::try_from(header.as_str()).is_err()) {
+                    return Err(RateLimiterError::config(format!("Invalid cors header {:?}", header)));
+                }
+
+                Ok(CorsRoute {
+                    path_prefix: route.path_prefix.trim_end_matches('/').to_string(),
+                    allowed_origins: (!any_origin).then(|| route.allowed_origins.clone()),
+                    allowed_methods: list(&route.allowed_methods)?,
+                    allowed_headers: match any_header {
+                        true => None,
+                        false => Some(list(&route.allowed_headers)?),
+                    },
+                    exposed_headers: (!route.exposed_headers.is_empty()).then(|| list(&route.exposed_headers)).transpose()?,
+                    allow_credentials: route.allow_credentials,
+                    max_age_secs: route.max_age_secs,
+                    preflight_bypasses_limits: route.preflight_bypasses_limits,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            routes,
+        })
+    }
+
+    // The route of a cross-origin request and its origin, None for same-origin requests and paths without CORS
+    pub fn route(&self, request: &Request<Body>) -> Option<(&CorsRoute, HeaderValue)> {
+        let origin = request.headers().get(ORIGIN)?.clone();
+        let path = request.uri().path();
+        self.routes.iter()
+            .filter(|route| path.strip_prefix(route.path_prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|route| route.path_prefix.len())
+            .map(|route| (route, origin))
+    }
+}
+
+// The method the preflight asks for, None if the request is not a preflight
+pub fn preflight_method(request: &Request<Body>) -> Option<Method> {
+    if request.method() != Method::OPTIONS || !request.headers().contains_key(ORIGIN) {
+        return None;
+    }
+    request.headers().get(ACCESS_CONTROL_REQUEST_METHOD).and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+}
+
+fn list(values: &[String]) -> Result<HeaderValue, RateLimiterError> {
+    HeaderValue::from_str(&values.join(", ")).map_err(|_| RateLimiterError::config(format!("Invalid cors list {:?}", values)))
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderName, HeaderValue, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use axum::routing::post;
+use serde::{Deserialize, Serialize};
+use crate::limiter::RateLimiterManager;
+use crate::strategy::SafeRequest;
+
+
+// Describes the request another gateway wants a decision for
+#[derive(Deserialize, Debug)]
+pub struct CheckRequest {
+    pub ip: IpAddr,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckResponse {
+    pub allowed: bool,
+    pub limit: Option<u32>,
+    pub remaining: Option<i32>,
+}
+
+
+// Serves rate limit decisions instead of proxying, so other gateways can delegate limiting to this service
+pub fn router(manager: Arc<RateLimiterManager>) -> Router {
+    Router::new()
+        .route("/v1/ratelimit/check", post(check_handler))
+        .with_state(manager)
+}
+
+async fn check_handler(State(manager): State<Arc<RateLimiterManager>>, Json(check_request): Json<CheckRequest>) -> Response {
+    let addr = SocketAddr::new(check_request.ip, 0);
+    if manager.is_whitelisted(&addr.ip()) {
+        return (StatusCode::OK, Json(CheckResponse { allowed: true, limit: None, remaining: None })).into_response();
+    }
+
+    let mut safe_request = match build_request(check_request) {
+        Ok(safe_request) => safe_request,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+
+    manager.classify_ip(&mut safe_request, addr.ip());
+    let lowest_limit = manager.check(&safe_request, addr).await;
+    let (status, response) = match lowest_limit {
+        Some(limit) => (
+            if limit.is_limit_exceeded { StatusCode::TOO_MANY_REQUESTS } else { StatusCode::OK },
+            CheckResponse { allowed: !limit.is_limit_exceeded, limit: Some(limit.total_limit), remaining: Some(limit.requests_to_exceed_limit.max(0)) },
+        ),
+        // No limiter matches the request
+        None if manager.denies_unmatched() => (StatusCode::FORBIDDEN, CheckResponse { allowed: false, limit: None, remaining: None }),
+        None => (StatusCode::OK, CheckResponse { allowed: true, limit: None, remaining: None }),
+    };
+
+    (status, Json(response)).into_response()
+}
+
+fn build_request(check_request: CheckRequest) -> Result<SafeRequest, String> {
+    let mut builder = Request::builder()
+        .method(check_request.method.as_str())
+        .uri(check_request.path.as_str());
+
+    for (name, value) in &check_request.headers {
+        let name = HeaderName::try_from(name.as_str()).map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+        let value = HeaderValue::try_from(value.as_str()).map_err(|e| format!("Invalid value of header {}: {}", name, e))?;
+        builder = builder.header(name, value);
+    }
+
+    let (parts, _) = builder.body(()).map_err(|e| format!("Invalid request: {}", e))?.into_parts();
+    Ok(SafeRequest::new(parts, Bytes::from(check_request.body)))
+}
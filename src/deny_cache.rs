@@ -0,0 +1,89 @@
+use std::sync::{Arc, Once, Weak};
+use std::time::Duration;
+use dashmap::DashMap;
+use crate::clock::Clock;
+use crate::gcra;
+use crate::settings::DenyCacheSettings;
+use crate::strategy::Bucket;
+
+
+// Remembers keys the store reported as exhausted, so their next requests are denied without a store round trip.
+// A verdict is kept until the window of the bucket ends in the store, but never longer than `max_ttl`:
+// this bounds how long a client stays denied after its counters were reset in the store.
+#[derive(Debug)]
+pub struct DenyCache {
+    // Microseconds since the unix epoch, as told by the clock of the manager
+    denied_until_us: Arc<DashMap<String, u64>>,
+    max_ttl: Duration,
+    max_keys: usize,
+    clock: Arc<dyn Clock>,
+    sweeper: Once,
+}
+
+impl DenyCache {
+    // The cleanup of expired verdicts starts with the first one, so the cache can be built outside of a tokio runtime
+    pub fn new(settings: &DenyCacheSettings, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            denied_until_us: Arc::new(DashMap::new()),
+            max_ttl: Duration::from_millis(settings.max_ttl_ms.max(1)),
+            max_keys: settings.max_keys,
+            clock,
+            sweeper: Once::new(),
+        }
+    }
+
+    pub fn is_denied(&self, key: &str) -> bool {
+        self.denied_until_us.get(key).is_some_and(|denied_until_us| *denied_until_us > self.clock.now_us())
+    }
+
+    // `window_ends_in` comes from the store, a key that exhausted late in its window is only denied until that window ends
+    pub fn deny(&self, key: &str, bucket: &Bucket, window_ends_in: Option<Duration>) {
+        // New keys are dropped once full, they are just checked in the store as usual
+        if self.denied_until_us.len() >= self.max_keys && !self.denied_until_us.contains_key(key) {
+            return;
+        }
+
+        // Buckets with a burst get a token back every emission interval, when the store can't tell
+        // a whole window is the upper bound for the others
+        let refill = match bucket.burst {
+            Some(_) => Duration::from_micros(gcra::emission_interval_us(bucket)),
+            None => window_ends_in.unwrap_or(Duration::from_secs(bucket.add_tokens_every as u64)),
+        };
+        if refill.is_zero() {
+            return;
+        }
+        self.sweeper.call_once(|| {
+            tokio::spawn(remove_expired(Arc::downgrade(&self.denied_until_us), self.max_ttl, self.clock.clone()));
+        });
+        self.denied_until_us.insert(key.to_string(), self.clock.now_us() + refill.min(self.max_ttl).as_micros() as u64);
+    }
+}
+
+async fn remove_expired(denied_until_us: Weak<DashMap<String, u64>>, every: Duration, clock: Arc<dyn Clock>) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(denied_until_us) = denied_until_us.upgrade() else {
+            return;
+        };
+        let now_us = clock.now_us();
+        denied_until_us.retain(|_, denied_until_us| *denied_until_us > now_us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::SystemClock;
+    use super::*;
+
+    #[test]
+    fn can_be_built_outside_of_a_runtime() {
+        let deny_cache = DenyCache::new(&DenyCacheSettings { max_ttl_ms: 60_000, max_keys: 10 }, Arc::new(SystemClock));
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async { deny_cache.deny("key", &Bucket::new(2, 60), None) });
+        assert!(deny_cache.is_denied("key"));
+        assert!(!deny_cache.is_denied("other"));
+    }
+}
@@ -0,0 +1,229 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+use hickory_resolver::TokioAsyncResolver;
+use crate::error::RateLimiterError;
+use crate::kubernetes::KubernetesSource;
+use crate::metrics;
+use crate::settings::{DiscoverySettings, DnsDiscoverySettings};
+
+// Wait before listing the EndpointSlices again after the API server failed
+const KUBERNETES_RETRY: Duration = Duration::from_secs(5);
+
+
+#[derive(Debug)]
+enum DnsQuery {
+    // A and AAAA records, all with the same port
+    Host { host: String, port: u16 },
+    Srv(String),
+}
+
+#[derive(Debug)]
+struct DnsSource {
+    resolver: TokioAsyncResolver,
+    query: DnsQuery,
+    min_refresh: Duration,
+    max_refresh: Duration,
+}
+
+impl DnsSource {
+    fn new(settings: &DnsDiscoverySettings) -> Result<Self, RateLimiterError> {
+        let query = match (&settings.host, settings.port, &settings.srv) {
+            (Some(host), Some(port), None) => DnsQuery::Host { host: host.clone(), port },
+            (Some(host), None, None) => return Err(RateLimiterError::config(format!("dns discovery of {} needs a port", host))),
+            (None, None, Some(srv)) => DnsQuery::Srv(srv.clone()),
+            _ => return Err(RateLimiterError::config("dns discovery needs either host and port or srv")),
+        };
+        if settings.min_refresh_secs == 0 || settings.min_refresh_secs > settings.max_refresh_secs {
+            return Err(RateLimiterError::config("min_refresh_secs of dns discovery must be greater than 0 and at most max_refresh_secs"));
+        }
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| RateLimiterError::config(format!("Failed to read the system DNS configuration for dns discovery: {}", e)))?;
+
+        Ok(Self {
+            resolver,
+            query,
+            min_refresh: Duration::from_secs(settings.min_refresh_secs),
+            max_refresh: Duration::from_secs(settings.max_refresh_secs),
+        })
+    }
+
+    fn name(&self) -> &str {
+        match &self.query {
+            DnsQuery::Host { host, .. } => host,
+            DnsQuery::Srv(srv) => srv,
+        }
+    }
+
+    // Returns the weighted upstreams with the time the shortest TTL of their records runs out
+    async fn resolve(&self) -> Result<(Vec<(String, u64)>, Instant), String> {
+        match &self.query {
+            DnsQuery::Host { host, port } => {
+                let lookup = self.resolver.lookup_ip(host.as_str()).await.map_err(|e| e.to_string())?;
+                let upstreams = lookup.iter().map(|ip| (SocketAddr::new(ip, *port).to_string(), 1)).collect();
+                Ok((upstreams, lookup.valid_until()))
+            },
+            DnsQuery::Srv(srv) => {
+                let lookup = self.resolver.srv_lookup(srv.as_str()).await.map_err(|e| e.to_string())?;
+                let mut valid_until = lookup.as_lookup().valid_until();
+                // Records of a higher priority value are backups, and upstreams aren't health checked, so they are left out
+                let Some(priority) = lookup.iter().map(|record| record.priority()).min() else {
+                    return Ok((Vec::new(), valid_until));
+                };
+                let mut upstreams = Vec::new();
+                for record in lookup.iter().filter(|record| record.priority() == priority) {
+                    let ips = self.resolver.lookup_ip(record.target().clone()).await.map_err(|e| format!("{}: {}", record.target(), e))?;
+                    valid_until = valid_until.min(ips.valid_until());
+                    // Records of weight 0 still get a share
+                    let weight = record.weight().max(1) as u64;
+                    upstreams.extend(ips.iter().map(|ip| (SocketAddr::new(ip, record.port()).to_string(), weight)));
+                }
+                Ok((upstreams, valid_until))
+            },
+        }
+    }
+}
+
+
+#[derive(Debug)]
+enum DiscoverySource {
+    Dns(DnsSource),
+    Kubernetes(KubernetesSource),
+}
+
+// Upstreams of a listener found at runtime instead of a fixed target_url, requests are spread across them by weight
+#[derive(Debug)]
+pub struct Discovery {
+    source: DiscoverySource,
+    // Upstreams with the running total of the weights up to and including them
+    upstreams: RwLock<Vec<(String, u64)>>,
+    requests: AtomicU64,
+}
+
+impl Discovery {
+    pub fn new(settings: &DiscoverySettings) -> Result<Arc<Self>, RateLimiterError> {
+        let source = match settings {
+            DiscoverySettings::Dns(settings) => DiscoverySource::Dns(DnsSource::new(settings)?),
+            DiscoverySettings::Kubernetes(settings) => DiscoverySource::Kubernetes(KubernetesSource::new(settings)?),
+        };
+        Ok(Arc::new(Self {
+            source,
+            upstreams: RwLock::new(Vec::new()),
+            requests: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.source {
+            DiscoverySource::Dns(dns) => dns.name(),
+            DiscoverySource::Kubernetes(kubernetes) => kubernetes.service(),
+        }
+    }
+
+    // Finds the upstreams before the listener serves, then keeps them fresh in the background
+    pub async fn start(self: &Arc<Self>) {
+        match &self.source {
+            DiscoverySource::Dns(dns) => {
+                let next_refresh = self.refresh(dns).await;
+                tokio::spawn(refresh_upstreams(Arc::downgrade(self), next_refresh));
+            },
+            DiscoverySource::Kubernetes(kubernetes) => {
+                let resource_version = self.list(kubernetes).await;
+                tokio::spawn(watch_upstreams(Arc::downgrade(self), resource_version));
+            },
+        }
+    }
+
+    pub fn upstreams(&self) -> Vec<String> {
+        self.upstreams.read().unwrap_or_else(|e| e.into_inner()).iter().map(|(target_url, _)| target_url.clone()).collect()
+    }
+
+    // Takes the upstreams in turn, as often as their weight says. None until the first upstream is found.
+    pub fn select(&self) -> Option<String> {
+        let upstreams = self.upstreams.read().unwrap_or_else(|e| e.into_inner());
+        let (_, total_weight) = upstreams.last()?;
+        let point = self.requests.fetch_add(1, Ordering::Relaxed) % total_weight;
+        upstreams.iter().find(|(_, weight)| point < *weight).map(|(target_url, _)| target_url.clone())
+    }
+
+    // On errors the last known upstreams are kept, returns when to look them up again
+    async fn refresh(&self, dns: &DnsSource) -> Duration {
+        match dns.resolve().await {
+            Ok((upstreams, valid_until)) => {
+                self.set_upstreams(upstreams);
+                valid_until.saturating_duration_since(Instant::now()).clamp(dns.min_refresh, dns.max_refresh)
+            },
+            Err(e) => {
+                println!("Failed to resolve the upstreams of dns discovery {}: {}", dns.name(), e);
+                dns.min_refresh
+            },
+        }
+    }
+
+    // Returns the version to watch the EndpointSlices from, None when they must be listed again
+    async fn list(&self, kubernetes: &KubernetesSource) -> Option<String> {
+        match kubernetes.list().await {
+            Ok((upstreams, resource_version)) => {
+                self.set_upstreams(upstreams.into_iter().map(|target_url| (target_url, 1)).collect());
+                Some(resource_version)
+            },
+            Err(e) => {
+                println!("Failed to list the EndpointSlices of kubernetes discovery {}: {}", kubernetes.service(), e);
+                None
+            },
+        }
+    }
+
+    fn set_upstreams(&self, upstreams: Vec<(String, u64)>) {
+        metrics::set_gauge("rate_limiter_discovery_upstreams", &[("discovery", self.name())], upstreams.len() as u64);
+        let mut total_weight = 0;
+        let upstreams = upstreams.into_iter()
+            .map(|(target_url, weight)| {
+                total_weight += weight;
+                (target_url, total_weight)
+            })
+            .collect();
+        *self.upstreams.write().unwrap_or_else(|e| e.into_inner()) = upstreams;
+    }
+}
+
+async fn refresh_upstreams(discovery: Weak<Discovery>, mut next_refresh: Duration) {
+    loop {
+        tokio::time::sleep(next_refresh).await;
+
+        let Some(discovery) = discovery.upgrade() else {
+            return;
+        };
+        let DiscoverySource::Dns(dns) = &discovery.source else {
+            return;
+        };
+        next_refresh = discovery.refresh(dns).await;
+    }
+}
+
+// On errors the last known upstreams are kept until the EndpointSlices could be listed again
+async fn watch_upstreams(discovery: Weak<Discovery>, mut resource_version: Option<String>) {
+    loop {
+        if resource_version.is_none() {
+            tokio::time::sleep(KUBERNETES_RETRY).await;
+        }
+
+        let Some(discovery) = discovery.upgrade() else {
+            return;
+        };
+        let DiscoverySource::Kubernetes(kubernetes) = &discovery.source else {
+            return;
+        };
+        resource_version = match resource_version {
+            Some(version) => match kubernetes.watch(&version, |upstreams| discovery.set_upstreams(upstreams.into_iter().map(|target_url| (target_url, 1)).collect())).await {
+                Ok(version) => Some(version),
+                Err(e) => {
+                    println!("Failed to watch the EndpointSlices of kubernetes discovery {}: {}", kubernetes.service(), e);
+                    None
+                },
+            },
+            None => discovery.list(kubernetes).await,
+        };
+    }
+}
@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+use axum::http::HeaderName;
+use url::form_urlencoded;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::DistinctLimitSettings;
+use crate::strategy::{header_value, SafeRequest};
+
+
+#[derive(Debug, Clone)]
+enum Resource {
+    QueryParam(String),
+    // Counting from 0, unlike the settings
+    PathSegment(usize),
+}
+
+
+// Bounds the number of distinct resources, e.g. user ids, a client touches per window. Enumeration and scraping
+// often stay under the request rate limits, but touch far more resources than regular clients.
+#[derive(Debug)]
+pub struct DistinctLimit {
+    pub name: String,
+    header: Option<HeaderName>,
+    path_prefixes: Vec<String>,
+    resource: Resource,
+    pub max_distinct: u64,
+    window_secs: u32,
+}
+
+impl DistinctLimit {
+    pub fn new(settings: &DistinctLimitSettings) -> Result<Self, RateLimiterError> {
+        let name = &settings.name;
+        if settings.max_distinct == 0 || settings.window_secs == 0 {
+            return Err(RateLimiterError::config(format!("max_distinct and window_secs of distinct limit {} must be greater than 0", name)));
+        }
+        let resource = match (&settings.query_param, settings.path_segment) {
+            (Some(param), None) => Resource::QueryParam(param.clone()),
+            (None, Some(segment)) if segment > 0 => Resource::PathSegment(segment - 1),
+            (None, Some(_)) => return Err(RateLimiterError::config(format!("path_segment of distinct limit {} counts from 1", name))),
+            _ => return Err(RateLimiterError::config(format!("Distinct limit {} needs either query_param or path_segment", name))),
+        };
+        if let Some(prefix) = settings.path_prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+            return Err(RateLimiterError::config(format!("Path prefix {} of distinct limit {} must start with /", prefix, name)));
+        }
+        let header = settings.header.as_deref()
+            .map(HeaderName::try_from)
+            .transpose()
+            .map_err(|e| RateLimiterError::config(format!("Invalid header of distinct limit {}: {}", name, e)))?;
+
+        Ok(Self {
+            name: name.clone(),
+            header,
+            path_prefixes: settings.path_prefixes.iter().map(|prefix| prefix.trim_end_matches('/').to_string()).collect(),
+            resource,
+            max_distinct: settings.max_distinct,
+            window_secs: settings.window_secs,
+        })
+    }
+
+    // Client and resource of the request, None if the limit doesn't apply to it
+    pub fn values(&self, request: &SafeRequest, addr: SocketAddr) -> Option<(String, String)> {
+        let path = request.parts.uri.path();
+        let is_counted = self.path_prefixes.is_empty() || self.path_prefixes.iter()
+            .any(|prefix| path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')));
+        if !is_counted {
+            return None;
+        }
+
+        let client = match &self.header {
+            Some(header) => header_value(request.parts.headers.get(header)?).into_owned(),
+            None => addr.ip().to_string(),
+        };
+        let resource = match &self.resource {
+            Resource::QueryParam(param) => form_urlencoded::parse(request.parts.uri.query()?.as_bytes())
+                .find(|(name, _)| name == param)
+                .map(|(_, value)| value.into_owned())?,
+            Resource::PathSegment(index) => path.split('/').filter(|segment| !segment.is_empty()).nth(*index)?.to_string(),
+        };
+        Some((client, resource))
+    }
+
+    // Windows are aligned on the unix epoch and part of the key, so every window starts with an empty set.
+    // Returns the key with the seconds until the window ends.
+    pub fn get_key(&self, client: &str, key_builder: &KeyBuilder, now: u64) -> (String, u32) {
+        let window_secs = self.window_secs as u64;
+        let key = key_builder.build("distinct", &format!("{}:{}:{}", self.name, now / window_secs, client));
+        (key, (window_secs - now % window_secs) as u32)
+    }
+}
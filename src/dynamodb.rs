@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use axum::async_trait;
+use tokio::sync::OnceCell;
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::strategy::Bucket;
+use crate::settings::DynamoDBSettings;
+use crate::store::LimitStore;
+
+const MAX_CONDITIONAL_RETRIES: usize = 16;
+
+
+// Every bucket is an item with the `key` partition key, a `remaining` counter and an `expires_at` unix timestamp.
+// Buckets with a burst store their GCRA `tat` instead of `remaining`, see the gcra module.
+// The next window of a bucket that can borrow starts with the debt of the last one, unless TTL removed it already.
+// Enable DynamoDB TTL on `expires_at` so expired buckets get removed from the table.
+// Timestamps come from the clocks of the proxies, so a window only ends once `skew_secs` passed after its `expires_at`.
+#[derive(Debug)]
+pub struct DynamoDBStore {
+    settings: DynamoDBSettings,
+    client: OnceCell<Client>,
+    clock: Arc<dyn Clock>,
+    skew_secs: u64,
+}
+
+impl DynamoDBStore {
+    pub fn new(settings: &DynamoDBSettings, clock: Arc<dyn Clock>, skew_ms: u64) -> Self {
+        Self {
+            settings: settings.clone(),
+            client: OnceCell::new(),
+            clock,
+            skew_secs: skew_ms.div_ceil(1000),
+        }
+    }
+
+    // Loading AWS credentials is async, so the client is created on first use
+    async fn client(&self) -> &Client {
+        self.client.get_or_init(|| async {
+            let mut loader = aws_config::defaults(BehaviorVersion::latest());
+            if let Some(region) = &self.settings.region {
+                loader = loader.region(Region::new(region.clone()));
+            }
+            if let Some(endpoint_url) = &self.settings.endpoint_url {
+                loader = loader.endpoint_url(endpoint_url);
+            }
+            Client::new(&loader.load().await)
+        }).await
+    }
+
+    async fn decrement(&self, key: &str, tokens: u32, now: u64) -> Result<Option<i32>, RateLimiterError> {
+        let result = self.client().await.update_item()
+            .table_name(&self.settings.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .update_expression("SET #remaining = #remaining - :tokens")
+            .condition_expression("attribute_exists(#remaining) AND #expires_at > :now")
+            .expression_attribute_names("#remaining", "remaining")
+            .expression_attribute_names("#expires_at", "expires_at")
+            .expression_attribute_values(":tokens", AttributeValue::N(tokens.to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => output.attributes()
+                .and_then(|attributes| attributes.get("remaining"))
+                .and_then(|remaining| remaining.as_n().ok())
+                .and_then(|remaining| remaining.parse().ok())
+                .map(Some)
+                .ok_or_else(|| RateLimiterError::config("DynamoDB returned no remaining tokens")),
+            // The bucket doesn't exist yet or is expired
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(None),
+            Err(e) => Err(RateLimiterError::Store(e.to_string())),
+        }
+    }
+
+    async fn create(&self, key: &str, remaining: i32, expires_at: u64, now: u64) -> Result<bool, RateLimiterError> {
+        let result = self.client().await.put_item()
+            .table_name(&self.settings.table)
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("remaining", AttributeValue::N(remaining.to_string()))
+            .item("expires_at", AttributeValue::N(expires_at.to_string()))
+            .condition_expression("attribute_not_exists(#remaining) OR #expires_at <= :now")
+            .expression_attribute_names("#remaining", "remaining")
+            .expression_attribute_names("#expires_at", "expires_at")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            // Another instance created the bucket first
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+            Err(e) => Err(RateLimiterError::Store(e.to_string())),
+        }
+    }
+
+    async fn read_window(&self, key: &str) -> Result<Option<(i32, u64)>, RateLimiterError> {
+        let output = self.client().await.get_item()
+            .table_name(&self.settings.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| RateLimiterError::Store(e.to_string()))?;
+
+        let number = |name: &str| output.item()
+            .and_then(|item| item.get(name))
+            .and_then(|value| value.as_n().ok())
+            .and_then(|value| value.parse::<i64>().ok());
+        Ok(number("remaining").zip(number("expires_at")).map(|(remaining, expires_at)| (remaining as i32, expires_at as u64)))
+    }
+
+    async fn read_tat(&self, key: &str) -> Result<Option<u64>, RateLimiterError> {
+        let output = self.client().await.get_item()
+            .table_name(&self.settings.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| RateLimiterError::Store(e.to_string()))?;
+
+        Ok(output.item()
+            .and_then(|item| item.get("tat"))
+            .and_then(|tat| tat.as_n().ok())
+            .and_then(|tat| tat.parse().ok()))
+    }
+
+    // Only writes if the TAT is still the one that was read, returns false otherwise
+    async fn write_tat(&self, key: &str, tat_us: u64, previous_tat_us: Option<u64>, expires_at: u64) -> Result<bool, RateLimiterError> {
+        let request = self.client().await.put_item()
+            .table_name(&self.settings.table)
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("tat", AttributeValue::N(tat_us.to_string()))
+            .item("expires_at", AttributeValue::N(expires_at.to_string()))
+            .expression_attribute_names("#tat", "tat");
+        let request = match previous_tat_us {
+            Some(previous_tat_us) => request
+                .condition_expression("#tat = :previous")
+                .expression_attribute_values(":previous", AttributeValue::N(previous_tat_us.to_string())),
+            None => request.condition_expression("attribute_not_exists(#tat)"),
+        };
+
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) => Ok(false),
+            Err(e) => Err(RateLimiterError::Store(e.to_string())),
+        }
+    }
+
+    async fn consume_burst(&self, key: &str, bucket: &Bucket, burst: u32, tokens: u32) -> Result<i32, RateLimiterError> {
+        for _ in 0..MAX_CONDITIONAL_RETRIES {
+            let previous_tat_us = self.read_tat(key).await?;
+            let now_us = self.clock.now_us();
+            let (tat_us, remaining) = gcra::consume(previous_tat_us, now_us, bucket, burst, tokens, self.skew_secs * 1_000_000);
+
+            // Nothing was taken, e.g. the bucket is empty
+            if previous_tat_us == Some(tat_us) {
+                return Ok(remaining);
+            }
+            let expires_at = (now_us + gcra::ttl_us(tat_us, now_us)).div_ceil(1_000_000) + self.skew_secs;
+            if self.write_tat(key, tat_us, previous_tat_us, expires_at).await? {
+                return Ok(remaining);
+            }
+        }
+
+        Err(RateLimiterError::Store(format!("Too many concurrent updates of DynamoDB key {}", key)))
+    }
+}
+
+#[async_trait]
+impl LimitStore for DynamoDBStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        if let Some(burst) = bucket.burst {
+            return self.consume_burst(key, bucket, burst, tokens).await;
+        }
+
+        for _ in 0..MAX_CONDITIONAL_RETRIES {
+            let now = self.clock.now_secs();
+            // Windows are compared as of `skew_secs` ago but new ones still last a full window from now
+            let skewed_now = now.saturating_sub(self.skew_secs);
+            if let Some(remaining) = self.decrement(key, tokens, skewed_now).await? {
+                return Ok(remaining);
+            }
+
+            let refill = match bucket.borrow {
+                0 => None,
+                _ => self.read_window(key).await?,
+            }
+                .filter(|(_, expires_at)| expires_at + bucket.debt_secs() as u64 > skewed_now)
+                .map_or(bucket.tokens_count as i32, |(remaining, _)| bucket.refill(remaining));
+            let remaining = refill - tokens as i32;
+            if self.create(key, remaining, now + bucket.add_tokens_every as u64, skewed_now).await? {
+                return Ok(remaining);
+            }
+        }
+
+        Err(RateLimiterError::Store(format!("Too many concurrent updates of DynamoDB key {}", key)))
+    }
+
+    // Only reads the item, so peeking doesn't create the bucket nor start its window
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        if let Some(burst) = bucket.burst {
+            let tat_us = self.read_tat(key).await?;
+            return Ok(gcra::consume(tat_us, self.clock.now_us(), bucket, burst, 0, self.skew_secs * 1_000_000).1);
+        }
+
+        let skewed_now = self.clock.now_secs().saturating_sub(self.skew_secs);
+        Ok(match self.read_window(key).await? {
+            Some((remaining, expires_at)) if expires_at > skewed_now => remaining,
+            Some((remaining, expires_at)) if expires_at + bucket.debt_secs() as u64 > skewed_now => bucket.refill(remaining),
+            _ => bucket.tokens_count as i32,
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::body::Bytes;
+use axum::http::Request;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::codec::ProstCodec;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::strategy::{SafeRequest, Strategy};
+
+
+// Messages of the envoy.service.ratelimit.v3 protocol, only the fields this server reads or writes are declared
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitRequest {
+    #[prost(string, tag = "1")]
+    pub domain: String,
+    #[prost(message, repeated, tag = "2")]
+    pub descriptors: Vec<RateLimitDescriptor>,
+    #[prost(uint32, tag = "3")]
+    pub hits_addend: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitDescriptor {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<DescriptorEntry>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DescriptorEntry {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitResponse {
+    #[prost(enumeration = "Code", tag = "1")]
+    pub overall_code: i32,
+    #[prost(message, repeated, tag = "2")]
+    pub statuses: Vec<DescriptorStatus>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DescriptorStatus {
+    #[prost(enumeration = "Code", tag = "1")]
+    pub code: i32,
+    #[prost(message, optional, tag = "2")]
+    pub current_limit: Option<RateLimit>,
+    #[prost(uint32, tag = "3")]
+    pub limit_remaining: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimit {
+    #[prost(uint32, tag = "1")]
+    pub requests_per_unit: u32,
+    #[prost(enumeration = "Unit", tag = "2")]
+    pub unit: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Code {
+    Unknown = 0,
+    Ok = 1,
+    OverLimit = 2,
+}
+
+// Buckets are defined in seconds, so the unit of the reported limit is always unknown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Unit {
+    Unknown = 0,
+}
+
+
+// Answers Envoy's ShouldRateLimit calls. Every descriptor is checked on its own against the limiters it has input for:
+// `remote_address` feeds ip limiters, `path` (or `:path`) url and query limiters, `method` sets the request method,
+// and any other entry is passed as a request header named after the entry key, for header limiters.
+#[derive(Clone, Debug)]
+pub struct RateLimitService {
+    manager: Arc<RateLimiterManager>,
+}
+
+impl RateLimitService {
+    pub fn new(manager: Arc<RateLimiterManager>) -> Self {
+        Self {
+            manager,
+        }
+    }
+
+    async fn should_rate_limit(&self, request: RateLimitRequest) -> RateLimitResponse {
+        let mut statuses = Vec::with_capacity(request.descriptors.len());
+        for descriptor in &request.descriptors {
+            statuses.push(self.check_descriptor(descriptor).await);
+        }
+
+        let overall_code = match statuses.iter().any(|status| status.code == Code::OverLimit as i32) {
+            true => Code::OverLimit,
+            false => Code::Ok,
+        };
+        RateLimitResponse { overall_code: overall_code as i32, statuses }
+    }
+
+    async fn check_descriptor(&self, descriptor: &RateLimitDescriptor) -> DescriptorStatus {
+        let mut builder = Request::builder();
+        let mut ip = None;
+        let (mut has_path, mut has_headers) = (false, false);
+
+        for entry in &descriptor.entries {
+            match entry.key.as_str() {
+                "remote_address" => ip = entry.value.parse::<IpAddr>().ok(),
+                "path" | ":path" => {
+                    builder = builder.uri(entry.value.as_str());
+                    has_path = true;
+                },
+                "method" | ":method" => builder = builder.method(entry.value.as_str()),
+                key => {
+                    builder = builder.header(key, entry.value.as_str());
+                    has_headers = true;
+                },
+            }
+        }
+
+        let Ok(request) = builder.body(()) else {
+            return DescriptorStatus { code: Code::Unknown as i32, current_limit: None, limit_remaining: 0 };
+        };
+        if let Some(ip) = ip && self.manager.is_whitelisted(&ip) {
+            return DescriptorStatus { code: Code::Ok as i32, current_limit: None, limit_remaining: 0 };
+        }
+
+        let (parts, _) = request.into_parts();
+        let mut safe_request = SafeRequest::new(parts, Bytes::new());
+        if let Some(ip) = ip {
+            self.manager.classify_ip(&mut safe_request, ip);
+        }
+        let addr = SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), 0);
+        let limit = self.manager.check_strategies(&safe_request, addr, |strategy| match strategy {
+            Strategy::IP(_) => ip.is_some(),
+            Strategy::Url(_) | Strategy::Query(_) | Strategy::Operation(_) => has_path,
+            Strategy::Header(_) => has_headers,
+            // Descriptors carry neither a body nor an identity authenticated by the proxy
+            Strategy::Body(_) | Strategy::Identity(_) => false,
+        }).await;
+
+        match limit {
+            Some(limit) => DescriptorStatus {
+                code: if limit.is_limit_exceeded { Code::OverLimit as i32 } else { Code::Ok as i32 },
+                current_limit: Some(RateLimit { requests_per_unit: limit.total_limit, unit: Unit::Unknown as i32 }),
+                limit_remaining: limit.requests_to_exceed_limit.max(0) as u32,
+            },
+            None => DescriptorStatus { code: Code::Ok as i32, current_limit: None, limit_remaining: 0 },
+        }
+    }
+}
+
+impl NamedService for RateLimitService {
+    const NAME: &'static str = "envoy.service.ratelimit.v3.RateLimitService";
+}
+
+impl<B> Service<http::Request<B>> for RateLimitService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        match request.uri().path() {
+            "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(ShouldRateLimit(service), request).await)
+            }),
+            _ => Box::pin(async move {
+                Ok(tonic::Status::unimplemented("Unknown method").into_http())
+            }),
+        }
+    }
+}
+
+
+struct ShouldRateLimit(RateLimitService);
+
+impl UnaryService<RateLimitRequest> for ShouldRateLimit {
+    type Response = RateLimitResponse;
+    type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<RateLimitRequest>) -> Self::Future {
+        let service = self.0.clone();
+        Box::pin(async move {
+            Ok(tonic::Response::new(service.should_rate_limit(request.into_inner()).await))
+        })
+    }
+}
+
+
+pub async fn serve(addr: &str, manager: Arc<RateLimiterManager>) -> Result<(), RateLimiterError> {
+    let addr = addr.parse::<SocketAddr>()
+        .map_err(|e| RateLimiterError::config(format!("Invalid api_gateway.grpc_addr {}: {}", addr, e)))?;
+
+    tonic::transport::Server::builder()
+        .add_service(RateLimitService::new(manager))
+        .serve(addr)
+        .await
+        .map_err(|e| RateLimiterError::Io(std::io::Error::other(e)))
+}
@@ -0,0 +1,45 @@
+use deadpool_redis::redis::RedisError;
+use deadpool_redis::PoolError;
+
+
+// Errors of the proxy and of the limiter API. Invalid settings are reported as `Config` with a message naming the setting.
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimiterError {
+    #[error("{0}")]
+    Config(String),
+    #[error("Redis error: {0}")]
+    Redis(String),
+    // Other storage backends, e.g. memcached or DynamoDB
+    #[error("Store error: {0}")]
+    Store(String),
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl RateLimiterError {
+    pub fn config(message: impl std::fmt::Display) -> Self {
+        Self::Config(message.to_string())
+    }
+}
+
+impl From<RedisError> for RateLimiterError {
+    fn from(e: RedisError) -> Self {
+        Self::Redis(e.to_string())
+    }
+}
+
+impl From<PoolError> for RateLimiterError {
+    fn from(e: PoolError) -> Self {
+        Self::Redis(format!("Could not get a Redis connection: {}", e))
+    }
+}
+
+impl From<config::ConfigError> for RateLimiterError {
+    fn from(e: config::ConfigError) -> Self {
+        Self::Config(e.to_string())
+    }
+}
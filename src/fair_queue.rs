@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use tokio::sync::oneshot;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::metrics;
+use crate::settings::{FairQueueSettings, KeySettings};
+use crate::strategy::{header_value, Bucket, BucketsPerValue, SafeRequest, Strategy};
+
+// Virtual time a request of weight 1 takes, heavier clients advance it by a fraction
+const WEIGHT_SCALE: u64 = 1 << 20;
+
+
+// Requests waiting for one upstream, ordered by start-time fair queuing: a request starts in virtual time where the
+// previous one of its client finished, or now if the client has nothing ahead, so busy clients queue behind themselves.
+#[derive(Debug, Default)]
+struct Schedule {
+    in_flight: usize,
+    // Start tag of the last request let through
+    now: u64,
+    // Where the last request of each client finishes in virtual time
+    finishes: HashMap<String, u64>,
+    // Senders of the waiting requests by start tag, ties broken by arrival
+    waiting: BTreeMap<(u64, u64), oneshot::Sender<Turn>>,
+    arrivals: u64,
+}
+
+
+// Lets requests through to each upstream at most max_concurrent at a time and queues the others, taking turns
+// between clients in proportion to the weight of their tier instead of first come, first served.
+#[derive(Debug)]
+pub struct FairQueue {
+    max_concurrent: usize,
+    max_queued: usize,
+    timeout: Duration,
+    strategy: Strategy,
+    // Names the strategy looks for, e.g. header names, with placeholder buckets
+    values: BucketsPerValue,
+    key_builder: KeyBuilder,
+    tier_header: Option<HeaderName>,
+    weights: HashMap<String, u32>,
+    schedules: DashMap<String, Arc<Mutex<Schedule>>>,
+}
+
+impl FairQueue {
+    pub fn new(settings: &FairQueueSettings) -> Result<Self, RateLimiterError> {
+        if settings.max_concurrent == 0 {
+            return Err(RateLimiterError::config("fair_queue.max_concurrent must be greater than 0"));
+        }
+        if let Some((tier, _)) = settings.weights.iter().find(|(_, weight)| **weight == 0) {
+            return Err(RateLimiterError::config(format!("Weight of fair_queue tier {} must be greater than 0", tier)));
+        }
+        let tier_header = settings.tier_header.as_deref()
+            .map(HeaderName::try_from)
+            .transpose()
+            .map_err(|e| RateLimiterError::config(format!("Invalid fair_queue.tier_header: {}", e)))?;
+
+        Ok(Self {
+            max_concurrent: settings.max_concurrent,
+            max_queued: settings.max_queued,
+            timeout: Duration::from_millis(settings.queue_timeout_ms),
+            strategy: Strategy::from_possible_strategy(&settings.strategy),
+            values: BucketsPerValue::new(settings.values.iter().map(|value| (value.clone(), Bucket::new(1, 1)))),
+            key_builder: KeyBuilder::new(&KeySettings::default())?,
+            tier_header,
+            weights: settings.weights.clone(),
+            schedules: DashMap::new(),
+        })
+    }
+
+    pub fn needs_body(&self) -> bool {
+        self.strategy.needs_body()
+    }
+
+    // Waits for the turn of the request to be sent to the upstream, or the 503 to answer once the queue is full
+    // or the request waited for queue_timeout_ms. The upstream is free for the next request when the turn is dropped.
+    pub async fn wait_turn(&self, upstream: &str, request: &SafeRequest, addr: SocketAddr) -> Result<Turn, Response> {
+        let placeholder = Bucket::new(1, 1);
+        let client = self.strategy.get_key(request, addr, Some(&placeholder), Some(&self.values), &self.key_builder)
+            .map(|limit_key| limit_key.key)
+            .unwrap_or_default();
+        let weight = self.tier_header.as_ref()
+            .and_then(|header| request.parts.headers.get(header))
+            .and_then(|value| self.weights.get(header_value(value).as_ref()))
+            .copied()
+            .unwrap_or(1);
+        let schedule = match self.schedules.get(upstream) {
+            Some(schedule) => schedule.clone(),
+            None => self.schedules.entry(upstream.to_string()).or_default().clone(),
+        };
+
+        let (tag, mut receiver) = {
+            let mut state = schedule.lock().unwrap_or_else(|e| e.into_inner());
+            if state.in_flight >= self.max_concurrent && state.waiting.len() >= self.max_queued {
+                return Err(self.reject(upstream, "full"));
+            }
+            let start = state.finishes.get(&client).map_or(state.now, |finish| (*finish).max(state.now));
+            state.finishes.insert(client, start + (WEIGHT_SCALE / weight as u64).max(1));
+            if state.in_flight < self.max_concurrent && state.waiting.is_empty() {
+                state.in_flight += 1;
+                state.now = start;
+                return Ok(Turn(Some(schedule.clone())));
+            }
+
+            let tag = (start, state.arrivals);
+            state.arrivals += 1;
+            let (sender, receiver) = oneshot::channel();
+            state.waiting.insert(tag, sender);
+            (tag, receiver)
+        };
+
+        if let Ok(Ok(turn)) = tokio::time::timeout(self.timeout, &mut receiver).await {
+            return Ok(turn);
+        }
+        // Turns are given under the lock, so a request that isn't waiting anymore already has its turn
+        let mut state = schedule.lock().unwrap_or_else(|e| e.into_inner());
+        if state.waiting.remove(&tag).is_some() {
+            return Err(self.reject(upstream, "timeout"));
+        }
+        drop(state);
+        receiver.try_recv().map_err(|_| self.reject(upstream, "timeout"))
+    }
+
+    fn reject(&self, upstream: &str, reason: &str) -> Response {
+        metrics::increment_counter("rate_limiter_fair_queue_rejected_total", &[("upstream", upstream), ("reason", reason)]);
+        (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, "1")], "Upstream overloaded").into_response()
+    }
+}
+
+
+// The right of a request to be at the upstream, handed to the next waiting request when dropped
+#[derive(Debug)]
+pub struct Turn(Option<Arc<Mutex<Schedule>>>);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let Some(schedule) = self.0.take() else {
+            return;
+        };
+        let mut state = schedule.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(((start, _), sender)) = state.waiting.pop_first() {
+            match sender.send(Turn(Some(schedule.clone()))) {
+                Ok(()) => {
+                    state.now = start;
+                    return;
+                },
+                // The client went away while waiting, its turn is given to the next request
+                Err(mut turn) => turn.0 = None,
+            }
+        }
+
+        state.in_flight -= 1;
+        // Clients finishing before now have nothing ahead of them anymore
+        let now = state.now;
+        state.finishes.retain(|_, finish| *finish > now);
+    }
+}
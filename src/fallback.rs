@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use dashmap::DashMap;
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::strategy::Bucket;
+use crate::memory::MemoryStore;
+use crate::metrics;
+use crate::settings::FallbackMemorySettings;
+use crate::store::{LimitStore, TokenRequest};
+
+
+#[derive(Debug)]
+struct ConsumedTokens {
+    bucket: Bucket,
+    tokens: u32,
+    first_consumed_at_us: u64,
+}
+
+
+// Enforces approximate per-instance limits while the main store is unreachable.
+// Every bucket is divided by the number of proxy replicas, so all instances together allow about the configured limit,
+// and tokens consumed during the outage are replayed into the main store once it's back.
+#[derive(Debug)]
+pub struct FallbackLimiter {
+    store: MemoryStore,
+    replicas: u32,
+    consumed: DashMap<String, ConsumedTokens>,
+    is_active: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl FallbackLimiter {
+    pub fn new(settings: &FallbackMemorySettings, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            store: MemoryStore::with_clock(clock.clone()),
+            replicas: settings.replicas.max(1),
+            consumed: DashMap::new(),
+            is_active: AtomicBool::new(false),
+            clock,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active.load(Ordering::Relaxed)
+    }
+
+    pub async fn consume(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        self.is_active.store(true, Ordering::Relaxed);
+
+        let instance_bucket = Bucket::new(bucket.tokens_count.div_ceil(self.replicas).max(1), bucket.add_tokens_every)
+            .with_burst(bucket.burst.map(|burst| burst.div_ceil(self.replicas).max(1)))
+            .with_borrow(bucket.borrow.div_ceil(self.replicas));
+        let count = self.store.consume(key, &instance_bucket, 1).await?;
+
+        self.consumed.entry(key.to_string())
+            .or_insert_with(|| ConsumedTokens { bucket: bucket.clone(), tokens: 0, first_consumed_at_us: self.clock.now_us() })
+            .tokens += 1;
+
+        Ok(count)
+    }
+
+    // Replays tokens consumed during the outage into the main store and starts the next outage from scratch
+    pub async fn resync(&self, store: &dyn LimitStore) {
+        if !self.is_active.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let now_us = self.clock.now_us();
+        let keys = self.consumed.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        let consumed = keys.into_iter()
+            .filter_map(|key| self.consumed.remove(&key))
+            // Windows that ended during the outage don't matter anymore
+            .filter(|(_, consumed)| now_us.saturating_sub(consumed.first_consumed_at_us) < consumed.bucket.add_tokens_every as u64 * 1_000_000)
+            .collect::<Vec<_>>();
+        self.store.clear();
+
+        let requests = consumed.iter()
+            .map(|(key, consumed)| TokenRequest::new(key, &consumed.bucket, consumed.tokens))
+            .collect::<Vec<_>>();
+        let results = store.consume_many(&requests).await;
+
+        let resynced = results.iter().filter(|result| result.is_ok()).count();
+        println!("Store is reachable again, resynchronized {} of {} fallback buckets", resynced, requests.len());
+        metrics::add_to_counter("rate_limiter_fallback_resynced_buckets_total", &[], resynced as u64);
+    }
+}
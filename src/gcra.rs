@@ -0,0 +1,101 @@
+use crate::strategy::Bucket;
+
+
+// Buckets with a burst are limited with GCRA: the only state is the theoretical arrival time (TAT) of the bucket,
+// in microseconds since the unix epoch. Tokens come back one by one at the sustained rate of `tokens_count` per
+// `add_tokens_every` seconds, and at most `burst` tokens can be available at once. Borrowed tokens push the TAT
+// further ahead, so they are repaid by the next tokens coming back.
+
+pub fn emission_interval_us(bucket: &Bucket) -> u64 {
+    (bucket.add_tokens_every as u64 * 1_000_000 / bucket.tokens_count.max(1) as u64).max(1)
+}
+
+// Takes `tokens` tokens from a bucket whose TAT is `tat_us`, None for a new bucket, and returns the new TAT with the tokens left.
+// Like fixed windows, a request for more tokens than available takes what's left and gets a negative count.
+// `skew_us` is how far apart the clocks of the instances sharing the TAT may be: tokens are counted as if it was that much
+// earlier but the TAT moves from now, so an instance whose clock is ahead doesn't give tokens back before the others.
+pub fn consume(tat_us: Option<u64>, now_us: u64, bucket: &Bucket, burst: u32, tokens: u32, skew_us: u64) -> (u64, i32) {
+    let interval_us = emission_interval_us(bucket);
+    let tat_us = tat_us.unwrap_or_default();
+
+    let ahead_us = tat_us.saturating_sub(now_us.saturating_sub(skew_us));
+    let available = ((burst as u64 + bucket.borrow as u64) * interval_us).saturating_sub(ahead_us) / interval_us;
+    let taken = available.min(tokens as u64);
+    let remaining = available as i64 - bucket.borrow as i64 - tokens as i64;
+
+    (tat_us.max(now_us) + taken * interval_us, remaining.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+}
+
+// The bucket is full again once the TAT is reached, so its state can be dropped then
+pub fn ttl_us(tat_us: u64, now_us: u64) -> u64 {
+    tat_us.saturating_sub(now_us).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_US: u64 = 1_700_000_000_000_000;
+    const SECOND_US: u64 = 1_000_000;
+
+    // A token per second, 3 at once
+    fn bucket() -> Bucket {
+        Bucket::new(10, 10).with_burst(Some(3))
+    }
+
+    #[test]
+    fn a_new_bucket_allows_its_burst_then_denies() {
+        let bucket = bucket();
+        let (tat_us, remaining) = consume(None, NOW_US, &bucket, 3, 1, 0);
+        assert_eq!((tat_us, remaining), (NOW_US + SECOND_US, 2));
+        let (tat_us, remaining) = consume(Some(tat_us), NOW_US, &bucket, 3, 1, 0);
+        assert_eq!((tat_us, remaining), (NOW_US + 2 * SECOND_US, 1));
+        let (tat_us, remaining) = consume(Some(tat_us), NOW_US, &bucket, 3, 1, 0);
+        assert_eq!((tat_us, remaining), (NOW_US + 3 * SECOND_US, 0));
+
+        // Denied requests don't move the TAT
+        assert_eq!(consume(Some(tat_us), NOW_US, &bucket, 3, 1, 0), (tat_us, -1));
+    }
+
+    #[test]
+    fn tokens_come_back_one_per_emission_interval() {
+        let bucket = bucket();
+        let tat_us = NOW_US + 3 * SECOND_US;
+        assert_eq!(emission_interval_us(&bucket), SECOND_US);
+
+        assert_eq!(consume(Some(tat_us), NOW_US + SECOND_US / 2, &bucket, 3, 1, 0).1, -1);
+        assert_eq!(consume(Some(tat_us), NOW_US + SECOND_US, &bucket, 3, 1, 0), (tat_us + SECOND_US, 0));
+        // Never more than the burst, however long the bucket wasn't used
+        assert_eq!(consume(Some(tat_us), NOW_US + 60 * SECOND_US, &bucket, 3, 1, 0), (NOW_US + 61 * SECOND_US, 2));
+        assert_eq!(ttl_us(tat_us, NOW_US), 3 * SECOND_US);
+        assert_eq!(ttl_us(tat_us, NOW_US + 60 * SECOND_US), 1);
+    }
+
+    #[test]
+    fn multi_token_requests_take_what_is_left() {
+        let bucket = bucket();
+        let (tat_us, remaining) = consume(None, NOW_US, &bucket, 3, 2, 0);
+        assert_eq!((tat_us, remaining), (NOW_US + 2 * SECOND_US, 1));
+        assert_eq!(consume(Some(tat_us), NOW_US, &bucket, 3, 5, 0), (NOW_US + 3 * SECOND_US, -4));
+    }
+
+    #[test]
+    fn borrowed_tokens_push_the_tat_further() {
+        let bucket = bucket().with_borrow(2);
+        let (tat_us, remaining) = consume(None, NOW_US, &bucket, 3, 5, 0);
+        assert_eq!((tat_us, remaining), (NOW_US + 5 * SECOND_US, -2));
+        assert_eq!(consume(Some(tat_us), NOW_US, &bucket, 3, 1, 0), (tat_us, -3));
+        assert_eq!(consume(Some(tat_us), NOW_US + 3 * SECOND_US, &bucket, 3, 1, 0), (tat_us + SECOND_US, 0));
+    }
+
+    #[test]
+    fn skew_delays_the_tokens_coming_back() {
+        let bucket = bucket();
+        let tat_us = NOW_US + 3 * SECOND_US;
+        assert_eq!(consume(Some(tat_us), NOW_US + SECOND_US, &bucket, 3, 1, 0).1, 0);
+        assert_eq!(consume(Some(tat_us), NOW_US + SECOND_US, &bucket, 3, 1, SECOND_US), (tat_us, -1));
+
+        // The TAT of a new bucket still starts from now
+        assert_eq!(consume(None, NOW_US, &bucket, 3, 1, SECOND_US), (NOW_US + SECOND_US, 2));
+    }
+}
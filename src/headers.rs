@@ -0,0 +1,88 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::http::header::RETRY_AFTER;
+use crate::error::RateLimiterError;
+use crate::settings::{HeadersSettings, SendHeaders};
+use crate::strategy::LimitForRequest;
+
+
+#[derive(Clone, Debug)]
+pub struct RouteHeaders {
+    send: SendHeaders,
+    limit: HeaderName,
+    remaining: HeaderName,
+    reset: HeaderName,
+    policy: HeaderName,
+}
+
+// Adds the rate limit headers to responses, following the settings of the longest matching route
+#[derive(Clone, Debug)]
+pub struct LimitHeaders {
+    default: RouteHeaders,
+    // Sorted by decreasing prefix length
+    routes: Vec<(String, RouteHeaders)>,
+}
+
+impl LimitHeaders {
+    pub fn new(settings: &HeadersSettings) -> Result<Self, RateLimiterError> {
+        let default = RouteHeaders {
+            send: settings.send,
+            limit: header_name("headers.limit", &settings.limit)?,
+            remaining: header_name("headers.remaining", &settings.remaining)?,
+            reset: header_name("headers.reset", &settings.reset)?,
+            policy: header_name("headers.policy", &settings.policy)?,
+        };
+
+        let mut routes = settings.routes.iter()
+            .map(|route| Ok((route.path_prefix.clone(), RouteHeaders {
+                send: route.send.unwrap_or(default.send),
+                limit: route.limit.as_deref().map(|name| header_name("headers.route.limit", name)).transpose()?.unwrap_or_else(|| default.limit.clone()),
+                remaining: route.remaining.as_deref().map(|name| header_name("headers.route.remaining", name)).transpose()?.unwrap_or_else(|| default.remaining.clone()),
+                reset: route.reset.as_deref().map(|name| header_name("headers.route.reset", name)).transpose()?.unwrap_or_else(|| default.reset.clone()),
+                policy: route.policy.as_deref().map(|name| header_name("headers.route.policy", name)).transpose()?.unwrap_or_else(|| default.policy.clone()),
+            })))
+            .collect::<Result<Vec<_>, RateLimiterError>>()?;
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Self {
+            default,
+            routes,
+        })
+    }
+
+    pub fn for_path(&self, path: &str) -> &RouteHeaders {
+        self.routes.iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map_or(&self.default, |(_, headers)| headers)
+    }
+}
+
+impl RouteHeaders {
+    // Takes the headers rather than the response, so they can be prepared before the upstream answers
+    pub fn insert(&self, headers: &mut HeaderMap, limit: &LimitForRequest, policy: Option<&str>, reset_secs: u32) {
+        let send = match self.send {
+            SendHeaders::Allowed => !limit.is_limit_exceeded,
+            SendHeaders::Denied => limit.is_limit_exceeded,
+            SendHeaders::Always => true,
+            SendHeaders::Never => false,
+        };
+        if !send {
+            return;
+        }
+
+        headers.insert(self.limit.clone(), HeaderValue::from(limit.total_limit));
+        headers.insert(self.remaining.clone(), HeaderValue::from(limit.requests_to_exceed_limit.max(0)));
+        headers.insert(self.reset.clone(), HeaderValue::from(reset_secs));
+        if limit.is_limit_exceeded {
+            headers.insert(RETRY_AFTER, HeaderValue::from(reset_secs));
+        }
+        match policy.and_then(|policy| HeaderValue::from_str(policy).ok()) {
+            Some(policy) => headers.insert(self.policy.clone(), policy),
+            // The limit may replace another one whose policy doesn't apply anymore
+            None => headers.remove(&self.policy),
+        };
+    }
+}
+
+fn header_name(setting: &str, name: &str) -> Result<HeaderName, RateLimiterError> {
+    HeaderName::try_from(name).map_err(|_| RateLimiterError::config(format!("Invalid {} {:?}", setting, name)))
+}
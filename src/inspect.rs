@@ -0,0 +1,126 @@
+use std::net::IpAddr;
+use deadpool_redis::redis;
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::{PossibleBackends, Settings};
+use crate::strategy::IPRateLimiterStrategy;
+
+pub const USAGE: &str = "Usage: rate_limiter inspect --strategy <ip|url|header|query|body|identity|operation> --value <value> [--limiter <name>] [--tenant <name>] [--instance <id>] [--client <ip>]";
+
+
+// Arguments of `rate_limiter inspect`. Values are given the way strategies build them, e.g. `X-Api-Key:abc` for headers
+// and `user_id:42` for query and body parameters.
+#[derive(Debug, Default)]
+pub struct InspectArgs {
+    strategy: String,
+    value: String,
+    // Keys of header limiters hold the limiter name, ip limiters may count networks of their own size
+    limiter: Option<String>,
+    tenant: Option<String>,
+    // Instance of limiters with `scope = "instance"`
+    instance: Option<String>,
+    // Client of limiters with `scope = "per_client"`
+    client: Option<String>,
+}
+
+impl InspectArgs {
+    pub fn parse(args: &[String]) -> Result<Self, RateLimiterError> {
+        let mut inspect_args = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args.next().cloned()
+                .ok_or_else(|| invalid(format!("Missing value of {}", arg)))?;
+            match arg.as_str() {
+                "--strategy" => inspect_args.strategy = value,
+                "--value" => inspect_args.value = value,
+                "--limiter" => inspect_args.limiter = Some(value),
+                "--tenant" => inspect_args.tenant = Some(value),
+                "--instance" => inspect_args.instance = Some(value),
+                "--client" => inspect_args.client = Some(value),
+                _ => return Err(invalid(format!("Unknown argument {}", arg))),
+            }
+        }
+
+        if inspect_args.strategy.is_empty() || inspect_args.value.is_empty() {
+            return Err(invalid("--strategy and --value are required".to_string()));
+        }
+        if inspect_args.strategy == "header" && inspect_args.limiter.is_none() {
+            return Err(invalid("--limiter is required for the header strategy".to_string()));
+        }
+        Ok(inspect_args)
+    }
+}
+
+#[derive(Debug)]
+pub struct Inspection {
+    pub key: String,
+    // Tokens left for buckets without a burst, the GCRA theoretical arrival time in microseconds otherwise
+    pub value: Option<String>,
+    pub ttl_ms: Option<i64>,
+}
+
+// Builds the key exactly like the limiters do and reads its bucket from Redis
+pub async fn inspect(settings: &Settings, args: &InspectArgs) -> Result<Inspection, RateLimiterError> {
+    let rate_limiter_settings = match &args.tenant {
+        Some(name) => settings.tenants_settings.iter()
+            .find(|tenant| &tenant.name == name)
+            .map(|tenant| {
+                let mut rate_limiter_settings = tenant.rate_limiter_settings.clone();
+                rate_limiter_settings.keys.prefix = format!("{}:{}", rate_limiter_settings.keys.prefix, name);
+                rate_limiter_settings
+            })
+            .ok_or_else(|| invalid(format!("Unknown tenant {}", name)))?,
+        None => settings.rate_limiter_settings.clone(),
+    };
+    if !matches!(rate_limiter_settings.backend, PossibleBackends::Redis) {
+        return Err(invalid("inspect only supports the redis backend".to_string()));
+    }
+
+    // The body strategy predates the others and stores its keys under `json`
+    let strategy = match args.strategy.as_str() {
+        "ip" | "url" | "header" | "query" | "identity" | "operation" => args.strategy.as_str(),
+        "body" => "json",
+        strategy => return Err(invalid(format!("Unknown strategy {}", strategy))),
+    };
+    let value = match (strategy, &args.limiter) {
+        ("header", Some(limiter)) => format!("{}:{}", limiter, args.value),
+        // Addresses are turned into the network the limiter counts them in
+        ("ip", limiter) if let Ok(ip) = args.value.parse::<IpAddr>() => {
+            let limiter_settings = limiter.as_ref().and_then(|limiter| rate_limiter_settings.limiters_settings.iter()
+                .enumerate()
+                .find(|(index, settings)| settings.name.clone().unwrap_or_else(|| format!("{}-{}", settings.strategy.as_str(), index)) == *limiter));
+            let strategy = match limiter_settings {
+                Some((_, settings)) => IPRateLimiterStrategy::new(settings.ipv4_prefix, settings.ipv6_prefix)?,
+                None => IPRateLimiterStrategy::default(),
+            };
+            strategy.network(ip)
+        },
+        _ => args.value.clone(),
+    };
+    let mut key = KeyBuilder::new(&rate_limiter_settings.keys)?.build(strategy, &value);
+    if let Some(instance) = &args.instance {
+        key = format!("{}:instance:{}", key, instance);
+    }
+    if let Some(client) = &args.client {
+        key = format!("{}:client:{}", key, client);
+    }
+
+    let pool = RedisPool::new(&rate_limiter_settings)?;
+    let mut connection = pool.get().await?;
+    let (value, ttl_ms): (Option<String>, i64) = redis::pipe()
+        .cmd("GET").arg(&key)
+        .cmd("PTTL").arg(&key)
+        .query_async(&mut connection).await?;
+
+    Ok(Inspection {
+        key,
+        value,
+        // -2 for missing keys, -1 for keys without an expiry
+        ttl_ms: (ttl_ms >= 0).then_some(ttl_ms),
+    })
+}
+
+fn invalid(message: String) -> RateLimiterError {
+    RateLimiterError::config(message)
+}
@@ -0,0 +1,59 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use axum::http::{HeaderName, HeaderValue};
+use crate::error::RateLimiterError;
+use crate::ip_list::IpList;
+use crate::metrics;
+use crate::settings::IpClassesSettings;
+use crate::strategy::SafeRequest;
+
+
+// Class of the client IP, added to the extensions of requests for ip limiters to pick a bucket with
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpClass(pub String);
+
+// Classifies client IPs with downloadable lists, e.g. of TOR exit nodes or datacenter ranges
+#[derive(Clone, Debug)]
+pub struct IpClasses {
+    default: String,
+    header: Option<HeaderName>,
+    lists: Vec<(String, Arc<IpList>)>,
+}
+
+impl IpClasses {
+    // Must be called inside a tokio runtime, as it spawns the refresh of the lists
+    pub fn new(settings: &IpClassesSettings) -> Result<Self, RateLimiterError> {
+        let mut classes = settings.lists.iter().map(|list| &list.class).chain([&settings.default]);
+        if let Some(class) = classes.find(|class| class.is_empty() || HeaderValue::try_from(class.as_str()).is_err()) {
+            return Err(RateLimiterError::config(format!("Invalid ip class {:?}", class)));
+        }
+        let header = settings.header.as_deref()
+            .map(|name| HeaderName::try_from(name).map_err(|_| RateLimiterError::config(format!("Invalid ip_classes.header {:?}", name))))
+            .transpose()?;
+        let lists = settings.lists.iter()
+            .map(|list| Ok((list.class.clone(), IpList::new("ip class list", &list.class, list.url.as_deref(), list.path.as_deref(), list.refresh_secs)?)))
+            .collect::<Result<Vec<_>, RateLimiterError>>()?;
+
+        Ok(Self {
+            default: settings.default.clone(),
+            header,
+            lists,
+        })
+    }
+
+    pub fn classify(&self, ip: IpAddr) -> &str {
+        self.lists.iter()
+            .find(|(_, list)| list.contains(ip))
+            .map_or(&self.default, |(class, _)| class)
+    }
+
+    // Adds the class to the extensions of the request, and to its headers when it's sent to the upstream
+    pub fn tag(&self, request: &mut SafeRequest, ip: IpAddr) {
+        let class = self.classify(ip);
+        metrics::increment_counter("rate_limiter_ip_class_requests_total", &[("class", class)]);
+        if let Some(header) = &self.header && let Ok(value) = HeaderValue::try_from(class) {
+            request.parts.headers.insert(header, value);
+        }
+        request.parts.extensions.insert(IpClass(class.to_string()));
+    }
+}
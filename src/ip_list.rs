@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use crate::error::RateLimiterError;
+use crate::metrics;
+
+const MAX_LIST_BYTES: usize = 16 * 1024 * 1024;
+
+
+// Inclusive ranges of addresses, sorted and merged so an address is found with a binary search
+#[derive(Debug, Default)]
+struct IpRanges {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpRanges {
+    // Takes the first word of every line as an IP or a CIDR range, after removing `;` and `#` comments,
+    // and returns the ranges with the number of invalid entries
+    fn parse(list: &str) -> (Self, usize) {
+        let (mut v4, mut v6, mut invalid) = (Vec::new(), Vec::new(), 0);
+        for line in list.lines() {
+            let Some(entry) = line.split([';', '#']).next().and_then(|line| line.split_whitespace().next()) else {
+                continue;
+            };
+            let parsed = match entry.split_once('/') {
+                Some((address, prefix)) => address.parse::<IpAddr>().ok().zip(prefix.parse::<u32>().ok()),
+                None => entry.parse::<IpAddr>().ok().map(|ip| (ip, if ip.is_ipv4() { 32 } else { 128 })),
+            };
+            match parsed {
+                Some((IpAddr::V4(ip), prefix)) if prefix <= 32 => {
+                    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                    v4.push((u32::from(ip) & mask, u32::from(ip) | !mask));
+                },
+                Some((IpAddr::V6(ip), prefix)) if prefix <= 128 => {
+                    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                    v6.push((u128::from(ip) & mask, u128::from(ip) | !mask));
+                },
+                _ => invalid += 1,
+            }
+        }
+        (Self { v4: merge(v4), v6: merge(v6) }, invalid)
+    }
+
+    fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+}
+
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end.max(*last_end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+
+#[derive(Debug)]
+enum ListSource {
+    Path(String),
+    Url(String),
+}
+
+// List of IPs and CIDR ranges read from a local file or an http:// URL, one per line with `;` or `#` comments,
+// and read again every `refresh` by every instance on its own. `kind` names the setting the list comes from in messages.
+#[derive(Debug)]
+pub struct IpList {
+    pub name: String,
+    kind: &'static str,
+    source: ListSource,
+    refresh: Duration,
+    ranges: RwLock<IpRanges>,
+}
+
+impl IpList {
+    // Must be called inside a tokio runtime, as it spawns the refresh
+    pub fn new(kind: &'static str, name: &str, url: Option<&str>, path: Option<&str>, refresh_secs: u64) -> Result<Arc<Self>, RateLimiterError> {
+        let source = match (url, path) {
+            (Some(url), None) if url.starts_with("http://") => ListSource::Url(url.to_string()),
+            (Some(url), None) => return Err(RateLimiterError::config(format!("url {} of {} {} must be an http:// URL, use path for other sources", url, kind, name))),
+            (None, Some(path)) => ListSource::Path(path.to_string()),
+            _ => return Err(RateLimiterError::config(format!("{} {} needs either url or path", kind, name))),
+        };
+        if refresh_secs == 0 {
+            return Err(RateLimiterError::config(format!("refresh_secs of {} {} must be greater than 0", kind, name)));
+        }
+
+        // A local list is in effect from the start and must be readable, a URL is fetched in the background
+        let ranges = match &source {
+            ListSource::Path(path) => {
+                let list = std::fs::read_to_string(path).map_err(|e| RateLimiterError::config(format!("Failed to read {} {} from {}: {}", kind, name, path, e)))?;
+                parse_list(kind, name, &list)
+            },
+            ListSource::Url(_) => IpRanges::default(),
+        };
+
+        let list = Arc::new(Self {
+            name: name.to_string(),
+            kind,
+            source,
+            refresh: Duration::from_secs(refresh_secs),
+            ranges: RwLock::new(ranges),
+        });
+        tokio::spawn(refresh_list(Arc::downgrade(&list)));
+        Ok(list)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ranges.read().unwrap_or_else(|e| e.into_inner()).contains(ip)
+    }
+
+    async fn fetch(&self) -> Result<IpRanges, String> {
+        let list = match &self.source {
+            ListSource::Path(path) => tokio::fs::read_to_string(path).await.map_err(|e| format!("{}: {}", path, e))?,
+            ListSource::Url(url) => {
+                let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+                let request = Request::get(url.as_str()).body(Body::empty()).map_err(|e| e.to_string())?;
+                let response = client.request(request).await.map_err(|e| format!("{}: {}", url, e))?;
+                if !response.status().is_success() {
+                    return Err(format!("{} answered {}", url, response.status()));
+                }
+                let bytes = to_bytes(Body::new(response.into_body()), MAX_LIST_BYTES).await.map_err(|e| format!("{}: {}", url, e))?;
+                String::from_utf8_lossy(&bytes).into_owned()
+            },
+        };
+        Ok(parse_list(self.kind, &self.name, &list))
+    }
+}
+
+fn parse_list(kind: &str, name: &str, list: &str) -> IpRanges {
+    let (ranges, invalid) = IpRanges::parse(list);
+    if invalid > 0 {
+        println!("Ignored {} invalid entries of {} {}", invalid, kind, name);
+    }
+    metrics::set_gauge("rate_limiter_ip_list_ranges", &[("list", name)], ranges.len() as u64);
+    ranges
+}
+
+async fn refresh_list(list: Weak<IpList>) {
+    let Some(every) = list.upgrade().map(|list| list.refresh) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(list) = list.upgrade() else {
+            return;
+        };
+        // On errors the last known list is kept
+        match list.fetch().await {
+            Ok(ranges) => *list.ranges.write().unwrap_or_else(|e| e.into_inner()) = ranges,
+            Err(e) => println!("Failed to refresh {} {}: {}", list.kind, list.name, e),
+        }
+    }
+}
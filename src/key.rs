@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::hash::Hasher;
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher13;
+use crate::error::RateLimiterError;
+use crate::settings::{KeyHashing, KeySettings};
+
+
+// Builds store keys as `<prefix>:<strategy>:<value>`, with the value hashed according to the configured scheme.
+// Every scheme is stable across Rust versions and deploys, so buckets survive restarts and upgrades.
+#[derive(Clone, Debug)]
+pub struct KeyBuilder {
+    prefix: String,
+    hashing: KeyHashing,
+    siphash_keys: (u64, u64),
+}
+
+impl KeyBuilder {
+    pub fn new(settings: &KeySettings) -> Result<Self, RateLimiterError> {
+        let siphash_keys = match &settings.siphash_key {
+            Some(siphash_key) => parse_siphash_key(siphash_key)?,
+            None => (0, 0),
+        };
+
+        Ok(Self {
+            prefix: settings.prefix.clone(),
+            hashing: settings.hashing,
+            siphash_keys,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn build(&self, strategy: &str, value: &str) -> String {
+        self.build_from(strategy, &[value])
+    }
+
+    // Same as `build` with the value made of `parts` joined by `:`, without building the value first.
+    // Keys are written into a single allocation sized up front.
+    pub fn build_from(&self, strategy: &str, parts: &[&str]) -> String {
+        match self.hashing {
+            KeyHashing::Plain => {
+                let value_len = parts.iter().map(|part| part.len()).sum::<usize>() + parts.len().saturating_sub(1);
+                let mut key = self.key_with_capacity(strategy, value_len);
+                for (index, part) in parts.iter().enumerate() {
+                    if index > 0 {
+                        key.push(':');
+                    }
+                    key.push_str(part);
+                }
+                key
+            },
+            KeyHashing::Sha256 => {
+                let mut hasher = Sha256::new();
+                for_each_byte_run(parts, |bytes| hasher.update(bytes));
+                let mut digest = [0; 64];
+                // The buffer is exactly twice the digest size, so encoding can't fail
+                let _ = hex::encode_to_slice(hasher.finalize(), &mut digest);
+                let mut key = self.key_with_capacity(strategy, digest.len());
+                key.push_str(std::str::from_utf8(&digest).unwrap_or_default());
+                key
+            },
+            KeyHashing::SipHash => {
+                // Hashing through `Hash` with zero keys gives the same keys as earlier versions that used `DefaultHasher`.
+                // SipHash is streamed, so writing the parts in turn then the `str` terminator hashes like the joined value.
+                let mut hasher = SipHasher13::new_with_keys(self.siphash_keys.0, self.siphash_keys.1);
+                for_each_byte_run(parts, |bytes| hasher.write(bytes));
+                hasher.write_u8(0xff);
+                let mut key = self.key_with_capacity(strategy, 20);
+                let _ = write!(key, "{}", hasher.finish());
+                key
+            },
+        }
+    }
+
+    fn key_with_capacity(&self, strategy: &str, value_len: usize) -> String {
+        let mut key = String::with_capacity(self.prefix.len() + strategy.len() + value_len + 2);
+        key.push_str(&self.prefix);
+        key.push(':');
+        key.push_str(strategy);
+        key.push(':');
+        key
+    }
+}
+
+// Calls `write` with the bytes of the parts and the `:` between them
+fn for_each_byte_run(parts: &[&str], mut write: impl FnMut(&[u8])) {
+    for (index, part) in parts.iter().enumerate() {
+        if index > 0 {
+            write(b":");
+        }
+        write(part.as_bytes());
+    }
+}
+
+fn parse_siphash_key(siphash_key: &str) -> Result<(u64, u64), RateLimiterError> {
+    let bytes: [u8; 16] = hex::decode(siphash_key).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| RateLimiterError::config("keys.siphash_key must be 32 hex characters (16 bytes)"))?;
+
+    let keys = u128::from_le_bytes(bytes);
+    Ok((keys as u64, (keys >> 64) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{DefaultHasher, Hash};
+    use super::*;
+
+    fn key_builder(hashing: KeyHashing, siphash_key: Option<&str>) -> KeyBuilder {
+        KeyBuilder::new(&KeySettings { prefix: "rl".to_string(), hashing, siphash_key: siphash_key.map(str::to_string) }).unwrap()
+    }
+
+    #[test]
+    fn plain_keys_keep_the_value() {
+        let key_builder = key_builder(KeyHashing::Plain, None);
+        assert_eq!(key_builder.build("ip", "1.2.3.4"), "rl:ip:1.2.3.4");
+        assert_eq!(key_builder.build_from("url", &["GET", "/api"]), "rl:url:GET:/api");
+        assert_eq!(key_builder.build_from("url", &[]), "rl:url:");
+    }
+
+    #[test]
+    fn sha256_keys_are_the_hex_digest_of_the_value() {
+        let key_builder = key_builder(KeyHashing::Sha256, None);
+        assert_eq!(key_builder.build("ip", "1.2.3.4"), "rl:ip:6694f83c9f476da31f5df6bcc520034e7e57d421d247b9d34f49edbfc84a764c");
+        assert_eq!(key_builder.build_from("url", &["GET", "/api"]), "rl:url:27b376b34dc224407a8eb56832eaa138673499d9d565e772d1cc28820ac83275");
+    }
+
+    // Buckets stored by versions that hashed with `DefaultHasher` must keep their keys
+    #[test]
+    fn siphash_keys_without_a_key_match_the_default_hasher() {
+        let key_builder = key_builder(KeyHashing::SipHash, None);
+        assert_eq!(key_builder.build("ip", "1.2.3.4"), "rl:ip:15419372994557198156");
+        assert_eq!(key_builder.build_from("url", &["GET", "/api"]), "rl:url:18445987216185423779");
+
+        let mut hasher = DefaultHasher::new();
+        "1.2.3.4".hash(&mut hasher);
+        assert_eq!(hasher.finish(), 15419372994557198156);
+    }
+
+    #[test]
+    fn siphash_keys_depend_on_the_configured_key() {
+        let key_builder = key_builder(KeyHashing::SipHash, Some("000102030405060708090a0b0c0d0e0f"));
+        assert_eq!(key_builder.build("ip", "1.2.3.4"), "rl:ip:13657541020148776806");
+        assert_eq!(key_builder.build_from("ip", &["1.2.3.4"]), key_builder.build("ip", "1.2.3.4"));
+    }
+
+    #[test]
+    fn rejects_siphash_keys_that_are_not_16_hex_bytes() {
+        for siphash_key in ["", "0001", "000102030405060708090a0b0c0d0e0f00", "zz0102030405060708090a0b0c0d0e0f"] {
+            let error = KeyBuilder::new(&KeySettings { prefix: "rl".to_string(), hashing: KeyHashing::SipHash, siphash_key: Some(siphash_key.to_string()) }).unwrap_err();
+            assert_eq!(error.to_string(), RateLimiterError::config("keys.siphash_key must be 32 hex characters (16 bytes)").to_string());
+        }
+    }
+}
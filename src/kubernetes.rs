@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request};
+use hyper::body::Body as _;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::{ClientConfig, RootCertStore};
+use serde::Deserialize;
+use url::Url;
+use crate::error::RateLimiterError;
+use crate::settings::KubernetesDiscoverySettings;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+// The API server ends watches after this long, so a dropped connection is noticed
+const WATCH_TIMEOUT_SECS: u64 = 300;
+
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    resource_version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    metadata: Metadata,
+    address_type: String,
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    ports: Vec<EndpointPort>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+}
+
+// A missing condition counts as ready, like for the API server itself
+#[derive(Deserialize, Debug, Default)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EndpointSliceList {
+    metadata: Metadata,
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+
+// Watches the EndpointSlices of a Service, so requests go to the ready pods directly instead of through kube-proxy
+#[derive(Debug)]
+pub struct KubernetesSource {
+    service: String,
+    port_name: Option<String>,
+    endpoint_slices_url: String,
+    token_path: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    // Ready pod addresses of every EndpointSlice by its name
+    slices: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl KubernetesSource {
+    pub fn new(settings: &KubernetesDiscoverySettings) -> Result<Self, RateLimiterError> {
+        if settings.service.is_empty() {
+            return Err(RateLimiterError::config("service of kubernetes discovery can't be empty"));
+        }
+        let in_cluster = settings.api_url.is_none();
+        let api_url = match &settings.api_url {
+            Some(api_url) => api_url.trim_end_matches('/').to_string(),
+            None => match (std::env::var("KUBERNETES_SERVICE_HOST"), std::env::var("KUBERNETES_SERVICE_PORT")) {
+                (Ok(host), Ok(port)) if host.contains(':') => format!("https://[{}]:{}", host, port),
+                (Ok(host), Ok(port)) => format!("https://{}:{}", host, port),
+                _ => return Err(RateLimiterError::config("kubernetes discovery needs api_url when not running in a cluster")),
+            },
+        };
+        let namespace = match &settings.namespace {
+            Some(namespace) => namespace.clone(),
+            None => std::fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT_DIR))
+                .map(|namespace| namespace.trim().to_string())
+                .unwrap_or_else(|_| "default".to_string()),
+        };
+
+        let mut endpoint_slices_url = Url::parse(&format!("{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices", api_url, namespace))
+            .map_err(|e| RateLimiterError::config(format!("Invalid api_url of kubernetes discovery {}: {}", api_url, e)))?;
+        endpoint_slices_url.query_pairs_mut().append_pair("labelSelector", &format!("kubernetes.io/service-name={}", settings.service));
+
+        // The service account token is sent to the in-cluster API server only, kubectl proxy adds its own credentials
+        let token_path = in_cluster.then(|| format!("{}/token", SERVICE_ACCOUNT_DIR));
+        let connector = match endpoint_slices_url.scheme() {
+            "https" => HttpsConnectorBuilder::new().with_tls_config(tls_config(settings.ca_path.as_deref().unwrap_or(&format!("{}/ca.crt", SERVICE_ACCOUNT_DIR)))?),
+            _ => HttpsConnectorBuilder::new().with_tls_config(tls_config_without_roots()?),
+        }
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Ok(Self {
+            service: settings.service.clone(),
+            port_name: settings.port_name.clone(),
+            endpoint_slices_url: endpoint_slices_url.to_string(),
+            token_path,
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            slices: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    // Replaces the known EndpointSlices, returns the upstreams with the version to watch from
+    pub async fn list(&self) -> Result<(Vec<String>, String), String> {
+        let response = self.get(&self.endpoint_slices_url).await?;
+        let bytes = to_bytes(Body::new(response.into_body()), MAX_RESPONSE_BYTES).await.map_err(|e| e.to_string())?;
+        let list: EndpointSliceList = serde_json::from_slice(&bytes).map_err(|e| format!("Invalid EndpointSlice list: {}", e))?;
+
+        let mut slices = self.slices.lock().unwrap_or_else(|e| e.into_inner());
+        *slices = list.items.iter().map(|slice| (slice.metadata.name.clone(), self.ready_addresses(slice))).collect();
+        Ok((upstreams(&slices), list.metadata.resource_version))
+    }
+
+    // Calls `on_change` with the upstreams on every change until the API server ends the watch,
+    // returns the version to watch from next. An expired version is an error, the slices must be listed again.
+    pub async fn watch(&self, resource_version: &str, mut on_change: impl FnMut(Vec<String>)) -> Result<String, String> {
+        let url = format!(
+            "{}&watch=1&allowWatchBookmarks=true&timeoutSeconds={}&resourceVersion={}",
+            self.endpoint_slices_url, WATCH_TIMEOUT_SECS, resource_version,
+        );
+        let mut body = self.get(&url).await?.into_body();
+        let mut resource_version = resource_version.to_string();
+        let mut buffer = Vec::new();
+        // Events are sent as one JSON object per line
+        while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            let Ok(data) = frame.map_err(|e| e.to_string())?.into_data() else {
+                continue;
+            };
+            buffer.extend_from_slice(&data);
+            if buffer.len() > MAX_RESPONSE_BYTES {
+                return Err("Watch event too large".to_string());
+            }
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let event: WatchEvent = serde_json::from_slice(&line).map_err(|e| format!("Invalid watch event: {}", e))?;
+                if event.kind == "ERROR" {
+                    return Err(format!("Watch failed: {}", event.object));
+                }
+                // Bookmarks only carry the metadata, and only move the version on
+                let metadata: Metadata = serde_json::from_value(event.object["metadata"].clone()).map_err(|e| format!("Invalid watch event: {}", e))?;
+                resource_version = metadata.resource_version;
+                let mut slices = self.slices.lock().unwrap_or_else(|e| e.into_inner());
+                match event.kind.as_str() {
+                    "ADDED" | "MODIFIED" => {
+                        let slice: EndpointSlice = serde_json::from_value(event.object).map_err(|e| format!("Invalid EndpointSlice: {}", e))?;
+                        slices.insert(metadata.name, self.ready_addresses(&slice))
+                    },
+                    "DELETED" => slices.remove(&metadata.name),
+                    _ => continue,
+                };
+                on_change(upstreams(&slices));
+            }
+        }
+        Ok(resource_version)
+    }
+
+    async fn get(&self, url: &str) -> Result<hyper::Response<hyper::body::Incoming>, String> {
+        let mut request = Request::get(url);
+        // Projected tokens are rotated, so the file is read for every request
+        if let Some(token_path) = &self.token_path {
+            let token = tokio::fs::read_to_string(token_path).await.map_err(|e| format!("{}: {}", token_path, e))?;
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token.trim()));
+        }
+        let request = request.body(Body::empty()).map_err(|e| e.to_string())?;
+        let response = self.client.request(request).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("API server answered {}", response.status()));
+        }
+        Ok(response)
+    }
+
+    // Pods that are not ready, e.g. failing their readiness probe or shutting down, get no requests
+    fn ready_addresses(&self, slice: &EndpointSlice) -> Vec<String> {
+        let port = slice.ports.iter()
+            .find(|port| self.port_name.is_none() || port.name == self.port_name)
+            .and_then(|port| port.port);
+        let Some(port) = port else {
+            return Vec::new();
+        };
+        // FQDN slices are left out, their names would need another lookup
+        if slice.address_type != "IPv4" && slice.address_type != "IPv6" {
+            return Vec::new();
+        }
+        slice.endpoints.iter()
+            .filter(|endpoint| endpoint.conditions.ready.unwrap_or(true))
+            .flat_map(|endpoint| &endpoint.addresses)
+            .filter_map(|address| address.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, port).to_string())
+            .collect()
+    }
+}
+
+// Sorted, so every instance takes the pods in the same order
+fn upstreams(slices: &HashMap<String, Vec<String>>) -> Vec<String> {
+    let mut upstreams: Vec<String> = slices.values().flatten().cloned().collect();
+    upstreams.sort_unstable();
+    upstreams.dedup();
+    upstreams
+}
+
+fn tls_config(ca_path: &str) -> Result<ClientConfig, RateLimiterError> {
+    let ca = std::fs::read(ca_path).map_err(|e| RateLimiterError::Tls(format!("Could not read the CA of the API server {}: {}", ca_path, e)))?;
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_slice_iter(&ca) {
+        let certificate = certificate.map_err(|e| RateLimiterError::Tls(format!("Invalid CA of the API server {}: {}", ca_path, e)))?;
+        roots.add(certificate).map_err(|e| RateLimiterError::Tls(format!("Invalid CA of the API server {}: {}", ca_path, e)))?;
+    }
+    client_config(roots)
+}
+
+// Plain http API servers, e.g. kubectl proxy, need no roots
+fn tls_config_without_roots() -> Result<ClientConfig, RateLimiterError> {
+    client_config(RootCertStore::empty())
+}
+
+fn client_config(roots: RootCertStore) -> Result<ClientConfig, RateLimiterError> {
+    Ok(ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| RateLimiterError::Tls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -0,0 +1,125 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use tower_layer::Layer;
+use tower_service::Service;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::quota::QuotaUsage;
+use crate::settings::RateLimiterSettings;
+use crate::strategy::LimitForRequest;
+
+
+// Applies the same limits as the proxy inside any axum or hyper app.
+// The client address is taken from `ConnectInfo<SocketAddr>`, so the app must be served with
+// `into_make_service_with_connect_info::<SocketAddr>()`.
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    manager: Arc<RateLimiterManager>,
+}
+
+impl RateLimitLayer {
+    // Must be called inside a tokio runtime, as some stores spawn background tasks
+    pub fn new(settings: RateLimiterSettings) -> Result<Self, RateLimiterError> {
+        Ok(Self::from_manager(Arc::new(RateLimiterManager::new(settings)?)))
+    }
+
+    pub fn from_manager(manager: Arc<RateLimiterManager>) -> Self {
+        Self {
+            manager,
+        }
+    }
+
+    pub fn manager(&self) -> &Arc<RateLimiterManager> {
+        &self.manager
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            manager: self.manager.clone(),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct RateLimitService<S> {
+    inner: S,
+    manager: Arc<RateLimiterManager>,
+}
+
+impl<S> Service<Request<Body>> for RateLimitService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // Take the service that was polled ready and leave a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let manager = self.manager.clone();
+
+        Box::pin(async move {
+            let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+                eprintln!("RateLimitLayer requires ConnectInfo<SocketAddr>, serve the app with into_make_service_with_connect_info");
+                return Ok((StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response());
+            };
+
+            manager.handle(request, addr, |request| inner.call(request)).await
+        })
+    }
+}
+
+
+// Rate limit state of a request the limiter let through, added to its extensions for the handlers behind the layer,
+// e.g. to degrade a response for clients close to their limit. Handlers can take it as an extractor:
+// `async fn handler(info: RateLimitInfo)`, which fails with a 500 on routes the layer doesn't cover.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitInfo {
+    // Most restrictive limit of the request, None if no limiter matched
+    pub limit: Option<LimitForRequest>,
+    // Name of the limiter behind `limit`
+    pub limiter: Option<String>,
+    // Names of all limiters that matched the request
+    pub limiters: Vec<String>,
+    // Quota closest to running out, if the client is subject to one
+    pub quota: Option<QuotaUsage>,
+    // Whitelisted clients skip every limit, so nothing else is set for them
+    pub is_whitelisted: bool,
+    // Same for requests with a valid bypass token
+    pub is_bypassed: bool,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RateLimitInfo {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<RateLimitInfo>().cloned().ok_or_else(|| {
+            eprintln!("RateLimitInfo was extracted from a request that didn't go through RateLimitLayer");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+        })
+    }
+}
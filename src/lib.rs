@@ -1,4 +1,69 @@
+pub mod error;
 pub mod server;
+pub mod connection_guard;
 pub mod settings;
 pub mod limiter;
-pub mod strategy;
\ No newline at end of file
+pub mod layer;
+pub mod builder;
+pub mod decision;
+pub mod tenant;
+pub mod split;
+pub mod discovery;
+pub mod kubernetes;
+pub mod load_shedding;
+pub mod concurrency_limit;
+pub mod memory_budget;
+pub mod fair_queue;
+pub mod coalesce;
+#[cfg(feature = "envoy")]
+pub mod envoy;
+pub mod strategy;
+pub mod headers;
+pub mod openapi;
+pub mod key;
+pub mod cardinality;
+pub mod gcra;
+pub mod clock;
+pub mod quota;
+pub mod priority;
+pub mod distinct;
+pub mod retry_budget;
+pub mod schedule;
+pub mod warm_up;
+pub mod usage;
+pub mod unique_clients;
+pub mod connection;
+pub mod store;
+pub mod memory;
+pub mod memcached;
+pub mod local_cache;
+pub mod deny_cache;
+pub mod upstream_limits;
+pub mod abuse;
+pub mod anomaly;
+pub mod tarpit;
+pub mod ban;
+pub mod ip_list;
+pub mod reputation;
+pub mod ip_class;
+pub mod waf;
+pub mod cors;
+pub mod auth;
+pub mod challenge;
+pub mod bypass;
+pub mod cluster;
+pub mod partition;
+pub mod overrides;
+pub mod snapshot;
+pub mod metrics;
+pub mod admin;
+pub mod inspect;
+pub mod simulate;
+pub mod maintenance;
+#[cfg(unix)]
+pub mod unix;
+pub mod fallback;
+#[cfg(feature = "dynamodb")]
+pub mod dynamodb;
+#[cfg(feature = "testing")]
+pub mod testing;
\ No newline at end of file
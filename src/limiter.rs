@@ -1,16 +1,65 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc};
+use std::time::Duration;
 use axum::body::{to_bytes, Body, Bytes};
 use axum::extract::{ConnectInfo, State};
-use axum::http::{HeaderValue, Request, StatusCode};
-use axum::http::request::Parts;
+use axum::http::header::{CACHE_CONTROL, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
+use axum::Json;
 use axum_macros::debug_middleware;
-use deadpool_redis::{Config, Pool};
-use crate::settings::{BucketSettings, RateLimiterSettings};
-use crate::strategy::{LimitForRequest, Strategy};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::cardinality::CardinalityGuard;
+use crate::connection_guard;
+use crate::anomaly::{Anomaly, AnomalyDetector};
+use crate::ban::Bans;
+use crate::challenge::Challenge;
+use crate::bypass::BypassTokens;
+use crate::clock::{Clock, SystemClock};
+use crate::cluster::{default_instance_id, Cluster};
+use crate::connection::RedisPool;
+#[cfg(feature = "dynamodb")]
+use crate::dynamodb::DynamoDBStore;
+use crate::deny_cache::DenyCache;
+use crate::distinct::DistinctLimit;
+use crate::error::RateLimiterError;
+use crate::fallback::FallbackLimiter;
+use crate::auth::Auth;
+use crate::cors::{self, Cors};
+use crate::headers::LimitHeaders;
+use crate::key::KeyBuilder;
+use crate::layer::RateLimitInfo;
+use crate::local_cache::LocalCacheStore;
+use crate::memcached::MemcachedStore;
+use crate::memory::MemoryStore;
+use crate::metrics;
+use crate::overrides::Overrides;
+use crate::openapi::{OpenApiRoutes, OperationRateLimiterStrategy};
+use crate::partition::Partitioner;
+use crate::priority::{Priority, PriorityClasses};
+use crate::quota::{Quota, QuotaStatus, QuotaUsage};
+use crate::ip_class::IpClasses;
+use crate::reputation::{Listed, Reputation};
+use crate::retry_budget::RetryBudget;
+use crate::schedule::Schedule;
+use crate::snapshot::{RestoredSnapshot, Snapshot};
+use crate::settings::{BuckerPerValue, BucketSettings, ConsumeMode, DecisionLogging, LimiterSettings, LimitScope, MostRestrictive, OnStoreError, PossibleBackends, PossibleStrategies, RateLimiterSettings, SpikeArrestSettings};
+use crate::store::{LimitStore, RedisStore, TokenRequest};
+use crate::strategy::{BucketsPerValue, LimitForRequest, LimitKey, Strategy, UrlRateLimiterStrategy};
+use crate::tarpit::Tarpit;
+use crate::unique_clients::{UniqueClients, UniqueClientsStatus};
+use crate::upstream_limits::UpstreamLimits;
+use crate::usage::UsageRecorder;
+use crate::waf::Waf;
+use crate::warm_up::WarmUp;
+// Kept here for code written before these types moved to the strategy module
+pub use crate::strategy::{Bucket, SafeRequest};
 
 #[debug_middleware]
 pub async fn middleware(
@@ -19,172 +68,1580 @@ pub async fn middleware(
     request: Request<Body>,
     next: Next,
 ) -> Response<Body> {
-    // Check whitelist
-    if rate_limiter_manager.ip_whitelist.contains(&addr.ip()) {
-        println!("IP {} is whitelisted", addr.ip());
-        return next.run(request).await;
+    let result = rate_limiter_manager.handle(request, addr, |request| async move {
+        Ok::<_, Infallible>(next.run(request).await)
+    }).await;
+
+    match result {
+        Ok(response) => response,
+        Err(never) => match never {},
     }
+}
 
-    // Split the request into parts and body because Request<Body> is not Send
-    let (parts, body) = request.into_parts();
-    let body_bytes = match to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response(),
-    };
-    
-    let safe_request = SafeRequest::new(parts, body_bytes);
-    let mut lowest_limit: Option<LimitForRequest> = None;
-    
-    let rate_limiter_groups = vec!(
-        &rate_limiter_manager.user_rate_limiters, // start to check the user
-        &rate_limiter_manager.request_rate_limiters // check the request
-    );
-    
-    for rate_limiters_group in rate_limiter_groups {
-        for rate_limiter in rate_limiters_group.iter() {
-            let limit = rate_limiter.check(&safe_request, addr).await;
-            match limit {
-                None => continue,
-                Some(limit) => {
-                    match &lowest_limit {
-                        Some(current) if current > &limit => lowest_limit = Some(limit),
-                        None => lowest_limit = Some(limit),
-                        _ => {}
-                    }
-                }
-            }
-        }
 
-        if let Some(limit) = &lowest_limit {
-            if limit.is_limit_exceeded {
-                println!("Rate limit exceeded for {}", addr.ip());
-                return (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response();
-            }
-        }
-    }
-    
-    let mut response = next.run(Request::from_parts(safe_request.parts, Body::from(safe_request.body))).await;
-    
-    if let Some(limit) = &lowest_limit {
-        let headers = response.headers_mut();   
-        headers.insert("X-RateLimit-Limit", HeaderValue::from(limit.total_limit));
-        headers.insert("X-RateLimit-Remaining", HeaderValue::from(limit.requests_to_exceed_limit));
-    }
-    
-    response
+// Path of the endpoint clients query their remaining budget on, answered by the limiter itself when enabled.
+// `status_path` serves the same response on a path of the operator's choice.
+pub const WELL_KNOWN_PATH: &str = "/.well-known/ratelimit";
+
+const QUOTA_LIMIT: HeaderName = HeaderName::from_static("x-quota-limit");
+const QUOTA_REMAINING: HeaderName = HeaderName::from_static("x-quota-remaining");
+const QUOTA_RESET: HeaderName = HeaderName::from_static("x-quota-reset");
+// Limit, remaining, reset, Retry-After, policy and the three quota headers
+const LIMIT_HEADERS_CAPACITY: usize = 8;
+
+
+// Budget a client has left in one limiter, as read by `peek`
+#[derive(Serialize, Debug, Clone)]
+pub struct LimitStatus {
+    pub limiter: String,
+    // Same as X-RateLimit-Policy
+    pub policy: String,
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until a token comes back at the latest
+    pub reset: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct StatusResponse {
+    limits: Vec<LimitStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    quotas: Vec<QuotaStatus>,
 }
 
 
 #[derive(Clone, Debug)]
 pub struct RateLimiterManager {
     ip_whitelist: HashSet<IpAddr>,
+    store: Arc<dyn LimitStore>,
+    clock: Arc<dyn Clock>,
+    fallback: Option<Arc<FallbackLimiter>>,
+    check_store_on_startup: bool,
+    log_decisions: DecisionLogging,
+    policy_header: bool,
+    most_restrictive: MostRestrictive,
+    consume: ConsumeMode,
+    well_known_endpoint: bool,
+    status_path: Option<String>,
+    headers: LimitHeaders,
+    priorities: PriorityClasses,
+    key_builder: KeyBuilder,
     user_rate_limiters: Vec<Arc<RateLimiter>>,
     request_rate_limiters: Vec<Arc<RateLimiter>>,
+    default_action: DefaultAction,
+    quotas: Vec<Arc<Quota>>,
+    distinct_limits: Vec<Arc<DistinctLimit>>,
+    usage: Option<Arc<UsageRecorder>>,
+    unique_clients: Option<Arc<UniqueClients>>,
+    anomalies: Option<Arc<AnomalyDetector>>,
+    warm_up: Option<Arc<WarmUp>>,
+    deny_cache: Option<Arc<DenyCache>>,
+    upstream_limits: Option<Arc<UpstreamLimits>>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    openapi: Option<Arc<OpenApiRoutes>>,
+    tarpit: Option<Arc<Tarpit>>,
+    bans: Option<Arc<Bans>>,
+    reputation: Option<Reputation>,
+    ip_classes: Option<IpClasses>,
+    waf: Option<Waf>,
+    // Whether anything reads the body of requests, which are buffered only then
+    needs_body: bool,
+    cors: Option<Cors>,
+    auth: Option<Auth>,
+    challenge: Option<Arc<Challenge>>,
+    bypass: Option<Arc<BypassTokens>>,
+    cluster: Option<Arc<Cluster>>,
+    overrides: Option<Arc<Overrides>>,
+    partitioner: Option<Partitioner>,
+    instance_id: String,
 }
 
 impl RateLimiterManager {
-    pub fn new(rate_limiter_settings: RateLimiterSettings) -> Result<Self, std::io::Error> {
+    pub fn new(rate_limiter_settings: RateLimiterSettings) -> Result<Self, RateLimiterError> {
+        Self::build(rate_limiter_settings, None, Arc::new(SystemClock))
+    }
+
+    // Uses `store` instead of the configured backend, e.g. a mock store in tests
+    pub fn with_store(rate_limiter_settings: RateLimiterSettings, store: Arc<dyn LimitStore>) -> Result<Self, RateLimiterError> {
+        Self::build(rate_limiter_settings, Some(store), Arc::new(SystemClock))
+    }
+
+    // Reads the time of windows, quota periods and schedules from `clock`, e.g. a `MockClock` in tests.
+    // A given `store` keeps its own clock.
+    pub fn with_clock(rate_limiter_settings: RateLimiterSettings, store: Option<Arc<dyn LimitStore>>, clock: Arc<dyn Clock>) -> Result<Self, RateLimiterError> {
+        Self::build(rate_limiter_settings, store, clock)
+    }
+
+    fn build(rate_limiter_settings: RateLimiterSettings, store: Option<Arc<dyn LimitStore>>, clock: Arc<dyn Clock>) -> Result<Self, RateLimiterError> {
+        if let Some(status_path) = &rate_limiter_settings.status_path && !status_path.starts_with('/') {
+            return Err(RateLimiterError::config(format!("status_path {} must start with /", status_path)));
+        }
         let mut user_rate_limiters = Vec::new();
         let mut request_rate_limiters = Vec::new();
 
+        // Only these backends can keep the sets of distinct values
+        if store.is_none() && matches!(rate_limiter_settings.backend, PossibleBackends::Memcached | PossibleBackends::DynamoDB) {
+            if !rate_limiter_settings.distinct_limits.is_empty() {
+                return Err(RateLimiterError::config("distinct_limit requires the redis or memory backend"));
+            }
+            if rate_limiter_settings.unique_clients.is_some() {
+                return Err(RateLimiterError::config("unique_clients requires the redis or memory backend"));
+            }
+        }
 
-        let cfg = Config::from_url(format!("redis://{}", rate_limiter_settings.redis_addr.as_str()));
-        let pool = cfg.create_pool(Some(deadpool_redis::Runtime::Tokio1)).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut redis_pool = None;
+        let store: Arc<dyn LimitStore> = match store {
+            Some(store) => store,
+            None => match rate_limiter_settings.backend {
+                PossibleBackends::Redis => Arc::new(RedisStore::new(redis_pool.insert(RedisPool::new(&rate_limiter_settings)?).clone(), clock.clone())),
+                PossibleBackends::Memory => Arc::new(MemoryStore::with_clock(clock.clone())),
+                PossibleBackends::Memcached => match &rate_limiter_settings.memcached {
+                    Some(settings) => Arc::new(MemcachedStore::new(settings, clock.clone(), rate_limiter_settings.max_clock_skew_ms)?),
+                    None => return Err(RateLimiterError::config("memcached backend requires a [rate_limiter.memcached] section")),
+                },
+                #[cfg(feature = "dynamodb")]
+                PossibleBackends::DynamoDB => match &rate_limiter_settings.dynamodb {
+                    Some(settings) => Arc::new(DynamoDBStore::new(settings, clock.clone(), rate_limiter_settings.max_clock_skew_ms)),
+                    None => return Err(RateLimiterError::config("dynamodb backend requires a [rate_limiter.dynamodb] section")),
+                },
+                #[cfg(not(feature = "dynamodb"))]
+                PossibleBackends::DynamoDB => return Err(RateLimiterError::config("rate_limiter was built without the dynamodb feature")),
+            },
+        };
+        let store: Arc<dyn LimitStore> = match &rate_limiter_settings.local_cache {
+            Some(settings) => Arc::new(LocalCacheStore::new(store, settings, clock.clone())),
+            None => store,
+        };
+        let overrides = rate_limiter_settings.overrides.as_ref()
+            .map(|settings| Overrides::new(settings, redis_pool.clone(), &rate_limiter_settings.keys.prefix))
+            .transpose()?;
+        let auth = (!rate_limiter_settings.auth_routes.is_empty())
+            .then(|| Auth::new(&rate_limiter_settings.auth_routes, redis_pool.clone(), &rate_limiter_settings.keys.prefix))
+            .transpose()?;
+        let bans = rate_limiter_settings.ban.as_ref().map(|settings| Bans::new(settings, redis_pool.as_ref()).map(Arc::new)).transpose()?;
+        let cluster = match (&rate_limiter_settings.cluster, redis_pool) {
+            (Some(settings), Some(pool)) => Some(Cluster::new(settings, pool, &rate_limiter_settings.keys.prefix, rate_limiter_settings.max_clock_skew_ms)?),
+            (Some(_), None) => return Err(RateLimiterError::config("cluster requires the redis backend")),
+            (None, _) => None,
+        };
+        let partitioner = match (&rate_limiter_settings.partition, &cluster) {
+            (Some(settings), Some(cluster)) => Some(Partitioner::new(settings, cluster.clone())),
+            (Some(_), None) => return Err(RateLimiterError::config("partition requires a [rate_limiter.cluster] section")),
+            (None, _) => None,
+        };
 
-        for settings in rate_limiter_settings.limiters_settings.iter() {
-            let strategy = Strategy::from_possible_strategy(&settings.strategy);
-            let global_bucket= settings.global_bucket.as_ref().map(Bucket::from);
-            
-            let buckets_per_value = settings.buckets_per_value.as_ref().map(
-                |buckets| buckets.iter().map(
-                    |b| (b.value.clone(), Bucket::new(b.tokens_count, b.add_tokens_every))
-                ).collect());
-            
-            if buckets_per_value.is_none() && global_bucket.is_none() {
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,"No bucket defined for rate limiter"))
+        for (index, settings) in rate_limiter_settings.limiters_settings.iter().enumerate() {
+            let name = settings.name.clone().unwrap_or_else(|| format!("{}-{}", settings.strategy.as_str(), index));
+            let path = format!("limiter[{}]", index);
+            validate_buckets(&name, &path, settings.global_bucket.as_ref(), settings.buckets_per_value.as_deref())?;
+            validate_default_bucket(&name, &path, settings)?;
+            let buckets = LimiterBuckets::new(settings.global_bucket.as_ref(), settings.buckets_per_value.as_deref());
+            if buckets.is_empty() && settings.default_bucket.is_none() {
+                return Err(RateLimiterError::config(format!("No bucket defined for rate limiter {} ({})", name, path)))
             }
-            
-            match strategy {
-                Strategy::IP(_) => user_rate_limiters.push(Arc::new(RateLimiter::new(strategy, pool.clone(), global_bucket, buckets_per_value))),
-                Strategy::Header(_) => user_rate_limiters.push(Arc::new(RateLimiter::new(strategy, pool.clone(), global_bucket, buckets_per_value))),
-                Strategy::Url(_) => request_rate_limiters.push(Arc::new(RateLimiter::new(strategy, pool.clone(), global_bucket, buckets_per_value))),
-                Strategy::Query(_) => request_rate_limiters.push(Arc::new(RateLimiter::new(strategy, pool.clone(), global_bucket, buckets_per_value))),
-                Strategy::Body(_) => request_rate_limiters.push(Arc::new(RateLimiter::new(strategy, pool.clone(), global_bucket, buckets_per_value))),
+
+            let schedules = settings.schedules.iter()
+                .enumerate()
+                .map(|(schedule_index, schedule)| {
+                    let path = format!("{}.schedule[{}]", path, schedule_index);
+                    validate_buckets(&name, &path, schedule.global_bucket.as_ref(), schedule.buckets_per_value.as_deref())?;
+                    let buckets = LimiterBuckets::new(schedule.global_bucket.as_ref(), schedule.buckets_per_value.as_deref());
+                    Ok((Schedule::new(schedule)?, buckets))
+                })
+                .collect::<Result<Vec<_>, RateLimiterError>>()?;
+
+            let log_decisions = settings.log_decisions.unwrap_or(rate_limiter_settings.log_decisions);
+            let rate_limiter = Arc::new(RateLimiter::new(name, settings, log_decisions, buckets, schedules, &clock)?);
+            match rate_limiter.strategy {
+                Strategy::IP(_) | Strategy::Header(_) | Strategy::Identity(_) => user_rate_limiters.push(rate_limiter),
+                Strategy::Url(_) | Strategy::Query(_) | Strategy::Body(_) | Strategy::Operation(_) => request_rate_limiters.push(rate_limiter),
             }
         }
+
+        let openapi = rate_limiter_settings.openapi.as_ref().map(|settings| OpenApiRoutes::load(settings).map(Arc::new)).transpose()?;
+        if let (Some(settings), Some(routes)) = (&rate_limiter_settings.openapi, &openapi) {
+            let buckets = LimiterBuckets { global_bucket: None, buckets_per_value: Some(BucketsPerValue::new(routes.buckets())) };
+            buckets.validate("openapi")?;
+            request_rate_limiters.push(Arc::new(RateLimiter {
+                name: "openapi".to_string(),
+                strategy: Strategy::Operation(OperationRateLimiterStrategy::new(routes.clone())),
+                on_store_error: settings.on_store_error,
+                scope: LimitScope::Global,
+                log_decisions: rate_limiter_settings.log_decisions,
+                cardinality: None,
+                spike_arrest: None,
+                buckets,
+                default_bucket: None,
+                schedules: Vec::new(),
+            }));
+        }
         
+        let quotas = rate_limiter_settings.quotas_settings.iter()
+            .map(|settings| Quota::new(settings).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let distinct_limits = rate_limiter_settings.distinct_limits.iter()
+            .map(|settings| DistinctLimit::new(settings).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Only allocate the fallback store if some limiter needs it
+        let fallback = rate_limiter_settings.limiters_settings.iter()
+            .any(|settings| matches!(settings.on_store_error, OnStoreError::FallbackMemory))
+            .then(|| Arc::new(FallbackLimiter::new(&rate_limiter_settings.fallback_memory, clock.clone())));
+
+        let default_action = DefaultAction::new(
+            rate_limiter_settings.default_action.as_deref(),
+            &user_rate_limiters.iter().chain(request_rate_limiters.iter()).cloned().collect::<Vec<_>>(),
+        )?;
+        let waf = (!rate_limiter_settings.waf_routes.is_empty()).then(|| Waf::new(&rate_limiter_settings.waf_routes)).transpose()?;
+        let upstream_limits = rate_limiter_settings.upstream_limits.as_ref().map(|settings| UpstreamLimits::new(settings).map(Arc::new)).transpose()?;
+        let retry_budget = rate_limiter_settings.retry_budget.as_ref().map(|settings| RetryBudget::new(settings).map(Arc::new)).transpose()?;
+        // Retries are told apart by their body too
+        let needs_body = user_rate_limiters.iter().chain(request_rate_limiters.iter()).any(|rate_limiter| rate_limiter.strategy.needs_body())
+            || waf.as_ref().is_some_and(Waf::needs_body)
+            || upstream_limits.as_ref().is_some_and(|upstream_limits| upstream_limits.needs_body())
+            || retry_budget.is_some();
+
         Ok(Self {
+            store,
+            fallback,
+            check_store_on_startup: rate_limiter_settings.redis.pool.check_on_startup,
+            log_decisions: rate_limiter_settings.log_decisions,
+            policy_header: rate_limiter_settings.policy_header,
+            most_restrictive: rate_limiter_settings.most_restrictive,
+            consume: rate_limiter_settings.consume,
+            well_known_endpoint: rate_limiter_settings.well_known_endpoint,
+            status_path: rate_limiter_settings.status_path.clone(),
+            headers: LimitHeaders::new(&rate_limiter_settings.headers)?,
+            priorities: PriorityClasses::new(&rate_limiter_settings.priority_classes)?,
+            key_builder: KeyBuilder::new(&rate_limiter_settings.keys)?,
             user_rate_limiters,
             request_rate_limiters,
+            default_action,
+            quotas,
+            distinct_limits,
+            usage: rate_limiter_settings.usage.as_ref().map(UsageRecorder::new).transpose()?,
+            unique_clients: rate_limiter_settings.unique_clients.as_ref().map(|settings| UniqueClients::new(settings).map(Arc::new)).transpose()?,
+            anomalies: rate_limiter_settings.anomaly_detection.as_ref().map(AnomalyDetector::new).transpose()?,
+            warm_up: rate_limiter_settings.warm_up.as_ref().map(|settings| WarmUp::new(settings, clock.clone()).map(Arc::new)).transpose()?,
+            tarpit: rate_limiter_settings.tarpit.as_ref().map(|settings| Tarpit::new(settings).map(Arc::new)).transpose()?,
+            bans,
+            ip_classes: rate_limiter_settings.ip_classes.as_ref().map(IpClasses::new).transpose()?,
+            reputation: (!rate_limiter_settings.reputation_lists.is_empty()).then(|| Reputation::new(&rate_limiter_settings.reputation_lists)).transpose()?,
+            waf,
+            cors: (!rate_limiter_settings.cors_routes.is_empty()).then(|| Cors::new(&rate_limiter_settings.cors_routes)).transpose()?,
+            auth,
+            challenge: rate_limiter_settings.challenge.as_ref().map(|settings| Challenge::new(settings).map(Arc::new)).transpose()?,
+            bypass: rate_limiter_settings.bypass.as_ref().map(|settings| BypassTokens::new(settings).map(Arc::new)).transpose()?,
+            openapi,
+            deny_cache: rate_limiter_settings.deny_cache.as_ref().map(|settings| Arc::new(DenyCache::new(settings, clock.clone()))),
+            upstream_limits,
+            retry_budget,
+            needs_body,
+            instance_id: cluster.as_ref().map_or_else(default_instance_id, |cluster| cluster.instance_id().to_string()),
+            cluster,
+            overrides,
+            partitioner,
             ip_whitelist: rate_limiter_settings.ip_whitelist.clone(),
+            clock,
+        })
+    }
+
+    pub fn is_whitelisted(&self, ip: &IpAddr) -> bool {
+        self.ip_whitelist.contains(ip)
+    }
+
+    // Tags the request with the class of the client IP, for ip limiters with buckets per class
+    pub fn classify_ip(&self, request: &mut SafeRequest, ip: IpAddr) {
+        if let Some(ip_classes) = &self.ip_classes {
+            ip_classes.tag(request, ip);
+        }
+    }
+
+    // Limits the request and passes it to `next` if it's allowed, this is the shared logic of the middleware and the tower layer
+    pub async fn handle<F, Fut, E>(&self, request: Request<Body>, addr: SocketAddr, next: F) -> Result<Response<Body>, E>
+    where
+        F: FnOnce(Request<Body>) -> Fut,
+        Fut: Future<Output = Result<Response<Body>, E>>,
+    {
+        if self.is_status_request(&request) {
+            return Ok(self.status(request, addr).await);
+        }
+        // Preflights are known routes when the method they ask for is
+        let preflight_method = cors::preflight_method(&request);
+        if let Some(openapi) = &self.openapi && !openapi.is_known(preflight_method.as_ref().unwrap_or(request.method()), request.uri().path()) {
+            return Ok((StatusCode::NOT_FOUND, "Unknown route").into_response());
+        }
+
+        let cors = self.cors.as_ref().and_then(|cors| cors.route(&request));
+        if let Some((cors_route, origin)) = &cors && preflight_method.is_some() {
+            if !cors_route.allows(origin) {
+                return Ok((StatusCode::FORBIDDEN, "Origin not allowed").into_response());
+            }
+            let preflight = cors_route.preflight(origin, request.headers());
+            if cors_route.preflight_bypasses_limits {
+                return Ok(preflight);
+            }
+            return self.limit_request(request, addr, |_| async { Ok(preflight) }).await;
+        }
+
+        // Unauthenticated requests are rejected before any limiter is charged
+        let mut request = request;
+        let mut response = match self.authenticate(&mut request, addr).await {
+            Ok(()) => self.limit_request(request, addr, next).await?,
+            Err(response) => response,
+        };
+        // Denials get the CORS headers too, so browsers let scripts read them
+        if let Some((cors_route, origin)) = &cors && cors_route.allows(origin) {
+            cors_route.insert(&mut response, origin);
+        }
+        Ok(response)
+    }
+
+    // Adds the identity of authenticated requests to their extensions, for the identity strategy
+    async fn authenticate(&self, request: &mut Request<Body>, addr: SocketAddr) -> Result<(), Response<Body>> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+        let body = match auth.needs_body(request.uri().path()) {
+            true => {
+                let body = to_bytes(std::mem::take(request.body_mut()), usize::MAX).await
+                    .map_err(|e| connection_guard::body_error_response(&e))?;
+                *request.body_mut() = Body::from(body.clone());
+                body
+            },
+            false => Bytes::new(),
+        };
+
+        // Clients that sent too many invalid signatures are denied before theirs are checked
+        let failure_limit = auth.signature_failure_limit(request.uri().path())
+            .map(|bucket| (self.key_builder.build("signature_failures", &addr.ip().to_string()), bucket));
+        if let Some((key, bucket)) = &failure_limit && self.store.peek(key, bucket).await.is_ok_and(|remaining| remaining <= 0) {
+            metrics::increment_counter("rate_limiter_auth_failures_total", &[("reason", "too_many_signature_failures")]);
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, "Too many invalid signatures").into_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(bucket.reset_secs()));
+            return Err(response);
+        }
+
+        match auth.authenticate(request.uri().path(), request.headers(), &body).await {
+            Ok(identity) => {
+                if let Some(identity) = identity {
+                    request.extensions_mut().insert(identity);
+                }
+                Ok(())
+            },
+            Err(rejection) => {
+                // Store errors leave the failure uncounted
+                if let Some((key, bucket)) = &failure_limit && rejection.is_signature_failure() && let Err(e) = self.store.consume(key, bucket, 1).await {
+                    println!("Store error in signature failures of {}, leaving the failure of {} uncounted: {}", request.uri().path(), addr.ip(), e);
+                    metrics::increment_counter("rate_limiter_store_errors_total", &[("limiter", "signature_failures"), ("action", "allow")]);
+                }
+                Err(rejection.response)
+            },
+        }
+    }
+
+    async fn limit_request<F, Fut, E>(&self, request: Request<Body>, addr: SocketAddr, next: F) -> Result<Response<Body>, E>
+    where
+        F: FnOnce(Request<Body>) -> Fut,
+        Fut: Future<Output = Result<Response<Body>, E>>,
+    {
+        // Check whitelist
+        if self.is_whitelisted(&addr.ip()) {
+            if self.log_decisions.should_log(false) {
+                println!("IP {} is whitelisted", addr.ip());
+            }
+            let mut request = request;
+            request.extensions_mut().insert(RateLimitInfo { is_whitelisted: true, ..RateLimitInfo::default() });
+            return next(request).await;
+        }
+        let mut request = request;
+        if self.is_bypassed(&mut request, addr) {
+            request.extensions_mut().insert(RateLimitInfo { is_bypassed: true, ..RateLimitInfo::default() });
+            return next(request).await;
+        }
+        if let Some(banned_for) = self.bans.as_ref().and_then(|bans| bans.banned_for(addr.ip())) {
+            let mut response = (StatusCode::FORBIDDEN, "Banned").into_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(banned_for.as_millis().div_ceil(1000) as u64));
+            return Ok(response);
+        }
+        // Listed clients are blocked, or charged to the bucket of their list before the limiters
+        match self.check_reputation(addr).await {
+            Some(Listed::Blocked) => return Ok((StatusCode::FORBIDDEN, "Blocked").into_response()),
+            Some(Listed::Limited(retry_after)) => {
+                self.hold_denied(addr).await;
+                let mut response = (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response();
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return Ok(response);
+            },
+            None => {},
+        }
+
+        // Large uploads stream to the upstream as they come unless something checks the body
+        let (mut safe_request, unread_body) = match SafeRequest::read(request, self.needs_body).await {
+            Ok(request) => request,
+            Err(response) => return Ok(response),
+        };
+        self.classify_ip(&mut safe_request, addr.ip());
+        if let Some(rule) = self.waf.as_ref().and_then(|waf| waf.violation(&safe_request)) {
+            metrics::increment_counter("rate_limiter_waf_blocked_total", &[("rule", rule)]);
+            if self.log_decisions.should_log(true) {
+                println!("Request blocked: rule={} client={} path={}", rule, addr.ip(), safe_request.parts.uri.path());
+            }
+            self.hold_denied(addr).await;
+            return Ok((StatusCode::FORBIDDEN, "Request blocked").into_response());
+        }
+        if let Some(unique_clients) = &self.unique_clients {
+            unique_clients.record(&safe_request, addr, &self.store, &self.key_builder);
+        }
+        // Clients the upstream ran out for are denied until its reset, before any limiter is charged
+        let upstream_client = self.upstream_limits.as_ref().map(|upstream_limits| upstream_limits.client(&safe_request, addr));
+        if let Some(throttled_for) = self.upstream_limits.as_ref().zip(upstream_client.as_ref())
+            .and_then(|(upstream_limits, client)| upstream_limits.throttled_for(client)) {
+            metrics::increment_counter("rate_limiter_upstream_throttled_total", &[]);
+            self.hold_denied(addr).await;
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(throttled_for.as_millis().div_ceil(1000) as u64));
+            return Ok(response);
+        }
+        // Retries over the budget of the client are denied without charging the limiters
+        if let Some(retry_budget) = &self.retry_budget
+            && let Some(retry_after) = retry_budget.check(&safe_request, addr, self.store.as_ref(), &self.key_builder).await {
+            self.hold_denied(addr).await;
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, "Retry budget exceeded").into_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+            return Ok(response);
+        }
+        // Clients that solved a challenge skip the rate limits for a while, quotas still apply
+        let is_unblocked = self.challenge.as_ref().is_some_and(|challenge| challenge.is_unblocked(addr.ip()));
+        let limits = match is_unblocked {
+            true => Vec::new(),
+            false => self.decide(&safe_request, addr, |_| true).await,
+        };
+        if limits.is_empty() && !is_unblocked && self.denies_unmatched() {
+            metrics::increment_counter("rate_limiter_unmatched_denied_total", &[]);
+            if self.log_decisions.should_log(true) {
+                println!("Request denied: no limiter matches client={} path={}", addr.ip(), safe_request.parts.uri.path());
+            }
+            return Ok((StatusCode::FORBIDDEN, "No rate limit applies to this request").into_response());
+        }
+        let limiters = limits.iter().map(|(rate_limiter, _, _)| rate_limiter.name.clone()).collect::<Vec<_>>();
+        let lowest_limit = limits.into_iter()
+            .min_by(|(_, limit, _), (_, other, _)| self.most_restrictive.compare(limit, other));
+        // The headers of the route are looked up once, for the denial as much as for the upstream's response
+        let route_headers = self.headers.for_path(safe_request.parts.uri.path());
+        let mut limit_headers = HeaderMap::with_capacity(LIMIT_HEADERS_CAPACITY);
+        if let Some((rate_limiter, limit, bucket)) = &lowest_limit {
+            let policy = self.policy_header.then(|| rate_limiter.policy(bucket.add_tokens_every));
+            route_headers.insert(&mut limit_headers, limit, policy.as_deref(), bucket.reset_secs());
+        }
+
+        if let Some((_, limit, _)) = &lowest_limit && limit.is_limit_exceeded {
+            if let Some(response) = self.challenge.as_ref().and_then(|challenge| challenge.challenge(addr.ip())) {
+                return Ok(response);
+            }
+            self.hold_denied(addr).await;
+            return Ok(with_headers((StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded").into_response(), limit_headers));
+        }
+
+        if let Some((distinct_limit, resets_in)) = self.check_distinct(&safe_request, addr).await {
+            self.hold_denied(addr).await;
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, format!("Too many distinct resources, at most {} allowed", distinct_limit.max_distinct)).into_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(resets_in));
+            return Ok(response);
+        }
+
+        // Quotas are only charged for requests the rate limits let through
+        let quota_usage = self.check_quotas(&safe_request).await;
+        if let Some(usage) = &quota_usage && usage.is_exceeded() {
+            self.hold_denied(addr).await;
+            let mut quota_headers = HeaderMap::with_capacity(LIMIT_HEADERS_CAPACITY);
+            insert_quota_headers(&mut quota_headers, usage);
+            return Ok(with_headers((StatusCode::TOO_MANY_REQUESTS, "Quota exceeded").into_response(), quota_headers));
+        }
+        if let Some(usage) = &self.usage {
+            usage.record(&safe_request);
+        }
+
+        let priority = self.priorities.classify(&safe_request);
+        safe_request.parts.extensions.insert(priority);
+        // Everything the headers need is known before the upstream is called, and they only go to the head of its response,
+        // so streamed bodies such as server-sent events are passed through untouched as they come
+        if let Some(usage) = &quota_usage {
+            insert_quota_headers(&mut limit_headers, usage);
+        }
+        let lowest_limit = lowest_limit.map(|(rate_limiter, limit, _)| (limit, rate_limiter.name.clone()));
+        safe_request.parts.extensions.insert(RateLimitInfo {
+            limit: lowest_limit.as_ref().map(|(limit, _)| limit.clone()),
+            limiter: lowest_limit.as_ref().map(|(_, name)| name.clone()),
+            limiters,
+            quota: quota_usage,
+            is_whitelisted: false,
+            is_bypassed: false,
+        });
+
+        let mut response = next(safe_request.with_body(unread_body)).await?;
+        // The upstream's own limit is sent instead when it's the most restrictive one
+        if let Some((upstream_limits, client)) = self.upstream_limits.as_ref().zip(upstream_client) {
+            let status = response.status();
+            if let Some(upstream) = upstream_limits.read(&client, status, response.headers_mut(), self.clock.now_secs())
+                && lowest_limit.as_ref().is_none_or(|(limit, _)| self.most_restrictive.compare(&upstream.limit, limit).is_lt()) {
+                route_headers.insert(&mut limit_headers, &upstream.limit, None, upstream.reset_secs);
+            }
+        }
+        Ok(with_headers(response, limit_headers))
+    }
+
+    // Requests with a valid bypass token skip the rate limits like whitelisted IPs, every use is logged.
+    // The token is never forwarded, invalid ones are ignored and the request is limited as usual.
+    fn is_bypassed(&self, request: &mut Request<Body>, addr: SocketAddr) -> bool {
+        let Some(bypass) = &self.bypass else {
+            return false;
+        };
+        let Some(token) = request.headers_mut().remove(bypass.header()) else {
+            return false;
+        };
+        match bypass.verify(token.to_str().unwrap_or_default(), self.clock.now_secs()) {
+            Ok((key_id, subject)) => {
+                metrics::increment_counter("rate_limiter_bypassed_total", &[("key_id", key_id)]);
+                println!("Rate limits bypassed: key_id={} subject={} client={} path={}", key_id, subject, addr.ip(), request.uri().path());
+                true
+            },
+            Err(reason) => {
+                metrics::increment_counter("rate_limiter_bypass_rejected_total", &[("reason", reason)]);
+                false
+            },
+        }
+    }
+
+    // Whether requests no limiter matches are denied, by default_action = "deny"
+    pub fn denies_unmatched(&self) -> bool {
+        matches!(self.default_action, DefaultAction::Deny)
+    }
+
+    fn is_status_request(&self, request: &Request<Body>) -> bool {
+        let path = request.uri().path();
+        matches!(*request.method(), Method::GET | Method::HEAD)
+            && ((self.well_known_endpoint && path == WELL_KNOWN_PATH) || self.status_path.as_deref() == Some(path))
+    }
+
+    // Answers the status endpoints with the budget of the client, the request itself is not charged
+    async fn status(&self, request: Request<Body>, addr: SocketAddr) -> Response<Body> {
+        let (parts, _) = request.into_parts();
+        let mut request = SafeRequest::new(parts, Default::default());
+        self.classify_ip(&mut request, addr.ip());
+        let limits = match self.is_whitelisted(&addr.ip()) {
+            true => Vec::new(),
+            false => self.peek(&request, addr).await,
+        };
+        let quotas = self.peek_quotas(&request).await;
+        ([(CACHE_CONTROL, "no-store")], Json(StatusResponse { limits, quotas })).into_response()
+    }
+
+    async fn check_reputation(&self, addr: SocketAddr) -> Option<Listed> {
+        self.reputation.as_ref()?.check(addr.ip(), self.store.as_ref(), &self.key_builder).await
+    }
+
+    async fn hold_denied(&self, addr: SocketAddr) {
+        if let Some(bans) = &self.bans {
+            bans.record_denial(addr.ip());
+        }
+        if let Some(tarpit) = &self.tarpit {
+            tarpit.hold(addr.ip()).await;
+        }
+    }
+
+    // Fails if the store is unreachable and some limiter denies requests on store errors,
+    // with only fail-open limiters the proxy starts anyway and applies their policy
+    pub async fn check_store_connection(&self) -> Result<(), RateLimiterError> {
+        if !self.check_store_on_startup {
+            return Ok(());
+        }
+        let Err(e) = self.store.check_connection().await else {
+            return Ok(());
+        };
+
+        let denying_limiters = self.user_rate_limiters.iter()
+            .chain(self.request_rate_limiters.iter())
+            .filter(|rate_limiter| matches!(rate_limiter.on_store_error, OnStoreError::Deny))
+            .map(|rate_limiter| rate_limiter.name.as_str())
+            .collect::<Vec<_>>();
+        if denying_limiters.is_empty() {
+            println!("Store is unreachable on startup, starting anyway as all limiters fail open: {}", e);
+            return Ok(());
+        }
+
+        Err(RateLimiterError::Store(format!(
+            "{}. Limiters {} deny requests while the store is unreachable; check the store address and credentials, \
+            or set rate_limiter.redis.pool.check_on_startup = false to start anyway",
+            e, denying_limiters.join(", "),
+        )))
+    }
+
+    // Consumes a token from every matching limiter in one store call and returns the most restrictive limit
+    pub async fn check(&self, request: &SafeRequest, addr: SocketAddr) -> Option<LimitForRequest> {
+        self.check_strategies(request, addr, |_| true).await
+    }
+
+    // Same as `check`, but only limiters whose strategy passes `filter` are applied
+    pub async fn check_strategies(&self, request: &SafeRequest, addr: SocketAddr, filter: impl Fn(&Strategy) -> bool) -> Option<LimitForRequest> {
+        self.decide(request, addr, filter).await.into_iter()
+            .map(|(_, limit, _)| limit)
+            .min_by(|limit, other| self.most_restrictive.compare(limit, other))
+    }
+
+    // Same as `check`, with the limit of every limiter that applied to the request by limiter name.
+    // A request denied by the deny cache only gets the limit of the limiter that denied it.
+    pub async fn check_each(&self, request: &SafeRequest, addr: SocketAddr) -> Vec<(String, LimitForRequest)> {
+        self.decide(request, addr, |_| true).await.into_iter()
+            .map(|(rate_limiter, limit, _)| (rate_limiter.name.clone(), limit))
+            .collect()
+    }
+
+    // Remaining tokens of every limiter that would apply to the request, without consuming any.
+    // Limiters the store can't be read for are left out.
+    pub async fn peek(&self, request: &SafeRequest, addr: SocketAddr) -> Vec<LimitStatus> {
+        let mut limit_keys = self.limit_keys(request, addr, |_| true);
+        if let Some(anomalies) = &self.anomalies {
+            limit_keys.iter_mut().for_each(|(_, limit_key)| anomalies.penalize(limit_key));
+        }
+        let buckets = limit_keys.iter()
+            .map(|(_, limit_key)| (limit_key.key.as_str(), &limit_key.bucket))
+            .collect::<Vec<_>>();
+        let counts = self.store.peek_many(&buckets).await;
+
+        limit_keys.iter()
+            .zip(counts)
+            .filter_map(|((rate_limiter, limit_key), count)| match count {
+                Ok(remaining) => Some(LimitStatus {
+                    limiter: rate_limiter.name.clone(),
+                    policy: rate_limiter.policy(limit_key.bucket.add_tokens_every),
+                    limit: limit_key.bucket.capacity(),
+                    remaining: remaining.max(0) as u32,
+                    reset: limit_key.bucket.reset_secs(),
+                }),
+                Err(e) => {
+                    println!("Store error in limiter {}, leaving it out of the peek: {}", rate_limiter.name, e);
+                    None
+                },
+            })
+            .collect()
+    }
+
+    // Every limit comes with the bucket it was checked against
+    async fn decide(&self, request: &SafeRequest, addr: SocketAddr, filter: impl Fn(&Strategy) -> bool) -> Vec<(&RateLimiter, LimitForRequest, Bucket)> {
+        let mut limit_keys = self.limit_keys(request, addr, filter);
+        if limit_keys.is_empty() {
+            return Vec::new();
+        }
+        if let Some(anomalies) = &self.anomalies {
+            for (rate_limiter, limit_key) in limit_keys.iter_mut() {
+                anomalies.record(&rate_limiter.name, &limit_key.key);
+                anomalies.penalize(limit_key);
+            }
+        }
+
+        // The request is denied anyway, so none of its buckets is charged
+        if let Some(deny_cache) = &self.deny_cache
+            && let Some((rate_limiter, limit_key)) = limit_keys.iter().find(|(_, limit_key)| deny_cache.is_denied(&limit_key.key)) {
+            metrics::increment_counter("rate_limiter_deny_cache_hits_total", &[("limiter", &rate_limiter.name)]);
+            if rate_limiter.log_decisions.should_log(true) {
+                println!("Rate limit decision: limiter={} key={} client={} remaining=0 outcome=denied cached=true", rate_limiter.name, limit_key.key, addr.ip());
+            }
+            return vec![(Arc::as_ref(*rate_limiter), LimitForRequest::new(limit_key.bucket.capacity(), -1, true), limit_key.bucket.clone())];
+        }
+        if let Some(arrested) = self.arrest_spikes(&limit_keys, addr).await {
+            return vec![arrested];
+        }
+
+        let priority = self.priorities.classify(request);
+        let over_share = match priority == Priority::FULL {
+            true => None,
+            false => self.deny_over_share(&limit_keys, priority).await,
+        };
+        // Requests denied over their share weren't charged, so they can't borrow either
+        let can_borrow = over_share.is_none();
+        let counts = match (over_share, self.consume) {
+            (Some(counts), _) => counts,
+            (None, ConsumeMode::MostRestrictive) => self.consume_most_restrictive(&limit_keys).await,
+            (None, ConsumeMode::All | ConsumeMode::First) => {
+                let token_requests = limit_keys.iter()
+                    .map(|(_, limit_key)| TokenRequest::new(&limit_key.key, &limit_key.bucket, 1))
+                    .collect::<Vec<_>>();
+                self.store.consume_many(&token_requests).await
+            },
+        };
+
+        if let Some(fallback) = &self.fallback && fallback.is_active() && counts.iter().any(|count| count.is_ok()) {
+            let (fallback, store) = (fallback.clone(), self.store.clone());
+            tokio::spawn(async move { fallback.resync(store.as_ref()).await });
+        }
+
+        let mut limits = Vec::with_capacity(limit_keys.len());
+        for ((rate_limiter, limit_key), count) in limit_keys.iter().zip(counts) {
+            let count = match count {
+                Ok(count) => count,
+                Err(e) => match self.handle_store_error(rate_limiter, limit_key, e).await {
+                    Some(count) => count,
+                    None => continue,
+                },
+            };
+
+            // Lower classes can't take the tokens kept for the others, only an empty bucket denies every class.
+            // Buckets in debt still let requests through until they borrowed all they can.
+            let remaining = count - priority.reserved(limit_key.bucket.capacity()) as i32;
+            let borrow = if can_borrow { limit_key.bucket.borrow as i32 } else { 0 };
+            let limit = LimitForRequest::new(limit_key.bucket.capacity(), remaining, remaining < -borrow);
+            if let Some(deny_cache) = &self.deny_cache && count < -borrow {
+                // Errors only lose the precise end of the window, the whole window is kept instead
+                let window_ends_in = match limit_key.bucket.burst {
+                    Some(_) => None,
+                    None => self.store.window_ends_in_ms(&limit_key.key, &limit_key.bucket).await.ok().flatten().map(Duration::from_millis),
+                };
+                deny_cache.deny(&limit_key.key, &limit_key.bucket, window_ends_in);
+            }
+            if rate_limiter.log_decisions.should_log(limit.is_limit_exceeded) {
+                println!(
+                    "Rate limit decision: limiter={} key={} client={} remaining={} outcome={}",
+                    rate_limiter.name, limit_key.key, addr.ip(), remaining.max(0), if limit.is_limit_exceeded { "denied" } else { "allowed" },
+                );
+            }
+            limits.push((Arc::as_ref(*rate_limiter), limit, limit_key.bucket.clone()));
+        }
+
+        limits
+    }
+
+    // Keys of the limiters matching the request, scoped and partitioned, in the order they are charged
+    fn limit_keys(&self, request: &SafeRequest, addr: SocketAddr, filter: impl Fn(&Strategy) -> bool) -> Vec<(&Arc<RateLimiter>, LimitKey)> {
+        let now = self.clock.now_secs();
+        let mut limit_keys = self.user_rate_limiters.iter() // start to check the user
+            .chain(self.request_rate_limiters.iter()) // check the request
+            .filter(|rate_limiter| filter(&rate_limiter.strategy) && !self.default_action.is_limiter(rate_limiter))
+            .filter_map(|rate_limiter| rate_limiter.get_key(request, addr, &self.key_builder, self.overrides.as_deref(), now).map(|limit_key| (rate_limiter, limit_key)))
+            .collect::<Vec<_>>();
+        if limit_keys.is_empty() && let DefaultAction::Limiter(rate_limiter) = &self.default_action && filter(&rate_limiter.strategy)
+            && let Some(limit_key) = rate_limiter.get_key(request, addr, &self.key_builder, self.overrides.as_deref(), now) {
+            limit_keys.push((rate_limiter, limit_key));
+        }
+        if let Some(warm_up) = &self.warm_up {
+            limit_keys.iter_mut().for_each(|(_, limit_key)| warm_up.scale(&limit_key.key, &mut limit_key.bucket));
+        }
+        for (rate_limiter, limit_key) in limit_keys.iter_mut() {
+            match (rate_limiter.scope, &self.partitioner) {
+                // Appended in place rather than formatting the key again
+                (LimitScope::Instance, _) => {
+                    let _ = write!(limit_key.key, ":instance:{}", self.instance_id);
+                },
+                (LimitScope::PerClient, partitioner) => {
+                    let _ = write!(limit_key.key, ":client:{}", addr.ip());
+                    if let Some(partitioner) = partitioner {
+                        partitioner.partition(limit_key);
+                    }
+                },
+                (LimitScope::Global, Some(partitioner)) => partitioner.partition(limit_key),
+                (LimitScope::Global, None) => {},
+            }
+        }
+        if self.consume == ConsumeMode::First {
+            limit_keys.truncate(1);
+        }
+        limit_keys
+    }
+
+    // Spike arrests are charged before the buckets, so a request they stop doesn't use up the budget of the client.
+    // They fail open on store errors, the buckets still apply then.
+    async fn arrest_spikes<'a>(&'a self, limit_keys: &[(&'a Arc<RateLimiter>, LimitKey)], addr: SocketAddr) -> Option<(&'a RateLimiter, LimitForRequest, Bucket)> {
+        let spike_keys = limit_keys.iter()
+            .filter_map(|(rate_limiter, limit_key)| rate_limiter.spike_arrest.as_ref().map(|bucket| (*rate_limiter, format!("{}:spike", limit_key.key), bucket)))
+            .collect::<Vec<_>>();
+        if spike_keys.is_empty() {
+            return None;
+        }
+
+        let token_requests = spike_keys.iter()
+            .map(|(_, key, bucket)| TokenRequest::new(key, bucket, 1))
+            .collect::<Vec<_>>();
+        let counts = self.store.consume_many(&token_requests).await;
+        let ((rate_limiter, key, bucket), count) = spike_keys.into_iter()
+            .zip(counts)
+            .find_map(|(spike_key, count)| count.ok().filter(|count| *count < 0).map(|count| (spike_key, count)))?;
+
+        metrics::increment_counter("rate_limiter_spike_arrests_total", &[("limiter", &rate_limiter.name)]);
+        if rate_limiter.log_decisions.should_log(true) {
+            println!("Rate limit decision: limiter={} key={} client={} remaining=0 outcome=denied spike=true", rate_limiter.name, key, addr.ip());
+        }
+        Some((Arc::as_ref(rate_limiter), LimitForRequest::new(bucket.capacity(), count, true), bucket.clone()))
+    }
+
+    // Requests of a lower class are denied without being charged once they would take tokens kept for the other classes,
+    // so they can't drain them. Returns the tokens that would be left after the request, None if it may be charged.
+    async fn deny_over_share(&self, limit_keys: &[(&Arc<RateLimiter>, LimitKey)], priority: Priority) -> Option<Vec<Result<i32, RateLimiterError>>> {
+        let buckets = limit_keys.iter()
+            .map(|(_, limit_key)| (limit_key.key.as_str(), &limit_key.bucket))
+            .collect::<Vec<_>>();
+        let counts = self.store.peek_many(&buckets).await;
+
+        let is_over_share = counts.iter()
+            .zip(limit_keys)
+            .any(|(count, (_, limit_key))| count.as_ref().is_ok_and(|count| count - 1 < priority.reserved(limit_key.bucket.capacity()) as i32));
+        is_over_share.then(|| counts.into_iter().map(|count| count.map(|count| count - 1)).collect())
+    }
+
+    // Reads every bucket without charging it, then charges only the one that would be the most restrictive after the
+    // request. The others report the tokens they have left.
+    async fn consume_most_restrictive(&self, limit_keys: &[(&Arc<RateLimiter>, LimitKey)]) -> Vec<Result<i32, RateLimiterError>> {
+        let buckets = limit_keys.iter()
+            .map(|(_, limit_key)| (limit_key.key.as_str(), &limit_key.bucket))
+            .collect::<Vec<_>>();
+        let mut counts = self.store.peek_many(&buckets).await;
+
+        let charged = counts.iter()
+            .zip(limit_keys)
+            .enumerate()
+            .filter_map(|(index, (count, (_, limit_key)))| count.as_ref().ok()
+                .map(|count| (index, LimitForRequest::new(limit_key.bucket.capacity(), count - 1, count - 1 < 0))))
+            .min_by(|(_, limit), (_, other)| self.most_restrictive.compare(limit, other))
+            .map(|(index, _)| index);
+        if let Some(index) = charged {
+            let limit_key = &limit_keys[index].1;
+            counts[index] = self.store.consume(&limit_key.key, &limit_key.bucket, 1).await;
+        }
+        counts
+    }
+
+    // Charges one request to every quota the client is subject to and returns the one closest to running out.
+    // Quotas fail open, a store error only skips them.
+    pub async fn check_quotas(&self, request: &SafeRequest) -> Option<QuotaUsage> {
+        let now = self.clock.now_secs();
+        let quota_keys = self.quotas.iter()
+            .filter_map(|quota| quota.client_value(request).map(|value| (quota, quota.get_key(&value, &self.key_builder, now))))
+            .collect::<Vec<_>>();
+        if quota_keys.is_empty() {
+            return None;
+        }
+
+        let token_requests = quota_keys.iter()
+            .map(|(_, (limit_key, _))| TokenRequest::new(&limit_key.key, &limit_key.bucket, 1))
+            .collect::<Vec<_>>();
+        let counts = self.store.consume_many(&token_requests).await;
+
+        let mut lowest_usage: Option<QuotaUsage> = None;
+        for ((quota, (limit_key, resets_in)), count) in quota_keys.iter().zip(counts) {
+            let remaining = match count {
+                Ok(remaining) => remaining,
+                Err(e) => {
+                    println!("Store error in quota {}, allowing the request: {}", quota.name, e);
+                    metrics::increment_counter("rate_limiter_store_errors_total", &[("limiter", &quota.name), ("action", "allow")]);
+                    continue;
+                },
+            };
+
+            let usage = QuotaUsage { limit: limit_key.bucket.tokens_count, remaining, resets_in: *resets_in };
+            if lowest_usage.as_ref().is_none_or(|lowest| usage.remaining < lowest.remaining) {
+                lowest_usage = Some(usage);
+            }
+        }
+
+        lowest_usage
+    }
+
+    // Adds the resource of the request to the set of its client for every distinct limit, and returns the first limit
+    // the client went over with the seconds until its window ends. Like quotas, distinct limits fail open.
+    pub async fn check_distinct(&self, request: &SafeRequest, addr: SocketAddr) -> Option<(&DistinctLimit, u32)> {
+        for distinct_limit in &self.distinct_limits {
+            let Some((client, resource)) = distinct_limit.values(request, addr) else {
+                continue;
+            };
+            let (key, resets_in) = distinct_limit.get_key(&client, &self.key_builder, self.clock.now_secs());
+            match self.store.count_distinct(&key, &resource, resets_in).await {
+                Ok(count) if count > distinct_limit.max_distinct => {
+                    metrics::increment_counter("rate_limiter_distinct_exceeded_total", &[("limit", &distinct_limit.name)]);
+                    if self.log_decisions.should_log(true) {
+                        println!("Distinct limit decision: limit={} client={} distinct={} outcome=denied", distinct_limit.name, client, count);
+                    }
+                    return Some((distinct_limit, resets_in));
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    println!("Store error in distinct limit {}, allowing the request: {}", distinct_limit.name, e);
+                    metrics::increment_counter("rate_limiter_store_errors_total", &[("limiter", &distinct_limit.name), ("action", "allow")]);
+                },
+            }
+        }
+        None
+    }
+
+    // Anomalies flagged from now on, None without anomaly_detection
+    pub fn subscribe_anomalies(&self) -> Option<broadcast::Receiver<Anomaly>> {
+        self.anomalies.as_ref().map(|anomalies| anomalies.subscribe())
+    }
+
+    // Distinct clients of the configured routes in the current window, empty without unique_clients
+    pub fn unique_clients(&self) -> Vec<UniqueClientsStatus> {
+        self.unique_clients.as_ref().map(|unique_clients| unique_clients.statuses()).unwrap_or_default()
+    }
+
+    // Budget left in every quota the client is subject to, without charging it. Quotas the store can't be read for are left out.
+    pub async fn peek_quotas(&self, request: &SafeRequest) -> Vec<QuotaStatus> {
+        let mut statuses = Vec::new();
+        for quota in &self.quotas {
+            let Some(value) = quota.client_value(request) else {
+                continue;
+            };
+            let (limit_key, resets_in) = quota.get_key(&value, &self.key_builder, self.clock.now_secs());
+            match self.store.peek(&limit_key.key, &limit_key.bucket).await {
+                Ok(remaining) => statuses.push(QuotaStatus {
+                    quota: quota.name.clone(),
+                    limit: limit_key.bucket.tokens_count,
+                    remaining: remaining.max(0) as u32,
+                    resets_in,
+                }),
+                Err(e) => println!("Store error in quota {}, leaving it out of the peek: {}", quota.name, e),
+            }
+        }
+        statuses
+    }
+
+    pub fn challenge(&self) -> Option<&Challenge> {
+        self.challenge.as_deref()
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_deref()
+    }
+
+    pub fn overrides(&self) -> Option<&Overrides> {
+        self.overrides.as_deref()
+    }
+
+    // Buckets created after the rules changed ramp up again, like after a restart
+    pub fn restart_warm_up(&self) {
+        if let Some(warm_up) = &self.warm_up {
+            warm_up.restart();
+        }
+    }
+
+    // Counters of the store under the key prefix with the bans and overrides, for `/snapshot` of the admin server
+    pub async fn snapshot(&self) -> Result<Snapshot, RateLimiterError> {
+        Ok(Snapshot {
+            exported_at: self.clock.now_secs(),
+            counters: self.store.export(self.key_builder.prefix()).await?,
+            bans: self.bans.as_ref().map(|bans| bans.export()).unwrap_or_default(),
+            overrides: self.overrides.as_ref().map(|overrides| overrides.list()).unwrap_or_default(),
         })
     }
+
+    // Writes a snapshot back, replacing the current counters. Counters outside of the key prefix, and bans and overrides
+    // this limiter doesn't have, e.g. of limiters that no longer exist, are skipped.
+    pub async fn restore(&self, snapshot: &Snapshot) -> Result<RestoredSnapshot, RateLimiterError> {
+        let prefix = format!("{}:", self.key_builder.prefix());
+        let counters = snapshot.counters.iter().filter(|counter| counter.key.starts_with(&prefix)).cloned().collect::<Vec<_>>();
+        let mut restored = RestoredSnapshot::default();
+        if !counters.is_empty() {
+            restored.counters = self.store.import(&counters).await?;
+        }
+        if let Some(bans) = &self.bans {
+            restored.bans = bans.import(&snapshot.bans);
+        }
+        if let Some(overrides) = &self.overrides {
+            let now = self.clock.now_secs();
+            for bucket_override in snapshot.overrides.iter().filter(|bucket_override| bucket_override.expires_at > now) {
+                if let Some(key) = self.override_key(&bucket_override.limiter, &bucket_override.value) {
+                    overrides.set(key, bucket_override.clone()).await?;
+                    restored.overrides += 1;
+                }
+            }
+            if restored.overrides > 0 {
+                self.restart_warm_up();
+            }
+        }
+
+        println!("Restored a snapshot of {}: counters={} bans={} overrides={}", snapshot.exported_at, restored.counters, restored.bans, restored.overrides);
+        Ok(restored)
+    }
+
+    // Store key of the bucket `value` gets from the limiter named `limiter`, None if there is no such limiter
+    pub fn override_key(&self, limiter: &str, value: &str) -> Option<String> {
+        self.user_rate_limiters.iter()
+            .chain(self.request_rate_limiters.iter())
+            .find(|rate_limiter| rate_limiter.name == limiter)
+            .map(|rate_limiter| rate_limiter.value_key(value, &self.key_builder))
+    }
+
+    // Reads the usage of a client without charging it, None if there is no quota with this name
+    pub async fn quota_usage(&self, name: &str, value: &str) -> Option<Result<QuotaUsage, RateLimiterError>> {
+        let quota = self.quotas.iter().find(|quota| quota.name == name)?;
+        let (limit_key, resets_in) = quota.get_key(value, &self.key_builder, self.clock.now_secs());
+
+        Some(self.store.peek(&limit_key.key, &limit_key.bucket).await.map(|remaining| QuotaUsage {
+            limit: limit_key.bucket.tokens_count,
+            remaining,
+            resets_in,
+        }))
+    }
+
+    // Returns the remaining tokens to use instead of the failed store result, None skips the limiter
+    async fn handle_store_error(&self, rate_limiter: &RateLimiter, limit_key: &LimitKey, error: RateLimiterError) -> Option<i32> {
+        println!("Store error in limiter {}, applying {}: {}", rate_limiter.name, rate_limiter.on_store_error.as_str(), error);
+        metrics::increment_counter("rate_limiter_store_errors_total", &[
+            ("limiter", &rate_limiter.name),
+            ("action", rate_limiter.on_store_error.as_str()),
+        ]);
+
+        match rate_limiter.on_store_error {
+            OnStoreError::Allow => None,
+            // Past what the bucket can borrow, so the request is denied whatever its debt allowance
+            OnStoreError::Deny => Some(-(limit_key.bucket.borrow as i32) - 1),
+            OnStoreError::FallbackMemory => match &self.fallback {
+                Some(fallback) => fallback.consume(&limit_key.key, &limit_key.bucket).await.ok(),
+                None => None,
+            },
+        }
+    }
+}
+
+fn insert_quota_headers(headers: &mut HeaderMap, usage: &QuotaUsage) {
+    headers.insert(QUOTA_LIMIT, HeaderValue::from(usage.limit));
+    headers.insert(QUOTA_REMAINING, HeaderValue::from(usage.remaining.max(0)));
+    headers.insert(QUOTA_RESET, HeaderValue::from(usage.resets_in));
+}
+
+// Writes the headers prepared for a response in one go, replacing the ones of the same name
+fn with_headers(mut response: Response<Body>, headers: HeaderMap) -> Response<Body> {
+    if !headers.is_empty() {
+        response.headers_mut().extend(headers);
+    }
+    response
 }
 
 
+// What happens to requests no limiter matches
 #[derive(Clone, Debug)]
-pub struct Bucket {
-    pub tokens_count: u32,
-    pub add_tokens_every: u32,
+enum DefaultAction {
+    Allow,
+    Deny,
+    // Charged to this limiter, which is left out for the requests other limiters match
+    Limiter(Arc<RateLimiter>),
 }
 
-impl Bucket {
-    pub fn new(tokens_count: u32, add_tokens_every: u32) -> Self {
-        Self {
-            tokens_count,
-            add_tokens_every,
+impl DefaultAction {
+    fn new(action: Option<&str>, rate_limiters: &[Arc<RateLimiter>]) -> Result<Self, RateLimiterError> {
+        match action {
+            None | Some("allow") => Ok(Self::Allow),
+            Some("deny") => Ok(Self::Deny),
+            Some(action) => {
+                let Some(name) = action.strip_prefix("bucket:") else {
+                    return Err(RateLimiterError::config(format!("default_action must be allow, deny or bucket:<limiter name>, not {:?}", action)));
+                };
+                rate_limiters.iter()
+                    .find(|rate_limiter| rate_limiter.name == name)
+                    .map(|rate_limiter| Self::Limiter(rate_limiter.clone()))
+                    .ok_or_else(|| RateLimiterError::config(format!("default_action names limiter {}, which doesn't exist", name)))
+            },
         }
     }
+
+    fn is_limiter(&self, rate_limiter: &Arc<RateLimiter>) -> bool {
+        matches!(self, Self::Limiter(default_limiter) if Arc::ptr_eq(default_limiter, rate_limiter))
+    }
 }
 
-impl From<&BucketSettings> for Bucket {
-    fn from(settings: &BucketSettings) -> Self {
+
+#[derive(Clone, Debug)]
+struct LimiterBuckets {
+    global_bucket: Option<Bucket>,
+    buckets_per_value: Option<BucketsPerValue>,
+}
+
+impl LimiterBuckets {
+    fn new(global_bucket: Option<&BucketSettings>, buckets_per_value: Option<&[BuckerPerValue]>) -> Self {
         Self {
-            tokens_count: settings.tokens_count,
-            add_tokens_every: settings.add_tokens_every,
+            global_bucket: global_bucket.map(Bucket::from),
+            buckets_per_value: buckets_per_value.map(
+                |buckets| BucketsPerValue::new(buckets.iter().map(
+                    |b| (b.value.clone(), Bucket::new(b.tokens_count, b.add_tokens_every).with_burst(b.burst).with_borrow(b.borrow))
+                ))),
         }
-    }   
+    }
+
+    fn is_empty(&self) -> bool {
+        self.global_bucket.is_none() && self.buckets_per_value.is_none()
+    }
+
+    // Values of url limiters are compared to the bucket values of requests, given the same way, e.g. `/api/export?format=csv`
+    fn canonicalize_urls(&mut self, strategy: &UrlRateLimiterStrategy, limiter: &str) -> Result<(), RateLimiterError> {
+        let Some(buckets) = self.buckets_per_value.take() else {
+            return Ok(());
+        };
+
+        let mut normalized = Vec::new();
+        let mut urls = HashSet::new();
+        for (value, bucket) in buckets.iter() {
+            let url = match value.split_once('?') {
+                Some((path, query)) => strategy.bucket_value(path, Some(query)),
+                None => strategy.bucket_value(value, None),
+            }.into_owned();
+            if !urls.insert(url.clone()) {
+                return Err(RateLimiterError::config(format!(
+                    "Several buckets_per_value of limiter {} are the same url {} once normalized", limiter, url,
+                )));
+            }
+            normalized.push((url, bucket.clone()));
+        }
+        self.buckets_per_value = Some(BucketsPerValue::new(normalized));
+        Ok(())
+    }
+
+    // A refilling bucket needs a rate and room for at least one token
+    fn validate(&self, limiter: &str) -> Result<(), RateLimiterError> {
+        let buckets = self.global_bucket.iter().chain(self.buckets_per_value.iter().flat_map(|buckets| buckets.iter().map(|(_, bucket)| bucket)));
+        for bucket in buckets {
+            if bucket.tokens_count == 0 || bucket.add_tokens_every == 0 || bucket.burst == Some(0) {
+                return Err(RateLimiterError::config(format!(
+                    "Buckets of limiter {} need tokens_count, add_tokens_every and burst greater than 0", limiter,
+                )));
+            }
+        }
+        Ok(())
+    }
 }
-#[derive(Clone, Debug)]
+
+// Spikes are limited with a GCRA bucket of `requests` tokens, one coming back every `window_ms / requests`.
+// The rate is reduced to whole seconds, e.g. 5 requests per 100ms is 50 tokens every second with a burst of 5.
+fn spike_arrest_bucket(limiter: &str, settings: SpikeArrestSettings) -> Result<Bucket, RateLimiterError> {
+    if settings.requests == 0 || !(1..=60_000).contains(&settings.window_ms) {
+        return Err(RateLimiterError::config(format!(
+            "Limiter {}: spike_arrest needs requests greater than 0 and window_ms from 1 to 60000", limiter,
+        )));
+    }
+
+    let tokens = settings.requests as u64 * 1000;
+    let (mut a, mut b) = (tokens, settings.window_ms as u64);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let tokens_count = u32::try_from(tokens / a)
+        .map_err(|_| RateLimiterError::config(format!("Limiter {}: spike_arrest.requests is too large", limiter)))?;
+    Ok(Bucket::new(tokens_count, settings.window_ms / a as u32).with_burst(Some(settings.requests)))
+}
+
+// Checks the buckets of a limiter before they are built, so errors point to the field, e.g. `limiter[1].buckets_per_value[0].tokens_count`.
+// An empty bucket would deny every request, a bucket refilled every 0 seconds would never expire.
+fn validate_buckets(limiter: &str, path: &str, global_bucket: Option<&BucketSettings>, buckets_per_value: Option<&[BuckerPerValue]>) -> Result<(), RateLimiterError> {
+    let buckets_per_value = buckets_per_value.unwrap_or_default();
+    let buckets = global_bucket
+        .map(|bucket| (format!("{}.global_bucket", path), bucket.tokens_count, bucket.add_tokens_every, bucket.burst))
+        .into_iter()
+        .chain(buckets_per_value.iter().enumerate().map(|(index, bucket)| (
+            format!("{}.buckets_per_value[{}]", path, index), bucket.tokens_count, bucket.add_tokens_every, bucket.burst,
+        )));
+    for (path, tokens_count, add_tokens_every, burst) in buckets {
+        validate_bucket_fields(limiter, &path, tokens_count, add_tokens_every, burst)?;
+    }
+
+    let mut values = HashSet::new();
+    for (index, bucket) in buckets_per_value.iter().enumerate() {
+        if !values.insert(bucket.value.as_str()) {
+            return Err(RateLimiterError::config(format!(
+                "Limiter {}: {}.buckets_per_value[{}].value {:?} is defined more than once", limiter, path, index, bucket.value,
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_bucket_fields(limiter: &str, path: &str, tokens_count: u32, add_tokens_every: u32, burst: Option<u32>) -> Result<(), RateLimiterError> {
+    let zero_field = match (tokens_count, add_tokens_every, burst) {
+        (0, _, _) => Some("tokens_count"),
+        (_, 0, _) => Some("add_tokens_every"),
+        (_, _, Some(0)) => Some("burst"),
+        _ => None,
+    };
+    match zero_field {
+        Some(field) => Err(RateLimiterError::config(format!("Limiter {}: {}.{} must be greater than 0", limiter, path, field))),
+        None => Ok(()),
+    }
+}
+
+// A default bucket is shared by the requests left without a bucket, which a global bucket leaves none of with the ip and url strategies
+fn validate_default_bucket(limiter: &str, path: &str, settings: &LimiterSettings) -> Result<(), RateLimiterError> {
+    let Some(bucket) = &settings.default_bucket else {
+        return Ok(());
+    };
+    validate_bucket_fields(limiter, &format!("{}.default_bucket", path), bucket.tokens_count, bucket.add_tokens_every, bucket.burst)?;
+    if settings.global_bucket.is_some() && matches!(settings.strategy, PossibleStrategies::IP | PossibleStrategies::URL) {
+        return Err(RateLimiterError::config(format!(
+            "Limiter {}: {}.default_bucket never applies, as global_bucket gives every {} a bucket", limiter, path, settings.strategy.as_str(),
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
 struct RateLimiter {
+    name: String,
     strategy: Strategy,
-    redis_pool: Pool,
-    global_bucket: Option<Bucket>,
-    buckets_per_value: Option<HashMap<String, Bucket>>,
+    on_store_error: OnStoreError,
+    scope: LimitScope,
+    log_decisions: DecisionLogging,
+    cardinality: Option<CardinalityGuard>,
+    spike_arrest: Option<Bucket>,
+    buckets: LimiterBuckets,
+    default_bucket: Option<Bucket>,
+    schedules: Vec<(Schedule, LimiterBuckets)>,
 }
 
 
 impl RateLimiter {
-    pub fn new(strategy: Strategy, redis_pool: Pool, global_bucket: Option<Bucket>, buckets_per_value: Option<HashMap<String, Bucket>>) -> Self {
-        Self {
+    pub fn new(name: String, settings: &LimiterSettings, log_decisions: DecisionLogging, mut buckets: LimiterBuckets, mut schedules: Vec<(Schedule, LimiterBuckets)>, clock: &Arc<dyn Clock>) -> Result<Self, RateLimiterError> {
+        let strategy = Strategy::for_limiter(&name, settings)?;
+        if let Strategy::Url(strategy) = &strategy {
+            buckets.canonicalize_urls(strategy, &name)?;
+            for (_, buckets) in schedules.iter_mut() {
+                buckets.canonicalize_urls(strategy, &name)?;
+            }
+        }
+        let spike_arrest = settings.spike_arrest.map(|spike_arrest| spike_arrest_bucket(&name, spike_arrest)).transpose()?;
+
+        Ok(Self {
             strategy,
-            redis_pool,
-            global_bucket,
-            buckets_per_value,
+            name,
+            on_store_error: settings.on_store_error,
+            scope: settings.scope,
+            log_decisions,
+            cardinality: settings.cardinality.as_ref().map(|settings| CardinalityGuard::new(settings, clock.clone())),
+            spike_arrest,
+            buckets,
+            default_bucket: settings.default_bucket.as_ref().map(Bucket::from),
+            schedules,
+        })
+    }
+
+    // Store key of the bucket of `value`, given the way `rate_limiter inspect` takes values
+    fn value_key(&self, value: &str, key_builder: &KeyBuilder) -> String {
+        match &self.strategy {
+            Strategy::IP(strategy) => key_builder.build("ip", &value.parse::<IpAddr>().map_or_else(|_| value.to_string(), |ip| strategy.network(ip))),
+            Strategy::Url(strategy) => {
+                let (path, query) = value.split_once('?').map_or((value, None), |(path, query)| (path, Some(query)));
+                key_builder.build("url", &strategy.bucket_value(path, query))
+            },
+            Strategy::Header(_) => key_builder.build("header", &format!("{}:{}", self.name, value)),
+            Strategy::Query(_) => key_builder.build("query", value),
+            Strategy::Body(_) => key_builder.build("json", value),
+            Strategy::Identity(_) => key_builder.build("identity", value),
+            Strategy::Operation(_) => key_builder.build("operation", value),
         }
     }
-    
-    pub async fn check(&self, request: &SafeRequest, addr: SocketAddr) -> Option<LimitForRequest> {
-        let redis_conn = match self.redis_pool.get().await {
-            Ok(redis_conn) => redis_conn,
-            Err(_) => return None,
+
+    // Value of X-RateLimit-Policy, e.g. `login;w=60;scope=global`
+    fn policy(&self, window: u32) -> String {
+        format!("{};w={};scope={}", self.name, window, self.scope.as_str())
+    }
+
+    // Buckets of the first active schedule, falling back to the limiter's ones for what the schedule doesn't define
+    fn current_buckets(&self, now: u64) -> (Option<&Bucket>, Option<&BucketsPerValue>) {
+        match self.schedules.iter().find(|(schedule, _)| schedule.is_active(now)) {
+            Some((_, buckets)) => (
+                buckets.global_bucket.as_ref().or(self.buckets.global_bucket.as_ref()),
+                buckets.buckets_per_value.as_ref().or(self.buckets.buckets_per_value.as_ref()),
+            ),
+            None => (self.buckets.global_bucket.as_ref(), self.buckets.buckets_per_value.as_ref()),
+        }
+    }
+
+    // `now` is in seconds since the unix epoch and picks the active schedule
+    pub fn get_key(&self, request: &SafeRequest, addr: SocketAddr, key_builder: &KeyBuilder, overrides: Option<&Overrides>, now: u64) -> Option<LimitKey> {
+        let (global_bucket, buckets_per_value) = self.current_buckets(now);
+        let mut limit_key = match self.strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder) {
+            Some(limit_key) => limit_key,
+            // Requests the limiter has no bucket of their own for share its default bucket
+            None => LimitKey::new(key_builder.build("default", &self.name), self.default_bucket.clone()?),
         };
-        self.strategy.check_limit(redis_conn, self.global_bucket.as_ref(), self.buckets_per_value.as_ref(), request, addr).await
+        if let Some(overrides) = overrides {
+            overrides.apply(&mut limit_key);
+        }
+
+        // Keys over the cardinality cap share one overflow bucket of the limiter
+        if let Some(cardinality) = &self.cardinality {
+            let (is_admitted, is_first_overflow) = cardinality.admit(&limit_key.key);
+            if !is_admitted {
+                if is_first_overflow {
+                    println!("Limiter {} reached its key cardinality cap, new keys share the overflow bucket", self.name);
+                }
+                metrics::increment_counter("rate_limiter_cardinality_overflow_total", &[("limiter", &self.name)]);
+                limit_key.key = key_builder.build("overflow", &self.name);
+                if let Some(global_bucket) = global_bucket {
+                    limit_key.bucket = global_bucket.clone();
+                }
+            }
+        }
 
+        Some(limit_key)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::builder::RateLimiterBuilder;
+    use crate::overrides::BucketOverride;
+    use crate::settings::{DenyCacheSettings, OverridesSettings};
+    use crate::testing::{MockClock, MockStore, TestRequest};
+    use super::*;
 
-pub struct SafeRequest {
-    pub parts: Parts,
-    pub body: Bytes,
-}
+    #[tokio::test]
+    async fn store_errors_deny_buckets_that_can_borrow() {
+        let mut settings = RateLimiterBuilder::new()
+            .limiter(PossibleStrategies::IP)
+            .on_store_error(OnStoreError::Deny)
+            .log_decisions(DecisionLogging::Off)
+            .global_bucket(3, "1m")
+            .into_settings()
+            .unwrap();
+        if let Some(bucket) = settings.limiters_settings[0].global_bucket.as_mut() {
+            bucket.borrow = 5;
+        }
+        let clock = MockClock::new();
+        let store = MockStore::new(clock.clone());
+        store.set_failing(true);
+        let manager = RateLimiterManager::with_clock(settings, Some(store), Arc::new(clock)).unwrap();
 
-impl SafeRequest {
-    pub fn new(parts: Parts, body: Bytes) -> Self {
-        Self {
-            parts,
-            body,
+        let request = TestRequest::get("/");
+        let addr = request.addr();
+        let limit = manager.check(&request.into_safe_request(), addr).await.unwrap();
+        assert!(limit.is_limit_exceeded);
+    }
+
+    #[tokio::test]
+    async fn denials_are_cached_until_the_window_of_the_store_ends() {
+        let mut settings = RateLimiterBuilder::new()
+            .limiter(PossibleStrategies::IP)
+            .log_decisions(DecisionLogging::Off)
+            .global_bucket(2, "1m")
+            .into_settings()
+            .unwrap();
+        settings.deny_cache = Some(DenyCacheSettings { max_ttl_ms: 600_000, max_keys: 100 });
+        let clock = MockClock::new();
+        let store = MockStore::new(clock.clone());
+        let manager = RateLimiterManager::with_clock(settings, Some(store.clone()), Arc::new(clock.clone())).unwrap();
+        let check = || async {
+            let request = TestRequest::get("/");
+            let addr = request.addr();
+            manager.check(&request.into_safe_request(), addr).await.unwrap().is_limit_exceeded
+        };
+
+        // The key exhausts 50ms before its window ends
+        assert!(!check().await);
+        clock.advance(Duration::from_millis(59_950));
+        assert!(!check().await);
+        assert!(check().await);
+        let calls = store.calls();
+        assert!(check().await);
+        assert_eq!(store.calls(), calls);
+
+        clock.advance(Duration::from_millis(50));
+        assert!(!check().await);
+    }
+
+    fn bucket(tokens_count: u32, add_tokens_every: u32, burst: Option<u32>) -> BucketSettings {
+        BucketSettings { tokens_count, add_tokens_every, burst, borrow: 0 }
+    }
+
+    fn bucket_per_value(value: &str, tokens_count: u32) -> BuckerPerValue {
+        BuckerPerValue { value: value.to_string(), tokens_count, add_tokens_every: 60, burst: None, borrow: 0 }
+    }
+
+    fn config_message(result: Result<(), RateLimiterError>) -> String {
+        match result {
+            Err(RateLimiterError::Config(message)) => message,
+            result => panic!("expected a configuration error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_bucket_fields_of_zero() {
+        assert_eq!(
+            config_message(validate_buckets("api", "limiter[0]", Some(&bucket(0, 60, None)), None)),
+            "Limiter api: limiter[0].global_bucket.tokens_count must be greater than 0",
+        );
+        assert_eq!(
+            config_message(validate_buckets("api", "limiter[0]", Some(&bucket(10, 0, None)), None)),
+            "Limiter api: limiter[0].global_bucket.add_tokens_every must be greater than 0",
+        );
+        assert_eq!(
+            config_message(validate_buckets("api", "limiter[0]", Some(&bucket(10, 60, Some(0))), None)),
+            "Limiter api: limiter[0].global_bucket.burst must be greater than 0",
+        );
+        assert_eq!(
+            config_message(validate_buckets("api", "limiter[2]", None, Some(&[bucket_per_value("a", 10), bucket_per_value("b", 0)]))),
+            "Limiter api: limiter[2].buckets_per_value[1].tokens_count must be greater than 0",
+        );
+        assert!(validate_buckets("api", "limiter[0]", Some(&bucket(10, 60, Some(5))), Some(&[bucket_per_value("a", 10)])).is_ok());
+    }
+
+    #[test]
+    fn rejects_values_defined_more_than_once() {
+        let buckets = [bucket_per_value("a", 10), bucket_per_value("b", 10), bucket_per_value("a", 20)];
+        assert_eq!(
+            config_message(validate_buckets("api", "limiter[0]", None, Some(&buckets))),
+            "Limiter api: limiter[0].buckets_per_value[2].value \"a\" is defined more than once",
+        );
+    }
+
+    #[test]
+    fn rejects_default_buckets_that_never_apply_or_are_empty() {
+        let settings = |strategy| RateLimiterBuilder::new()
+            .limiter(strategy)
+            .global_bucket(10, "1m")
+            .default_bucket(5, "1m")
+            .into_settings()
+            .unwrap()
+            .limiters_settings
+            .remove(0);
+
+        assert_eq!(
+            config_message(validate_default_bucket("ip-0", "limiter[0]", &settings(PossibleStrategies::IP))),
+            "Limiter ip-0: limiter[0].default_bucket never applies, as global_bucket gives every ip a bucket",
+        );
+        assert_eq!(
+            config_message(validate_default_bucket("url-0", "limiter[0]", &settings(PossibleStrategies::URL))),
+            "Limiter url-0: limiter[0].default_bucket never applies, as global_bucket gives every url a bucket",
+        );
+        assert!(validate_default_bucket("header-0", "limiter[0]", &settings(PossibleStrategies::Header)).is_ok());
+
+        let mut settings = settings(PossibleStrategies::Header);
+        settings.default_bucket = Some(bucket(5, 0, None));
+        assert_eq!(
+            config_message(validate_default_bucket("header-0", "limiter[0]", &settings)),
+            "Limiter header-0: limiter[0].default_bucket.add_tokens_every must be greater than 0",
+        );
+    }
+
+    #[test]
+    fn bucket_errors_name_the_limiter_and_its_position() {
+        let mut settings = RateLimiterBuilder::new()
+            .limiter(PossibleStrategies::IP)
+            .global_bucket(10, "1m")
+            .limiter(PossibleStrategies::Header)
+            .bucket_per_value("X-Api-Key", 10, "1m")
+            .into_settings()
+            .unwrap();
+        if let Some(buckets) = settings.limiters_settings[1].buckets_per_value.as_mut() {
+            buckets[0].burst = Some(0);
         }
+        let clock = MockClock::new();
+
+        let error = RateLimiterManager::with_clock(settings, Some(MockStore::new(clock.clone())), Arc::new(clock)).err().unwrap();
+        assert_eq!(error.to_string(), RateLimiterError::config("Limiter header-1: limiter[1].buckets_per_value[0].burst must be greater than 0").to_string());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn requests_without_a_bucket_share_the_default_bucket() {
+        let manager = RateLimiterBuilder::new()
+            .store(MockStore::new(MockClock::new()))
+            .limiter(PossibleStrategies::Header)
+            .log_decisions(DecisionLogging::Off)
+            .bucket_per_value("X-Api-Key", 5, "1h")
+            .global_bucket(3, "1h")
+            .default_bucket(2, "1h")
+            .build()
+            .unwrap();
+
+        // buckets_per_value first, then global_bucket for the fallback header, then the default bucket shared by every client
+        let clients = [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])];
+        for (request, limit, remaining) in [
+            (TestRequest::get("/").header("X-Api-Key", "abc"), 5, 4),
+            (TestRequest::get("/").header("Authorization", "abc"), 3, 2),
+            (TestRequest::get("/").ip(clients[0]), 2, 1),
+            (TestRequest::get("/").ip(clients[1]), 2, 0),
+        ] {
+            let addr = request.addr();
+            let limit_for_request = manager.check(&request.into_safe_request(), addr).await.unwrap();
+            assert_eq!((limit_for_request.total_limit, limit_for_request.requests_to_exceed_limit), (limit, remaining));
+        }
+
+        // A global bucket already gives every address a bucket
+        let both = RateLimiterBuilder::new()
+            .store(MockStore::new(MockClock::new()))
+            .limiter(PossibleStrategies::IP)
+            .global_bucket(3, "1h")
+            .default_bucket(2, "1h")
+            .build();
+        assert!(both.is_err_and(|e| e.to_string().contains("default_bucket never applies")));
+
+        let empty = RateLimiterBuilder::new()
+            .store(MockStore::new(MockClock::new()))
+            .limiter(PossibleStrategies::Header)
+            .bucket_per_value("X-Api-Key", 5, "1h")
+            .default_bucket(0, "1h")
+            .build();
+        assert!(empty.is_err_and(|e| e.to_string().contains("limiter[0].default_bucket.tokens_count must be greater than 0")));
+    }
+
+    #[tokio::test]
+    async fn snapshots_follow_the_clock_of_the_manager() {
+        let clock = MockClock::new();
+        let manager = RateLimiterBuilder::new()
+            .store(Arc::new(MemoryStore::with_clock(Arc::new(clock.clone()))))
+            .clock(Arc::new(clock.clone()))
+            .overrides(OverridesSettings { refresh_secs: 10 })
+            .limiter(PossibleStrategies::IP)
+            .name("per-ip")
+            .log_decisions(DecisionLogging::Off)
+            .global_bucket(3, "1m")
+            .build()
+            .unwrap();
+        let request = TestRequest::get("/");
+        let addr = request.addr();
+        manager.check(&request.into_safe_request(), addr).await.unwrap();
+
+        let mut snapshot = manager.snapshot().await.unwrap();
+        assert_eq!(snapshot.exported_at, clock.now_secs());
+        assert_eq!(snapshot.counters.len(), 1);
+        assert_eq!(snapshot.counters[0].expires_at_ms, Some(clock.now_us() / 1000 + 60_000));
+
+        // Overrides expired on the clock of the manager are skipped, whatever the time of the system
+        let bucket_override = |expires_at| BucketOverride {
+            limiter: "per-ip".to_string(),
+            value: "10.0.0.1".to_string(),
+            tokens_count: None,
+            add_tokens_every: None,
+            burst: None,
+            multiplier: Some(2),
+            expires_at,
+        };
+        snapshot.overrides = vec![bucket_override(clock.now_secs()), bucket_override(u32::MAX as u64)];
+        let restored = manager.restore(&snapshot).await.unwrap();
+        assert_eq!((restored.counters, restored.overrides), (1, 1));
+        assert_eq!(manager.overrides().unwrap().list(), vec![bucket_override(u32::MAX as u64)]);
+    }
+
+    // Remaining tokens of every limiter after 3 requests of the same client with an API key
+    async fn remaining_after_three_requests(consume: ConsumeMode) -> Vec<(String, i32)> {
+        let manager = RateLimiterBuilder::new()
+            .store(MockStore::new(MockClock::new()))
+            .consume(consume)
+            .limiter(PossibleStrategies::IP)
+            .log_decisions(DecisionLogging::Off)
+            .global_bucket(10, "60s")
+            .limiter(PossibleStrategies::Header)
+            .log_decisions(DecisionLogging::Off)
+            .bucket_per_value("X-Api-Key", 3, "60s")
+            .build()
+            .unwrap();
+
+        let mut limits = Vec::new();
+        for _ in 0..3 {
+            let request = TestRequest::get("/").header("X-Api-Key", "abc");
+            let addr = request.addr();
+            limits = manager.check_each(&request.into_safe_request(), addr).await;
+        }
+        limits.into_iter().map(|(name, limit)| (name, limit.requests_to_exceed_limit)).collect()
+    }
+
+    #[tokio::test]
+    async fn only_the_charged_limiters_lose_tokens() {
+        assert_eq!(remaining_after_three_requests(ConsumeMode::All).await, [("ip-0".to_string(), 7), ("header-1".to_string(), 0)]);
+        assert_eq!(remaining_after_three_requests(ConsumeMode::First).await, [("ip-0".to_string(), 7)]);
+        assert_eq!(remaining_after_three_requests(ConsumeMode::MostRestrictive).await, [("ip-0".to_string(), 10), ("header-1".to_string(), 0)]);
+    }
+}
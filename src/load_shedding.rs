@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::priority::Priority;
+use crate::settings::LoadSheddingSettings;
+
+
+// Counts the requests waiting for each upstream and sheds new ones above the high-water mark, whoever the client is.
+// Requests of lower priority classes are shed first, once they reach their share of the high-water mark.
+// A request holds its slot until the upstream answered with the response headers.
+#[derive(Debug)]
+pub struct LoadShedder {
+    max_in_flight: usize,
+    retry_after_secs: u64,
+    in_flight: DashMap<String, Arc<AtomicUsize>>,
+}
+
+impl LoadShedder {
+    pub fn new(settings: &LoadSheddingSettings) -> Result<Self, RateLimiterError> {
+        if settings.max_in_flight == 0 {
+            return Err(RateLimiterError::config("load_shedding.max_in_flight must be greater than 0"));
+        }
+
+        Ok(Self {
+            max_in_flight: settings.max_in_flight,
+            retry_after_secs: settings.retry_after_secs,
+            in_flight: DashMap::new(),
+        })
+    }
+
+    // Takes a slot of the upstream, None once the share of the slots the priority may use is taken.
+    // The slot is given back when the guard is dropped.
+    pub fn acquire(&self, upstream: &str, priority: Priority) -> Option<InFlight> {
+        let max_in_flight = (self.max_in_flight - priority.reserved(self.max_in_flight as u32) as usize).max(1);
+        let in_flight = match self.in_flight.get(upstream) {
+            Some(in_flight) => in_flight.clone(),
+            None => self.in_flight.entry(upstream.to_string()).or_default().clone(),
+        };
+
+        in_flight.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < max_in_flight).then_some(count + 1))
+            .ok()
+            .map(|_| InFlight(in_flight))
+    }
+
+    pub fn shed(&self, upstream: &str) -> Response {
+        metrics::increment_counter("rate_limiter_shed_total", &[("upstream", upstream)]);
+        (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, self.retry_after_secs.to_string())], "Upstream overloaded").into_response()
+    }
+}
+
+
+#[derive(Debug)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use axum::async_trait;
+use dashmap::DashMap;
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::strategy::Bucket;
+use crate::settings::LocalCacheSettings;
+use crate::store::{LimitStore, StoredCounter, TokenRequest};
+
+const HOT_KEY_WINDOW: Duration = Duration::from_secs(1);
+
+
+#[derive(Debug)]
+struct LocalBudget {
+    // Tokens claimed from the store that this instance didn't hand out yet
+    tokens: i32,
+    // Remaining tokens reported by the store on the last claim
+    store_remaining: i32,
+    claimed_at_us: u64,
+    window_started_at_us: u64,
+    window_hits: u32,
+}
+
+impl LocalBudget {
+    fn new(now_us: u64) -> Self {
+        Self {
+            tokens: 0,
+            store_remaining: 0,
+            claimed_at_us: now_us,
+            window_started_at_us: now_us,
+            window_hits: 0,
+        }
+    }
+}
+
+
+// Serves hot keys from tokens claimed from the wrapped store in batches, so most of their requests don't need a round trip.
+// Every instance can hold at most `batch_size - 1` unused tokens of a key for at most `max_staleness_ms`, this is the accuracy bound:
+// unused tokens are dropped, so the limit can be hit earlier than configured, but never later.
+#[derive(Debug)]
+pub struct LocalCacheStore {
+    store: Arc<dyn LimitStore>,
+    budgets: Arc<DashMap<String, LocalBudget>>,
+    clock: Arc<dyn Clock>,
+    hot_key_threshold: u32,
+    batch_size: u32,
+    max_staleness: Duration,
+}
+
+impl LocalCacheStore {
+    pub fn new(store: Arc<dyn LimitStore>, settings: &LocalCacheSettings, clock: Arc<dyn Clock>) -> Self {
+        let max_staleness = Duration::from_millis(settings.max_staleness_ms);
+        let budgets = Arc::new(DashMap::new());
+        tokio::spawn(remove_stale_budgets(Arc::downgrade(&budgets), clock.clone(), max_staleness.max(HOT_KEY_WINDOW)));
+
+        Self {
+            store,
+            budgets,
+            clock,
+            hot_key_threshold: settings.hot_key_threshold,
+            batch_size: settings.batch_size.max(1),
+            max_staleness,
+        }
+    }
+
+    // Takes a token from the local budget, returns how many tokens are left and whether a batch should be claimed
+    fn take_local_token(&self, key: &str, now_us: u64) -> (Option<i32>, bool) {
+        let mut budget = self.budgets.entry(key.to_string()).or_insert_with(|| LocalBudget::new(now_us));
+
+        if elapsed(budget.window_started_at_us, now_us) >= HOT_KEY_WINDOW {
+            budget.window_started_at_us = now_us;
+            budget.window_hits = 0;
+        }
+        budget.window_hits += 1;
+
+        if budget.tokens > 0 && elapsed(budget.claimed_at_us, now_us) < self.max_staleness {
+            budget.tokens -= 1;
+            return (Some(budget.store_remaining + budget.tokens), false);
+        }
+
+        (None, budget.window_hits >= self.hot_key_threshold)
+    }
+
+    // Hands out one of the `claim` tokens taken from the store and keeps the rest locally, returns how many tokens are left
+    fn keep_claimed_tokens(&self, key: &str, claim: u32, count: i32, now_us: u64) -> i32 {
+        // The store may have had fewer tokens than claimed, only those that really existed can be handed out
+        let claimed = (count + claim as i32).min(claim as i32);
+        if claimed <= 0 {
+            return count;
+        }
+
+        if let Some(mut budget) = self.budgets.get_mut(key) {
+            budget.tokens = claimed - 1;
+            budget.store_remaining = count.max(0);
+            budget.claimed_at_us = now_us;
+        }
+
+        count.max(0) + claimed - 1
+    }
+}
+
+#[async_trait]
+impl LimitStore for LocalCacheStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        self.consume_many(&[TokenRequest::new(key, bucket, tokens)]).await
+            .pop()
+            .unwrap_or_else(|| Err(RateLimiterError::Store("Store returned no result".to_string())))
+    }
+
+    async fn consume_many(&self, requests: &[TokenRequest<'_>]) -> Vec<Result<i32, RateLimiterError>> {
+        let now_us = self.clock.now_us();
+        let mut results: Vec<Option<Result<i32, RateLimiterError>>> = requests.iter().map(|_| None).collect();
+        let mut store_requests = Vec::new();
+        let mut store_indexes = Vec::new();
+
+        for (index, request) in requests.iter().enumerate() {
+            // Only single token requests are served from the local budget, and only from buckets that can't go into debt
+            if request.tokens != 1 || request.bucket.borrow > 0 {
+                store_requests.push(*request);
+                store_indexes.push(index);
+                continue;
+            }
+
+            let (remaining, is_hot) = self.take_local_token(request.key, now_us);
+            if let Some(remaining) = remaining {
+                results[index] = Some(Ok(remaining));
+                continue;
+            }
+
+            let claim = match is_hot {
+                true => self.batch_size.min(request.bucket.capacity().max(1)),
+                false => 1,
+            };
+            store_requests.push(TokenRequest::new(request.key, request.bucket, claim));
+            store_indexes.push(index);
+        }
+
+        if !store_requests.is_empty() {
+            let counts = self.store.consume_many(&store_requests).await;
+            for ((index, request), count) in store_indexes.into_iter().zip(store_requests).zip(counts) {
+                results[index] = Some(match (requests[index].tokens, count) {
+                    (1, Ok(count)) if request.tokens > 1 => Ok(self.keep_claimed_tokens(request.key, request.tokens, count, now_us)),
+                    (_, count) => count,
+                });
+            }
+        }
+
+        results.into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(RateLimiterError::Store("Store returned no result".to_string()))))
+            .collect()
+    }
+
+    // Tokens claimed by this instance are already taken from the store, so a peek may be lower than what's left here
+    async fn peek_many(&self, buckets: &[(&str, &Bucket)]) -> Vec<Result<i32, RateLimiterError>> {
+        self.store.peek_many(buckets).await
+    }
+
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        self.store.peek(key, bucket).await
+    }
+
+    async fn window_ends_in_ms(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        self.store.window_ends_in_ms(key, bucket).await
+    }
+
+    async fn count_distinct(&self, key: &str, value: &str, ttl_secs: u32) -> Result<u64, RateLimiterError> {
+        self.store.count_distinct(key, value, ttl_secs).await
+    }
+
+    async fn check_connection(&self) -> Result<(), RateLimiterError> {
+        self.store.check_connection().await
+    }
+
+    // Tokens claimed by this instance are already taken from the store, so they are part of its counters
+    async fn export(&self, prefix: &str) -> Result<Vec<StoredCounter>, RateLimiterError> {
+        self.store.export(prefix).await
+    }
+
+    async fn import(&self, counters: &[StoredCounter]) -> Result<usize, RateLimiterError> {
+        self.store.import(counters).await
+    }
+}
+
+async fn remove_stale_budgets(budgets: Weak<DashMap<String, LocalBudget>>, clock: Arc<dyn Clock>, max_age: Duration) {
+    let mut interval = tokio::time::interval(max_age);
+    loop {
+        interval.tick().await;
+
+        let Some(budgets) = budgets.upgrade() else {
+            return;
+        };
+        let now_us = clock.now_us();
+        budgets.retain(|_, budget| elapsed(budget.claimed_at_us.max(budget.window_started_at_us), now_us) < max_age);
+    }
+}
+
+// Time between two readings of the clock, zero if it went backwards
+fn elapsed(since_us: u64, now_us: u64) -> Duration {
+    Duration::from_micros(now_us.saturating_sub(since_us))
+}
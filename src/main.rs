@@ -1,10 +1,72 @@
+use tokio::runtime::Runtime;
+use rate_limiter::bypass::{self, BypassTokenArgs, BypassTokens};
+use rate_limiter::clock::{Clock, SystemClock};
+use rate_limiter::error::RateLimiterError;
+use rate_limiter::inspect::{self, InspectArgs};
 use rate_limiter::server::ProxyServer;
 use rate_limiter::settings::Settings;
+use rate_limiter::simulate::{self, SimulateArgs};
 
-#[tokio::main]
-async fn main() {
-    let settings = Settings::new().expect("Failed to load settings");
-    
-    let server = ProxyServer::new(settings);
-    server.run().await.expect("Failed to run server");
-}
\ No newline at end of file
+fn main() {
+    let settings = Settings::new().unwrap_or_else(|e| exit(format!("Failed to load settings: {}", e)));
+
+    // The runtime is built by hand, as its size comes from the settings
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = settings.runtime_settings.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    let runtime = runtime.enable_all().build().unwrap_or_else(|e| exit(format!("Failed to build the Tokio runtime: {}", e)));
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(String::as_str) {
+        None => {
+            let server = ProxyServer::new(settings);
+            if let Err(e) = runtime.block_on(server.run()) {
+                exit(format!("Failed to run server: {}", e));
+            }
+            return;
+        },
+        Some("inspect") => run_inspect(&runtime, &settings, &args[1..]),
+        Some("simulate") => run_simulate(&runtime, &settings, &args[1..]),
+        Some("bypass-token") => run_bypass_token(&settings, &args[1..]),
+        Some(command) => Err(RateLimiterError::config(format!("Unknown command {}\n{}\n{}\n{}", command, inspect::USAGE, simulate::USAGE, bypass::USAGE))),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+}
+
+fn exit(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn run_inspect(runtime: &Runtime, settings: &Settings, args: &[String]) -> Result<(), RateLimiterError> {
+    let args = InspectArgs::parse(args).map_err(|e| RateLimiterError::config(format!("{}\n{}", e, inspect::USAGE)))?;
+    let inspection = runtime.block_on(inspect::inspect(settings, &args))?;
+
+    println!("key:   {}", inspection.key);
+    println!("value: {}", inspection.value.as_deref().unwrap_or("none, the bucket is full"));
+    match inspection.ttl_ms {
+        Some(ttl_ms) => println!("ttl:   {} ms", ttl_ms),
+        None => println!("ttl:   none"),
+    }
+    Ok(())
+}
+
+fn run_simulate(runtime: &Runtime, settings: &Settings, args: &[String]) -> Result<(), RateLimiterError> {
+    let args = SimulateArgs::parse(args).map_err(|e| RateLimiterError::config(format!("{}\n{}", e, simulate::USAGE)))?;
+    print!("{}", runtime.block_on(simulate::simulate(settings, &args))?);
+    Ok(())
+}
+
+fn run_bypass_token(settings: &Settings, args: &[String]) -> Result<(), RateLimiterError> {
+    let args = BypassTokenArgs::parse(args).map_err(|e| RateLimiterError::config(format!("{}\n{}", e, bypass::USAGE)))?;
+    let bypass = settings.rate_limiter_settings.bypass.as_ref()
+        .ok_or_else(|| RateLimiterError::config("bypass-token needs a [rate_limiter.bypass] section"))?;
+    let bypass = BypassTokens::new(bypass)?;
+    println!("{}", bypass.issue(&args.key_id, &args.subject, args.ttl_secs.unwrap_or(bypass.max_ttl_secs()), SystemClock.now_secs())?);
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::metrics;
+use crate::settings::MaintenanceSettings;
+
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MaintenanceState {
+    pub routes: Vec<String>,
+    pub lockdown: bool,
+    pub throttle_percent: u8,
+}
+
+
+// Routes under maintenance, the emergency lockdown and the global throttle, all changed at runtime by the admin server.
+// Affected requests get the configured static response without reaching the limiters or the upstream.
+#[derive(Debug)]
+pub struct Maintenance {
+    status: StatusCode,
+    content_type: String,
+    body: String,
+    routes: RwLock<Vec<String>>,
+    lockdown: AtomicBool,
+    // Share of the requests of non-whitelisted clients rejected at random
+    throttle_percent: AtomicU8,
+    requests: AtomicU64,
+    random: RandomState,
+}
+
+impl Maintenance {
+    pub fn new(settings: &MaintenanceSettings) -> Result<Self, RateLimiterError> {
+        let status = StatusCode::from_u16(settings.status)
+            .map_err(|e| RateLimiterError::config(format!("Invalid maintenance status {}: {}", settings.status, e)))?;
+        let routes = settings.routes.iter().map(|route| normalize_route(route)).collect::<Result<Vec<_>, _>>()?;
+        check_throttle_percent(settings.throttle_percent)?;
+
+        Ok(Self {
+            status,
+            content_type: settings.content_type.clone(),
+            body: settings.body.clone(),
+            routes: RwLock::new(routes),
+            lockdown: AtomicBool::new(settings.lockdown),
+            throttle_percent: AtomicU8::new(settings.throttle_percent),
+            requests: AtomicU64::new(0),
+            random: RandomState::new(),
+        })
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        MaintenanceState {
+            routes: self.routes.read().unwrap_or_else(|e| e.into_inner()).clone(),
+            lockdown: self.lockdown.load(Ordering::Relaxed),
+            throttle_percent: self.throttle_percent.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_route(&self, route: &str, enabled: bool) -> Result<(), RateLimiterError> {
+        let route = normalize_route(route)?;
+        let mut routes = self.routes.write().unwrap_or_else(|e| e.into_inner());
+        routes.retain(|r| r != &route);
+        if enabled {
+            routes.push(route);
+        }
+        Ok(())
+    }
+
+    pub fn set_lockdown(&self, enabled: bool) {
+        self.lockdown.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_throttle(&self, percent: u8) -> Result<(), RateLimiterError> {
+        check_throttle_percent(percent)?;
+        self.throttle_percent.store(percent, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Every request draws a number below 100 from a hash of a counter, so no lock is needed
+    fn is_throttled(&self) -> bool {
+        let percent = self.throttle_percent.load(Ordering::Relaxed);
+        percent > 0 && self.random.hash_one(self.requests.fetch_add(1, Ordering::Relaxed)) % 100 < percent as u64
+    }
+
+    // A route only matches whole path segments, `/` puts every path under maintenance
+    fn is_under_maintenance(&self, path: &str) -> bool {
+        self.routes.read().unwrap_or_else(|e| e.into_inner()).iter()
+            .any(|route| route == "/" || path.strip_prefix(route.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+    }
+
+    fn response(&self) -> Response {
+        (self.status, [(CONTENT_TYPE, self.content_type.clone())], self.body.clone()).into_response()
+    }
+}
+
+fn normalize_route(route: &str) -> Result<String, RateLimiterError> {
+    match route.starts_with('/') {
+        true if route.trim_end_matches('/').is_empty() => Ok("/".to_string()),
+        true => Ok(route.trim_end_matches('/').to_string()),
+        false => Err(RateLimiterError::config(format!("Maintenance route {} must start with /", route))),
+    }
+}
+
+fn check_throttle_percent(percent: u8) -> Result<(), RateLimiterError> {
+    match percent {
+        0..=100 => Ok(()),
+        _ => Err(RateLimiterError::config(format!("Throttle percent must be between 0 and 100, got {}", percent))),
+    }
+}
+
+// During a lockdown only whitelisted clients get through, the throttle rejects a share of the others before any limiter
+pub async fn middleware(
+    State((maintenance, limiter)): State<(Arc<Maintenance>, Arc<RateLimiterManager>)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if maintenance.lockdown.load(Ordering::Relaxed) && !limiter.is_whitelisted(&addr.ip()) {
+        return maintenance.response();
+    }
+    if maintenance.is_throttled() && !limiter.is_whitelisted(&addr.ip()) {
+        metrics::increment_counter("rate_limiter_throttled_total", &[]);
+        return maintenance.response();
+    }
+    if maintenance.is_under_maintenance(request.uri().path()) {
+        return maintenance.response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,489 @@
+use std::sync::Arc;
+use axum::async_trait;
+use deadpool::managed::{self, Metrics, RecycleError, RecycleResult};
+use deadpool::Runtime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::strategy::Bucket;
+use crate::settings::MemcachedSettings;
+use crate::store::LimitStore;
+
+// Binary protocol, see https://github.com/memcached/memcached/wiki/BinaryProtocolRevamped
+const MAGIC_REQUEST: u8 = 0x80;
+const MAGIC_RESPONSE: u8 = 0x81;
+const HEADER_LENGTH: usize = 24;
+
+const OPCODE_GET: u8 = 0x00;
+const OPCODE_SET: u8 = 0x01;
+const OPCODE_ADD: u8 = 0x02;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_ITEM_NOT_STORED: u16 = 0x0005;
+
+// Expiration times bigger than 30 days are treated by memcached as unix timestamps
+const MAX_RELATIVE_EXPIRATION: u64 = 60 * 60 * 24 * 30;
+const MAX_CAS_RETRIES: usize = 16;
+const TAT_PREFIX: &str = "tat:";
+
+
+struct Response {
+    status: u16,
+    cas: u64,
+    value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct ResponseHeader {
+    status: u16,
+    cas: u64,
+    // Extras and key come before the value in the body
+    value_offset: usize,
+    body_length: usize,
+}
+
+#[derive(Debug)]
+pub struct MemcachedConnection {
+    stream: TcpStream,
+    is_broken: bool,
+}
+
+impl MemcachedConnection {
+    pub async fn connect(addr: &str) -> Result<Self, RateLimiterError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            is_broken: false,
+        })
+    }
+
+    async fn request(&mut self, opcode: u8, key: &[u8], extras: &[u8], value: &[u8], cas: u64) -> Result<Response, RateLimiterError> {
+        let result = self.send_request(opcode, key, extras, value, cas).await;
+        // A failed read or write leaves unread bytes in the stream, so the connection can't be reused
+        if result.is_err() {
+            self.is_broken = true;
+        }
+        result
+    }
+
+    async fn send_request(&mut self, opcode: u8, key: &[u8], extras: &[u8], value: &[u8], cas: u64) -> Result<Response, RateLimiterError> {
+        let packet = encode_request(opcode, key, extras, value, cas)?;
+        self.stream.write_all(&packet).await?;
+
+        let mut header = [0u8; HEADER_LENGTH];
+        self.stream.read_exact(&mut header).await?;
+        let header = decode_header(&header)?;
+
+        let mut body = vec![0u8; header.body_length];
+        self.stream.read_exact(&mut body).await?;
+        let value = body.split_off(header.value_offset.min(header.body_length));
+
+        Ok(Response {
+            status: header.status,
+            cas: header.cas,
+            value,
+        })
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<(Vec<u8>, u64)>, RateLimiterError> {
+        let response = self.request(OPCODE_GET, key.as_bytes(), &[], &[], 0).await?;
+        match response.status {
+            STATUS_OK => Ok(Some((response.value, response.cas))),
+            STATUS_KEY_NOT_FOUND => Ok(None),
+            status => Err(RateLimiterError::Store(format!("Memcached GET failed with status {:#06x}", status))),
+        }
+    }
+
+    // Returns false if the value wasn't stored because of a concurrent update (CAS mismatch, key already added or removed)
+    pub async fn store(&mut self, opcode: u8, key: &str, value: &[u8], expiration: u32, cas: u64) -> Result<bool, RateLimiterError> {
+        let mut extras = [0u8; 8];
+        extras[4..].copy_from_slice(&expiration.to_be_bytes()); // flags are left empty
+
+        let response = self.request(opcode, key.as_bytes(), &extras, value, cas).await?;
+        match response.status {
+            STATUS_OK => Ok(true),
+            STATUS_KEY_NOT_FOUND | STATUS_KEY_EXISTS | STATUS_ITEM_NOT_STORED => Ok(false),
+            status => Err(RateLimiterError::Store(format!("Memcached store failed with status {:#06x}", status))),
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct MemcachedManager {
+    addr: String,
+}
+
+impl managed::Manager for MemcachedManager {
+    type Type = MemcachedConnection;
+    type Error = RateLimiterError;
+
+    async fn create(&self) -> Result<MemcachedConnection, RateLimiterError> {
+        MemcachedConnection::connect(&self.addr).await
+    }
+
+    async fn recycle(&self, connection: &mut MemcachedConnection, _: &Metrics) -> RecycleResult<RateLimiterError> {
+        match connection.is_broken {
+            true => Err(RecycleError::message("Connection is broken")),
+            false => Ok(()),
+        }
+    }
+}
+
+pub type MemcachedPool = managed::Pool<MemcachedManager>;
+
+
+// Every bucket is stored as "<remaining>:<expires_at>", where `expires_at` is a unix timestamp,
+// or as "tat:<tat_us>" for buckets with a burst, see the gcra module. Buckets that can borrow are kept for another
+// window after `expires_at`, so the next window starts with their debt.
+// Memcached can't decrement below zero, so counters are updated with GET + CAS instead of DECR.
+// Timestamps come from the clocks of the proxies, so a window only ends once `skew_secs` passed after its `expires_at`.
+#[derive(Clone, Debug)]
+pub struct MemcachedStore {
+    pool: MemcachedPool,
+    clock: Arc<dyn Clock>,
+    skew_secs: u64,
+}
+
+impl MemcachedStore {
+    pub fn new(settings: &MemcachedSettings, clock: Arc<dyn Clock>, skew_ms: u64) -> Result<Self, RateLimiterError> {
+        let pool = MemcachedPool::builder(MemcachedManager { addr: settings.addr.clone() })
+            .runtime(Runtime::Tokio1)
+            .build()
+            .map_err(RateLimiterError::config)?;
+
+        Ok(Self {
+            pool,
+            clock,
+            skew_secs: skew_ms.div_ceil(1000),
+        })
+    }
+
+    async fn connection(&self) -> Result<managed::Object<MemcachedManager>, RateLimiterError> {
+        self.pool.get().await.map_err(|e| RateLimiterError::Store(format!("Could not get a memcached connection: {}", e)))
+    }
+
+    // Tokens of the fixed window stored as `value` and when the window ends, those of a new window if it's over
+    fn window(&self, value: Option<&[u8]>, bucket: &Bucket, now: u64) -> (i32, u64) {
+        match value.and_then(decode_bucket) {
+            Some((remaining, expires_at)) if expires_at + self.skew_secs > now => (remaining, expires_at),
+            Some((remaining, expires_at)) if expires_at + self.skew_secs + bucket.debt_secs() as u64 > now => {
+                (bucket.refill(remaining), now + bucket.add_tokens_every as u64)
+            },
+            // Reset buckets memcached didn't evict yet or that can't be parsed
+            _ => (bucket.tokens_count as i32, now + bucket.add_tokens_every as u64),
+        }
+    }
+}
+
+#[async_trait]
+impl LimitStore for MemcachedStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        let mut connection = self.connection().await?;
+
+        for _ in 0..MAX_CAS_RETRIES {
+            let now_us = self.clock.now_us();
+            let now = now_us / 1_000_000;
+            let stored = connection.get(key).await?;
+            let cas = stored.as_ref().map_or(0, |(_, cas)| *cas);
+
+            let (value, remaining, expires_at) = match bucket.burst {
+                Some(burst) => {
+                    let tat_us = stored.and_then(|(value, _)| decode_tat(&value));
+                    let (tat_us, remaining) = gcra::consume(tat_us, now_us, bucket, burst, tokens, self.skew_secs * 1_000_000);
+                    (format!("{}{}", TAT_PREFIX, tat_us), remaining, now + gcra::ttl_us(tat_us, now_us).div_ceil(1_000_000) + self.skew_secs)
+                },
+                None => {
+                    let (available, expires_at) = self.window(stored.as_ref().map(|(value, _)| value.as_slice()), bucket, now);
+                    let remaining = available - tokens as i32;
+                    (format!("{}:{}", remaining, expires_at), remaining, expires_at + self.skew_secs + bucket.debt_secs() as u64)
+                },
+            };
+
+            let opcode = if cas == 0 { OPCODE_ADD } else { OPCODE_SET };
+            if connection.store(opcode, key, value.as_bytes(), expiration(expires_at, now), cas).await? {
+                return Ok(remaining);
+            }
+        }
+
+        Err(RateLimiterError::Store(format!("Too many concurrent updates of memcached key {}", key)))
+    }
+
+    // Only reads the bucket, so peeking doesn't create it nor start its window
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        let mut connection = self.connection().await?;
+        let now_us = self.clock.now_us();
+        let stored = connection.get(key).await?.map(|(value, _)| value);
+
+        Ok(match bucket.burst {
+            Some(burst) => gcra::consume(stored.and_then(|value| decode_tat(&value)), now_us, bucket, burst, 0, self.skew_secs * 1_000_000).1,
+            None => self.window(stored.as_deref(), bucket, now_us / 1_000_000).0,
+        })
+    }
+
+    // Windows end on a whole second, past their skew
+    async fn window_ends_in_ms(&self, key: &str, _bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        let mut connection = self.connection().await?;
+        let now_ms = self.clock.now_us() / 1000;
+        let stored = connection.get(key).await?.map(|(value, _)| value);
+        let window_ends_at_ms = stored.as_deref().and_then(decode_bucket).map_or(now_ms, |(_, expires_at)| (expires_at + self.skew_secs) * 1000);
+        Ok(Some(window_ends_at_ms.saturating_sub(now_ms)))
+    }
+}
+
+fn encode_request(opcode: u8, key: &[u8], extras: &[u8], value: &[u8], cas: u64) -> Result<Vec<u8>, RateLimiterError> {
+    let key_length = u16::try_from(key.len()).map_err(|_| RateLimiterError::Store(format!("Memcached key is too long: {} bytes", key.len())))?;
+    let body_length = (extras.len() + key.len() + value.len()) as u32;
+
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + body_length as usize);
+    packet.push(MAGIC_REQUEST);
+    packet.push(opcode);
+    packet.extend_from_slice(&key_length.to_be_bytes());
+    packet.push(extras.len() as u8);
+    packet.push(0); // data type
+    packet.extend_from_slice(&0u16.to_be_bytes()); // vbucket id
+    packet.extend_from_slice(&body_length.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes()); // opaque
+    packet.extend_from_slice(&cas.to_be_bytes());
+    packet.extend_from_slice(extras);
+    packet.extend_from_slice(key);
+    packet.extend_from_slice(value);
+    Ok(packet)
+}
+
+fn decode_header(header: &[u8; HEADER_LENGTH]) -> Result<ResponseHeader, RateLimiterError> {
+    if header[0] != MAGIC_RESPONSE {
+        return Err(RateLimiterError::Store("Invalid memcached response magic".to_string()));
+    }
+
+    let key_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let extras_length = header[4] as usize;
+    Ok(ResponseHeader {
+        status: u16::from_be_bytes([header[6], header[7]]),
+        cas: u64::from_be_bytes(header[16..24].try_into().unwrap_or_default()),
+        value_offset: extras_length + key_length,
+        body_length: u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize,
+    })
+}
+
+fn decode_tat(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.strip_prefix(TAT_PREFIX)?.parse().ok()
+}
+
+fn decode_bucket(value: &[u8]) -> Option<(i32, u64)> {
+    let (remaining, expires_at) = std::str::from_utf8(value).ok()?.split_once(':')?;
+    Some((remaining.parse().ok()?, expires_at.parse().ok()?))
+}
+
+fn expiration(expires_at: u64, now: u64) -> u32 {
+    let ttl = expires_at.saturating_sub(now).max(1);
+    match ttl > MAX_RELATIVE_EXPIRATION {
+        true => expires_at as u32,
+        false => ttl as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use testcontainers::core::{IntoContainerPort, WaitFor};
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers::{GenericImage, ImageExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use crate::clock::SystemClock;
+    use super::*;
+
+    fn response(status: u16, cas: u64, extras: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut packet = encode_request(OPCODE_GET, &[], extras, value, cas).unwrap();
+        packet[0] = MAGIC_RESPONSE;
+        packet[6..8].copy_from_slice(&status.to_be_bytes());
+        packet
+    }
+
+    // Answers every request with the next of `responses` and closes the connection after the last one.
+    // Responses are written a byte at a time, so the client only ever reads part of them. Requests are sent back on the channel
+    // while it is open.
+    async fn fake_memcached(responses: Vec<Vec<u8>>) -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.set_nodelay(true).unwrap();
+            for response in responses {
+                let mut header = [0u8; HEADER_LENGTH];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut body = vec![0u8; u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize];
+                stream.read_exact(&mut body).await.unwrap();
+                let _ = sender.send([&header[..], &body].concat());
+
+                for byte in response {
+                    stream.write_all(&[byte]).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        (addr, receiver)
+    }
+
+    #[test]
+    fn encodes_requests_in_the_binary_protocol() {
+        let packet = encode_request(OPCODE_SET, b"key", &[0, 0, 0, 0, 0, 0, 0, 60], b"3:1700000060", 42).unwrap();
+        assert_eq!(packet[..HEADER_LENGTH], [
+            MAGIC_REQUEST, OPCODE_SET, 0, 3, 8, 0, 0, 0, // key length, extras length, data type, vbucket id
+            0, 0, 0, 23, 0, 0, 0, 0, // body length, opaque
+            0, 0, 0, 0, 0, 0, 0, 42, // cas
+        ]);
+        assert_eq!(&packet[HEADER_LENGTH..], b"\0\0\0\0\0\0\0\x3ckey3:1700000060");
+
+        let key = "k".repeat(u16::MAX as usize + 1);
+        assert!(encode_request(OPCODE_GET, key.as_bytes(), &[], &[], 0).is_err_and(|e| e.to_string().contains("key is too long")));
+    }
+
+    #[test]
+    fn decodes_response_headers() {
+        let packet = response(STATUS_KEY_EXISTS, 7, &[0; 4], b"value");
+        let header = decode_header(packet[..HEADER_LENGTH].try_into().unwrap()).unwrap();
+        assert_eq!(header, ResponseHeader { status: STATUS_KEY_EXISTS, cas: 7, value_offset: 4, body_length: 9 });
+
+        let request = encode_request(OPCODE_GET, b"key", &[], &[], 0).unwrap();
+        assert!(decode_header(request[..HEADER_LENGTH].try_into().unwrap()).is_err());
+    }
+
+    #[test]
+    fn expirations_over_30_days_are_timestamps() {
+        let now = 1_700_000_000;
+        assert_eq!(expiration(now + 60, now), 60);
+        // memcached would never expire a TTL of 0
+        assert_eq!(expiration(now, now), 1);
+        assert_eq!(expiration(now + MAX_RELATIVE_EXPIRATION + 1, now), (now + MAX_RELATIVE_EXPIRATION + 1) as u32);
+    }
+
+    #[tokio::test]
+    async fn reads_responses_arriving_in_pieces() {
+        let (addr, _) = fake_memcached(vec![
+            response(STATUS_OK, 9, &[0; 4], b"2:1700000060"),
+            response(STATUS_KEY_NOT_FOUND, 0, &[], b"Not found"),
+        ]).await;
+        let mut connection = MemcachedConnection::connect(&addr).await.unwrap();
+
+        assert_eq!(connection.get("key").await.unwrap(), Some((b"2:1700000060".to_vec(), 9)));
+        // The first response was read to its end, so the next one is read from its header
+        assert_eq!(connection.get("other").await.unwrap(), None);
+        assert!(!connection.is_broken);
+    }
+
+    #[tokio::test]
+    async fn breaks_connections_closed_in_the_middle_of_a_response() {
+        let (addr, _) = fake_memcached(vec![response(STATUS_OK, 9, &[0; 4], b"2:1700000060")[..HEADER_LENGTH / 2].to_vec()]).await;
+        let mut connection = MemcachedConnection::connect(&addr).await.unwrap();
+
+        assert!(connection.get("key").await.is_err());
+        assert!(connection.is_broken);
+    }
+
+    #[tokio::test]
+    async fn retries_updates_rejected_by_a_concurrent_one() {
+        let expires_at = SystemClock.now_secs() + 60;
+        let (addr, mut requests) = fake_memcached(vec![
+            response(STATUS_OK, 1, &[0; 4], format!("5:{}", expires_at).as_bytes()),
+            response(STATUS_KEY_EXISTS, 0, &[], &[]),
+            // Another proxy took a token in the meantime
+            response(STATUS_OK, 2, &[0; 4], format!("4:{}", expires_at).as_bytes()),
+            response(STATUS_OK, 3, &[], &[]),
+        ]).await;
+        let store = MemcachedStore::new(&MemcachedSettings { addr }, Arc::new(SystemClock), 0).unwrap();
+
+        assert_eq!(store.consume("key", &Bucket::new(10, 60), 1).await.unwrap(), 3);
+        let mut sets = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            if request[1] == OPCODE_SET {
+                sets.push(request);
+            }
+        }
+        assert_eq!(sets.len(), 2);
+        // The retry is checked against the CAS of the value it read again
+        assert_eq!(u64::from_be_bytes(sets[1][16..24].try_into().unwrap()), 2);
+        assert!(sets[1].ends_with(format!("3:{}", expires_at).as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_concurrent_updates() {
+        let expires_at = SystemClock.now_secs() + 60;
+        let responses = (1..=MAX_CAS_RETRIES as u64)
+            .flat_map(|cas| [response(STATUS_OK, cas, &[0; 4], format!("5:{}", expires_at).as_bytes()), response(STATUS_KEY_EXISTS, 0, &[], &[])])
+            .collect();
+        let (addr, _) = fake_memcached(responses).await;
+        let store = MemcachedStore::new(&MemcachedSettings { addr }, Arc::new(SystemClock), 0).unwrap();
+
+        let result = store.consume("key", &Bucket::new(10, 60), 1).await;
+        assert!(result.is_err_and(|e| e.to_string().contains("Too many concurrent updates")));
+    }
+
+    #[tokio::test]
+    async fn peeks_only_read_buckets() {
+        let expires_at = SystemClock.now_secs() + 60;
+        let (addr, mut requests) = fake_memcached(vec![
+            response(STATUS_KEY_NOT_FOUND, 0, &[], b"Not found"),
+            response(STATUS_OK, 1, &[0; 4], format!("4:{}", expires_at).as_bytes()),
+            // Ended a minute ago
+            response(STATUS_OK, 2, &[0; 4], format!("0:{}", expires_at - 120).as_bytes()),
+            response(STATUS_KEY_NOT_FOUND, 0, &[], b"Not found"),
+        ]).await;
+        let store = MemcachedStore::new(&MemcachedSettings { addr }, Arc::new(SystemClock), 0).unwrap();
+
+        let bucket = Bucket::new(10, 60);
+        assert_eq!(store.peek("new", &bucket).await.unwrap(), 10);
+        assert_eq!(store.peek("used", &bucket).await.unwrap(), 4);
+        assert_eq!(store.peek("expired", &bucket).await.unwrap(), 10);
+        assert_eq!(store.peek("burst", &bucket.clone().with_burst(Some(3))).await.unwrap(), 3);
+
+        let mut opcodes = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            opcodes.push(request[1]);
+        }
+        assert_eq!(opcodes, [OPCODE_GET; 4]);
+    }
+
+    // Needs Docker, run with `cargo test --lib memcached -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn counts_concurrent_requests_and_expires_windows_on_a_real_memcached() {
+        let memcached = GenericImage::new("memcached", "1.6")
+            .with_exposed_port(11211.tcp())
+            .with_wait_for(WaitFor::message_on_stderr("server listening"))
+            .with_cmd(["-vv"])
+            .start()
+            .await
+            .expect("Failed to start memcached, is Docker running?");
+        let addr = format!("127.0.0.1:{}", memcached.get_host_port_ipv4(11211).await.unwrap());
+        let store = Arc::new(MemcachedStore::new(&MemcachedSettings { addr }, Arc::new(SystemClock), 0).unwrap());
+
+        // Requests sent at once conflict on CAS, every one is still counted exactly once
+        let bucket = Bucket::new(10, 60);
+        let tasks = (0..20).map(|_| {
+            let (store, bucket) = (store.clone(), bucket.clone());
+            tokio::spawn(async move { store.consume("concurrent", &bucket, 1).await.unwrap() })
+        }).collect::<Vec<_>>();
+        let mut counts = Vec::new();
+        for task in tasks {
+            counts.push(task.await.unwrap());
+        }
+        counts.sort();
+        assert_eq!(counts, (-10..10).collect::<Vec<_>>());
+
+        // A new window starts with a full bucket
+        let bucket = Bucket::new(2, 1);
+        assert_eq!(store.consume("expiring", &bucket, 1).await.unwrap(), 1);
+        assert_eq!(store.consume("expiring", &bucket, 2).await.unwrap(), -1);
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(store.consume("expiring", &bucket, 1).await.unwrap(), 1);
+    }
+}
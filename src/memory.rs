@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Once, Weak};
+use std::time::Duration;
+use axum::async_trait;
+use dashmap::DashMap;
+use crate::clock::{Clock, SystemClock};
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::strategy::Bucket;
+use crate::store::{LimitStore, StoredCounter};
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+
+// Times are in microseconds since the unix epoch, as read from the clock of the store
+#[derive(Clone, Debug)]
+struct MemoryBucket {
+    remaining: i32,
+    // Only used by buckets with a burst
+    tat_us: Option<u64>,
+    // Fixed windows of buckets that can borrow are kept past their end, for the next window to start with their debt
+    window_ends_at_us: u64,
+    expires_at_us: u64,
+}
+
+impl MemoryBucket {
+    fn new(bucket: &Bucket, remaining: i32, now_us: u64) -> Self {
+        let window_ends_at_us = now_us + bucket.add_tokens_every as u64 * 1_000_000;
+        Self {
+            remaining,
+            tat_us: None,
+            window_ends_at_us,
+            expires_at_us: window_ends_at_us + bucket.debt_secs() as u64 * 1_000_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct DistinctSet {
+    values: HashSet<String>,
+    expires_at_us: u64,
+}
+
+
+// Same semantics as the Redis store: a bucket is created full on first use, drained by every request
+// and dropped once `add_tokens_every` seconds passed since its creation.
+#[derive(Clone, Debug)]
+pub struct MemoryStore {
+    buckets: Arc<DashMap<String, MemoryBucket>>,
+    // Counted exactly, unlike the HyperLogLogs of Redis
+    sets: Arc<DashMap<String, DistinctSet>>,
+    clock: Arc<dyn Clock>,
+    // Shared by the clones of the store, so only one sweeper runs
+    sweeper: Arc<Once>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    // Can be built outside of a tokio runtime, the removal of expired buckets starts with the first write
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            buckets: Arc::new(DashMap::new()),
+            sets: Arc::new(DashMap::new()),
+            clock,
+            sweeper: Arc::new(Once::new()),
+        }
+    }
+}
+
+impl MemoryStore {
+    fn start_sweeper(&self) {
+        self.sweeper.call_once(|| {
+            tokio::spawn(remove_expired_buckets(Arc::downgrade(&self.buckets), Arc::downgrade(&self.sets), self.clock.clone()));
+        });
+    }
+
+    pub fn clear(&self) {
+        self.buckets.clear();
+        self.sets.clear();
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl LimitStore for MemoryStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        self.start_sweeper();
+        let now_us = self.clock.now_us();
+        // Existing buckets are looked up by reference, only new keys are copied
+        let mut entry = match self.buckets.get_mut(key) {
+            Some(entry) => entry,
+            None => self.buckets.entry(key.to_string()).or_insert_with(|| MemoryBucket::new(bucket, bucket.tokens_count as i32, now_us)),
+        };
+
+        if let Some(burst) = bucket.burst {
+            let tat_us = entry.tat_us.filter(|_| entry.expires_at_us > now_us);
+            let (tat_us, remaining) = gcra::consume(tat_us, now_us, bucket, burst, tokens, 0);
+            entry.tat_us = Some(tat_us);
+            entry.remaining = remaining;
+            entry.expires_at_us = now_us + gcra::ttl_us(tat_us, now_us);
+            return Ok(remaining);
+        }
+
+        if entry.window_ends_at_us <= now_us {
+            let remaining = match entry.expires_at_us > now_us {
+                true => bucket.refill(entry.remaining),
+                false => bucket.tokens_count as i32,
+            };
+            *entry = MemoryBucket::new(bucket, remaining, now_us);
+        }
+        // Imported counters set a TAT too, which fixed windows don't have
+        entry.tat_us = None;
+        entry.remaining -= tokens as i32;
+
+        Ok(entry.remaining)
+    }
+
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        let now_us = self.clock.now_us();
+        let entry = self.buckets.get(key).filter(|entry| entry.expires_at_us > now_us);
+        match bucket.burst {
+            Some(burst) => Ok(gcra::consume(entry.and_then(|entry| entry.tat_us), now_us, bucket, burst, 0, 0).1),
+            None => Ok(entry.map_or(bucket.tokens_count as i32, |entry| match entry.window_ends_at_us > now_us {
+                true => entry.remaining,
+                false => bucket.refill(entry.remaining),
+            })),
+        }
+    }
+
+    async fn window_ends_in_ms(&self, key: &str, _bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        let now_us = self.clock.now_us();
+        let window_ends_at_us = self.buckets.get(key).map_or(now_us, |entry| entry.window_ends_at_us);
+        Ok(Some(window_ends_at_us.saturating_sub(now_us).div_ceil(1000)))
+    }
+
+    async fn count_distinct(&self, key: &str, value: &str, ttl_secs: u32) -> Result<u64, RateLimiterError> {
+        self.start_sweeper();
+        let now_us = self.clock.now_us();
+        let mut entry = self.sets.entry(key.to_string()).or_insert_with(|| DistinctSet { values: HashSet::new(), expires_at_us: now_us });
+        if entry.expires_at_us <= now_us {
+            entry.values.clear();
+        }
+        entry.expires_at_us = now_us + ttl_secs as u64 * 1_000_000;
+        if !entry.values.contains(value) {
+            entry.values.insert(value.to_string());
+        }
+        Ok(entry.values.len() as u64)
+    }
+
+    // Counters are exported like the Redis store stores them, the TAT of buckets with a burst and the tokens left of others.
+    // Sets of distinct values aren't counters and are left out.
+    async fn export(&self, prefix: &str) -> Result<Vec<StoredCounter>, RateLimiterError> {
+        let now_us = self.clock.now_us();
+        let prefix = format!("{}:", prefix);
+        Ok(self.buckets.iter()
+            .filter(|entry| entry.key().starts_with(&prefix) && entry.expires_at_us > now_us)
+            .map(|entry| StoredCounter {
+                key: entry.key().clone(),
+                value: entry.tat_us.map_or(entry.remaining as i64, |tat_us| tat_us as i64),
+                expires_at_ms: Some(entry.expires_at_us / 1000),
+            })
+            .collect())
+    }
+
+    // Whether a counter is a TAT or tokens left is only known once the bucket is used, so both are set: buckets with a burst
+    // only read the TAT, others the tokens left. The window of buckets that can borrow runs until the counter expires.
+    async fn import(&self, counters: &[StoredCounter]) -> Result<usize, RateLimiterError> {
+        self.start_sweeper();
+        let now_us = self.clock.now_us();
+        let mut imported = 0;
+        for counter in counters {
+            let expires_at_us = counter.expires_at_ms.map_or(u64::MAX, |expires_at_ms| expires_at_ms.saturating_mul(1000));
+            if expires_at_us <= now_us {
+                continue;
+            }
+            self.buckets.insert(counter.key.clone(), MemoryBucket {
+                remaining: counter.value.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                tat_us: u64::try_from(counter.value).ok(),
+                window_ends_at_us: expires_at_us,
+                expires_at_us,
+            });
+            imported += 1;
+        }
+        Ok(imported)
+    }
+}
+
+async fn remove_expired_buckets(buckets: Weak<DashMap<String, MemoryBucket>>, sets: Weak<DashMap<String, DistinctSet>>, clock: Arc<dyn Clock>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        // Stop once the store itself has been dropped
+        let (Some(buckets), Some(sets)) = (buckets.upgrade(), sets.upgrade()) else {
+            return;
+        };
+        let now_us = clock.now_us();
+        buckets.retain(|_, bucket| bucket.expires_at_us > now_us);
+        sets.retain(|_, set| set.expires_at_us > now_us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_be_built_outside_of_a_runtime() {
+        let store = MemoryStore::default();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        assert_eq!(runtime.block_on(store.consume("key", &Bucket::new(2, 60), 1)).unwrap(), 1);
+        assert_eq!(runtime.block_on(store.clone().consume("key", &Bucket::new(2, 60), 1)).unwrap(), 0);
+    }
+}
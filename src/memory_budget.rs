@@ -0,0 +1,146 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper::body::{Frame, SizeHint};
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::MemoryBudgetSettings;
+
+
+// Counts the bytes of the requests the proxy holds, their headers from the start and their bodies as they arrive,
+// and sheds requests that would take it over the budget, so a burst of large uploads can't run the proxy out of memory.
+// Shared by every listener, a request holds its bytes until its response headers are sent.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    max_bytes: usize,
+    used_bytes: AtomicUsize,
+    retry_after_secs: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(settings: &MemoryBudgetSettings) -> Result<Self, RateLimiterError> {
+        if settings.max_bytes == 0 {
+            return Err(RateLimiterError::config("memory_budget.max_bytes must be greater than 0"));
+        }
+
+        Ok(Self {
+            max_bytes: settings.max_bytes,
+            used_bytes: AtomicUsize::new(0),
+            retry_after_secs: settings.retry_after_secs,
+        })
+    }
+
+    fn reserve(&self, bytes: usize) -> bool {
+        let reserved = self.used_bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(bytes).filter(|used| *used <= self.max_bytes))
+            .is_ok();
+        self.report();
+        reserved
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used_bytes.fetch_sub(bytes, Ordering::AcqRel);
+        self.report();
+    }
+
+    fn report(&self) {
+        metrics::set_gauge("rate_limiter_memory_budget_used_bytes", &[], self.used_bytes.load(Ordering::Acquire) as u64);
+    }
+
+    fn shed(&self, part: &str) -> Response {
+        metrics::increment_counter("rate_limiter_memory_shed_total", &[("part", part)]);
+        shed_response(self.retry_after_secs)
+    }
+}
+
+fn shed_response(retry_after_secs: u64) -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, [(RETRY_AFTER, retry_after_secs.to_string())], "Server overloaded").into_response()
+}
+
+
+// Bytes taken from the budget by one request, given back when it's dropped
+#[derive(Debug)]
+struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: AtomicUsize,
+}
+
+impl Reservation {
+    fn reserve(&self, bytes: usize) -> bool {
+        let reserved = self.budget.reserve(bytes);
+        if reserved {
+            self.bytes.fetch_add(bytes, Ordering::AcqRel);
+        }
+        reserved
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes.load(Ordering::Acquire));
+    }
+}
+
+
+#[derive(Debug, thiserror::Error)]
+#[error("Request body over the memory budget")]
+pub struct MemoryBudgetExceeded {
+    pub retry_after_secs: u64,
+}
+
+// Takes the bytes of every frame from the reservation before handing it on
+struct BudgetedBody {
+    inner: Body,
+    reservation: Arc<Reservation>,
+}
+
+impl hyper::body::Body for BudgetedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame && let Some(data) = frame.data_ref() && !self.reservation.reserve(data.len()) {
+            metrics::increment_counter("rate_limiter_memory_shed_total", &[("part", "body")]);
+            return Poll::Ready(Some(Err(axum::Error::new(MemoryBudgetExceeded { retry_after_secs: self.reservation.budget.retry_after_secs }))));
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Runs before everything else on the listener. Requests announcing a body over the budget are shed before it's read,
+// others once the bytes received so far take the budget over it.
+pub async fn middleware(State(budget): State<Arc<MemoryBudget>>, request: Request<Body>, next: Next) -> Response {
+    let reservation = Arc::new(Reservation { budget: budget.clone(), bytes: AtomicUsize::new(0) });
+    let head_bytes = request.uri().to_string().len() + request.headers().iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>();
+    if !reservation.reserve(head_bytes) {
+        return budget.shed("headers");
+    }
+    let content_length = request.headers().get(CONTENT_LENGTH).and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|content_length| budget.used_bytes.load(Ordering::Acquire).saturating_add(content_length) > budget.max_bytes) {
+        return budget.shed("body");
+    }
+
+    let request = request.map(|body| Body::new(BudgetedBody { inner: body, reservation: reservation.clone() }));
+    next.run(request).await
+}
+
+// Answers requests whose body took the budget over it
+pub fn exceeded_response(e: &MemoryBudgetExceeded) -> Response {
+    shed_response(e.retry_after_secs)
+}
@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Default)]
+struct Metrics {
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>>,
+    gauges: Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>>,
+}
+
+pub fn increment_counter(name: &'static str, labels: &[(&'static str, &str)]) {
+    add_to_counter(name, labels, 1);
+}
+
+pub fn add_to_counter(name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    let mut counters = METRICS.counters.lock().unwrap_or_else(|e| e.into_inner());
+    *counters.entry(name).or_default().entry(labels).or_default() += value;
+}
+
+pub fn set_gauge(name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    let mut gauges = METRICS.gauges.lock().unwrap_or_else(|e| e.into_inner());
+    gauges.entry(name).or_default().insert(labels, value);
+}
+
+// Renders all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let counters = METRICS.counters.lock().unwrap_or_else(|e| e.into_inner());
+    let gauges = METRICS.gauges.lock().unwrap_or_else(|e| e.into_inner());
+    let mut output = String::new();
+
+    let metrics = counters.iter().map(|metric| (metric, "counter")).chain(gauges.iter().map(|metric| (metric, "gauge")));
+    for ((name, values), metric_type) in metrics {
+        let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+        for (labels, value) in values {
+            let _ = writeln!(output, "{}{} {}", name, render_labels(labels), value);
+        }
+    }
+
+    output
+}
+
+fn render_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels = labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::http::Method;
+use config::{Config, File};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::{BucketSettings, OpenApiSettings};
+use crate::strategy::{Bucket, BucketsPerValue, LimitKey, RateLimiterChecker, SafeRequest};
+
+const RATE_LIMIT_EXTENSION: &str = "x-rate-limit";
+const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+
+// Only the parts of the document the gateway reads
+#[derive(Deserialize, Debug)]
+struct OpenApiDocument {
+    #[serde(default)]
+    paths: HashMap<String, HashMap<String, Value>>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Parameter,
+}
+
+#[derive(Debug)]
+struct Operation {
+    // `operationId`, or `<METHOD> <path>` without one
+    id: String,
+    method: Method,
+    segments: Vec<Segment>,
+    bucket: Option<Bucket>,
+}
+
+impl Operation {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let mut segments = path.trim_matches('/').split('/').filter(|segment| !segment.is_empty());
+        self.method == method
+            && self.segments.iter().all(|expected| match (expected, segments.next()) {
+                (Segment::Literal(literal), Some(segment)) => literal == segment,
+                (Segment::Parameter, Some(_)) => true,
+                (_, None) => false,
+            })
+            && segments.next().is_none()
+    }
+
+    fn literal_segments(&self) -> usize {
+        self.segments.iter().filter(|segment| matches!(segment, Segment::Literal(_))).count()
+    }
+}
+
+
+// Routes of an OpenAPI document and the limits of its operations, read from their `x-rate-limit` extension
+#[derive(Debug)]
+pub struct OpenApiRoutes {
+    operations: Vec<Operation>,
+    reject_unknown: bool,
+}
+
+impl OpenApiRoutes {
+    // JSON and YAML documents are read like the settings file, the format follows the extension
+    pub fn load(settings: &OpenApiSettings) -> Result<Self, RateLimiterError> {
+        let document: OpenApiDocument = Config::builder()
+            .add_source(File::with_name(&settings.path))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| RateLimiterError::config(format!("Could not read the OpenAPI document {}: {}", settings.path, e)))?;
+
+        let mut operations = Vec::new();
+        for (path, item) in &document.paths {
+            let template = format!("{}{}", settings.base_path.trim_end_matches('/'), path);
+            for (method, operation) in item.iter().filter(|(method, _)| METHODS.contains(&method.as_str())) {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(RateLimiterError::config)?;
+                let bucket = operation.get(RATE_LIMIT_EXTENSION)
+                    .map(|limit| serde_json::from_value::<BucketSettings>(limit.clone()))
+                    .transpose()
+                    .map_err(|e| RateLimiterError::config(format!("Invalid {} of {} {}: {}", RATE_LIMIT_EXTENSION, method, path, e)))?;
+
+                operations.push(Operation {
+                    id: operation.get("operationId").and_then(Value::as_str).map_or_else(|| format!("{} {}", method, path), str::to_string),
+                    segments: parse_template(&template),
+                    method,
+                    bucket: bucket.as_ref().map(Bucket::from),
+                });
+            }
+        }
+        // The most specific template wins, e.g. /users/me before /users/{id}
+        operations.sort_by_key(|operation| std::cmp::Reverse(operation.literal_segments()));
+
+        Ok(Self {
+            operations,
+            reject_unknown: settings.reject_unknown,
+        })
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<&Operation> {
+        self.operations.iter().find(|operation| operation.matches(method, path))
+    }
+
+    // Requests outside of the document are only rejected with `reject_unknown`
+    pub fn is_known(&self, method: &Method, path: &str) -> bool {
+        !self.reject_unknown || self.find(method, path).is_some()
+    }
+
+    // Buckets of the operations with a limit, by operation id
+    pub fn buckets(&self) -> HashMap<String, Bucket> {
+        self.operations.iter()
+            .filter_map(|operation| Some((operation.id.clone(), operation.bucket.clone()?)))
+            .collect()
+    }
+}
+
+fn parse_template(template: &str) -> Vec<Segment> {
+    template.trim_matches('/').split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| match segment.starts_with('{') && segment.ends_with('}') {
+            true => Segment::Parameter,
+            false => Segment::Literal(segment.to_string()),
+        })
+        .collect()
+}
+
+
+// Limits every operation of the document as a whole, like the url strategy does for a path
+#[derive(Clone, Debug)]
+pub struct OperationRateLimiterStrategy {
+    routes: Arc<OpenApiRoutes>,
+}
+
+impl OperationRateLimiterStrategy {
+    pub fn new(routes: Arc<OpenApiRoutes>) -> Self {
+        Self {
+            routes,
+        }
+    }
+}
+
+impl RateLimiterChecker for OperationRateLimiterStrategy {
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, _global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
+        let operation = self.routes.find(&request.parts.method, request.parts.uri.path())?;
+        let bucket = buckets_per_value?.get(&operation.id)?;
+        Some(LimitKey::new(key_builder.build("operation", &operation.id), bucket.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"{
+        "paths": {
+            "/users/{id}": {
+                "get": { "operationId": "getUser", "x-rate-limit": { "tokens_count": 100, "add_tokens_every": 60 } },
+                "parameters": []
+            },
+            "/users/me": {
+                "get": { "operationId": "getMe" },
+                "delete": { "x-rate-limit": { "tokens_count": 1, "add_tokens_every": 60 } }
+            }
+        }
+    }"#;
+
+    fn load(name: &str, document: &str, base_path: &str, reject_unknown: bool) -> Result<OpenApiRoutes, RateLimiterError> {
+        let path = std::env::temp_dir().join(format!("rate-limiter-openapi-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, document).unwrap();
+        let settings = OpenApiSettings { path: path.to_string_lossy().to_string(), base_path: base_path.to_string(), reject_unknown, on_store_error: Default::default() };
+        let routes = OpenApiRoutes::load(&settings);
+        let _ = std::fs::remove_file(path);
+        routes
+    }
+
+    fn operation_id(routes: &OpenApiRoutes, method: Method, path: &str) -> Option<String> {
+        routes.find(&method, path).map(|operation| operation.id.clone())
+    }
+
+    #[test]
+    fn templated_segments_match_any_value_and_literals_win() {
+        let routes = load("templates", DOCUMENT, "/api/", true).unwrap();
+        assert_eq!(operation_id(&routes, Method::GET, "/api/users/42"), Some("getUser".to_string()));
+        assert_eq!(operation_id(&routes, Method::GET, "/api/users/42/"), Some("getUser".to_string()));
+        assert_eq!(operation_id(&routes, Method::GET, "/api/users/me"), Some("getMe".to_string()));
+        assert_eq!(operation_id(&routes, Method::DELETE, "/api/users/me"), Some("DELETE /users/me".to_string()));
+        assert_eq!(operation_id(&routes, Method::GET, "/api/users"), None);
+        assert_eq!(operation_id(&routes, Method::GET, "/api/users/42/posts"), None);
+        assert_eq!(operation_id(&routes, Method::GET, "/users/42"), None);
+
+        let mut buckets = routes.buckets().into_iter().map(|(id, bucket)| (id, bucket.tokens_count)).collect::<Vec<_>>();
+        buckets.sort();
+        assert_eq!(buckets, [("DELETE /users/me".to_string(), 1), ("getUser".to_string(), 100)]);
+    }
+
+    #[test]
+    fn unknown_paths_and_methods_are_only_rejected_with_reject_unknown() {
+        let routes = load("reject", DOCUMENT, "", true).unwrap();
+        assert!(routes.is_known(&Method::GET, "/users/42"));
+        assert!(!routes.is_known(&Method::GET, "/orders"));
+        assert!(!routes.is_known(&Method::POST, "/users/42"));
+
+        let routes = load("accept", DOCUMENT, "", false).unwrap();
+        assert!(routes.is_known(&Method::GET, "/orders"));
+        assert!(routes.is_known(&Method::POST, "/users/42"));
+    }
+
+    #[test]
+    fn rejects_invalid_limits_and_documents() {
+        let error = load("invalid", r#"{ "paths": { "/users": { "get": { "x-rate-limit": { "tokens_count": "many" } } } } }"#, "", false).unwrap_err();
+        assert!(error.to_string().contains("Invalid x-rate-limit of GET /users"), "{}", error);
+
+        let settings = OpenApiSettings { path: "missing-openapi.json".to_string(), base_path: String::new(), reject_unknown: false, on_store_error: Default::default() };
+        let error = OpenApiRoutes::load(&settings).unwrap_err();
+        assert!(error.to_string().contains("Could not read the OpenAPI document missing-openapi.json"), "{}", error);
+    }
+}
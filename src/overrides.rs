@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use deadpool_redis::redis;
+use serde::{Deserialize, Serialize};
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::settings::OverridesSettings;
+use crate::strategy::{Bucket, LimitKey};
+
+
+// Replaces the bucket of one key of a limiter until `expires_at`, e.g. to give a customer 10 times their limit until Friday
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BucketOverride {
+    pub limiter: String,
+    // Given the way `rate_limiter inspect` takes values, e.g. `X-Api-Key:abc` for header limiters
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add_tokens_every: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    // Scales the tokens and the burst, after the fields above replaced those of the configured bucket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multiplier: Option<u32>,
+    // Unix time in seconds
+    pub expires_at: u64,
+}
+
+impl BucketOverride {
+    pub fn validate(&self) -> Result<(), RateLimiterError> {
+        let fields = [("tokens_count", self.tokens_count), ("add_tokens_every", self.add_tokens_every), ("burst", self.burst), ("multiplier", self.multiplier)];
+        if fields.iter().all(|(_, value)| value.is_none()) {
+            return Err(RateLimiterError::config("An override needs tokens_count, add_tokens_every, burst or multiplier"));
+        }
+        if let Some((field, _)) = fields.iter().find(|(_, value)| *value == Some(0)) {
+            return Err(RateLimiterError::config(format!("{} of an override must be greater than 0", field)));
+        }
+        if self.expires_at <= unix_now() {
+            return Err(RateLimiterError::config("An override must expire in the future"));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, bucket: &mut Bucket) {
+        let multiplier = self.multiplier.unwrap_or(1);
+        bucket.tokens_count = self.tokens_count.unwrap_or(bucket.tokens_count).saturating_mul(multiplier);
+        bucket.add_tokens_every = self.add_tokens_every.unwrap_or(bucket.add_tokens_every);
+        bucket.burst = self.burst.or(bucket.burst).map(|burst| burst.saturating_mul(multiplier));
+    }
+}
+
+
+// Overrides set at runtime through the admin server, consulted before the configured buckets.
+// With Redis they are kept in the `<prefix>:overrides` hash, by store key, so every instance applies them once it refreshed.
+// Fields of a hash can't expire before Redis 7.4, so every override holds its expiry and expired ones are dropped on refresh.
+// Other backends keep them in the memory of the instance.
+#[derive(Debug)]
+pub struct Overrides {
+    key: String,
+    pool: Option<RedisPool>,
+    refresh: Duration,
+    overrides: RwLock<HashMap<String, BucketOverride>>,
+}
+
+impl Overrides {
+    // Must be called inside a tokio runtime when given a pool, as it spawns the refresh
+    pub fn new(settings: &OverridesSettings, pool: Option<RedisPool>, key_prefix: &str) -> Result<Arc<Self>, RateLimiterError> {
+        if settings.refresh_secs == 0 {
+            return Err(RateLimiterError::config("overrides.refresh_secs must be greater than 0"));
+        }
+
+        let overrides = Arc::new(Self {
+            key: format!("{}:overrides", key_prefix),
+            pool,
+            refresh: Duration::from_secs(settings.refresh_secs),
+            overrides: RwLock::new(HashMap::new()),
+        });
+        if overrides.pool.is_some() {
+            tokio::spawn(refresh_overrides(Arc::downgrade(&overrides)));
+        }
+        Ok(overrides)
+    }
+
+    // Replaces the bucket of the key if it has an override that didn't expire yet
+    pub fn apply(&self, limit_key: &mut LimitKey) {
+        let overrides = self.overrides.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket_override) = overrides.get(&limit_key.key) && bucket_override.expires_at > unix_now() {
+            bucket_override.apply(&mut limit_key.bucket);
+        }
+    }
+
+    // Overrides that didn't expire yet, sorted by limiter and value
+    pub fn list(&self) -> Vec<BucketOverride> {
+        let now = unix_now();
+        let mut overrides = self.overrides.read().unwrap_or_else(|e| e.into_inner()).values()
+            .filter(|bucket_override| bucket_override.expires_at > now)
+            .cloned()
+            .collect::<Vec<_>>();
+        overrides.sort_unstable_by(|a, b| (&a.limiter, &a.value).cmp(&(&b.limiter, &b.value)));
+        overrides
+    }
+
+    // `key` is the store key of the bucket, as limiters build it
+    pub async fn set(&self, key: String, bucket_override: BucketOverride) -> Result<(), RateLimiterError> {
+        bucket_override.validate()?;
+        if let Some(pool) = &self.pool {
+            let value = serde_json::to_string(&bucket_override).map_err(|e| RateLimiterError::Store(e.to_string()))?;
+            let mut connection = pool.get().await?;
+            redis::cmd("HSET").arg(&self.key).arg(&key).arg(value).query_async::<()>(&mut connection).await?;
+        }
+        self.overrides.write().unwrap_or_else(|e| e.into_inner()).insert(key, bucket_override);
+        Ok(())
+    }
+
+    // Returns whether the key had an override
+    pub async fn remove(&self, key: &str) -> Result<bool, RateLimiterError> {
+        let mut removed = false;
+        if let Some(pool) = &self.pool {
+            let mut connection = pool.get().await?;
+            removed = redis::cmd("HDEL").arg(&self.key).arg(key).query_async::<u32>(&mut connection).await? > 0;
+        }
+        let removed_locally = self.overrides.write().unwrap_or_else(|e| e.into_inner()).remove(key).is_some();
+        Ok(removed || removed_locally)
+    }
+
+    async fn refresh(&self, pool: &RedisPool) -> Result<(), RateLimiterError> {
+        let mut connection = pool.get().await?;
+        let entries: HashMap<String, String> = redis::cmd("HGETALL").arg(&self.key).query_async(&mut connection).await?;
+
+        let now = unix_now();
+        let (mut overrides, mut expired) = (HashMap::with_capacity(entries.len()), Vec::new());
+        for (key, value) in entries {
+            match serde_json::from_str::<BucketOverride>(&value) {
+                Ok(bucket_override) if bucket_override.expires_at > now => {
+                    overrides.insert(key, bucket_override);
+                },
+                Ok(_) => expired.push(key),
+                Err(e) => println!("Ignoring the invalid override of key {}: {}", key, e),
+            }
+        }
+        if !expired.is_empty() {
+            redis::cmd("HDEL").arg(&self.key).arg(&expired).query_async::<()>(&mut connection).await?;
+        }
+
+        *self.overrides.write().unwrap_or_else(|e| e.into_inner()) = overrides;
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+async fn refresh_overrides(overrides: Weak<Overrides>) {
+    let Some(every) = overrides.upgrade().map(|overrides| overrides.refresh) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let Some(overrides) = overrides.upgrade() else {
+            return;
+        };
+        let Some(pool) = &overrides.pool else {
+            return;
+        };
+        // On errors the last known overrides are kept
+        if let Err(e) = overrides.refresh(pool).await {
+            println!("Failed to refresh the overrides: {}", e);
+        }
+    }
+}
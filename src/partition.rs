@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use crate::cluster::Cluster;
+use crate::settings::PartitionSettings;
+use crate::strategy::LimitKey;
+
+
+// Splits the budget of large buckets between the proxy instances sharing Redis: every instance consumes from its own
+// sub-bucket, so the hottest keys don't all hit the same Redis key, and the shares always add up to the configured limit.
+// Shares follow the instances that joined or left on the next heartbeat, buckets already in the store keep their capacity until their window ends.
+#[derive(Clone, Debug)]
+pub struct Partitioner {
+    cluster: Arc<Cluster>,
+    min_tokens: u32,
+}
+
+impl Partitioner {
+    pub fn new(settings: &PartitionSettings, cluster: Arc<Cluster>) -> Self {
+        Self {
+            cluster,
+            min_tokens: settings.min_tokens,
+        }
+    }
+
+    // Replaces the bucket with the share of this instance. Buckets smaller than `min_tokens` or than the number of instances stay shared,
+    // as rounding would take too much from them
+    pub fn partition(&self, limit_key: &mut LimitKey) {
+        let (rank, instances) = self.cluster.position();
+        let bucket = &mut limit_key.bucket;
+        if instances <= 1 || bucket.capacity() < self.min_tokens || bucket.tokens_count < instances || bucket.burst.is_some_and(|burst| burst < instances) {
+            return;
+        }
+
+        bucket.tokens_count = share(bucket.tokens_count, rank, instances);
+        bucket.burst = bucket.burst.map(|burst| share(burst, rank, instances));
+        limit_key.key = format!("{}:{}", limit_key.key, self.cluster.instance_id());
+    }
+}
+
+// The remainder goes to the first instances, one token each
+fn share(tokens: u32, rank: u32, instances: u32) -> u32 {
+    tokens / instances + u32::from(rank < tokens % instances)
+}
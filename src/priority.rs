@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+use axum::http::HeaderName;
+use crate::error::RateLimiterError;
+use crate::settings::PriorityClassSettings;
+use crate::strategy::{header_value, SafeRequest};
+
+
+// Share of the capacity a request may use, added to the requests the limiter lets through so load shedding sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    pub share_percent: u8,
+}
+
+impl Priority {
+    pub const FULL: Priority = Priority { share_percent: 100 };
+
+    // Part of `capacity` kept for requests of higher classes
+    pub fn reserved(&self, capacity: u32) -> u32 {
+        capacity - (capacity as u64 * self.share_percent as u64 / 100) as u32
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct PriorityClass {
+    header: Option<HeaderName>,
+    values: HashSet<String>,
+    path_prefixes: Vec<String>,
+    priority: Priority,
+}
+
+impl PriorityClass {
+    fn matches(&self, request: &SafeRequest) -> bool {
+        let header_matches = self.header.as_ref()
+            .and_then(|header| request.parts.headers.get(header))
+            .is_some_and(|value| self.values.contains(header_value(value).as_ref()));
+        let path = request.parts.uri.path();
+        let path_matches = self.path_prefixes.iter()
+            .any(|prefix| path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')));
+        header_matches || path_matches
+    }
+}
+
+
+// Sorts requests into priority classes by header value or path prefix, the first matching class wins.
+// Requests of no class use the full capacity.
+#[derive(Debug, Clone, Default)]
+pub struct PriorityClasses {
+    classes: Vec<PriorityClass>,
+}
+
+impl PriorityClasses {
+    pub fn new(settings: &[PriorityClassSettings]) -> Result<Self, RateLimiterError> {
+        let classes = settings.iter()
+            .map(|class| {
+                if class.share_percent == 0 || class.share_percent > 100 {
+                    return Err(RateLimiterError::config(format!("share_percent of priority class {} must be between 1 and 100", class.name)));
+                }
+                if class.header.is_none() && class.path_prefixes.is_empty() {
+                    return Err(RateLimiterError::config(format!("Priority class {} needs a header or path_prefixes", class.name)));
+                }
+                if let Some(prefix) = class.path_prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+                    return Err(RateLimiterError::config(format!("Path prefix {} of priority class {} must start with /", prefix, class.name)));
+                }
+                let header = class.header.as_deref()
+                    .map(HeaderName::try_from)
+                    .transpose()
+                    .map_err(|e| RateLimiterError::config(format!("Invalid header of priority class {}: {}", class.name, e)))?;
+
+                Ok(PriorityClass {
+                    header,
+                    values: class.values.iter().cloned().collect(),
+                    path_prefixes: class.path_prefixes.iter().map(|prefix| prefix.trim_end_matches('/').to_string()).collect(),
+                    priority: Priority { share_percent: class.share_percent },
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            classes,
+        })
+    }
+
+    pub fn classify(&self, request: &SafeRequest) -> Priority {
+        self.classes.iter()
+            .find(|class| class.matches(request))
+            .map_or(Priority::FULL, |class| class.priority)
+    }
+}
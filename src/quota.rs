@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use axum::http::HeaderName;
+use serde::Serialize;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::{QuotaPeriod, QuotaSettings};
+use crate::strategy::{header_value, Bucket, LimitKey, SafeRequest};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaUsage {
+    pub limit: u32,
+    // Negative once the quota is used up
+    pub remaining: i32,
+    // Seconds until the current period ends
+    pub resets_in: u64,
+}
+
+impl QuotaUsage {
+    pub fn is_exceeded(&self) -> bool {
+        self.remaining < 0
+    }
+}
+
+// Budget a client has left in one quota, as read by `RateLimiterManager::peek_quotas`
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaStatus {
+    pub quota: String,
+    pub limit: u32,
+    pub remaining: u32,
+    pub resets_in: u64,
+}
+
+
+#[derive(Debug)]
+pub struct Quota {
+    pub name: String,
+    header: HeaderName,
+    period: QuotaPeriod,
+    limit: u32,
+    limits_per_value: HashMap<String, u32>,
+}
+
+impl Quota {
+    pub fn new(settings: &QuotaSettings) -> Result<Self, RateLimiterError> {
+        let header = HeaderName::try_from(settings.header.as_str())
+            .map_err(|e| RateLimiterError::config(format!("Invalid header {} of quota {}: {}", settings.header, settings.name, e)))?;
+
+        Ok(Self {
+            name: settings.name.clone(),
+            header,
+            period: settings.period,
+            limit: settings.limit,
+            limits_per_value: settings.limits_per_value.iter().map(|plan| (plan.value.clone(), plan.limit)).collect(),
+        })
+    }
+
+    pub fn client_value(&self, request: &SafeRequest) -> Option<String> {
+        request.parts.headers.get(&self.header).map(|value| header_value(value).into_owned())
+    }
+
+    // The period is part of the key and the bucket expires when the period ends, so every period starts with a fresh budget
+    pub fn get_key(&self, value: &str, key_builder: &KeyBuilder, now: u64) -> (LimitKey, u64) {
+        let (period_id, resets_in) = current_period(self.period, now);
+        let limit = self.limits_per_value.get(value).copied().unwrap_or(self.limit);
+
+        let key = key_builder.build("quota", &format!("{}:{}:{}", self.name, period_id, value));
+        let bucket = Bucket::new(limit, u32::try_from(resets_in).unwrap_or(u32::MAX));
+        (LimitKey::new(key, bucket), resets_in)
+    }
+}
+
+
+// Returns the id of the UTC calendar period containing `now` and the seconds left until it ends
+fn current_period(period: QuotaPeriod, now: u64) -> (String, u64) {
+    let days = now / SECONDS_PER_DAY;
+    let left_today = SECONDS_PER_DAY - now % SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+
+    match period {
+        QuotaPeriod::Daily => (format!("{:04}-{:02}-{:02}", year, month, day), left_today),
+        QuotaPeriod::Monthly => (
+            format!("{:04}-{:02}", year, month),
+            (days_in_month(year, month) - day) as u64 * SECONDS_PER_DAY + left_today,
+        ),
+    }
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) date, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u64, u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: u64, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
@@ -0,0 +1,82 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use crate::error::RateLimiterError;
+use crate::ip_list::IpList;
+use crate::key::KeyBuilder;
+use crate::metrics;
+use crate::settings::{ReputationAction, ReputationListSettings};
+use crate::store::LimitStore;
+use crate::strategy::Bucket;
+
+
+#[derive(Debug)]
+struct ReputationList {
+    list: Arc<IpList>,
+    // Only set for the limit action
+    bucket: Option<Bucket>,
+}
+
+impl ReputationList {
+    fn new(settings: &ReputationListSettings) -> Result<Self, RateLimiterError> {
+        let name = &settings.name;
+        let bucket = match (settings.action, &settings.bucket) {
+            (ReputationAction::Limit, Some(bucket)) if bucket.tokens_count == 0 || bucket.add_tokens_every == 0 => {
+                return Err(RateLimiterError::config(format!("The bucket of reputation list {} must have tokens_count and add_tokens_every greater than 0", name)));
+            },
+            (ReputationAction::Limit, Some(bucket)) => Some(Bucket::from(bucket)),
+            (ReputationAction::Limit, None) => return Err(RateLimiterError::config(format!("Reputation list {} limits clients, it needs a bucket", name))),
+            (ReputationAction::Block, _) => None,
+        };
+
+        Ok(Self {
+            list: IpList::new("reputation list", name, settings.url.as_deref(), settings.path.as_deref(), settings.refresh_secs)?,
+            bucket,
+        })
+    }
+}
+
+
+// What a reputation list does to a listed client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Listed {
+    Blocked,
+    // Seconds until the bucket of the client refills
+    Limited(u32),
+}
+
+// Looks client IPs up in external reputation lists. The first list the client is blocked or limited by decides.
+#[derive(Clone, Debug)]
+pub struct Reputation {
+    lists: Vec<Arc<ReputationList>>,
+}
+
+impl Reputation {
+    // Must be called inside a tokio runtime, as it spawns the refresh of the lists
+    pub fn new(settings: &[ReputationListSettings]) -> Result<Self, RateLimiterError> {
+        let lists = settings.iter()
+            .map(|settings| ReputationList::new(settings).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { lists })
+    }
+
+    // Limited clients are charged to the bucket of the list, store errors let them through like quotas do
+    pub async fn check(&self, ip: IpAddr, store: &dyn LimitStore, key_builder: &KeyBuilder) -> Option<Listed> {
+        for reputation_list in self.lists.iter().filter(|reputation_list| reputation_list.list.contains(ip)) {
+            let name = &reputation_list.list.name;
+            let Some(bucket) = &reputation_list.bucket else {
+                metrics::increment_counter("rate_limiter_reputation_listed_total", &[("list", name), ("action", "block")]);
+                return Some(Listed::Blocked);
+            };
+            metrics::increment_counter("rate_limiter_reputation_listed_total", &[("list", name), ("action", "limit")]);
+            match store.consume(&key_builder.build(&format!("reputation:{}", name), &ip.to_canonical().to_string()), bucket, 1).await {
+                Ok(remaining) if remaining < 0 => return Some(Listed::Limited(bucket.reset_secs())),
+                Ok(_) => {},
+                Err(e) => {
+                    println!("Store error in reputation list {}, allowing the request: {}", name, e);
+                    metrics::increment_counter("rate_limiter_store_errors_total", &[("limiter", "reputation"), ("action", "allow")]);
+                },
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,111 @@
+use std::hash::Hasher;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use axum::http::HeaderName;
+use dashmap::DashMap;
+use siphasher::sip::SipHasher13;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::metrics;
+use crate::settings::RetryBudgetSettings;
+use crate::store::LimitStore;
+use crate::strategy::{header_value, Bucket, SafeRequest};
+
+
+// Charges the retries of a client to a budget of their own. A retry is a request repeating one the client sent within
+// `interval`, or one marked by the retry count header. Recent requests are remembered by every proxy instance on its own,
+// the budget is kept in the store.
+#[derive(Debug)]
+pub struct RetryBudget {
+    header: Option<HeaderName>,
+    retry_count_header: HeaderName,
+    bucket: Bucket,
+    interval: Duration,
+    max_requests: usize,
+    // When each request was last seen, by hash of the client and the request
+    seen_at: Arc<DashMap<u64, Instant>>,
+}
+
+impl RetryBudget {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of old requests
+    pub fn new(settings: &RetryBudgetSettings) -> Result<Self, RateLimiterError> {
+        if settings.retries == 0 || settings.window_secs == 0 || settings.interval_ms == 0 {
+            return Err(RateLimiterError::config("retries, window_secs and interval_ms of retry_budget must be greater than 0"));
+        }
+        let header_name = |setting: &str, name: &str| HeaderName::try_from(name)
+            .map_err(|_| RateLimiterError::config(format!("Invalid retry_budget.{} {:?}", setting, name)));
+        let interval = Duration::from_millis(settings.interval_ms);
+        let seen_at = Arc::new(DashMap::new());
+        tokio::spawn(remove_old(Arc::downgrade(&seen_at), interval));
+
+        Ok(Self {
+            header: settings.header.as_deref().map(|name| header_name("header", name)).transpose()?,
+            retry_count_header: header_name("retry_count_header", &settings.retry_count_header)?,
+            bucket: Bucket::new(settings.retries, settings.window_secs),
+            interval,
+            max_requests: settings.max_requests,
+            seen_at,
+        })
+    }
+
+    // Charges the request to the budget of its client if it's a retry, and returns the seconds until the budget refills
+    // once it's exhausted. Requests without the client header aren't counted. Like quotas, the budget fails open.
+    pub async fn check(&self, request: &SafeRequest, addr: SocketAddr, store: &dyn LimitStore, key_builder: &KeyBuilder) -> Option<u32> {
+        let client = match &self.header {
+            Some(header) => header_value(request.parts.headers.get(header)?).into_owned(),
+            None => addr.ip().to_string(),
+        };
+        if !self.is_retry(request, &client) {
+            return None;
+        }
+
+        metrics::increment_counter("rate_limiter_retries_total", &[]);
+        match store.consume(&key_builder.build("retry", &client), &self.bucket, 1).await {
+            Ok(remaining) if remaining < 0 => {
+                metrics::increment_counter("rate_limiter_retry_budget_exceeded_total", &[]);
+                Some(self.bucket.reset_secs())
+            },
+            Ok(_) => None,
+            Err(e) => {
+                println!("Store error in retry budget, allowing the request: {}", e);
+                metrics::increment_counter("rate_limiter_store_errors_total", &[("limiter", "retry_budget"), ("action", "allow")]);
+                None
+            },
+        }
+    }
+
+    fn is_retry(&self, request: &SafeRequest, client: &str) -> bool {
+        let is_marked = request.parts.headers.get(&self.retry_count_header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .is_some_and(|count| count > 0);
+
+        let mut hasher = SipHasher13::new();
+        hasher.write(client.as_bytes());
+        hasher.write(request.parts.method.as_str().as_bytes());
+        hasher.write(request.parts.uri.path_and_query().map_or("", |path| path.as_str()).as_bytes());
+        hasher.write(&request.body);
+        let fingerprint = hasher.finish();
+
+        let now = Instant::now();
+        // New requests are dropped once full, only the retry count header tells their retries apart then
+        if self.seen_at.len() >= self.max_requests && !self.seen_at.contains_key(&fingerprint) {
+            return is_marked;
+        }
+        let seen_at = self.seen_at.insert(fingerprint, now);
+        is_marked || seen_at.is_some_and(|seen_at| now.duration_since(seen_at) < self.interval)
+    }
+}
+
+async fn remove_old(seen_at: Weak<DashMap<u64, Instant>>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+
+        let Some(seen_at) = seen_at.upgrade() else {
+            return;
+        };
+        seen_at.retain(|_, seen_at| seen_at.elapsed() < interval);
+    }
+}
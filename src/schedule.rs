@@ -0,0 +1,95 @@
+use crate::error::RateLimiterError;
+use crate::settings::ScheduleSettings;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+
+// A weekly time window in a fixed UTC offset
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    // Indexed like DAY_NAMES
+    days: [bool; 7],
+    // Minutes since midnight, the end is excluded
+    hours: Option<(i64, i64)>,
+    offset_minutes: i64,
+}
+
+impl Schedule {
+    pub fn new(settings: &ScheduleSettings) -> Result<Self, RateLimiterError> {
+        let mut days = [settings.days.is_empty(); 7];
+        for day in &settings.days {
+            let index = DAY_NAMES.iter().position(|name| name.eq_ignore_ascii_case(day))
+                .ok_or_else(|| invalid(format!("Invalid schedule day {}, expected one of {}", day, DAY_NAMES.join(", "))))?;
+            days[index] = true;
+        }
+
+        let hours = match &settings.hours {
+            Some(hours) => {
+                let (start, end) = hours.split_once('-')
+                    .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+                    .ok_or_else(|| invalid(format!("Invalid schedule hours {}, expected HH:MM-HH:MM", hours)))?;
+                Some((start, end))
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            days,
+            hours,
+            offset_minutes: parse_offset(&settings.timezone)
+                .ok_or_else(|| invalid(format!("Invalid schedule timezone {}, expected UTC or an offset such as +02:00", settings.timezone)))?,
+        })
+    }
+
+    // `now` is in seconds since the unix epoch
+    pub fn is_active(&self, now: u64) -> bool {
+        self.is_active_at(now as i64 / 60)
+    }
+
+    fn is_active_at(&self, unix_minutes: i64) -> bool {
+        let local_minutes = unix_minutes + self.offset_minutes;
+        let minute_of_day = local_minutes.rem_euclid(MINUTES_PER_DAY);
+        // 1970-01-01 was a Thursday
+        let weekday = (local_minutes.div_euclid(MINUTES_PER_DAY) + 3).rem_euclid(7);
+
+        match self.hours {
+            None => self.days[weekday as usize],
+            Some((start, end)) if start < end => self.days[weekday as usize] && (start..end).contains(&minute_of_day),
+            // A window wrapping around midnight belongs to the day it starts on
+            Some((start, end)) => {
+                (self.days[weekday as usize] && minute_of_day >= start)
+                    || (self.days[(weekday as usize + 6) % 7] && minute_of_day < end)
+            },
+        }
+    }
+}
+
+fn invalid(message: String) -> RateLimiterError {
+    RateLimiterError::config(message)
+}
+
+fn parse_time(time: &str) -> Option<i64> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+    match (hours, minutes) {
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        // Lets ranges end at midnight, e.g. 18:00-24:00
+        (24, 0) => Some(MINUTES_PER_DAY),
+        _ => None,
+    }
+}
+
+fn parse_offset(timezone: &str) -> Option<i64> {
+    if timezone.eq_ignore_ascii_case("utc") || timezone == "Z" {
+        return Some(0);
+    }
+
+    let (sign, offset) = match timezone.split_at_checked(1)? {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return None,
+    };
+    let minutes = parse_time(offset)?;
+    (minutes < MINUTES_PER_DAY).then_some(sign * minutes)
+}
@@ -1,17 +1,51 @@
+use std::convert::Infallible;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use axum::body::Body;
-use axum::extract::State;
-use axum::http::{Request, Response, StatusCode};
-use axum::middleware::{from_fn_with_state};
-use axum::response::IntoResponse;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{Request, StatusCode};
+use hyper::body::Incoming;
+use axum::response::{IntoResponse, Response};
 use axum::Router;
 use axum::routing::any;
 use axum_proxy::AppendSuffix;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::TcpSocket;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
 use tower_service::Service;
-use crate::limiter;
-use crate::limiter::{RateLimiterManager};
-use crate::settings::{ApiGatewaySettings, Settings};
+use url::Url;
+use crate::admin::{AdminAccess, AdminServer};
+use crate::coalesce::{self, Coalescer};
+use crate::concurrency_limit::{self, ConcurrencyLimit};
+use crate::memory_budget::{self, MemoryBudget};
+use crate::connection_guard::{self, ConnectionGuard};
+use crate::decision;
+use crate::discovery::Discovery;
+#[cfg(feature = "envoy")]
+use crate::envoy;
+use crate::error::RateLimiterError;
+use crate::fair_queue::FairQueue;
+use crate::layer::RateLimitLayer;
+use crate::load_shedding::LoadShedder;
+use crate::limiter::RateLimiterManager;
+use crate::maintenance::{self, Maintenance};
+use crate::priority::Priority;
+use crate::settings::{ApiGatewaySettings, ServerMode, Settings};
+use crate::split::TrafficSplit;
+use crate::strategy::SafeRequest;
+use crate::tenant::{self, Tenant};
+#[cfg(unix)]
+use crate::unix;
+
+const UPSTREAM_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// Same backlog as `TcpListener::bind` uses on Linux
+#[cfg(unix)]
+const LISTEN_BACKLOG: u32 = 1024;
 
 pub struct ProxyServer {
     settings: Settings
@@ -24,41 +58,459 @@ impl ProxyServer {
         }
     }
 
-    pub async fn run(self) -> Result<(), std::io::Error>{
-        let listener = tokio::net::TcpListener::bind(self.settings.api_gateway_settings.proxy_server_addr.clone())
-            .await?;
+    pub async fn run(self) -> Result<(), RateLimiterError>{
+        check_listeners(&self.settings)?;
+        let listeners = self.settings.listeners();
 
         let limiter = Arc::new(
-            RateLimiterManager::new(self.settings.rate_limiter_settings.clone()).map_err(
-                |e| std::io::Error::new(std::io::ErrorKind::Other,  e)
-            )?
+            RateLimiterManager::new(self.settings.rate_limiter_settings.clone())?
         );
-        
-        let app = Router::new()
-            .route("/*path", any(handler))
-            .route("/", any(handler))
-            .layer(from_fn_with_state(limiter, limiter::middleware))
-            .with_state(Arc::new(self.settings.api_gateway_settings));
+        limiter.check_store_connection().await?;
+
+        check_tenant_names(&self.settings)?;
+        let mut tenants = Vec::with_capacity(self.settings.tenants_settings.len());
+        for tenant_settings in &self.settings.tenants_settings {
+            tenants.push(Tenant::new(tenant_settings).await?);
+        }
+        let tenants = Arc::new(tenants);
+        let maintenance = Arc::new(Maintenance::new(&self.settings.maintenance_settings)?);
+        let memory_budget = self.settings.memory_budget_settings.as_ref().map(MemoryBudget::new).transpose()?.map(Arc::new);
+
+        if let Some(admin_settings) = &self.settings.admin_settings {
+            check_admin_addr(&self.settings)?;
+            let access = AdminAccess::new(admin_settings)?;
+            let listener = TcpListener::bind(admin_settings.addr.as_str()).await?;
+            let admin = AdminServer::new(access, self.settings.clone(), limiter.clone(), tenants.clone(), maintenance.clone());
+            tokio::spawn(async move {
+                if let Err(e) = admin.run(listener).await {
+                    eprintln!("Admin server error: {}", e);
+                }
+            });
+        }
+
+        // Every listener is bound before any of them starts serving, so a bad address fails the whole startup
+        let mut servers = JoinSet::new();
+        for listener_settings in listeners {
+            let limiter = match listener_settings.rate_limiter_settings {
+                Some(rate_limiter_settings) => {
+                    let limiter = Arc::new(RateLimiterManager::new(rate_limiter_settings)?);
+                    limiter.check_store_connection().await?;
+                    limiter
+                },
+                None => limiter.clone(),
+            };
+            let (listeners, app) = prepare_listener(listener_settings.api_gateway_settings, limiter, &tenants, &maintenance, memory_budget.clone()).await?;
+            for listener in listeners {
+                servers.spawn(listener.serve(app.clone()));
+            }
+        }
+
+        // The server stops as soon as one of the listeners fails
+        while let Some(result) = servers.join_next().await {
+            result.map_err(|e| RateLimiterError::Io(e.into()))??;
+        }
+        Ok(())
+    }
+}
+
+// Checks a configuration the way `run` does, without binding listeners, probing upstreams or connecting to stores.
+// Must not be called from async code: the background tasks of the candidate limiters are spawned on a runtime that never runs them.
+pub fn validate(settings: &Settings) -> Result<(), RateLimiterError> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let _runtime = runtime.enter();
+
+    check_listeners(settings)?;
+    let limiter = Arc::new(RateLimiterManager::new(settings.rate_limiter_settings.clone())?);
+    check_tenant_names(settings)?;
+    let tenants = settings.tenants_settings.iter()
+        .map(Tenant::build)
+        .collect::<Result<Vec<_>, RateLimiterError>>()?;
+    Maintenance::new(&settings.maintenance_settings)?;
+    settings.memory_budget_settings.as_ref().map(MemoryBudget::new).transpose()?;
+    if let Some(admin_settings) = &settings.admin_settings {
+        check_admin_addr(settings)?;
+        AdminAccess::new(admin_settings)?;
+    }
+
+    for listener_settings in settings.listeners() {
+        let limiter = match listener_settings.rate_limiter_settings {
+            Some(rate_limiter_settings) => Arc::new(RateLimiterManager::new(rate_limiter_settings)?),
+            None => limiter.clone(),
+        };
+        let settings = listener_settings.api_gateway_settings;
+        check_upstream(&settings)?;
+        ConnectionGuard::new(settings.connection_limit.as_ref(), settings.read_timeouts.as_ref(), limiter)?;
+        settings.load_shedding.as_ref().map(LoadShedder::new).transpose()?;
+        settings.fair_queue.as_ref().map(FairQueue::new).transpose()?;
+        settings.coalesce.as_ref().map(Coalescer::new).transpose()?;
+        settings.concurrency_limit.as_ref().map(ConcurrencyLimit::new).transpose()?;
+        settings.discovery.as_ref().map(Discovery::new).transpose()?;
+        if !settings.splits.is_empty() {
+            TrafficSplit::new(&settings.splits, settings.sticky.as_ref())?;
+        }
+    }
+    for tenant in tenants.iter().filter(|tenant| !tenant.splits.is_empty()) {
+        TrafficSplit::new(&tenant.splits, tenant.sticky.as_ref())?;
+    }
+    Ok(())
+}
+
+fn check_listeners(settings: &Settings) -> Result<(), RateLimiterError> {
+    match settings.listeners().is_empty() {
+        true => Err(RateLimiterError::config("Either [api_gateway] or [[listeners]] must be defined")),
+        false => Ok(()),
+    }
+}
+
+fn check_tenant_names(settings: &Settings) -> Result<(), RateLimiterError> {
+    let mut tenant_names = HashSet::new();
+    for tenant_settings in &settings.tenants_settings {
+        if !tenant_names.insert(tenant_settings.name.as_str()) {
+            return Err(RateLimiterError::config(format!("Tenant {} is defined more than once", tenant_settings.name)));
+        }
+    }
+    Ok(())
+}
+
+// The admin server never shares a port with a proxy listener, where its endpoints would be served to any client
+fn check_admin_addr(settings: &Settings) -> Result<(), RateLimiterError> {
+    let Some(admin_settings) = &settings.admin_settings else {
+        return Ok(());
+    };
+    let Ok(admin_addr) = admin_settings.addr.parse::<SocketAddr>() else {
+        return Ok(());
+    };
+    for listener_settings in settings.listeners() {
+        let proxy_addr = listener_settings.api_gateway_settings.proxy_server_addr;
+        let Ok(addr) = proxy_addr.parse::<SocketAddr>() else {
+            continue;
+        };
+        let same_interface = addr.ip() == admin_addr.ip() || addr.ip().is_unspecified() || admin_addr.ip().is_unspecified();
+        if addr.port() == admin_addr.port() && addr.port() != 0 && same_interface {
+            return Err(RateLimiterError::config(format!("admin.addr {} overlaps the proxy listener {}", admin_settings.addr, proxy_addr)));
+        }
+    }
+    Ok(())
+}
 
-        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+fn check_upstream(settings: &ApiGatewaySettings) -> Result<(), RateLimiterError> {
+    let has_fixed_upstream = !settings.target_url.is_empty() || !settings.splits.is_empty();
+    if matches!(settings.mode, ServerMode::Proxy) && !has_fixed_upstream && settings.discovery.is_none() {
+        return Err(RateLimiterError::config(format!("target_url, splits or discovery of listener {} are required in proxy mode", settings.proxy_server_addr)));
+    }
+    if has_fixed_upstream && settings.discovery.is_some() {
+        return Err(RateLimiterError::config(format!("discovery of listener {} replaces target_url and splits, they can't be combined", settings.proxy_server_addr)));
+    }
+    Ok(())
+}
+
+enum Listener {
+    Tcp(TcpListener, Option<Arc<ConnectionGuard>>),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // Binds one listener per worker, the kernel then spreads incoming connections across their accept loops
+    // Workers share the connection guard, so a client gets the same number of connections whichever socket accepts them
+    async fn bind(addr: &str, workers: usize, connection_guard: Option<Arc<ConnectionGuard>>) -> Result<Vec<Self>, RateLimiterError> {
+        #[cfg(unix)]
+        if let Some(path) = unix::socket_path(addr) {
+            if workers > 1 {
+                return Err(RateLimiterError::config(format!("Listener {} is a Unix socket, which doesn't support workers", addr)));
+            }
+            if connection_guard.is_some() {
+                return Err(RateLimiterError::config(format!("Listener {} is a Unix socket, connection_limit and read_timeouts need a TCP address", addr)));
+            }
+            return Ok(vec![Self::Unix(unix::bind(path)?)]);
+        }
+
+        match workers {
+            0 => Err(RateLimiterError::config(format!("workers of listener {} must be at least 1", addr))),
+            1 => Ok(vec![Self::Tcp(TcpListener::bind(addr).await?, connection_guard)]),
+            _ => {
+                let socket_addr = tokio::net::lookup_host(addr).await?.next()
+                    .ok_or_else(|| RateLimiterError::config(format!("Listener address {} doesn't resolve", addr)))?;
+                (0..workers).map(|_| bind_reuseport(socket_addr).map(|listener| Self::Tcp(listener, connection_guard.clone()))).collect()
+            },
+        }
+    }
+
+    async fn serve(self, app: Router) -> Result<(), RateLimiterError> {
+        match self {
+            Self::Tcp(listener, None) => axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?,
+            Self::Tcp(listener, Some(connection_guard)) => connection_guard::serve(listener, app, connection_guard).await?,
+            #[cfg(unix)]
+            Self::Unix(listener) => unix::serve(listener, app).await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn bind_reuseport(addr: SocketAddr) -> Result<TcpListener, RateLimiterError> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(LISTEN_BACKLOG)?)
+}
+
+#[cfg(not(unix))]
+fn bind_reuseport(addr: SocketAddr) -> Result<TcpListener, RateLimiterError> {
+    Err(RateLimiterError::config(format!("Listener {} has several workers, but SO_REUSEPORT is only available on Unix", addr)))
+}
+
+async fn prepare_listener(settings: ApiGatewaySettings, limiter: Arc<RateLimiterManager>, tenants: &[Tenant], maintenance: &Arc<Maintenance>, memory_budget: Option<Arc<MemoryBudget>>) -> Result<(Vec<Listener>, Router), RateLimiterError> {
+    let connection_guard = ConnectionGuard::new(settings.connection_limit.as_ref(), settings.read_timeouts.as_ref(), limiter.clone())?.map(Arc::new);
+    let listeners = Listener::bind(&settings.proxy_server_addr, settings.workers, connection_guard).await?;
+
+    check_upstream(&settings)?;
+    let is_proxy = matches!(settings.mode, ServerMode::Proxy);
+    let discovery = match &settings.discovery {
+        Some(discovery_settings) if is_proxy => {
+            let discovery = Discovery::new(discovery_settings)?;
+            discovery.start().await;
+            Some(discovery)
+        },
+        _ => None,
+    };
+    if is_proxy && settings.probe_on_startup {
+        let listener_upstreams = Some(settings.target_url.as_str()).filter(|target_url| !target_url.is_empty())
+            .into_iter()
+            .chain(settings.splits.iter().map(|split| split.target_url.as_str()));
+        let tenant_upstreams = tenants.iter()
+            .flat_map(|tenant| tenant.target_url.as_deref().into_iter().chain(tenant.splits.iter().map(|split| split.target_url.as_str())));
+        for target_url in listener_upstreams.chain(tenant_upstreams) {
+            probe_upstream(target_url).await?;
+        }
+        if let Some(discovery) = &discovery {
+            let upstreams = discovery.upstreams();
+            if upstreams.is_empty() {
+                return Err(RateLimiterError::Upstream(format!("Discovery {} found no upstream; set probe_on_startup = false to start without one", discovery.name())));
+            }
+            for target_url in &upstreams {
+                probe_upstream(target_url).await?;
+            }
+        }
+    }
+
+    if let Some(grpc_addr) = settings.grpc_addr.clone() {
+        serve_grpc(grpc_addr, limiter.clone())?;
+    }
+
+    // Tenants share the upstreams of the listener, so they share the in-flight counts, queues and discovered upstreams too
+    let load_shedder = settings.load_shedding.as_ref().map(LoadShedder::new).transpose()?.map(Arc::new);
+    let fair_queue = settings.fair_queue.as_ref().map(FairQueue::new).transpose()?.map(Arc::new);
+    let concurrency_limit = settings.concurrency_limit.as_ref().map(ConcurrencyLimit::new).transpose()?;
+    let app = match settings.mode {
+        ServerMode::Proxy if tenants.is_empty() => proxy_router(settings, limiter, maintenance.clone(), load_shedder, fair_queue, discovery)?,
+        ServerMode::Proxy => {
+            let tenant_routers = tenants.iter()
+                .map(|tenant| {
+                    // A tenant with its own upstream replaces the whole upstream of the listener
+                    let mut tenant_settings = settings.clone();
+                    let mut tenant_discovery = discovery.clone();
+                    if tenant.target_url.is_some() || !tenant.splits.is_empty() {
+                        tenant_settings.target_url = tenant.target_url.clone().unwrap_or_default();
+                        tenant_settings.splits = tenant.splits.clone();
+                        tenant_settings.sticky = tenant.sticky.clone();
+                        tenant_discovery = None;
+                    }
+                    let router = proxy_router(tenant_settings, tenant.limiter.clone(), maintenance.clone(), load_shedder.clone(), fair_queue.clone(), tenant_discovery)?;
+                    Ok((tenant.matcher.clone(), router))
+                })
+                .collect::<Result<Vec<_>, RateLimiterError>>()?;
+            tenant::router(tenant_routers, proxy_router(settings, limiter, maintenance.clone(), load_shedder, fair_queue, discovery)?)
+        },
+        ServerMode::Decision => decision::router(limiter),
+    };
+    // Covers every tenant and both modes of the listener
+    let app = match concurrency_limit {
+        Some(concurrency_limit) => app.layer(axum::middleware::from_fn_with_state(Arc::new(concurrency_limit), concurrency_limit::middleware)),
+        None => app,
+    };
+    // Outside the concurrency limit, so requests waiting in its queue hold their bytes too
+    let app = match memory_budget {
+        Some(memory_budget) => app.layer(axum::middleware::from_fn_with_state(memory_budget, memory_budget::middleware)),
+        None => app,
+    };
+
+    Ok((listeners, app))
+}
+
+struct ProxyState {
+    target_url: String,
+    split: Option<TrafficSplit>,
+    load_shedder: Option<Arc<LoadShedder>>,
+    fair_queue: Option<Arc<FairQueue>>,
+    discovery: Option<Arc<Discovery>>,
+}
+
+fn proxy_router(
+    settings: ApiGatewaySettings,
+    limiter: Arc<RateLimiterManager>,
+    maintenance: Arc<Maintenance>,
+    load_shedder: Option<Arc<LoadShedder>>,
+    fair_queue: Option<Arc<FairQueue>>,
+    discovery: Option<Arc<Discovery>>,
+) -> Result<Router, RateLimiterError> {
+    let split = match settings.splits.is_empty() {
+        true => None,
+        false => Some(TrafficSplit::new(&settings.splits, settings.sticky.as_ref())?),
+    };
+
+    let coalescer = settings.coalesce.as_ref().map(Coalescer::new).transpose()?.map(Arc::new);
+
+    let mut router = Router::new()
+        .route("/*path", any(handler))
+        .route("/", any(handler));
+    // Charged duplicates are coalesced once the limiters let them through, the others before the limiters see them
+    if let Some(coalescer) = coalescer.clone().filter(|coalescer| coalescer.charges_duplicates()) {
+        router = router.layer(axum::middleware::from_fn_with_state(coalescer, coalesce::middleware));
+    }
+    router = router.layer(RateLimitLayer::from_manager(limiter.clone()));
+    if let Some(coalescer) = coalescer.filter(|coalescer| !coalescer.charges_duplicates()) {
+        router = router.layer(axum::middleware::from_fn_with_state(coalescer, coalesce::middleware));
+    }
+    Ok(router
+        .layer(axum::middleware::from_fn_with_state((maintenance, limiter), maintenance::middleware))
+        .with_state(Arc::new(ProxyState { target_url: settings.target_url, split, load_shedder, fair_queue, discovery })))
+}
+
+#[cfg(feature = "envoy")]
+fn serve_grpc(grpc_addr: String, limiter: Arc<RateLimiterManager>) -> Result<(), RateLimiterError> {
+    tokio::spawn(async move {
+        if let Err(e) = envoy::serve(&grpc_addr, limiter).await {
+            eprintln!("gRPC server error: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "envoy"))]
+fn serve_grpc(_grpc_addr: String, _limiter: Arc<RateLimiterManager>) -> Result<(), RateLimiterError> {
+    Err(RateLimiterError::config("api_gateway.grpc_addr requires building with the envoy feature"))
+}
+
+// Opens a TCP connection to the upstream, so a wrong target_url shows up on startup instead of on the first request
+async fn probe_upstream(target_url: &str) -> Result<(), RateLimiterError> {
+    #[cfg(unix)]
+    if let Some(path) = unix::socket_path(target_url) {
+        return probe_result(target_url, tokio::time::timeout(UPSTREAM_PROBE_TIMEOUT, UnixStream::connect(path)).await);
+    }
+
+    // target_url is usually given as host:port without a scheme
+    let url = match target_url.contains("://") {
+        true => Url::parse(target_url),
+        false => Url::parse(&format!("http://{}", target_url)),
+    }
+        .map_err(|e| RateLimiterError::config(format!("Invalid target_url {}: {}", target_url, e)))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(RateLimiterError::config(format!("target_url {} has no host or port", target_url)));
+    };
+
+    probe_result(target_url, tokio::time::timeout(UPSTREAM_PROBE_TIMEOUT, TcpStream::connect((host, port))).await)
+}
+
+fn probe_result<T>(target_url: &str, result: Result<Result<T, std::io::Error>, tokio::time::error::Elapsed>) -> Result<(), RateLimiterError> {
+    match result {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(RateLimiterError::Upstream(format!(
+            "Upstream {} is not reachable: {}; check target_url or set probe_on_startup = false", target_url, e,
+        ))),
+        Err(_) => Err(RateLimiterError::Upstream(format!(
+            "Upstream {} did not accept a connection within {:?}", target_url, UPSTREAM_PROBE_TIMEOUT,
+        ))),
     }
 }
 
 async fn handler(
-    State(settings): State<Arc<ApiGatewaySettings>>,
+    State(state): State<Arc<ProxyState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     request: Request<Body>,
 ) -> impl IntoResponse {
-    let host = axum_proxy::builder_http(settings.target_url.clone()).unwrap();
-    let mut svc = host.build(AppendSuffix(""));
+    let discovered = match &state.discovery {
+        Some(discovery) => match discovery.select() {
+            Some(target_url) => Some(target_url),
+            None => return (StatusCode::SERVICE_UNAVAILABLE, "No upstream available").into_response(),
+        },
+        None => None,
+    };
+    let target_url = discovered.as_deref().unwrap_or(&state.target_url);
+    if state.split.is_none() && state.fair_queue.is_none() {
+        return forward_unless_overloaded(&state, target_url, request).await;
+    }
+
+    // Sticky and client values may be read from the body, which the limiter may already have buffered
+    let needs_body = state.split.as_ref().is_some_and(|split| split.needs_body()) || state.fair_queue.as_ref().is_some_and(|fair_queue| fair_queue.needs_body());
+    let (safe_request, unread_body) = match SafeRequest::read(request, needs_body).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let target_url = match &state.split {
+        Some(split) => split.select(&safe_request, addr),
+        None => target_url,
+    };
+    // Queued requests were already charged by the limiters, and hold their turn until the upstream answered
+    let _turn = match &state.fair_queue {
+        Some(fair_queue) => match fair_queue.wait_turn(target_url, &safe_request, addr).await {
+            Ok(turn) => Some(turn),
+            Err(response) => return response,
+        },
+        None => None,
+    };
+    forward_unless_overloaded(&state, target_url, safe_request.with_body(unread_body)).await
+}
+
+// Requests shed because of an overloaded upstream were already charged by the limiters
+async fn forward_unless_overloaded(state: &ProxyState, target_url: &str, request: Request<Body>) -> Response {
+    let _in_flight = match &state.load_shedder {
+        Some(load_shedder) => match load_shedder.acquire(target_url, request.extensions().get::<Priority>().copied().unwrap_or(Priority::FULL)) {
+            Some(in_flight) => Some(in_flight),
+            None => return load_shedder.shed(target_url),
+        },
+        None => None,
+    };
+    forward(target_url, request).await
+}
 
+async fn forward(target_url: &str, request: Request<Body>) -> Response {
+    #[cfg(unix)]
+    if let Some(path) = unix::socket_path(target_url) {
+        // The connector always dials the socket, so the authority is only a placeholder
+        let client = axum_proxy::client::with_connector_default(unix::UnixConnector::new(path));
+        return match axum_proxy::builder(client, "http", "localhost") {
+            Ok(host) => proxy(host.build(AppendSuffix("")), request).await,
+            Err(err) => invalid_target_url(target_url, err),
+        };
+    }
+
+    match axum_proxy::builder_http(target_url.to_string()) {
+        Ok(host) => proxy(host.build(AppendSuffix("")), request).await,
+        Err(err) => invalid_target_url(target_url, err),
+    }
+}
+
+fn invalid_target_url(target_url: &str, err: impl std::fmt::Display) -> Response {
+    eprintln!("Invalid target url {}: {}", target_url, err);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+}
+
+async fn proxy<S>(mut svc: S, request: Request<Body>) -> Response
+where
+    S: Service<Request<Body>, Response = Result<Response<Incoming>, axum_proxy::Error>, Error = Infallible>,
+{
     match svc.call(request).await {
-        Ok(response) => response.unwrap().into_response(),
-        Err(err) => {
+        Ok(Ok(response)) => response.into_response(),
+        Ok(Err(axum_proxy::Error::RequestFailed(err))) if let Some(response) = connection_guard::read_error_response(&err) => response,
+        Ok(Err(err)) => {
             eprintln!("Error: {}", err);
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from("Internal Server Error"))
-                .unwrap()
-        }
+            (StatusCode::BAD_GATEWAY, "Bad Gateway").into_response()
+        },
+        Err(never) => match never {},
     }
 }
\ No newline at end of file
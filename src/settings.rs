@@ -1,34 +1,1378 @@
-use std::collections::{HashSet};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::net::IpAddr;
-use config::{Config, ConfigError, File};
-use serde::Deserialize;
+use config::{Config, ConfigError, Environment, File, FileFormat, Source};
+use serde::{Deserialize, Serialize, Serializer};
+use crate::strategy::LimitForRequest;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Settings {
     #[serde(rename = "rate_limiter")]
     pub rate_limiter_settings: RateLimiterSettings,
 
     #[serde(rename = "api_gateway")]
+    pub api_gateway_settings: Option<ApiGatewaySettings>,
+
+    #[serde(rename = "listeners", default)]
+    pub listeners_settings: Vec<ListenerSettings>,
+
+    #[serde(rename = "tenants", default)]
+    pub tenants_settings: Vec<TenantSettings>,
+
+    #[serde(rename = "admin")]
+    pub admin_settings: Option<AdminSettings>,
+
+    #[serde(rename = "runtime", default)]
+    pub runtime_settings: RuntimeSettings,
+
+    #[serde(rename = "maintenance", default)]
+    pub maintenance_settings: MaintenanceSettings,
+
+    #[serde(rename = "memory_budget")]
+    pub memory_budget_settings: Option<MemoryBudgetSettings>,
+}
+
+// Bytes of request headers and bodies all listeners may hold at once, requests over it are shed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MemoryBudgetSettings {
+    pub max_bytes: usize,
+    #[serde(default = "default_shed_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+// Static response of routes under maintenance and of the lockdown, which can both also be toggled on the admin server
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MaintenanceSettings {
+    #[serde(default = "default_maintenance_status")]
+    pub status: u16,
+    #[serde(default = "default_maintenance_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_maintenance_body")]
+    pub body: String,
+    // Path prefixes under maintenance on startup
+    #[serde(default)]
+    pub routes: Vec<String>,
+    // Blocks every client outside of the ip_whitelist
+    #[serde(default)]
+    pub lockdown: bool,
+    // Rejects this share of the requests of clients outside of the ip_whitelist, from 0 to 100
+    #[serde(default)]
+    pub throttle_percent: u8,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            status: default_maintenance_status(),
+            content_type: default_maintenance_content_type(),
+            body: default_maintenance_body(),
+            routes: Vec::new(),
+            lockdown: false,
+            throttle_percent: 0,
+        }
+    }
+}
+
+fn default_maintenance_status() -> u16 {
+    503
+}
+
+fn default_maintenance_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_maintenance_body() -> String {
+    r#"{"error":"Service under maintenance"}"#.to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RuntimeSettings {
+    // Threads of the Tokio runtime, defaults to the number of CPU cores
+    pub worker_threads: Option<usize>,
+}
+
+// An additional address to serve, with its own upstream and optionally its own limiters
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ListenerSettings {
+    #[serde(flatten)]
     pub api_gateway_settings: ApiGatewaySettings,
+
+    // Listeners without their own limiters share the ones of the top level [rate_limiter] table
+    #[serde(rename = "rate_limiter")]
+    pub rate_limiter_settings: Option<RateLimiterSettings>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// A customer or a route served by the same deployment, matched by Host header, path prefix and/or header values.
+// When several are set a request has to match all of them.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TenantSettings {
+    pub name: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub path_prefix: Option<String>,
+    // Header name to expected value, `*` only requires the header to be present
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // Defaults to the upstream of the listener
+    pub target_url: Option<String>,
+    #[serde(rename = "split", default)]
+    pub splits: Vec<SplitSettings>,
+    pub sticky: Option<StickySettings>,
+
+    // Store keys of the tenant are prefixed with `<keys.prefix>:<name>`
+    #[serde(rename = "rate_limiter")]
+    pub rate_limiter_settings: RateLimiterSettings,
+}
+
+// Bound apart from the proxy listeners, so operational endpoints are never served to the clients of the proxy
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminSettings {
+    pub addr: String,
+    // Hex SHA-256 of the bearer tokens by name, requests need none when empty
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    // Roles of the tokens by name, tokens without one are admin
+    #[serde(default)]
+    pub roles: HashMap<String, AdminRole>,
+    pub tls: Option<AdminTlsSettings>,
+}
+
+// Each role may call the endpoints of the roles before it
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    // Reads state and metrics
+    ReadOnly,
+    // Also responds to incidents: maintenance, lockdown, throttling and challenges
+    Operator,
+    // Also changes the limits of clients with overrides, and restores snapshots
+    #[default]
+    Admin,
+}
+
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminRole::ReadOnly => "read_only",
+            AdminRole::Operator => "operator",
+            AdminRole::Admin => "admin",
+        }
+    }
+}
+
+// Serves the admin endpoints over HTTPS, only to clients with a certificate signed by client_ca_path when set
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminTlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ApiGatewaySettings {
+    #[serde(default)]
+    pub mode: ServerMode,
+    #[serde(default)]
     pub target_url: String,
     pub proxy_server_addr: String,
+    #[serde(default)]
+    pub probe_on_startup: bool,
+    pub grpc_addr: Option<String>,
+    // Number of sockets bound to proxy_server_addr with SO_REUSEPORT, each with its own accept loop
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    // Weighted upstreams used instead of target_url
+    #[serde(rename = "split", default)]
+    pub splits: Vec<SplitSettings>,
+    pub sticky: Option<StickySettings>,
+    pub load_shedding: Option<LoadSheddingSettings>,
+    pub fair_queue: Option<FairQueueSettings>,
+    pub coalesce: Option<CoalesceSettings>,
+    // Upstreams found at runtime, used instead of target_url
+    pub discovery: Option<DiscoverySettings>,
+    pub connection_limit: Option<ConnectionLimitSettings>,
+    pub read_timeouts: Option<ReadTimeoutSettings>,
+    pub concurrency_limit: Option<ConcurrencyLimitSettings>,
+}
+
+// Caps the requests the listener handles at once, whatever their upstream
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ConcurrencyLimitSettings {
+    pub max_in_flight: usize,
+    // Requests waiting for a slot, new ones are rejected above it
+    #[serde(default = "default_concurrency_max_queued")]
+    pub max_queued: usize,
+    #[serde(default = "default_concurrency_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    #[serde(default = "default_shed_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+fn default_concurrency_max_queued() -> usize {
+    1000
+}
+
+fn default_concurrency_queue_timeout_ms() -> u64 {
+    5000
+}
+
+// Caps TCP connections per client IP when they are accepted, before any HTTP is parsed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ConnectionLimitSettings {
+    // New connections per second
+    pub max_per_ip_per_sec: Option<u32>,
+    // Connections open at once
+    pub max_concurrent_per_ip: Option<u32>,
+}
+
+// Clients sending slower are answered with a 408 and disconnected
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadTimeoutSettings {
+    // From the start of a request, or of waiting for the next one on a kept-alive connection, until all headers are in
+    pub headers_ms: Option<u64>,
+    // From the headers until the whole body is in
+    pub body_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoverySettings {
+    Dns(DnsDiscoverySettings),
+    Kubernetes(KubernetesDiscoverySettings),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DnsDiscoverySettings {
+    // A and AAAA records of `host` with `port`, or SRV records of `srv` with the ports they give
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub srv: Option<String>,
+    // Records are looked up again once their TTL ran out, within these bounds
+    #[serde(default = "default_dns_min_refresh_secs")]
+    pub min_refresh_secs: u64,
+    #[serde(default = "default_dns_max_refresh_secs")]
+    pub max_refresh_secs: u64,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+fn default_dns_min_refresh_secs() -> u64 {
+    5
+}
+
+fn default_dns_max_refresh_secs() -> u64 {
+    300
+}
+
+// Watches the EndpointSlices of a Service and sends requests to its ready pods directly
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KubernetesDiscoverySettings {
+    pub service: String,
+    // Defaults to the namespace of the pod
+    pub namespace: Option<String>,
+    // Name of the port to use when the Service has more than one
+    pub port_name: Option<String>,
+    // Defaults to the in-cluster API server with the service account of the pod, e.g. http://127.0.0.1:8001 for kubectl proxy
+    pub api_url: Option<String>,
+    // CA of an https API server, defaults to the one of the service account
+    pub ca_path: Option<String>,
+}
+
+// Sheds requests to an upstream that already has too many of them in flight
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoadSheddingSettings {
+    // High-water mark of requests waiting for a response, per upstream
+    pub max_in_flight: usize,
+    #[serde(default = "default_shed_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+fn default_shed_retry_after_secs() -> u64 {
+    1
+}
+
+// Identical GETs arriving while one of them is in flight wait for it and share its response
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CoalesceSettings {
+    // Duplicates are charged to the limiters like any request, otherwise they are coalesced before the limiters run
+    #[serde(default = "default_coalesce_charge_duplicates")]
+    pub charge_duplicates: bool,
+    // Requests only share a response when these headers are equal too
+    #[serde(default = "default_coalesce_vary")]
+    pub vary: Vec<String>,
+    // Larger responses, and responses without a Content-Length, are not shared
+    #[serde(default = "default_coalesce_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_coalesce_charge_duplicates() -> bool {
+    true
+}
+
+fn default_coalesce_vary() -> Vec<String> {
+    ["Authorization", "Cookie", "Accept", "Accept-Encoding", "Accept-Language"].map(String::from).to_vec()
+}
+
+fn default_coalesce_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+// Queues requests above max_concurrent per upstream and lets them through by client in turn instead of first come,
+// first served. Clients are told apart like the limiter strategy does.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FairQueueSettings {
+    // Requests sent to one upstream at once
+    pub max_concurrent: usize,
+    // Requests waiting for one upstream, new ones are rejected above it
+    #[serde(default = "default_fair_queue_max_queued")]
+    pub max_queued: usize,
+    #[serde(default = "default_fair_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    #[serde(default = "default_fair_queue_strategy")]
+    pub strategy: PossibleStrategies,
+    // Header, query or body field names, like the values of buckets_per_value
+    #[serde(default)]
+    pub values: Vec<String>,
+    // Clients whose header has one of the values get that many turns, the others 1
+    pub tier_header: Option<String>,
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+}
+
+fn default_fair_queue_max_queued() -> usize {
+    1000
+}
+
+fn default_fair_queue_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_fair_queue_strategy() -> PossibleStrategies {
+    PossibleStrategies::IP
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SplitSettings {
+    pub target_url: String,
+    #[serde(default = "default_split_weight")]
+    pub weight: u32,
+}
+
+fn default_split_weight() -> u32 {
+    1
+}
+
+// Keeps a client on the same split upstream, the value is extracted like the limiter strategy does
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StickySettings {
+    pub strategy: PossibleStrategies,
+    // Header, query or body field names, like the values of buckets_per_value
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerMode {
+    #[default]
+    Proxy,
+    Decision,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct RateLimiterSettings {
-    pub redis_addr: String,
+    #[serde(default)]
+    pub backend: PossibleBackends,
+    #[serde(serialize_with = "redact")]
+    pub redis_addr: Option<String>,
+    #[serde(default)]
+    pub redis: RedisSettings,
+    pub memcached: Option<MemcachedSettings>,
+    pub dynamodb: Option<DynamoDBSettings>,
+    pub local_cache: Option<LocalCacheSettings>,
+    pub deny_cache: Option<DenyCacheSettings>,
+    pub upstream_limits: Option<UpstreamLimitsSettings>,
+    pub retry_budget: Option<RetryBudgetSettings>,
+    pub openapi: Option<OpenApiSettings>,
+    pub tarpit: Option<TarpitSettings>,
+    pub ban: Option<BanSettings>,
+    #[serde(rename = "reputation_list", default)]
+    pub reputation_lists: Vec<ReputationListSettings>,
+    pub ip_classes: Option<IpClassesSettings>,
+    #[serde(rename = "waf", default)]
+    pub waf_routes: Vec<WafSettings>,
+    #[serde(rename = "cors", default)]
+    pub cors_routes: Vec<CorsSettings>,
+    #[serde(rename = "auth", default)]
+    pub auth_routes: Vec<AuthSettings>,
+    pub challenge: Option<ChallengeSettings>,
+    pub bypass: Option<BypassSettings>,
+    pub cluster: Option<ClusterSettings>,
+    pub overrides: Option<OverridesSettings>,
+    pub partition: Option<PartitionSettings>,
+    // How far apart the clocks of the proxies sharing a memcached or DynamoDB store may be,
+    // windows written by one proxy are only considered over by the others once this much time passed after their end
+    #[serde(default)]
+    pub max_clock_skew_ms: u64,
+    #[serde(default)]
+    pub fallback_memory: FallbackMemorySettings,
+    #[serde(default)]
+    pub keys: KeySettings,
+    #[serde(default)]
+    pub log_decisions: DecisionLogging,
+    // Adds X-RateLimit-Policy, naming the limiter behind X-RateLimit-Limit and X-RateLimit-Remaining
+    #[serde(default)]
+    pub policy_header: bool,
+    #[serde(default)]
+    pub most_restrictive: MostRestrictive,
+    #[serde(default)]
+    pub consume: ConsumeMode,
+    // Answers GET /.well-known/ratelimit with the remaining budget of the client instead of forwarding it
+    #[serde(default)]
+    pub well_known_endpoint: bool,
+    // Answers the same status on this path too, e.g. `/ratelimit/status`
+    pub status_path: Option<String>,
+    #[serde(default)]
+    pub headers: HeadersSettings,
+    pub warm_up: Option<WarmUpSettings>,
     pub ip_whitelist: HashSet<IpAddr>,
+    // What happens to requests no limiter matches: `allow` (default), `deny`, or `bucket:<name>` to charge them
+    // to the limiter of that name, which then applies to them only
+    pub default_action: Option<String>,
 
     #[serde(rename = "limiter")]
     pub limiters_settings: Vec<LimiterSettings>,
+
+    #[serde(rename = "quota", default)]
+    pub quotas_settings: Vec<QuotaSettings>,
+
+    #[serde(rename = "priority_class", default)]
+    pub priority_classes: Vec<PriorityClassSettings>,
+
+    #[serde(rename = "distinct_limit", default)]
+    pub distinct_limits: Vec<DistinctLimitSettings>,
+
+    pub usage: Option<UsageSettings>,
+
+    pub unique_clients: Option<UniqueClientsSettings>,
+
+    pub anomaly_detection: Option<AnomalyDetectionSettings>,
+}
+
+// Which responses get the rate limit headers and under which names
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HeadersSettings {
+    #[serde(default)]
+    pub send: SendHeaders,
+    #[serde(default = "default_limit_header")]
+    pub limit: String,
+    #[serde(default = "default_remaining_header")]
+    pub remaining: String,
+    #[serde(default = "default_reset_header")]
+    pub reset: String,
+    #[serde(default = "default_policy_header")]
+    pub policy: String,
+    // Overrides for paths starting with a prefix, the longest matching prefix wins
+    #[serde(rename = "route", default)]
+    pub routes: Vec<RouteHeadersSettings>,
+}
+
+impl Default for HeadersSettings {
+    fn default() -> Self {
+        Self {
+            send: SendHeaders::default(),
+            limit: default_limit_header(),
+            remaining: default_remaining_header(),
+            reset: default_reset_header(),
+            policy: default_policy_header(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+fn default_limit_header() -> String {
+    "X-RateLimit-Limit".to_string()
+}
+
+fn default_remaining_header() -> String {
+    "X-RateLimit-Remaining".to_string()
+}
+
+fn default_reset_header() -> String {
+    "X-RateLimit-Reset".to_string()
+}
+
+fn default_policy_header() -> String {
+    "X-RateLimit-Policy".to_string()
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// Unset fields keep the values of [rate_limiter.headers]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RouteHeadersSettings {
+    pub path_prefix: String,
+    pub send: Option<SendHeaders>,
+    pub limit: Option<String>,
+    pub remaining: Option<String>,
+    pub reset: Option<String>,
+    pub policy: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SendHeaders {
+    // Only on responses of requests that were let through
+    Allowed,
+    // Only on 429 responses
+    Denied,
+    #[default]
+    Always,
+    Never,
+}
+
+// Flags limiter keys whose requests per interval go over `factor` times their EWMA baseline
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AnomalyDetectionSettings {
+    #[serde(default = "default_anomaly_interval_secs")]
+    pub interval_secs: u64,
+    // Weight of the last interval in the baseline
+    #[serde(default = "default_anomaly_smoothing")]
+    pub smoothing: f64,
+    #[serde(default = "default_anomaly_factor")]
+    pub factor: f64,
+    // Keys are never flagged under this many requests in an interval
+    #[serde(default = "default_anomaly_min_requests")]
+    pub min_requests: u64,
+    #[serde(default = "default_anomaly_max_keys")]
+    pub max_keys: usize,
+    pub penalty: Option<AnomalyPenaltySettings>,
+    // Every anomaly is posted there as JSON
+    pub webhook_url: Option<String>,
+}
+
+fn default_anomaly_interval_secs() -> u64 {
+    60
+}
+
+fn default_anomaly_smoothing() -> f64 {
+    0.2
+}
+
+fn default_anomaly_factor() -> f64 {
+    5.0
+}
+
+fn default_anomaly_min_requests() -> u64 {
+    20
+}
+
+fn default_anomaly_max_keys() -> usize {
+    100_000
+}
+
+// Flagged keys get a bucket `divisor` times smaller for `duration_secs`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AnomalyPenaltySettings {
+    pub divisor: u32,
+    #[serde(default = "default_anomaly_penalty_duration_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_anomaly_penalty_duration_secs() -> u64 {
+    300
+}
+
+// Estimates the distinct clients of routes per window, to size buckets from real traffic
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UniqueClientsSettings {
+    // Path prefixes, a request is counted under the longest one it's under
+    pub routes: Vec<String>,
+    // Clients are told apart by this header, by IP when not set
+    pub header: Option<String>,
+    #[serde(default = "default_unique_clients_window_secs")]
+    pub window_secs: u32,
+}
+
+fn default_unique_clients_window_secs() -> u32 {
+    3600
+}
+
+// Counts allowed requests per client and periodically exports the counts, e.g. for billing
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UsageSettings {
+    // Header identifying the client, requests without it aren't counted
+    pub header: String,
+    #[serde(default = "default_export_interval_secs")]
+    pub export_interval_secs: u64,
+    #[serde(default)]
+    pub format: UsageFormat,
+    // Records are appended to this file
+    pub file: Option<String>,
+    // Records are POSTed to this http:// URL
+    pub http_url: Option<String>,
+}
+
+fn default_export_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+// Buckets created during `duration_secs` after startup get a capacity ramping from `initial_fraction` to full,
+// so a restart or an override doesn't let every client burst against a cold upstream at once
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WarmUpSettings {
+    pub duration_secs: u64,
+    #[serde(default = "default_initial_fraction")]
+    pub initial_fraction: f64,
+}
+
+fn default_initial_fraction() -> f64 {
+    0.1
+}
+
+// A long-horizon budget per client, e.g. 100k requests per calendar month per API key.
+// Periods follow the UTC calendar and usage is kept in the store, so it survives restarts with a durable backend.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QuotaSettings {
+    pub name: String,
+    // Header identifying the client, requests without it aren't counted
+    pub header: String,
+    pub period: QuotaPeriod,
+    pub limit: u32,
+    // Plans of specific clients, matched by the header value
+    #[serde(default)]
+    pub limits_per_value: Vec<QuotaPerValue>,
+}
+
+// Requests of a class may only use `share_percent` of every bucket and of the upstream's max_in_flight,
+// the rest is kept for requests of other classes
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PriorityClassSettings {
+    pub name: String,
+    // Requests whose header has one of the values are in the class, e.g. header = "X-Api-Tier" and values = ["free"]
+    pub header: Option<String>,
+    #[serde(default)]
+    pub values: Vec<String>,
+    // ...as are requests with a path under one of the prefixes
+    #[serde(default)]
+    pub path_prefixes: Vec<String>,
+    pub share_percent: u8,
+}
+
+// Bounds the distinct values of a query parameter or path segment a client sends per window
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DistinctLimitSettings {
+    pub name: String,
+    // Clients are told apart by this header, by IP when not set
+    pub header: Option<String>,
+    // Only requests under one of the prefixes are counted, every request when empty
+    #[serde(default)]
+    pub path_prefixes: Vec<String>,
+    pub query_param: Option<String>,
+    // Counting from 1, e.g. 3 for the id of /api/users/42
+    pub path_segment: Option<usize>,
+    pub max_distinct: u64,
+    #[serde(default = "default_distinct_window_secs")]
+    pub window_secs: u32,
+}
+
+fn default_distinct_window_secs() -> u32 {
+    3600
+}
+
+// Retries of a client are charged to a budget of their own before the limiters, so retry storms are cut off
+// well before fresh requests would be
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RetryBudgetSettings {
+    // Clients are told apart by this header, by IP when not set
+    pub header: Option<String>,
+    // Retries allowed per client and window
+    pub retries: u32,
+    #[serde(default = "default_retry_budget_window_secs")]
+    pub window_secs: u32,
+    // A request with the same method, path, query and body as one of the client's within this interval is a retry
+    #[serde(default = "default_retry_budget_interval_ms")]
+    pub interval_ms: u64,
+    // ...as is a request whose header holds a number above 0
+    #[serde(default = "default_retry_count_header")]
+    pub retry_count_header: String,
+    // Recent requests remembered at most, newer ones are not
+    #[serde(default = "default_retry_budget_max_requests")]
+    pub max_requests: usize,
+}
+
+fn default_retry_budget_window_secs() -> u32 {
+    60
+}
+
+fn default_retry_budget_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_retry_count_header() -> String {
+    "X-Retry-Count".to_string()
+}
+
+fn default_retry_budget_max_requests() -> usize {
+    100_000
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QuotaPerValue {
+    pub value: String,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RedisSettings {
+    #[serde(serialize_with = "redact")]
+    pub url: Option<String>,
+    pub username: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub password: Option<String>,
+    pub db: Option<i64>,
+    #[serde(default)]
+    pub tls: bool,
+    pub ca_path: Option<String>,
+    pub sentinel: Option<SentinelSettings>,
+    #[serde(default)]
+    pub pool: RedisPoolSettings,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RedisPoolSettings {
+    pub max_size: Option<usize>,
+    pub wait_timeout_ms: Option<u64>,
+    pub create_timeout_ms: Option<u64>,
+    pub recycle_timeout_ms: Option<u64>,
+    #[serde(default = "default_check_on_startup")]
+    pub check_on_startup: bool,
+}
+
+impl Default for RedisPoolSettings {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            wait_timeout_ms: None,
+            create_timeout_ms: None,
+            recycle_timeout_ms: None,
+            check_on_startup: default_check_on_startup(),
+        }
+    }
+}
+
+fn default_check_on_startup() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SentinelSettings {
+    pub addrs: Vec<String>,
+    pub master_name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MemcachedSettings {
+    pub addr: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DynamoDBSettings {
+    pub table: String,
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeySettings {
+    #[serde(default = "default_key_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub hashing: KeyHashing,
+    #[serde(serialize_with = "redact")]
+    pub siphash_key: Option<String>,
+}
+
+impl Default for KeySettings {
+    fn default() -> Self {
+        Self {
+            prefix: default_key_prefix(),
+            hashing: KeyHashing::default(),
+            siphash_key: None,
+        }
+    }
+}
+
+// Secrets are never exported by the admin server, only whether they are set
+fn redact<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Like `redact`, keeping the keys so the admin server still shows which key IDs are configured
+fn redact_values<S: Serializer>(value: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(value.keys().map(|key| (key, "<redacted>")))
+}
+
+fn default_key_prefix() -> String {
+    "rate_limiter".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyHashing {
+    Plain,
+    Sha256,
+    #[default]
+    SipHash,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FallbackMemorySettings {
+    #[serde(default = "default_replicas")]
+    pub replicas: u32,
+}
+
+impl Default for FallbackMemorySettings {
+    fn default() -> Self {
+        Self {
+            replicas: default_replicas(),
+        }
+    }
+}
+
+fn default_replicas() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LocalCacheSettings {
+    #[serde(default = "default_hot_key_threshold")]
+    pub hot_key_threshold: u32,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+    #[serde(default = "default_max_staleness_ms")]
+    pub max_staleness_ms: u64,
+}
+
+fn default_hot_key_threshold() -> u32 {
+    100
+}
+
+fn default_batch_size() -> u32 {
+    10
+}
+
+fn default_max_staleness_ms() -> u64 {
+    1000
+}
+
+// Limits operations of an OpenAPI document with their `x-rate-limit` extension
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OpenApiSettings {
+    // JSON or YAML document
+    pub path: String,
+    // Prepended to the paths of the document, e.g. `/api/v1`
+    #[serde(default)]
+    pub base_path: String,
+    // Answers 404 to requests matching no operation of the document
+    #[serde(default)]
+    pub reject_unknown: bool,
+    #[serde(default)]
+    pub on_store_error: OnStoreError,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DenyCacheSettings {
+    #[serde(default = "default_deny_cache_max_ttl_ms")]
+    pub max_ttl_ms: u64,
+    #[serde(default = "default_deny_cache_max_keys")]
+    pub max_keys: usize,
+}
+
+// What to do with the rate limit headers the upstream answers with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UpstreamLimitsSettings {
+    // Sends the upstream's limit instead of the gateway's when it's the most restrictive one
+    #[serde(default = "default_upstream_limits_merge")]
+    pub merge: bool,
+    // Denies clients the upstream ran out for until its reset instead of forwarding them
+    #[serde(default)]
+    pub throttle: bool,
+    #[serde(default = "default_upstream_limits_max_throttle_secs")]
+    pub max_throttle_secs: u64,
+    #[serde(default = "default_upstream_limits_max_clients")]
+    pub max_clients: usize,
+    // Clients are told apart like the limiter strategy does
+    #[serde(default = "default_fair_queue_strategy")]
+    pub strategy: PossibleStrategies,
+    #[serde(default)]
+    pub values: Vec<String>,
+    // Header names of the upstream
+    #[serde(default = "default_limit_header")]
+    pub limit: String,
+    #[serde(default = "default_remaining_header")]
+    pub remaining: String,
+    #[serde(default = "default_reset_header")]
+    pub reset: String,
+}
+
+fn default_upstream_limits_merge() -> bool {
+    true
+}
+
+fn default_upstream_limits_max_throttle_secs() -> u64 {
+    60
+}
+
+fn default_upstream_limits_max_clients() -> usize {
+    100_000
+}
+
+fn default_deny_cache_max_ttl_ms() -> u64 {
+    1000
+}
+
+fn default_deny_cache_max_keys() -> usize {
+    100_000
+}
+
+// Delays the 429 responses of clients denied at least `min_denials` times within `window_secs`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TarpitSettings {
+    #[serde(default = "default_tarpit_delay_ms")]
+    pub delay_ms: u64,
+    // A random extra delay between 0 and `jitter_ms`
+    #[serde(default = "default_tarpit_jitter_ms")]
+    pub jitter_ms: u64,
+    #[serde(default = "default_tarpit_min_denials")]
+    pub min_denials: u32,
+    #[serde(default = "default_denial_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_tarpit_max_concurrent")]
+    pub max_concurrent: usize,
+}
+
+fn default_tarpit_delay_ms() -> u64 {
+    2000
+}
+
+fn default_tarpit_jitter_ms() -> u64 {
+    1000
+}
+
+fn default_tarpit_min_denials() -> u32 {
+    10
+}
+
+fn default_denial_window_secs() -> u64 {
+    60
+}
+
+// Rules of requests under `path_prefix`, matching requests are rejected before they are limited
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WafSettings {
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    // Rejects `..` segments in the path or query, also percent-encoded
+    #[serde(default)]
+    pub path_traversal: bool,
+    // JSON bodies only, by Content-Type
+    pub max_json_depth: Option<usize>,
+    // Case-insensitive substrings
+    #[serde(default)]
+    pub blocked_user_agents: Vec<String>,
+    #[serde(default)]
+    pub blocked_body_patterns: Vec<String>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+// CORS of requests under `path_prefix`, answered by the proxy so the upstream doesn't have to
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CorsSettings {
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    // `*` allows any origin
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    // `*` allows the headers the preflight asks for
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub exposed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u32>,
+    // Preflights are answered before any limiter is charged
+    #[serde(default)]
+    pub preflight_bypasses_limits: bool,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST"].map(String::from).to_vec()
+}
+
+// Credentials requests under `path_prefix` need, any of the configured methods is accepted. Routes without any are public.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuthSettings {
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    pub api_key: Option<ApiKeyAuthSettings>,
+    pub jwt: Option<JwtAuthSettings>,
+    pub introspection: Option<IntrospectionSettings>,
+    pub hmac: Option<HmacAuthSettings>,
+    pub basic: Option<BasicAuthSettings>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKeyAuthSettings {
+    #[serde(default = "default_api_key_header")]
+    pub header: String,
+    // Hex SHA-256 of the keys by name, the name being the identity
+    pub keys: HashMap<String, String>,
+}
+
+fn default_api_key_header() -> String {
+    "X-Api-Key".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JwtAuthSettings {
+    pub jwks_url: Option<String>,
+    pub jwks_path: Option<String>,
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub refresh_secs: u64,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    #[serde(default = "default_identity_claim")]
+    pub identity_claim: String,
+    // Clock skew tolerated on exp and nbf
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_identity_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+// RFC 7662 introspection of opaque bearer tokens
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IntrospectionSettings {
+    pub endpoint: String,
+    // Sent with basic auth when set
+    pub client_id: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub client_secret: Option<String>,
+    #[serde(default = "default_identity_claim")]
+    pub identity_claim: String,
+    // How long active tokens are cached at most, never past their exp
+    #[serde(default = "default_introspection_cache_secs")]
+    pub cache_secs: u64,
+    #[serde(default = "default_introspection_negative_cache_secs")]
+    pub negative_cache_secs: u64,
+}
+
+fn default_introspection_cache_secs() -> u64 {
+    300
+}
+
+fn default_introspection_negative_cache_secs() -> u64 {
+    30
+}
+
+// Requests signed with a secret shared per key ID, the key ID being the identity
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HmacAuthSettings {
+    #[serde(default = "default_key_id_header")]
+    pub key_id_header: String,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    // Stripped from the signature, e.g. `sha256=`
+    #[serde(default)]
+    pub signature_prefix: String,
+    #[serde(default)]
+    pub algorithm: HmacAlgorithm,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    // Unix time in seconds, signed along with the body when set
+    pub timestamp_header: Option<String>,
+    #[serde(default = "default_signature_max_age_secs")]
+    pub max_age_secs: u64,
+    // Secrets by key ID
+    #[serde(serialize_with = "redact_values")]
+    pub secrets: HashMap<String, String>,
+    // Invalid signatures allowed per client IP
+    pub failure_limit: Option<BucketSettings>,
+}
+
+fn default_key_id_header() -> String {
+    "X-Key-Id".to_string()
+}
+
+fn default_signature_header() -> String {
+    "X-Signature".to_string()
+}
+
+fn default_signature_max_age_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BasicAuthSettings {
+    // Hex SHA-256 of the passwords by user
+    pub users: HashMap<String, String>,
+    #[serde(default = "default_basic_realm")]
+    pub realm: String,
+}
+
+fn default_basic_realm() -> String {
+    "rate_limiter".to_string()
+}
+
+// Bans clients denied at least `min_denials` times within `window_secs` for `duration_secs`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BanSettings {
+    #[serde(default = "default_ban_min_denials")]
+    pub min_denials: u32,
+    #[serde(default = "default_denial_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_ban_duration_secs")]
+    pub duration_secs: u64,
+    #[serde(rename = "action", default)]
+    pub actions: Vec<BanActionSettings>,
+}
+
+fn default_ban_min_denials() -> u32 {
+    50
+}
+
+fn default_ban_duration_secs() -> u64 {
+    600
+}
+
+// IPs and CIDR ranges of an external list, e.g. Spamhaus DROP, one per line with `;` or `#` comments.
+// Listed clients are blocked, or limited by a bucket of their own on top of the limiters.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReputationListSettings {
+    // Names the list in metrics and keys
+    pub name: String,
+    // Either an http:// URL or a local file
+    pub url: Option<String>,
+    pub path: Option<String>,
+    #[serde(default = "default_ip_list_refresh_secs")]
+    pub refresh_secs: u64,
+    #[serde(default)]
+    pub action: ReputationAction,
+    // Bucket of every listed IP, for the limit action
+    pub bucket: Option<BucketSettings>,
+}
+
+fn default_ip_list_refresh_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReputationAction {
+    #[default]
+    Block,
+    Limit,
+}
+
+// Tags requests with the class of their IP, e.g. tor or datacenter, so ip limiters can give every class its own bucket
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IpClassesSettings {
+    // Class of the IPs on none of the lists
+    #[serde(default = "default_ip_class")]
+    pub default: String,
+    // Sends the class to the upstream in this header, replacing the one the client sent
+    pub header: Option<String>,
+    // The first list the IP is on gives its class
+    #[serde(rename = "list", default)]
+    pub lists: Vec<IpClassListSettings>,
+}
+
+fn default_ip_class() -> String {
+    "residential".to_string()
+}
+
+// Read like reputation lists
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IpClassListSettings {
+    pub class: String,
+    pub url: Option<String>,
+    pub path: Option<String>,
+    #[serde(default = "default_ip_list_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+// Run on every ban, so external tooling can block the client at a lower layer
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BanActionSettings {
+    // Appends `<unix time> banned <ip> for <duration>s`
+    File { path: String },
+    // `{ip}` and `{duration}` in the arguments are replaced, no shell is involved
+    Command { program: String, #[serde(default)] args: Vec<String> },
+    // LPUSHes the ban as JSON, redis backend only
+    RedisList { key: String },
+}
+
+fn default_tarpit_max_concurrent() -> usize {
+    1000
+}
+
+// Answers clients denied at least `min_denials` times within `window_secs` with a challenge instead of a 429
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChallengeSettings {
+    #[serde(default = "default_challenge_min_denials")]
+    pub min_denials: u32,
+    #[serde(default = "default_denial_window_secs")]
+    pub window_secs: u64,
+    // Verification page the client is redirected to with `?token=`, without it the token is sent in the X-Challenge-Token header
+    pub redirect_url: Option<String>,
+    #[serde(default = "default_challenge_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    // How long a client that solved its challenge skips the rate limits
+    #[serde(default = "default_challenge_unblock_secs")]
+    pub unblock_secs: u64,
+}
+
+// Signed tokens that let internal tools skip the rate limits, see the bypass module
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BypassSettings {
+    #[serde(default = "default_bypass_header")]
+    pub header: String,
+    // Tokens valid for longer are refused
+    #[serde(default = "default_bypass_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+    // Secrets by key ID
+    #[serde(serialize_with = "redact_values")]
+    pub secrets: HashMap<String, String>,
+}
+
+fn default_bypass_header() -> String {
+    "X-RateLimit-Bypass".to_string()
+}
+
+fn default_bypass_max_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_challenge_min_denials() -> u32 {
+    20
+}
+
+fn default_challenge_token_ttl_secs() -> u64 {
+    300
+}
+
+fn default_challenge_unblock_secs() -> u64 {
+    60
+}
+
+// Registers the instance in Redis so instances know about each other
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ClusterSettings {
+    // Must be unique per instance, defaults to the hostname and the process id
+    pub instance_id: Option<String>,
+    #[serde(default = "default_heartbeat_secs")]
+    pub heartbeat_secs: u64,
+}
+
+// Splits large buckets between the instances of the cluster
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PartitionSettings {
+    #[serde(default = "default_min_tokens")]
+    pub min_tokens: u32,
+}
+
+fn default_min_tokens() -> u32 {
+    100
+}
+
+fn default_heartbeat_secs() -> u64 {
+    5
+}
+
+// Per-key overrides of the buckets, managed on the admin server
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OverridesSettings {
+    // How often overrides set on other instances are read from Redis
+    #[serde(default = "default_overrides_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn default_overrides_refresh_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PossibleBackends {
+    #[default]
+    Redis,
+    Memory,
+    Memcached,
+    DynamoDB,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PossibleStrategies {
     IP,
@@ -36,36 +1380,330 @@ pub enum PossibleStrategies {
     Header,
     Query,
     Body,
+    // The identity of authenticated requests, see `auth`
+    Identity,
+}
+
+impl PossibleStrategies {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PossibleStrategies::IP => "ip",
+            PossibleStrategies::URL => "url",
+            PossibleStrategies::Header => "header",
+            PossibleStrategies::Query => "query",
+            PossibleStrategies::Body => "body",
+            PossibleStrategies::Identity => "identity",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnStoreError {
+    #[default]
+    Allow,
+    Deny,
+    FallbackMemory,
+}
+
+impl OnStoreError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnStoreError::Allow => "allow",
+            OnStoreError::Deny => "deny",
+            OnStoreError::FallbackMemory => "fallback_memory",
+        }
+    }
+}
+
+// `global` buckets are shared by all instances, e.g. to be fair to clients,
+// `instance` buckets are counted by every instance on its own, e.g. to protect the machine it runs on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    #[default]
+    Global,
+    Instance,
+    // Every client IP gets its own bucket per value, e.g. a per-path limit for each user instead of one per path
+    #[serde(rename = "per_client")]
+    PerClient,
+}
+
+impl LimitScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitScope::Global => "global",
+            LimitScope::Instance => "instance",
+            LimitScope::PerClient => "per_client",
+        }
+    }
+}
+
+// How the limit reported in the headers is picked among the limits of a request. A denied limit always wins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MostRestrictive {
+    // Lowest share of the bucket left, so 5 requests left of 10000 are more restrictive than 2 left of 10
+    #[default]
+    Ratio,
+    // Lowest number of requests left
+    Remaining,
+}
+
+impl MostRestrictive {
+    pub fn compare(&self, limit: &LimitForRequest, other: &LimitForRequest) -> Ordering {
+        // Buckets that can borrow are still allowed with fewer tokens left than a denied one
+        let denied_first = other.is_limit_exceeded.cmp(&limit.is_limit_exceeded);
+        denied_first.then_with(|| match self {
+            // Cross-multiplied to compare remaining / total_limit without floats, limits are greater than 0
+            MostRestrictive::Ratio => (limit.requests_to_exceed_limit as i64 * other.total_limit as i64)
+                .cmp(&(other.requests_to_exceed_limit as i64 * limit.total_limit as i64)),
+            MostRestrictive::Remaining => limit.requests_to_exceed_limit.cmp(&other.requests_to_exceed_limit),
+        })
+    }
+}
+
+// Which of the limiters matching a request are charged a token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumeMode {
+    #[default]
+    All,
+    // The first matching limiter, user limiters before request limiters, in the order of the configuration
+    First,
+    // The limiter picked by `most_restrictive`, the others are only read
+    MostRestrictive,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecisionLogging {
+    Off,
+    #[default]
+    Denied,
+    All,
+}
+
+impl DecisionLogging {
+    pub fn should_log(&self, is_denied: bool) -> bool {
+        match self {
+            DecisionLogging::Off => false,
+            DecisionLogging::Denied => is_denied,
+            DecisionLogging::All => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LimiterSettings {
+    pub name: Option<String>,
     pub strategy: PossibleStrategies,
+    #[serde(default)]
+    pub on_store_error: OnStoreError,
+    #[serde(default)]
+    pub scope: LimitScope,
+    pub cardinality: Option<CardinalitySettings>,
+    pub spike_arrest: Option<SpikeArrestSettings>,
+    pub log_decisions: Option<DecisionLogging>,
+    pub global_bucket: Option<BucketSettings>,
+    pub buckets_per_value: Option<Vec<BuckerPerValue>>,
+    // One bucket shared by the requests neither buckets_per_value nor global_bucket give a bucket
+    pub default_bucket: Option<BucketSettings>,
+    #[serde(rename = "schedule", default)]
+    pub schedules: Vec<ScheduleSettings>,
+    // Header limited by global_bucket when the request has none of the headers of buckets_per_value, header strategy only
+    #[serde(default = "default_fallback_header")]
+    pub fallback_header: String,
+    // url strategy only, paths are compared as given when not set
+    pub normalize_path: Option<PathNormalizationSettings>,
+    // url strategy only, query parameters that are part of the bucket value, the query string is ignored when empty
+    #[serde(default)]
+    pub query_params: Vec<String>,
+    // ip strategy only, clients are limited by network instead of by address
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+pub fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+// A single IPv6 user usually gets a whole /64
+pub fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+// Every step is enabled unless turned off, e.g. `normalize_path = { lowercase = false }`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct PathNormalizationSettings {
+    #[serde(default = "default_normalize")]
+    pub percent_decode: bool,
+    #[serde(default = "default_normalize")]
+    pub collapse_slashes: bool,
+    #[serde(default = "default_normalize")]
+    pub trailing_slash: bool,
+    #[serde(default = "default_normalize")]
+    pub lowercase: bool,
+}
+
+impl Default for PathNormalizationSettings {
+    fn default() -> Self {
+        Self {
+            percent_decode: default_normalize(),
+            collapse_slashes: default_normalize(),
+            trailing_slash: default_normalize(),
+            lowercase: default_normalize(),
+        }
+    }
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+pub fn default_fallback_header() -> String {
+    "authorization".to_string()
+}
+
+// Buckets used instead of the limiter's ones while the schedule is active, the first active schedule wins
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ScheduleSettings {
+    // Three letter day names, e.g. ["mon", "tue"], defaults to every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    // `HH:MM-HH:MM`, the end is excluded and ranges can wrap around midnight, e.g. `22:00-06:00`
+    pub hours: Option<String>,
+    // `UTC` or a fixed offset such as `+02:00`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
     pub global_bucket: Option<BucketSettings>,
     pub buckets_per_value: Option<Vec<BuckerPerValue>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CardinalitySettings {
+    pub max_keys: usize,
+    #[serde(default = "default_cardinality_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_cardinality_window_secs() -> u64 {
+    60
+}
+
+// At most `requests` requests per key within any `window_ms`, on top of the buckets of the limiter
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct SpikeArrestSettings {
+    pub requests: u32,
+    pub window_ms: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct BuckerPerValue {
     pub value: String,
+    #[serde(alias = "rate")]
     pub tokens_count: u32,
+    #[serde(default = "default_add_tokens_every")]
     pub add_tokens_every: u32,
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub borrow: u32,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// `tokens_count` tokens every `add_tokens_every` seconds. Without a burst the tokens are given back all at once when the
+// window ends, with a burst they come back one by one at that rate and at most `burst` of them can be used at once.
+// Once empty, up to `borrow` more tokens can be taken and are repaid from the next refill.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct BucketSettings {
+    #[serde(alias = "rate")]
     pub tokens_count: u32,
+    #[serde(default = "default_add_tokens_every")]
     pub add_tokens_every: u32,
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub borrow: u32,
+}
+
+fn default_add_tokens_every() -> u32 {
+    1
 }
 
 impl Settings {
     pub fn new() -> Result<Settings, ConfigError> {
         let config_path = env::var("RL_SETTINGS_PATH").unwrap_or_else(|_| "./Settings.toml".to_string());
+        Self::load(File::with_name(&config_path))
+    }
+
+    // Candidate configurations get the same environment overrides as the configuration file
+    pub fn parse(content: &str, format: FileFormat) -> Result<Settings, ConfigError> {
+        Self::load(File::from_str(content, format))
+    }
 
+    fn load(file: impl Source + Send + Sync + 'static) -> Result<Settings, ConfigError> {
         let settings = Config::builder()
-            .add_source(File::with_name(&config_path))
+            .add_source(file)
+            // e.g. RL__RATE_LIMITER__REDIS__PASSWORD overrides the password of [rate_limiter.redis], single underscores stay part of the keys
+            .add_source(Environment::with_prefix("RL").separator("__").try_parsing(true))
             .build()?;
 
         settings.try_deserialize()
     }
-}
\ No newline at end of file
+
+    // The [api_gateway] table is served as the first listener, followed by every [[listeners]] entry
+    pub fn listeners(&self) -> Vec<ListenerSettings> {
+        self.api_gateway_settings.iter()
+            .map(|api_gateway_settings| ListenerSettings { api_gateway_settings: api_gateway_settings.clone(), rate_limiter_settings: None })
+            .chain(self.listeners_settings.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Headers {
+        most_restrictive: MostRestrictive,
+    }
+
+    #[test]
+    fn ratio_ranks_the_lowest_share_left_first() {
+        let (small, large) = (LimitForRequest::new(10, 2, false), LimitForRequest::new(10_000, 5, false));
+        assert_eq!(MostRestrictive::Ratio.compare(&large, &small), Ordering::Less);
+        assert_eq!(MostRestrictive::Ratio.compare(&small, &large), Ordering::Greater);
+        assert_eq!(MostRestrictive::Ratio.compare(&LimitForRequest::new(10, 5, false), &LimitForRequest::new(100, 50, false)), Ordering::Equal);
+    }
+
+    #[test]
+    fn remaining_ranks_the_lowest_count_first() {
+        let (small, large) = (LimitForRequest::new(10, 2, false), LimitForRequest::new(10_000, 5, false));
+        assert_eq!(MostRestrictive::Remaining.compare(&small, &large), Ordering::Less);
+        assert_eq!(MostRestrictive::Remaining.compare(&large, &small), Ordering::Greater);
+    }
+
+    #[test]
+    fn denied_limits_rank_before_buckets_deeper_in_debt() {
+        let denied = LimitForRequest::new(10, -1, true);
+        let borrowing = LimitForRequest::new(10, -3, false);
+        for most_restrictive in [MostRestrictive::Ratio, MostRestrictive::Remaining] {
+            assert_eq!(most_restrictive.compare(&denied, &borrowing), Ordering::Less);
+            assert_eq!(most_restrictive.compare(&borrowing, &denied), Ordering::Greater);
+        }
+    }
+
+    #[test]
+    fn parses_the_policy_and_defaults_to_ratio() {
+        assert_eq!(MostRestrictive::default(), MostRestrictive::Ratio);
+        assert_eq!(toml::from_str::<Headers>("most_restrictive = \"remaining\"").unwrap().most_restrictive, MostRestrictive::Remaining);
+
+        let error = toml::from_str::<Headers>("most_restrictive = \"lowest\"").err().unwrap();
+        assert!(error.message().contains("unknown variant `lowest`, expected `ratio` or `remaining`"), "{}", error);
+    }
+}
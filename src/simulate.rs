@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use axum::body::Body;
+use axum::http::Request;
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::settings::{DecisionLogging, PossibleBackends, Settings};
+use crate::strategy::SafeRequest;
+
+pub const USAGE: &str = "Usage: rate_limiter simulate [--profile <file.jsonl> | --clients <n> --rps <n> --duration-secs <n> --path <path>] [--memory]";
+
+
+// A request of a recorded profile, one JSON object per line, e.g.
+// `{"at_ms": 120, "ip": "1.2.3.4", "method": "POST", "path": "/api/orders", "headers": {"X-Api-Key": "abc"}}`
+#[derive(Deserialize, Debug, Clone)]
+struct SimulatedRequest {
+    // Time since the start of the simulation
+    #[serde(default)]
+    at_ms: u64,
+    ip: IpAddr,
+    #[serde(default = "default_method")]
+    method: String,
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug)]
+enum Profile {
+    Recorded(String),
+    // Requests evenly spread over the duration, the clients taking turns
+    Synthetic { clients: u32, rps: u32, duration_secs: u64, path: String },
+}
+
+#[derive(Debug)]
+pub struct SimulateArgs {
+    profile: Profile,
+    // Uses the in-memory backend instead of the configured store
+    memory: bool,
+}
+
+impl SimulateArgs {
+    pub fn parse(args: &[String]) -> Result<Self, RateLimiterError> {
+        let (mut profile, mut memory) = (None, false);
+        let (mut clients, mut rps, mut duration_secs, mut path) = (10, 10, 10, default_path());
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--memory" {
+                memory = true;
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| invalid(format!("Missing value of {}", arg)))?;
+            match arg.as_str() {
+                "--profile" => profile = Some(value.clone()),
+                "--clients" => clients = parse_number(arg, value)?,
+                "--rps" => rps = parse_number(arg, value)?,
+                "--duration-secs" => duration_secs = parse_number(arg, value)?,
+                "--path" => path = value.clone(),
+                _ => return Err(invalid(format!("Unknown argument {}", arg))),
+            }
+        }
+        if clients == 0 || rps == 0 {
+            return Err(invalid("--clients and --rps must be greater than 0".to_string()));
+        }
+
+        Ok(Self {
+            profile: match profile {
+                Some(file) => Profile::Recorded(file),
+                None => Profile::Synthetic { clients, rps, duration_secs, path },
+            },
+            memory,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SimulationReport {
+    pub requests: u64,
+    pub allowed: u64,
+    // (limiter, allowed, denied) in the order of the configuration
+    pub limiters: Vec<(String, u64, u64)>,
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<24} {:>10} {:>10} {:>8}", "limiter", "allowed", "denied", "denied%")?;
+        let total = [("all limiters".to_string(), self.allowed, self.requests - self.allowed)];
+        for (name, allowed, denied) in self.limiters.iter().chain(total.iter()) {
+            let rate = match allowed + denied {
+                0 => 0.0,
+                requests => *denied as f64 * 100.0 / requests as f64,
+            };
+            writeln!(f, "{:<24} {:>10} {:>10} {:>7.1}%", name, allowed, denied, rate)?;
+        }
+        Ok(())
+    }
+}
+
+// Replays a traffic profile in real time against the configured limiters. Buckets are kept under `<prefix>:simulate`,
+// so a simulation against the production store doesn't charge real clients.
+pub async fn simulate(settings: &Settings, args: &SimulateArgs) -> Result<SimulationReport, RateLimiterError> {
+    let requests = match &args.profile {
+        Profile::Recorded(file) => read_profile(file).await?,
+        Profile::Synthetic { clients, rps, duration_secs, path } => synthetic_profile(*clients, *rps, *duration_secs, path),
+    };
+
+    let mut rate_limiter_settings = settings.rate_limiter_settings.clone();
+    rate_limiter_settings.keys.prefix = format!("{}:simulate", rate_limiter_settings.keys.prefix);
+    rate_limiter_settings.log_decisions = DecisionLogging::Off;
+    rate_limiter_settings.limiters_settings.iter_mut().for_each(|limiter| limiter.log_decisions = None);
+    // Simulated clients are not exported as real usage
+    rate_limiter_settings.usage = None;
+    if args.memory {
+        rate_limiter_settings.backend = PossibleBackends::Memory;
+        rate_limiter_settings.cluster = None;
+        rate_limiter_settings.partition = None;
+    }
+    let limiter = Arc::new(RateLimiterManager::new(rate_limiter_settings)?);
+    limiter.check_store_connection().await?;
+
+    let started_at = Instant::now();
+    let mut decisions = JoinSet::new();
+    for simulated in requests {
+        tokio::time::sleep_until(started_at + Duration::from_millis(simulated.at_ms)).await;
+        let limiter = limiter.clone();
+        let addr = SocketAddr::new(simulated.ip, 0);
+        let request = build_request(simulated)?;
+        decisions.spawn(async move {
+            match limiter.is_whitelisted(&addr.ip()) {
+                true => Vec::new(),
+                false => limiter.check_each(&request, addr).await,
+            }
+        });
+    }
+
+    let mut report = SimulationReport::default();
+    while let Some(limits) = decisions.join_next().await {
+        let limits = limits.map_err(|e| RateLimiterError::Io(e.into()))?;
+        report.requests += 1;
+        if limits.iter().all(|(_, limit)| !limit.is_limit_exceeded) {
+            report.allowed += 1;
+        }
+        for (name, limit) in limits {
+            let index = match report.limiters.iter().position(|(limiter, _, _)| limiter == &name) {
+                Some(index) => index,
+                None => {
+                    report.limiters.push((name, 0, 0));
+                    report.limiters.len() - 1
+                },
+            };
+            match limit.is_limit_exceeded {
+                true => report.limiters[index].2 += 1,
+                false => report.limiters[index].1 += 1,
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn read_profile(file: &str) -> Result<Vec<SimulatedRequest>, RateLimiterError> {
+    let content = tokio::fs::read_to_string(file).await?;
+    let mut requests = content.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str::<SimulatedRequest>(line)
+            .map_err(|e| invalid(format!("Invalid request on line {} of {}: {}", index + 1, file, e))))
+        .collect::<Result<Vec<_>, _>>()?;
+    requests.sort_by_key(|request| request.at_ms);
+    Ok(requests)
+}
+
+fn synthetic_profile(clients: u32, rps: u32, duration_secs: u64, path: &str) -> Vec<SimulatedRequest> {
+    (0..rps as u64 * duration_secs)
+        .map(|index| SimulatedRequest {
+            at_ms: index * 1000 / rps as u64,
+            // Every client gets its own address in 10.0.0.0/8
+            ip: IpAddr::V4(Ipv4Addr::from((10 << 24) | (index % clients as u64) as u32)),
+            method: default_method(),
+            path: path.to_string(),
+            headers: HashMap::new(),
+            body: String::new(),
+        })
+        .collect()
+}
+
+fn build_request(simulated: SimulatedRequest) -> Result<SafeRequest, RateLimiterError> {
+    let mut request = Request::builder()
+        .method(simulated.method.as_str())
+        .uri(simulated.path.as_str());
+    for (name, value) in &simulated.headers {
+        request = request.header(name, value);
+    }
+    let (parts, _) = request.body(Body::empty())
+        .map_err(|e| invalid(format!("Invalid request {} {}: {}", simulated.method, simulated.path, e)))?
+        .into_parts();
+    Ok(SafeRequest::new(parts, simulated.body.into()))
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, RateLimiterError> {
+    value.parse().map_err(|_| invalid(format!("{} must be a number, got {}", arg, value)))
+}
+
+fn invalid(message: String) -> RateLimiterError {
+    RateLimiterError::config(message)
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use crate::ban::StoredBan;
+use crate::overrides::BucketOverride;
+use crate::store::StoredCounter;
+
+
+// State of a limiter that outlives requests, exported by the admin server to carry it over to another store or instance,
+// e.g. when moving to a new Redis. Counters are the buckets and quotas of the store under the key prefix.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Snapshot {
+    // Unix time in seconds
+    pub exported_at: u64,
+    pub counters: Vec<StoredCounter>,
+    #[serde(default)]
+    pub bans: Vec<StoredBan>,
+    #[serde(default)]
+    pub overrides: Vec<BucketOverride>,
+}
+
+// What a restore wrote back, entries that expired since the export are skipped
+#[derive(Serialize, Debug, Default)]
+pub struct RestoredSnapshot {
+    pub counters: usize,
+    pub bans: usize,
+    pub overrides: usize,
+}
@@ -0,0 +1,89 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use siphasher::sip::SipHasher13;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::{KeySettings, SplitSettings, StickySettings};
+use crate::strategy::{Bucket, BucketsPerValue, SafeRequest, Strategy};
+
+
+// Client values extracted with one of the limiter strategies, so a client keeps getting the same upstream
+#[derive(Debug)]
+struct Sticky {
+    strategy: Strategy,
+    // Names the strategy looks for, e.g. header names, with placeholder buckets
+    values: BucketsPerValue,
+    key_builder: KeyBuilder,
+}
+
+
+// Spreads requests between upstreams by weight, e.g. 90/10 between a stable and a canary upstream
+#[derive(Debug)]
+pub struct TrafficSplit {
+    // Upstreams with the running total of the weights up to and including them
+    upstreams: Vec<(String, u64)>,
+    total_weight: u64,
+    sticky: Option<Sticky>,
+    random: RandomState,
+    requests: AtomicU64,
+}
+
+impl TrafficSplit {
+    pub fn new(splits: &[SplitSettings], sticky: Option<&StickySettings>) -> Result<Self, RateLimiterError> {
+        let mut total_weight = 0;
+        let mut upstreams = Vec::with_capacity(splits.len());
+        for split in splits {
+            if split.target_url.is_empty() {
+                return Err(RateLimiterError::config("target_url of a split can't be empty"));
+            }
+            total_weight += split.weight as u64;
+            upstreams.push((split.target_url.clone(), total_weight));
+        }
+        if total_weight == 0 {
+            return Err(RateLimiterError::config("Splits need a total weight greater than 0"));
+        }
+
+        let sticky = sticky.map(|settings| Ok::<_, RateLimiterError>(Sticky {
+            strategy: Strategy::from_possible_strategy(&settings.strategy),
+            values: BucketsPerValue::new(settings.values.iter().map(|value| (value.clone(), Bucket::new(1, 1)))),
+            key_builder: KeyBuilder::new(&KeySettings::default())?,
+        })).transpose()?;
+
+        Ok(Self {
+            upstreams,
+            total_weight,
+            sticky,
+            random: RandomState::new(),
+            requests: AtomicU64::new(0),
+        })
+    }
+
+    pub fn needs_body(&self) -> bool {
+        self.sticky.as_ref().is_some_and(|sticky| sticky.strategy.needs_body())
+    }
+
+    // Requests without a sticky value are spread randomly
+    pub fn select(&self, request: &SafeRequest, addr: SocketAddr) -> &str {
+        let point = match self.sticky_hash(request, addr) {
+            Some(hash) => hash,
+            None => self.random.hash_one(self.requests.fetch_add(1, Ordering::Relaxed)),
+        } % self.total_weight;
+
+        self.upstreams.iter()
+            .find(|(_, weight)| point < *weight)
+            .map_or(self.upstreams[0].0.as_str(), |(target_url, _)| target_url.as_str())
+    }
+
+    // Zero keys give the same hash on every instance, so all replicas pick the same upstream for a client
+    fn sticky_hash(&self, request: &SafeRequest, addr: SocketAddr) -> Option<u64> {
+        let sticky = self.sticky.as_ref()?;
+        let placeholder = Bucket::new(1, 1);
+        let limit_key = sticky.strategy.get_key(request, addr, Some(&placeholder), Some(&sticky.values), &sticky.key_builder)?;
+
+        let mut hasher = SipHasher13::new();
+        hasher.write(limit_key.key.as_bytes());
+        Some(hasher.finish())
+    }
+}
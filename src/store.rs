@@ -0,0 +1,368 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use axum::async_trait;
+use deadpool_redis::redis;
+use serde::{Deserialize, Serialize};
+use crate::clock::Clock;
+use crate::connection::RedisPool;
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::strategy::Bucket;
+
+// GCRA for buckets with a burst, see the gcra module. The TAT is stored in microseconds and expires once the bucket is full again.
+// TIME makes every proxy instance use the clock of Redis, replicate_commands is needed by Redis before 5 to write after calling it.
+const GCRA_SCRIPT: &str = r"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local interval = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local tokens = tonumber(ARGV[3])
+local borrow = tonumber(ARGV[4])
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then
+    tat = now
+end
+local available = math.floor(math.max((burst + borrow) * interval - (tat - now), 0) / interval)
+local taken = math.min(available, tokens)
+if taken > 0 then
+    tat = tat + taken * interval
+    redis.call('SET', KEYS[1], string.format('%.0f', tat), 'PX', math.max(math.ceil((tat - now) / 1000), 1))
+end
+return available - borrow - tokens
+";
+
+// Fixed windows of buckets that can borrow are kept for a second window, the window ended once less than that is left.
+// The next window then starts with the debt of the last one. Taking 0 tokens doesn't write, so peeking doesn't start a window.
+const BORROW_SCRIPT: &str = r"
+local window = tonumber(ARGV[1]) * 1000
+local tokens_count = tonumber(ARGV[2])
+local borrow = tonumber(ARGV[3])
+local tokens = tonumber(ARGV[4])
+local remaining = tonumber(redis.call('GET', KEYS[1]))
+if remaining and redis.call('PTTL', KEYS[1]) > window then
+    if tokens == 0 then
+        return remaining
+    end
+    return redis.call('DECRBY', KEYS[1], tokens)
+end
+if remaining then
+    remaining = tokens_count - math.min(math.max(-remaining, 0), borrow)
+else
+    remaining = tokens_count
+end
+if tokens > 0 then
+    redis.call('SET', KEYS[1], remaining - tokens, 'PX', 2 * window)
+end
+return remaining - tokens
+";
+
+// Keys read or written at once when exporting or importing a snapshot
+const SNAPSHOT_BATCH: usize = 1000;
+
+
+// A counter of the store as saved in snapshots: the tokens left in a fixed window, or the TAT of a bucket with a burst
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredCounter {
+    pub key: String,
+    pub value: i64,
+    // Unix time in milliseconds, None for counters that don't expire
+    pub expires_at_ms: Option<u64>,
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct TokenRequest<'a> {
+    pub key: &'a str,
+    pub bucket: &'a Bucket,
+    pub tokens: u32,
+}
+
+impl<'a> TokenRequest<'a> {
+    pub fn new(key: &'a str, bucket: &'a Bucket, tokens: u32) -> Self {
+        Self {
+            key,
+            bucket,
+            tokens,
+        }
+    }
+}
+
+
+#[async_trait]
+pub trait LimitStore: Debug + Send + Sync {
+    // Takes `tokens` tokens from the bucket stored under `key`, creating the bucket if it doesn't exist yet.
+    // Returns the number of tokens left, a negative value means the bucket is exhausted.
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError>;
+
+    // Same as `consume` for several buckets at once, stores override it to save round trips
+    async fn consume_many(&self, requests: &[TokenRequest<'_>]) -> Vec<Result<i32, RateLimiterError>> {
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(self.consume(request.key, request.bucket, request.tokens).await);
+        }
+        results
+    }
+
+    // Tokens left in the bucket stored under `key` without taking any, a bucket that doesn't exist yet is full.
+    // Defaults to consuming 0 tokens, stores where that creates the bucket override it.
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        self.consume(key, bucket, 0).await
+    }
+
+    // Same as `peek` for several buckets at once
+    async fn peek_many(&self, buckets: &[(&str, &Bucket)]) -> Vec<Result<i32, RateLimiterError>> {
+        let mut results = Vec::with_capacity(buckets.len());
+        for (key, bucket) in buckets {
+            results.push(self.peek(key, bucket).await);
+        }
+        results
+    }
+
+    // Milliseconds until the fixed window of the bucket stored under `key` ends, a bucket that doesn't exist is full already.
+    // None when the store can't tell.
+    async fn window_ends_in_ms(&self, _key: &str, _bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        Ok(None)
+    }
+
+    // Adds `value` to the set stored under `key`, which expires in `ttl_secs`, and returns the number of distinct
+    // values in it. Stores that can't keep sets fail.
+    async fn count_distinct(&self, key: &str, _value: &str, _ttl_secs: u32) -> Result<u64, RateLimiterError> {
+        Err(RateLimiterError::Store(format!("The store can't count the distinct values of {}", key)))
+    }
+
+    // Verifies that the store is reachable, called once on startup
+    async fn check_connection(&self) -> Result<(), RateLimiterError> {
+        Ok(())
+    }
+
+    // Every counter whose key starts with `prefix`, for snapshots. Stores that can't list their keys fail.
+    async fn export(&self, prefix: &str) -> Result<Vec<StoredCounter>, RateLimiterError> {
+        Err(RateLimiterError::Store(format!("The store can't export the counters of {}", prefix)))
+    }
+
+    // Writes the counters of a snapshot back, replacing the current ones. Returns the number written, expired counters are skipped.
+    async fn import(&self, _counters: &[StoredCounter]) -> Result<usize, RateLimiterError> {
+        Err(RateLimiterError::Store("The store can't import counters".to_string()))
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct RedisStore {
+    pool: RedisPool,
+    // Only used to date the counters of snapshots, Redis expires keys on its own clock
+    clock: Arc<dyn Clock>,
+}
+
+impl RedisStore {
+    pub fn new(pool: RedisPool, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            pool,
+            clock,
+        }
+    }
+}
+
+#[async_trait]
+impl LimitStore for RedisStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        self.consume_many(&[TokenRequest::new(key, bucket, tokens)]).await
+            .pop()
+            .unwrap_or_else(|| Err(RateLimiterError::Redis("Redis returned no result".to_string())))
+    }
+
+    // All buckets are updated with a single pipeline, so a request costs one round trip whatever the number of limiters
+    async fn consume_many(&self, requests: &[TokenRequest<'_>]) -> Vec<Result<i32, RateLimiterError>> {
+        let mut redis_connection = match self.pool.get().await {
+            Ok(redis_connection) => redis_connection,
+            Err(e) => return requests.iter().map(|_| Err(RateLimiterError::Redis(format!("Could not get a Redis connection: {}", e)))).collect(),
+        };
+
+        let mut pipeline = redis::pipe();
+        for request in requests {
+            if let Some(burst) = request.bucket.burst {
+                pipeline.cmd("EVAL")
+                    .arg(GCRA_SCRIPT)
+                    .arg(1)
+                    .arg(request.key)
+                    .arg(gcra::emission_interval_us(request.bucket))
+                    .arg(burst)
+                    .arg(request.tokens)
+                    .arg(request.bucket.borrow);
+                continue;
+            }
+            if request.bucket.borrow > 0 {
+                pipeline.cmd("EVAL")
+                    .arg(BORROW_SCRIPT)
+                    .arg(1)
+                    .arg(request.key)
+                    .arg(request.bucket.add_tokens_every)
+                    .arg(request.bucket.tokens_count)
+                    .arg(request.bucket.borrow)
+                    .arg(request.tokens);
+                continue;
+            }
+
+            pipeline.cmd("SET")
+                .arg(request.key)
+                .arg(request.bucket.tokens_count)
+                .arg("EX")
+                .arg(request.bucket.add_tokens_every)
+                .arg("NX")
+                .ignore();
+            pipeline.cmd("DECRBY")
+                .arg(request.key)
+                .arg(request.tokens);
+        }
+
+        match pipeline.query_async::<Vec<i32>>(&mut redis_connection).await {
+            Ok(counts) => counts.into_iter().map(Ok).collect(),
+            Err(e) => requests.iter().map(|_| Err(RateLimiterError::Redis(e.to_string()))).collect(),
+        }
+    }
+
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        self.peek_many(&[(key, bucket)]).await
+            .pop()
+            .unwrap_or_else(|| Err(RateLimiterError::Redis("Redis returned no result".to_string())))
+    }
+
+    // Fixed windows are read with GET, so peeking doesn't start a window. The scripts don't write when taking 0 tokens.
+    async fn peek_many(&self, buckets: &[(&str, &Bucket)]) -> Vec<Result<i32, RateLimiterError>> {
+        let mut redis_connection = match self.pool.get().await {
+            Ok(redis_connection) => redis_connection,
+            Err(e) => return buckets.iter().map(|_| Err(RateLimiterError::Redis(format!("Could not get a Redis connection: {}", e)))).collect(),
+        };
+
+        let mut pipeline = redis::pipe();
+        for (key, bucket) in buckets {
+            match bucket.burst {
+                Some(burst) => pipeline.cmd("EVAL")
+                    .arg(GCRA_SCRIPT)
+                    .arg(1)
+                    .arg(*key)
+                    .arg(gcra::emission_interval_us(bucket))
+                    .arg(burst)
+                    .arg(0)
+                    .arg(bucket.borrow),
+                None if bucket.borrow > 0 => pipeline.cmd("EVAL")
+                    .arg(BORROW_SCRIPT)
+                    .arg(1)
+                    .arg(*key)
+                    .arg(bucket.add_tokens_every)
+                    .arg(bucket.tokens_count)
+                    .arg(bucket.borrow)
+                    .arg(0),
+                None => pipeline.cmd("GET").arg(*key),
+            };
+        }
+
+        match pipeline.query_async::<Vec<Option<i32>>>(&mut redis_connection).await {
+            Ok(counts) => counts.into_iter()
+                .zip(buckets)
+                .map(|(count, (_, bucket))| Ok(count.unwrap_or(bucket.tokens_count as i32)))
+                .collect(),
+            Err(e) => buckets.iter().map(|_| Err(RateLimiterError::Redis(e.to_string()))).collect(),
+        }
+    }
+
+    // Windows of buckets that can borrow are kept for another window after they ended
+    async fn window_ends_in_ms(&self, key: &str, bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        let mut redis_connection = self.pool.get().await?;
+        let ttl_ms: i64 = redis::cmd("PTTL").arg(key).query_async(&mut redis_connection).await?;
+        // -2 for a key that doesn't exist, -1 for one that doesn't expire
+        Ok(match ttl_ms {
+            -1 => None,
+            ttl_ms => Some((ttl_ms.max(0) as u64).saturating_sub(bucket.debt_secs() as u64 * 1000)),
+        })
+    }
+
+    // Sets are HyperLogLogs: 12KB at most whatever the number of values, counted with an error of about 0.81%
+    async fn count_distinct(&self, key: &str, value: &str, ttl_secs: u32) -> Result<u64, RateLimiterError> {
+        let mut redis_connection = self.pool.get().await?;
+        let (count,): (u64,) = redis::pipe()
+            .cmd("PFADD").arg(key).arg(value).ignore()
+            .cmd("EXPIRE").arg(key).arg(ttl_secs.max(1)).ignore()
+            .cmd("PFCOUNT").arg(key)
+            .query_async(&mut redis_connection).await?;
+        Ok(count)
+    }
+
+    async fn check_connection(&self) -> Result<(), RateLimiterError> {
+        self.pool.check_connection().await
+    }
+
+    // Keys are listed with SCAN, so Redis keeps serving requests meanwhile. Strings that aren't integers,
+    // e.g. the HyperLogLogs of distinct values, aren't counters and are left out.
+    async fn export(&self, prefix: &str) -> Result<Vec<StoredCounter>, RateLimiterError> {
+        let mut redis_connection = self.pool.get().await?;
+        let pattern = format!("{}:*", escape_glob(prefix));
+        let mut counters = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(SNAPSHOT_BATCH).arg("TYPE").arg("string")
+                .query_async(&mut redis_connection).await?;
+            if !keys.is_empty() {
+                let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(&keys).query_async(&mut redis_connection).await?;
+                let mut pipeline = redis::pipe();
+                for key in &keys {
+                    pipeline.cmd("PTTL").arg(key);
+                }
+                let ttls: Vec<i64> = pipeline.query_async(&mut redis_connection).await?;
+
+                let now_ms = self.clock.now_us() / 1000;
+                for ((key, value), ttl_ms) in keys.into_iter().zip(values).zip(ttls) {
+                    // Keys that expired since they were listed return no value and a TTL of -2
+                    let Some(value) = value.and_then(|value| std::str::from_utf8(&value).ok()?.parse::<i64>().ok()) else {
+                        continue;
+                    };
+                    let expires_at_ms = match ttl_ms {
+                        -1 => None,
+                        ttl_ms if ttl_ms > 0 => Some(now_ms + ttl_ms as u64),
+                        _ => continue,
+                    };
+                    counters.push(StoredCounter { key, value, expires_at_ms });
+                }
+            }
+            if next_cursor == 0 {
+                return Ok(counters);
+            }
+            cursor = next_cursor;
+        }
+    }
+
+    async fn import(&self, counters: &[StoredCounter]) -> Result<usize, RateLimiterError> {
+        let mut redis_connection = self.pool.get().await?;
+        let mut imported = 0;
+        for batch in counters.chunks(SNAPSHOT_BATCH) {
+            let (now_ms, batch_start) = (self.clock.now_us() / 1000, imported);
+            let mut pipeline = redis::pipe();
+            for counter in batch {
+                match counter.expires_at_ms {
+                    Some(expires_at_ms) if expires_at_ms <= now_ms => continue,
+                    Some(expires_at_ms) => pipeline.cmd("SET").arg(&counter.key).arg(counter.value).arg("PX").arg(expires_at_ms - now_ms).ignore(),
+                    None => pipeline.cmd("SET").arg(&counter.key).arg(counter.value).ignore(),
+                };
+                imported += 1;
+            }
+            if imported > batch_start {
+                pipeline.query_async::<()>(&mut redis_connection).await?;
+            }
+        }
+        Ok(imported)
+    }
+}
+
+// So the key prefix is matched as is by SCAN
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
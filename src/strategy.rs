@@ -1,12 +1,255 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::net::SocketAddr;
-use axum::async_trait;
-use deadpool_redis::{redis, Connection};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use axum::body::{to_bytes, Body, Bytes};
+use axum::http::Request;
+use axum::response::Response;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use percent_encoding::percent_decode_str;
 use serde_json::Value;
 use url::{form_urlencoded};
-use crate::limiter::{Bucket, SafeRequest};
-use crate::settings::PossibleStrategies;
+use crate::auth::Identity;
+use crate::connection_guard;
+use crate::ip_class::IpClass;
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::key::KeyBuilder;
+use crate::metrics;
+use crate::openapi::OperationRateLimiterStrategy;
+use crate::settings::{default_ipv4_prefix, default_ipv6_prefix, BucketSettings, LimiterSettings, PathNormalizationSettings, PossibleStrategies};
+
+
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    pub tokens_count: u32,
+    pub add_tokens_every: u32,
+    // Buckets with a burst refill continuously instead of once per window, see the gcra module
+    pub burst: Option<u32>,
+    // Tokens that can be taken from an empty bucket, a request is only denied once it's deeper in debt
+    pub borrow: u32,
+}
+
+impl Bucket {
+    pub fn new(tokens_count: u32, add_tokens_every: u32) -> Self {
+        Self {
+            tokens_count,
+            add_tokens_every,
+            burst: None,
+            borrow: 0,
+        }
+    }
+
+    pub fn with_burst(mut self, burst: Option<u32>) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    pub fn with_borrow(mut self, borrow: u32) -> Self {
+        self.borrow = borrow;
+        self
+    }
+
+    // Tokens a fixed window starts with after a window that ended with `remaining` tokens. Borrowed tokens are repaid
+    // from the refill, at most `borrow` of them as the requests denied beyond are not a debt.
+    pub fn refill(&self, remaining: i32) -> i32 {
+        self.tokens_count as i32 - remaining.saturating_neg().clamp(0, self.borrow as i32)
+    }
+
+    // Seconds a fixed window is kept after it ended, so the next one can start with its debt
+    pub fn debt_secs(&self) -> u32 {
+        match self.borrow {
+            0 => 0,
+            _ => self.add_tokens_every,
+        }
+    }
+
+    // Tokens available in a full bucket
+    pub fn capacity(&self) -> u32 {
+        self.burst.unwrap_or(self.tokens_count)
+    }
+
+    // Seconds until a client gets a token back at the latest: the window of fixed windows, one emission interval with a burst
+    pub fn reset_secs(&self) -> u32 {
+        match self.burst {
+            Some(_) => gcra::emission_interval_us(self).div_ceil(1_000_000) as u32,
+            None => self.add_tokens_every,
+        }
+    }
+}
+
+impl From<&BucketSettings> for Bucket {
+    fn from(settings: &BucketSettings) -> Self {
+        Self {
+            tokens_count: settings.tokens_count,
+            add_tokens_every: settings.add_tokens_every,
+            burst: settings.burst,
+            borrow: settings.borrow,
+        }
+    }
+}
+
+
+// Buckets of the values of a limiter, built at startup into a lookup per kind of value, so requests are matched without
+// building strings: exact values, IP networks by prefix length, header names in the configured order and path prefixes.
+#[derive(Clone, Debug, Default)]
+pub struct BucketsPerValue {
+    // In the configured order
+    values: Vec<(String, Bucket)>,
+    exact: HashMap<String, Bucket>,
+    // Masked networks of every prefix length, longest first, IPv4 networks are stored as u32
+    ipv4_networks: Vec<(u8, HashMap<u128, Bucket>)>,
+    ipv6_networks: Vec<(u8, HashMap<u128, Bucket>)>,
+    // Values that are valid header names, with the value as configured
+    headers: Vec<(HeaderName, usize)>,
+    // Values ending in `/*`, without the `*`
+    path_prefixes: HashMap<String, Bucket>,
+}
+
+impl BucketsPerValue {
+    pub fn new(values: impl IntoIterator<Item = (String, Bucket)>) -> Self {
+        let values = values.into_iter().collect::<Vec<_>>();
+        let mut buckets = Self {
+            exact: values.iter().cloned().collect(),
+            ..Self::default()
+        };
+
+        for (index, (value, bucket)) in values.iter().enumerate() {
+            if let Some((ip, prefix)) = parse_network(value) {
+                let networks = match ip {
+                    IpAddr::V4(_) => &mut buckets.ipv4_networks,
+                    IpAddr::V6(_) => &mut buckets.ipv6_networks,
+                };
+                let position = networks.iter().position(|(network_prefix, _)| *network_prefix == prefix).unwrap_or_else(|| {
+                    networks.push((prefix, HashMap::new()));
+                    networks.len() - 1
+                });
+                networks[position].1.entry(mask(ip, prefix)).or_insert_with(|| bucket.clone());
+            }
+            if let Ok(name) = HeaderName::try_from(value.as_str()) {
+                buckets.headers.push((name, index));
+            }
+            if let Some(prefix) = value.strip_suffix('*').filter(|prefix| prefix.ends_with('/')) {
+                buckets.path_prefixes.insert(prefix.to_string(), bucket.clone());
+            }
+        }
+        buckets.ipv4_networks.sort_by_key(|(prefix, _)| std::cmp::Reverse(*prefix));
+        buckets.ipv6_networks.sort_by_key(|(prefix, _)| std::cmp::Reverse(*prefix));
+        buckets.values = values;
+        buckets
+    }
+
+    // Values with their bucket, in the configured order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Bucket)> {
+        self.values.iter().map(|(value, bucket)| (value.as_str(), bucket))
+    }
+
+    pub fn get(&self, value: &str) -> Option<&Bucket> {
+        self.exact.get(value)
+    }
+
+    // Bucket of the most specific network or address containing `ip`
+    pub fn get_network(&self, ip: IpAddr) -> Option<&Bucket> {
+        let networks = match ip {
+            IpAddr::V4(_) => &self.ipv4_networks,
+            IpAddr::V6(_) => &self.ipv6_networks,
+        };
+        networks.iter().find_map(|(prefix, networks)| networks.get(&mask(ip, *prefix)))
+    }
+
+    // First configured header name the request has, with its value as configured and its bucket
+    pub fn find_header<'a>(&'a self, headers: &'a HeaderMap) -> Option<(&'a str, &'a HeaderValue, &'a Bucket)> {
+        self.headers.iter().find_map(|(name, index)| {
+            let (value, bucket) = &self.values[*index];
+            headers.get(name).map(|header| (value.as_str(), header, bucket))
+        })
+    }
+
+    // Bucket of the longest `/*` value `path` is under, matching whole segments
+    pub fn get_path_prefix(&self, path: &str) -> Option<&Bucket> {
+        if self.path_prefixes.is_empty() {
+            return None;
+        }
+        path.rmatch_indices('/').find_map(|(index, _)| self.path_prefixes.get(&path[..=index]))
+    }
+}
+
+// Addresses and networks in CIDR notation, e.g. `2001:db8::/64`
+fn parse_network(value: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, prefix.parse::<u8>().ok()?),
+        None => {
+            let ip = value.parse::<IpAddr>().ok()?;
+            (ip, if ip.is_ipv4() { 32 } else { 128 })
+        },
+    };
+    match ip {
+        IpAddr::V4(_) if prefix <= 32 => Some((ip, prefix)),
+        IpAddr::V6(_) if prefix <= 128 => Some((ip, prefix)),
+        _ => None,
+    }
+}
+
+fn mask(ip: IpAddr, prefix: u8) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)) as u128,
+        IpAddr::V6(ip) => u128::from(ip) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0),
+    }
+}
+
+pub struct SafeRequest {
+    pub parts: Parts,
+    pub body: Bytes,
+}
+
+impl SafeRequest {
+    pub fn new(parts: Parts, body: Bytes) -> Self {
+        Self {
+            parts,
+            body,
+        }
+    }
+
+    // Reads the whole body, as Request<Body> is not Send. Bodies that couldn't be read are answered right away.
+    pub async fn buffer(request: Request<Body>) -> Result<Self, Response> {
+        let (parts, body) = request.into_parts();
+        match to_bytes(body, usize::MAX).await {
+            Ok(body) => Ok(Self::new(parts, body)),
+            Err(e) => Err(connection_guard::body_error_response(&e)),
+        }
+    }
+
+    // For requests nothing reads the body of: it's left unread, to be handed back with `with_body`
+    pub fn without_body(request: Request<Body>) -> (Self, Body) {
+        let (parts, body) = request.into_parts();
+        (Self::new(parts, Bytes::new()), body)
+    }
+
+    // Buffers the body only when `needs_body`, the unread body is returned otherwise
+    pub async fn read(request: Request<Body>, needs_body: bool) -> Result<(Self, Option<Body>), Response> {
+        match needs_body {
+            true => Ok((Self::buffer(request).await?, None)),
+            false => {
+                let (request, body) = Self::without_body(request);
+                Ok((request, Some(body)))
+            },
+        }
+    }
+
+    // Hands the parts and the buffered body back as they are, without copying them
+    pub fn into_request(self) -> Request<Body> {
+        Request::from_parts(self.parts, Body::from(self.body))
+    }
+
+    // Hands the parts back with the body `read` left unread, or the buffered one
+    pub fn with_body(self, unread_body: Option<Body>) -> Request<Body> {
+        match unread_body {
+            Some(body) => Request::from_parts(self.parts, body),
+            None => self.into_request(),
+        }
+    }
+}
 
 
 #[derive(Clone, Debug)]
@@ -42,19 +285,19 @@ impl Ord for LimitForRequest {
 
 impl PartialOrd for LimitForRequest {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.requests_to_exceed_limit.cmp(&other.requests_to_exceed_limit))
+        Some(self.cmp(other))
     }
 }
 
 
-#[derive(Debug)]
-pub struct LimitRedisKey {
-    key: String,
-    bucket: Bucket,
+#[derive(Clone, Debug)]
+pub struct LimitKey {
+    pub key: String,
+    pub bucket: Bucket,
 }
 
-impl LimitRedisKey {
-    fn new(key: String, bucket: Bucket) -> Self {
+impl LimitKey {
+    pub fn new(key: String, bucket: Bucket) -> Self {
         Self {
             key,
             bucket
@@ -62,51 +305,138 @@ impl LimitRedisKey {
     }
 }
 
-#[async_trait]
 pub trait RateLimiterChecker {
-    async fn check_limit(&self, mut redis_connection: Connection, global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>, request: &SafeRequest, addr: SocketAddr) -> Option<LimitForRequest> {
-        let limit_redis_key = match self.get_redis_key(request, addr, global_bucket, buckets_per_value) {
-            Some(key) => key,
-            None => return None, // skip this check because we can't define what value we should check
-        };
-        
-        redis::cmd("SET")
-            .arg(&limit_redis_key.key)
-            .arg(limit_redis_key.bucket.tokens_count)
-            .arg("EX")
-            .arg(limit_redis_key.bucket.add_tokens_every)
-            .arg("NX")
-            .query_async::<()>(&mut redis_connection)
-            .await
-            .unwrap_or(()); // Ignore error
+    fn get_key(&self, request: &SafeRequest, addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey>;
+}
 
-        // Decrement key
-        let count: i32 = redis::cmd("DECR")
-            .arg(&limit_redis_key.key)
-            .query_async(&mut redis_connection)
-            .await
-            .unwrap_or(-1); // Set to 0 if the key doesn't exist
 
-        Some(LimitForRequest::new(limit_redis_key.bucket.tokens_count, count,count < 0))
+#[derive(Clone, Debug)]
+pub struct IPRateLimiterStrategy {
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl IPRateLimiterStrategy {
+    pub fn new(ipv4_prefix: u8, ipv6_prefix: u8) -> Result<Self, RateLimiterError> {
+        if ipv4_prefix > 32 || ipv6_prefix > 128 {
+            return Err(RateLimiterError::config(format!(
+                "ipv4_prefix must be at most 32 and ipv6_prefix at most 128, got {} and {}", ipv4_prefix, ipv6_prefix,
+            )));
+        }
+        Ok(Self {
+            ipv4_prefix,
+            ipv6_prefix,
+        })
     }
 
-    fn hash_key(&self, s: String) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        s.hash(&mut hasher);
-        hasher.finish()
+    // Full addresses stay as they are, networks are given in CIDR notation, e.g. `2001:db8::/64`
+    pub fn network(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(_) if self.ipv4_prefix == 32 => ip.to_string(),
+            IpAddr::V6(_) if self.ipv6_prefix == 128 => ip.to_string(),
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - self.ipv4_prefix as u32).unwrap_or(0);
+                format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), self.ipv4_prefix)
+            },
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - self.ipv6_prefix as u32).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), self.ipv6_prefix)
+            },
+        }
     }
+}
 
-    fn get_redis_key(&self, request: &SafeRequest, addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey>;
+impl Default for IPRateLimiterStrategy {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+        }
+    }
+}
+#[derive(Clone, Debug, Default)]
+pub struct UrlRateLimiterStrategy {
+    normalization: Option<PathNormalizationSettings>,
+    query_params: Vec<String>,
 }
 
+impl UrlRateLimiterStrategy {
+    pub fn new(normalization: Option<PathNormalizationSettings>, query_params: Vec<String>) -> Self {
+        Self {
+            normalization,
+            query_params,
+        }
+    }
 
-#[derive(Clone, Debug)]
-pub struct IPRateLimiterStrategy;
-#[derive(Clone, Debug)]
-pub struct UrlRateLimiterStrategy;
+    // Value the buckets are looked up with: the normalized path, followed by the kept query parameters sorted by name and value,
+    // e.g. `/api/export?format=csv&type=full`. Parameters that are not kept, and their order, don't change the value.
+    pub fn bucket_value<'a>(&self, path: &'a str, query: Option<&str>) -> Cow<'a, str> {
+        let path = self.normalize(path);
+        let Some(query) = query.filter(|_| !self.query_params.is_empty()) else {
+            return path;
+        };
+
+        let mut params = form_urlencoded::parse(query.as_bytes())
+            .filter(|(name, _)| self.query_params.iter().any(|param| param == name))
+            .collect::<Vec<_>>();
+        if params.is_empty() {
+            return path;
+        }
+        params.sort_unstable();
+        let query = form_urlencoded::Serializer::new(String::new()).extend_pairs(params).finish();
+        Cow::Owned(format!("{}?{}", path, query))
+    }
 
+    // Path used for bucketing, so trivial variations like `/API//users/` share the bucket of `/api/users`
+    pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let Some(normalization) = self.normalization else {
+            return Cow::Borrowed(path);
+        };
+
+        let mut path = match normalization.percent_decode {
+            true => percent_decode_str(path).decode_utf8_lossy(),
+            false => Cow::Borrowed(path),
+        };
+        if normalization.collapse_slashes && path.contains("//") {
+            let mut collapsed = String::with_capacity(path.len());
+            for c in path.chars() {
+                if !(c == '/' && collapsed.ends_with('/')) {
+                    collapsed.push(c);
+                }
+            }
+            path = Cow::Owned(collapsed);
+        }
+        if normalization.trailing_slash && path.len() > 1 && path.ends_with('/') {
+            path = match path {
+                Cow::Borrowed(path) => Cow::Borrowed(path.trim_end_matches('/')),
+                Cow::Owned(path) => Cow::Owned(path.trim_end_matches('/').to_string()),
+            };
+            if path.is_empty() {
+                path = Cow::Borrowed("/");
+            }
+        }
+        if normalization.lowercase && path.chars().any(char::is_uppercase) {
+            path = Cow::Owned(path.to_lowercase());
+        }
+        path
+    }
+}
+
+// Keys hold the limiter and header names, so the same value in two headers or two limiters doesn't share a bucket
 #[derive(Clone, Debug)]
-pub struct HeaderRateLimiterStrategy;
+pub struct HeaderRateLimiterStrategy {
+    limiter: Option<String>,
+    fallback_header: HeaderName,
+}
+
+impl HeaderRateLimiterStrategy {
+    pub fn new(limiter: Option<String>, fallback_header: HeaderName) -> Self {
+        Self {
+            limiter,
+            fallback_header,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct RequestQueryRateLimiterStrategy;
@@ -114,101 +444,129 @@ pub struct RequestQueryRateLimiterStrategy;
 #[derive(Clone, Debug)]
 pub struct RequestBodyRateLimiterStrategy;
 
+// Requests that were not authenticated are not limited by it
+#[derive(Clone, Debug)]
+pub struct IdentityRateLimiterStrategy;
+
 
-#[async_trait]
 impl RateLimiterChecker for IPRateLimiterStrategy {
-    fn get_redis_key(&self, _request: &SafeRequest, addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey> {
-        let ip = addr.ip();
+    fn get_key(&self, request: &SafeRequest, addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
+        let network = self.network(addr.ip());
 
+        // Values can be given as an address, as a network containing the address, the most specific one first,
+        // or as the class of the address, see `ip_class`
         let bucket = match buckets_per_value {
-            Some(bucket) => bucket.get(&ip.to_string()).or(global_bucket),
+            Some(bucket) => bucket.get_network(addr.ip())
+                .or_else(|| bucket.get(&request.parts.extensions.get::<IpClass>()?.0))
+                .or(global_bucket),
             None => global_bucket
         };
 
-        Some(LimitRedisKey::new(format!("rate_limiter:ip:{}", self.hash_key(ip.to_string())), bucket?.to_owned()))
+        Some(LimitKey::new(key_builder.build("ip", &network), bucket?.to_owned()))
     }
 }
 
 
-#[async_trait]
 impl RateLimiterChecker for UrlRateLimiterStrategy {
-    fn get_redis_key(&self, request: &SafeRequest, _addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey> {
-        let uri = request.parts.uri.path();
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
+        let uri = self.bucket_value(request.parts.uri.path(), request.parts.uri.query());
 
+        // Exact values first, then the longest `/*` value the path is under
         let bucket = match buckets_per_value {
-            Some(bucket) => bucket.get(&uri.to_string()).or(global_bucket),
+            Some(bucket) => bucket.get(uri.as_ref())
+                .or_else(|| bucket.get_path_prefix(uri.split_once('?').map_or(uri.as_ref(), |(path, _)| path)))
+                .or(global_bucket),
             None => global_bucket
         };
 
-        Some(LimitRedisKey::new(format!("rate_limiter:url:{}", self.hash_key(uri.to_string())), bucket?.to_owned()))
+        Some(LimitKey::new(key_builder.build("url", &uri), bucket?.to_owned()))
     }
 }
 
 
 impl RateLimiterChecker for HeaderRateLimiterStrategy {
-    fn get_redis_key(&self, request: &SafeRequest, _addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey> {
-        let mut found_header: Option<String> = None;
-        let mut found_bucket: Option<Bucket> = None;
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
+        // The first configured header the request has counts
+        let mut found_header = buckets_per_value.and_then(|buckets| buckets.find_header(&request.parts.headers))
+            .map(|(name, value, bucket)| (name, value, bucket.to_owned()));
+
+        if found_header.is_none() && let Some(global_bucket) = global_bucket
+            && let Some(value) = request.parts.headers.get(&self.fallback_header) {
+            found_header = Some((self.fallback_header.as_str(), value, global_bucket.to_owned()));
+        }
 
-        if let Some(buckets) = buckets_per_value {
-            for (k, v) in buckets {
-                match request.parts.headers.get(k.to_lowercase()) {
-                    Some(value) => {
-                        found_header = Some(format!("{}:{}", k, value.to_str().unwrap()));
-                        found_bucket = Some(v.to_owned())
-                    },
-                    None => continue,
-                };
-            };
+        let (name, value, bucket) = found_header?;
+        let value = header_value(value);
+        let key = match &self.limiter {
+            Some(limiter) => key_builder.build_from("header", &[limiter, name, &value]),
+            None => key_builder.build_from("header", &[name, &value]),
         };
+        Some(LimitKey::new(key, bucket))
+    }
+}
 
-        if found_header.is_none() && global_bucket.is_some() {
-            if let Some(value) = request.parts.headers.get("authorization") {
-                found_header = Some(value.to_str().unwrap().to_string());
-                found_bucket = global_bucket.cloned();
-            };
-        }
 
-        Some(LimitRedisKey::new(format!("rate_limiter:header:{}", self.hash_key(found_header?)), found_bucket?))
+// Header values may contain any bytes, values that aren't UTF-8 are hex encoded instead of failing the request
+pub(crate) fn header_value(value: &HeaderValue) -> Cow<'_, str> {
+    match std::str::from_utf8(value.as_bytes()) {
+        Ok(value) => Cow::Borrowed(value),
+        Err(_) => {
+            metrics::increment_counter("rate_limiter_invalid_header_values_total", &[("strategy", "header")]);
+            Cow::Owned(format!("0x{}", hex::encode(value.as_bytes())))
+        },
     }
 }
 
 
 impl RateLimiterChecker for RequestQueryRateLimiterStrategy {
-    fn get_redis_key(&self, request: &SafeRequest, _addr: SocketAddr, _global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey> {
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, _global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
         let mut found_param: Option<String> = None;
         let mut found_bucket: Option<Bucket> = None;
 
         if let Some(query) = request.parts.uri.query() {
             for (k, v) in form_urlencoded::parse(query.as_bytes()) {
-                if let Some(bucket) = buckets_per_value?.get(&k.to_string()) {
-                    found_param = Some(format!("{}:{}", k, v));
+                if let Some(bucket) = buckets_per_value?.get(k.as_ref()) {
+                    found_param = Some(key_builder.build_from("query", &[&k, &v]));
                     found_bucket = Some(bucket.to_owned());
                     break;
                 }
             }
         }
 
-        Some(LimitRedisKey::new(format!("rate_limiter:query:{}", self.hash_key(found_param?)), found_bucket?))
+        Some(LimitKey::new(found_param?, found_bucket?))
     }
 }
 
 
 impl RateLimiterChecker for RequestBodyRateLimiterStrategy {
-    fn get_redis_key(&self, request: &SafeRequest, _addr: SocketAddr, _global_bucket: Option<&Bucket>, buckets_per_value: Option<&HashMap<String, Bucket>>) -> Option<LimitRedisKey> {
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, _global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
         let mut found_param: Option<String> = None;
         let mut found_bucket: Option<Bucket> = None;
 
         let json_body: HashMap<String, Value> = serde_json::from_slice(&request.body).unwrap_or_default();
         for (k, v) in json_body {
-            if let Some(bucket) = buckets_per_value?.get(&k.to_string()) {
-                found_param = Some(format!("{}:{}", k, v));
+            if let Some(bucket) = buckets_per_value?.get(&k) {
+                found_param = Some(key_builder.build_from("json", &[&k, &v.to_string()]));
                 found_bucket = Some(bucket.to_owned());
                 break;
             }
         }
-        
-        Some(LimitRedisKey::new(format!("rate_limiter:json:{}", self.hash_key(found_param?)), found_bucket?))
+
+        Some(LimitKey::new(found_param?, found_bucket?))
+    }
+}
+
+
+impl RateLimiterChecker for IdentityRateLimiterStrategy {
+    fn get_key(&self, request: &SafeRequest, _addr: SocketAddr, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>, key_builder: &KeyBuilder) -> Option<LimitKey> {
+        let Identity(identity) = request.parts.extensions.get::<Identity>()?;
+
+        let bucket = match buckets_per_value {
+            Some(bucket) => bucket.get(identity).or(global_bucket),
+            None => global_bucket
+        };
+
+        Some(LimitKey::new(key_builder.build("identity", identity), bucket?.to_owned()))
     }
 }
 
@@ -220,35 +578,222 @@ pub enum Strategy {
     Header(HeaderRateLimiterStrategy),
     Query(RequestQueryRateLimiterStrategy),
     Body(RequestBodyRateLimiterStrategy),
+    Identity(IdentityRateLimiterStrategy),
+    // Only created from an OpenAPI document, see `openapi`
+    Operation(OperationRateLimiterStrategy),
 }
 
 impl Strategy {
+    // Only the body strategy reads the body of requests
+    pub fn needs_body(&self) -> bool {
+        matches!(self, Strategy::Body(_))
+    }
+
     pub fn from_possible_strategy(strategy: &PossibleStrategies) -> Self {
         match strategy {
-            PossibleStrategies::IP => Strategy::IP(IPRateLimiterStrategy),
-            PossibleStrategies::URL => Strategy::Url(UrlRateLimiterStrategy),
-            PossibleStrategies::Header => Strategy::Header(HeaderRateLimiterStrategy),
+            PossibleStrategies::IP => Strategy::IP(IPRateLimiterStrategy::default()),
+            PossibleStrategies::URL => Strategy::Url(UrlRateLimiterStrategy::default()),
+            PossibleStrategies::Header => Strategy::Header(HeaderRateLimiterStrategy::new(None, AUTHORIZATION)),
             PossibleStrategies::Query => Strategy::Query(RequestQueryRateLimiterStrategy),
             PossibleStrategies::Body => Strategy::Body(RequestBodyRateLimiterStrategy),
+            PossibleStrategies::Identity => Strategy::Identity(IdentityRateLimiterStrategy),
+        }
+    }
+
+    // Header limiters scope their keys by limiter name and fall back to their own header, url limiters normalize paths and keep query parameters, ip limiters aggregate networks
+    pub fn for_limiter(name: &str, settings: &LimiterSettings) -> Result<Self, RateLimiterError> {
+        match settings.strategy {
+            PossibleStrategies::Header => {
+                let fallback_header = HeaderName::try_from(settings.fallback_header.as_str())
+                    .map_err(|_| RateLimiterError::config(format!("Invalid fallback_header {:?} of limiter {}", settings.fallback_header, name)))?;
+                Ok(Strategy::Header(HeaderRateLimiterStrategy::new(Some(name.to_string()), fallback_header)))
+            },
+            PossibleStrategies::IP => Ok(Strategy::IP(IPRateLimiterStrategy::new(settings.ipv4_prefix, settings.ipv6_prefix)?)),
+            PossibleStrategies::URL => Ok(Strategy::Url(UrlRateLimiterStrategy::new(settings.normalize_path, settings.query_params.clone()))),
+            _ => Ok(Self::from_possible_strategy(&settings.strategy)),
         }
     }
 
-    pub async fn check_limit(
+    pub fn get_key(
         &self,
-        redis_connection: Connection,
-        global_bucket: Option<&Bucket>,
-        buckets_per_value: Option<&HashMap<String, Bucket>>,
         request: &SafeRequest,
         addr: SocketAddr,
-    ) -> Option<LimitForRequest> {
+        global_bucket: Option<&Bucket>,
+        buckets_per_value: Option<&BucketsPerValue>,
+        key_builder: &KeyBuilder,
+    ) -> Option<LimitKey> {
         match self {
-            Strategy::IP(strategy) => strategy.check_limit(redis_connection, global_bucket, buckets_per_value, request, addr).await,
-            Strategy::Url(strategy) => strategy.check_limit(redis_connection, global_bucket, buckets_per_value,request, addr).await,
-            Strategy::Header(strategy) => strategy.check_limit(redis_connection, global_bucket, buckets_per_value,request, addr).await,
-            Strategy::Query(strategy) => strategy.check_limit(redis_connection, global_bucket, buckets_per_value,request, addr).await,
-            Strategy::Body(strategy) => strategy.check_limit(redis_connection, global_bucket, buckets_per_value,request, addr).await,
-
+            Strategy::IP(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Url(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Header(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Query(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Body(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Identity(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
+            Strategy::Operation(strategy) => strategy.get_key(request, addr, global_bucket, buckets_per_value, key_builder),
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{KeyHashing, KeySettings};
+    use crate::testing::TestRequest;
+    use super::*;
+
+    fn key_builder() -> KeyBuilder {
+        KeyBuilder::new(&KeySettings { prefix: "rl".to_string(), hashing: KeyHashing::Plain, siphash_key: None }).unwrap()
+    }
+
+    fn get_key(strategy: &impl RateLimiterChecker, request: TestRequest, global_bucket: Option<&Bucket>, buckets_per_value: Option<&BucketsPerValue>) -> Option<(String, u32)> {
+        let addr = request.addr();
+        strategy.get_key(&request.into_safe_request(), addr, global_bucket, buckets_per_value, &key_builder())
+            .map(|limit_key| (limit_key.key, limit_key.bucket.tokens_count))
+    }
+
+    fn from_ip(ip: &str) -> TestRequest {
+        TestRequest::get("/").ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_by_their_prefix() {
+        let strategy = IPRateLimiterStrategy::default();
+        let global_bucket = Bucket::new(10, 60);
+        let key = |ip| get_key(&strategy, from_ip(ip), Some(&global_bucket), None).unwrap().0;
+
+        assert_eq!(key("2001:db8:0:1::1"), "rl:ip:2001:db8:0:1::/64");
+        assert_eq!(key("2001:db8:0:1:ffff:ffff:ffff:ffff"), "rl:ip:2001:db8:0:1::/64");
+        assert_eq!(key("2001:db8:0:2::1"), "rl:ip:2001:db8:0:2::/64");
+        assert_eq!(key("1.2.3.4"), "rl:ip:1.2.3.4");
+    }
+
+    #[test]
+    fn prefixes_are_configurable() {
+        let strategy = IPRateLimiterStrategy::new(24, 128).unwrap();
+        let global_bucket = Bucket::new(10, 60);
+        let key = |ip| get_key(&strategy, from_ip(ip), Some(&global_bucket), None).unwrap().0;
+
+        assert_eq!(key("1.2.3.4"), "rl:ip:1.2.3.0/24");
+        assert_eq!(key("2001:db8::1"), "rl:ip:2001:db8::1");
+        assert_eq!(IPRateLimiterStrategy::new(0, 0).unwrap().network("1.2.3.4".parse().unwrap()), "0.0.0.0/0");
+
+        let error = IPRateLimiterStrategy::new(33, 64).unwrap_err();
+        assert_eq!(error.to_string(), RateLimiterError::config("ipv4_prefix must be at most 32 and ipv6_prefix at most 128, got 33 and 64").to_string());
+        assert!(IPRateLimiterStrategy::new(32, 129).is_err());
+    }
+
+    #[test]
+    fn the_most_specific_network_gives_the_bucket() {
+        let strategy = IPRateLimiterStrategy::default();
+        let buckets = BucketsPerValue::new([
+            ("10.0.0.0/8".to_string(), Bucket::new(1, 60)),
+            ("10.1.0.0/16".to_string(), Bucket::new(2, 60)),
+            ("10.1.2.3".to_string(), Bucket::new(3, 60)),
+            ("2001:db8::/32".to_string(), Bucket::new(4, 60)),
+        ]);
+        let global_bucket = Bucket::new(10, 60);
+        let tokens = |ip, global_bucket| get_key(&strategy, from_ip(ip), global_bucket, Some(&buckets)).map(|(_, tokens)| tokens);
+
+        assert_eq!(tokens("10.9.9.9", None), Some(1));
+        assert_eq!(tokens("10.1.9.9", None), Some(2));
+        assert_eq!(tokens("10.1.2.3", None), Some(3));
+        assert_eq!(tokens("2001:db8:1::1", None), Some(4));
+        assert_eq!(tokens("192.0.2.1", Some(&global_bucket)), Some(10));
+        assert_eq!(tokens("192.0.2.1", None), None);
+    }
+
+    #[test]
+    fn header_keys_hold_the_limiter_and_header_names() {
+        let strategy = HeaderRateLimiterStrategy::new(Some("api".to_string()), AUTHORIZATION);
+        let buckets = BucketsPerValue::new([
+            ("X-Api-Key".to_string(), Bucket::new(1, 60)),
+            ("X-Tenant".to_string(), Bucket::new(2, 60)),
+        ]);
+        let get = |request: TestRequest| get_key(&strategy, request, None, Some(&buckets));
+
+        assert_eq!(get(TestRequest::get("/").header("X-Api-Key", "abc")), Some(("rl:header:api:X-Api-Key:abc".to_string(), 1)));
+        assert_eq!(get(TestRequest::get("/").header("X-Tenant", "abc")), Some(("rl:header:api:X-Tenant:abc".to_string(), 2)));
+        // The first configured header wins
+        assert_eq!(get(TestRequest::get("/").header("X-Tenant", "t").header("X-Api-Key", "k")), Some(("rl:header:api:X-Api-Key:k".to_string(), 1)));
+
+        let unnamed = HeaderRateLimiterStrategy::new(None, AUTHORIZATION);
+        assert_eq!(get_key(&unnamed, TestRequest::get("/").header("X-Api-Key", "abc"), None, Some(&buckets)).unwrap().0, "rl:header:X-Api-Key:abc");
+    }
+
+    #[test]
+    fn the_fallback_header_gets_the_global_bucket() {
+        let global_bucket = Bucket::new(10, 60);
+        let buckets = BucketsPerValue::new([("X-Api-Key".to_string(), Bucket::new(1, 60))]);
+        let strategy = HeaderRateLimiterStrategy::new(Some("api".to_string()), HeaderName::from_static("x-client-id"));
+        let get = |request: TestRequest, global_bucket| get_key(&strategy, request, global_bucket, Some(&buckets));
+
+        assert_eq!(get(TestRequest::get("/").header("X-Client-Id", "c1"), Some(&global_bucket)), Some(("rl:header:api:x-client-id:c1".to_string(), 10)));
+        assert_eq!(get(TestRequest::get("/").header("X-Client-Id", "c1").header("X-Api-Key", "k"), Some(&global_bucket)).unwrap().1, 1);
+        // Only the configured fallback header counts, and only with a global bucket
+        assert_eq!(get(TestRequest::get("/").header("Authorization", "Bearer t"), Some(&global_bucket)), None);
+        assert_eq!(get(TestRequest::get("/").header("X-Client-Id", "c1"), None), None);
+
+        let default_fallback = HeaderRateLimiterStrategy::new(None, AUTHORIZATION);
+        assert_eq!(get_key(&default_fallback, TestRequest::get("/").header("Authorization", "t"), Some(&global_bucket), None).unwrap().0, "rl:header:authorization:t");
+    }
+
+    #[test]
+    fn header_values_that_are_not_utf8_are_hex_encoded() {
+        let strategy = HeaderRateLimiterStrategy::new(None, AUTHORIZATION);
+        let global_bucket = Bucket::new(10, 60);
+        let mut request = TestRequest::get("/").into_safe_request();
+        request.parts.headers.insert(AUTHORIZATION, HeaderValue::from_bytes(b"a\xff").unwrap());
+
+        let limit_key = strategy.get_key(&request, "192.0.2.1:1".parse().unwrap(), Some(&global_bucket), None, &key_builder()).unwrap();
+        assert_eq!(limit_key.key, "rl:header:authorization:0x61ff");
+    }
+
+    fn normalization(percent_decode: bool, collapse_slashes: bool, trailing_slash: bool, lowercase: bool) -> Option<PathNormalizationSettings> {
+        Some(PathNormalizationSettings { percent_decode, collapse_slashes, trailing_slash, lowercase })
+    }
+
+    #[test]
+    fn paths_are_normalized_as_configured() {
+        let all = UrlRateLimiterStrategy::new(normalization(true, true, true, true), Vec::new());
+        assert_eq!(all.normalize("/API//users/"), "/api/users");
+        assert_eq!(all.normalize("/api/%55sers"), "/api/users");
+        assert_eq!(all.normalize("//"), "/");
+        assert_eq!(all.normalize("/"), "/");
+
+        assert_eq!(UrlRateLimiterStrategy::new(normalization(false, true, true, true), Vec::new()).normalize("/api/%55sers"), "/api/%55sers");
+        assert_eq!(UrlRateLimiterStrategy::new(normalization(true, false, true, true), Vec::new()).normalize("/api//users"), "/api//users");
+        assert_eq!(UrlRateLimiterStrategy::new(normalization(true, true, false, true), Vec::new()).normalize("/api/users/"), "/api/users/");
+        assert_eq!(UrlRateLimiterStrategy::new(normalization(true, true, true, false), Vec::new()).normalize("/API/Users"), "/API/Users");
+        assert!(matches!(UrlRateLimiterStrategy::default().normalize("/API//users/"), Cow::Borrowed("/API//users/")));
+    }
+
+    #[test]
+    fn only_kept_query_params_are_part_of_the_value_sorted() {
+        let strategy = UrlRateLimiterStrategy::new(normalization(true, true, true, true), vec!["format".to_string(), "type".to_string()]);
+        assert_eq!(strategy.bucket_value("/Export/", Some("type=full&page=2&format=csv")), "/export?format=csv&type=full");
+        assert_eq!(strategy.bucket_value("/export", Some("format=csv&type=full")), "/export?format=csv&type=full");
+        assert_eq!(strategy.bucket_value("/export", Some("page=2")), "/export");
+        assert_eq!(strategy.bucket_value("/export", None), "/export");
+        assert_eq!(strategy.bucket_value("/export", Some("format=a%20b")), "/export?format=a+b");
+        assert_eq!(UrlRateLimiterStrategy::default().bucket_value("/export", Some("format=csv")), "/export");
+    }
+
+    #[test]
+    fn url_keys_use_exact_values_then_path_prefixes() {
+        let strategy = UrlRateLimiterStrategy::new(normalization(true, true, true, true), vec!["format".to_string()]);
+        let buckets = BucketsPerValue::new([
+            ("/api/login".to_string(), Bucket::new(1, 60)),
+            ("/api/*".to_string(), Bucket::new(2, 60)),
+            ("/api/export?format=csv".to_string(), Bucket::new(3, 60)),
+        ]);
+        let global_bucket = Bucket::new(10, 60);
+        let get = |uri: &str, global_bucket| get_key(&strategy, TestRequest::get(uri), global_bucket, Some(&buckets));
+
+        assert_eq!(get("/API/Login/", None), Some(("rl:url:/api/login".to_string(), 1)));
+        assert_eq!(get("/api/users/42", None), Some(("rl:url:/api/users/42".to_string(), 2)));
+        assert_eq!(get("/api/export?format=csv&page=3", None), Some(("rl:url:/api/export?format=csv".to_string(), 3)));
+        assert_eq!(get("/api/export?format=json", None), Some(("rl:url:/api/export?format=json".to_string(), 2)));
+        assert_eq!(get("/apis", Some(&global_bucket)), Some(("rl:url:/apis".to_string(), 10)));
+        assert_eq!(get("/apis", None), None);
+    }
+}
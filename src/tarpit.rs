@@ -0,0 +1,62 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use crate::abuse::DenialCounter;
+use crate::error::RateLimiterError;
+use crate::metrics;
+use crate::settings::TarpitSettings;
+
+
+// Slows down the 429 responses of clients that keep retrying while limited.
+// Only `max_concurrent` responses are held at once, so the tarpit can't be used to exhaust the proxy.
+#[derive(Debug)]
+pub struct Tarpit {
+    denials: DenialCounter,
+    slots: Semaphore,
+    random: RandomState,
+    min_denials: u32,
+    delay: Duration,
+    jitter_ms: u64,
+}
+
+impl Tarpit {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of old windows
+    pub fn new(settings: &TarpitSettings) -> Result<Self, RateLimiterError> {
+        if settings.window_secs == 0 {
+            return Err(RateLimiterError::config("tarpit.window_secs must be greater than 0"));
+        }
+
+        Ok(Self {
+            denials: DenialCounter::new(Duration::from_secs(settings.window_secs)),
+            slots: Semaphore::new(settings.max_concurrent),
+            random: RandomState::new(),
+            min_denials: settings.min_denials,
+            delay: Duration::from_millis(settings.delay_ms),
+            jitter_ms: settings.jitter_ms,
+        })
+    }
+
+    // Counts a denial of the client and holds it back once it was denied `min_denials` times in the window
+    pub async fn hold(&self, ip: IpAddr) {
+        if self.denials.record(ip) < self.min_denials {
+            return;
+        }
+
+        // Past the limit of held responses clients get their 429 right away
+        let Ok(_slot) = self.slots.try_acquire() else {
+            return;
+        };
+        metrics::increment_counter("rate_limiter_tarpitted_total", &[]);
+        tokio::time::sleep(self.delay + self.jitter()).await;
+    }
+
+    // Spreads the delays, so held clients don't all retry at the same time
+    fn jitter(&self) -> Duration {
+        match self.jitter_ms {
+            0 => Duration::ZERO,
+            jitter_ms => Duration::from_millis(self.random.hash_one(Instant::now()) % (jitter_ms + 1)),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::HOST;
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, HeaderName, Request};
+use axum::response::Response;
+use axum::Router;
+use tower_service::Service;
+use crate::error::RateLimiterError;
+use crate::limiter::RateLimiterManager;
+use crate::settings::{SplitSettings, StickySettings, TenantSettings};
+
+
+#[derive(Debug, Clone)]
+pub struct TenantMatcher {
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    // Expected value of every header, None only requires the header to be present
+    headers: Vec<(HeaderName, Option<String>)>,
+}
+
+impl TenantMatcher {
+    pub fn new(settings: &TenantSettings) -> Result<Self, RateLimiterError> {
+        if settings.hosts.is_empty() && settings.path_prefix.is_none() && settings.headers.is_empty() {
+            return Err(RateLimiterError::config(format!("Tenant {} needs hosts, a path_prefix or headers", settings.name)));
+        }
+        if let Some(path_prefix) = &settings.path_prefix && !path_prefix.starts_with('/') {
+            return Err(RateLimiterError::config(format!("path_prefix of tenant {} must start with /", settings.name)));
+        }
+
+        Ok(Self {
+            hosts: settings.hosts.clone(),
+            path_prefix: settings.path_prefix.as_ref().map(|path_prefix| path_prefix.trim_end_matches('/').to_string()),
+            headers: parse_headers(&settings.name, &settings.headers)?,
+        })
+    }
+
+    // Hosts are compared without their port, a path prefix only matches whole segments and header values must be equal
+    pub fn matches(&self, host: Option<&str>, path: &str, headers: &HeaderMap) -> bool {
+        let host_matches = self.hosts.is_empty() || host.is_some_and(|host| self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)));
+        let path_matches = match &self.path_prefix {
+            Some(path_prefix) => path.strip_prefix(path_prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+            None => true,
+        };
+        let headers_match = self.headers.iter().all(|(name, expected)| match (headers.get(name), expected) {
+            (Some(value), Some(expected)) => value.as_bytes() == expected.as_bytes(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        });
+        host_matches && path_matches && headers_match
+    }
+}
+
+// `*` only requires the header to be present
+fn parse_headers(tenant: &str, headers: &HashMap<String, String>) -> Result<Vec<(HeaderName, Option<String>)>, RateLimiterError> {
+    headers.iter()
+        .map(|(name, value)| {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|e| RateLimiterError::config(format!("Invalid header {} of tenant {}: {}", name, tenant, e)))?;
+            Ok((name, (value != "*").then(|| value.clone())))
+        })
+        .collect()
+}
+
+
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    pub matcher: TenantMatcher,
+    pub target_url: Option<String>,
+    pub splits: Vec<SplitSettings>,
+    pub sticky: Option<StickySettings>,
+    pub limiter: Arc<RateLimiterManager>,
+}
+
+impl Tenant {
+    pub async fn new(settings: &TenantSettings) -> Result<Self, RateLimiterError> {
+        let tenant = Self::build(settings)?;
+        tenant.limiter.check_store_connection().await?;
+        Ok(tenant)
+    }
+
+    // Same as `new` without checking the connection to the store
+    pub fn build(settings: &TenantSettings) -> Result<Self, RateLimiterError> {
+        if settings.name.is_empty() || settings.name.contains(':') {
+            return Err(RateLimiterError::config(format!("Invalid tenant name {:?}, it must be non empty and can't contain ':'", settings.name)));
+        }
+
+        let mut rate_limiter_settings = settings.rate_limiter_settings.clone();
+        rate_limiter_settings.keys.prefix = format!("{}:{}", rate_limiter_settings.keys.prefix, settings.name);
+        let limiter = Arc::new(RateLimiterManager::new(rate_limiter_settings)?);
+
+        Ok(Self {
+            name: settings.name.clone(),
+            matcher: TenantMatcher::new(settings)?,
+            target_url: settings.target_url.clone(),
+            splits: settings.splits.clone(),
+            sticky: settings.sticky.clone(),
+            limiter,
+        })
+    }
+}
+
+
+struct TenantRoutes {
+    tenants: Vec<(TenantMatcher, Router)>,
+    default: Router,
+}
+
+// Sends every request to the router of the first matching tenant, or to the default one
+pub fn router(tenants: Vec<(TenantMatcher, Router)>, default: Router) -> Router {
+    Router::new()
+        .fallback(dispatch)
+        .with_state(Arc::new(TenantRoutes { tenants, default }))
+}
+
+async fn dispatch(State(routes): State<Arc<TenantRoutes>>, request: Request<Body>) -> Response {
+    let host = request.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .or_else(|| request.uri().authority().cloned());
+
+    let mut router = routes.tenants.iter()
+        .find(|(matcher, _)| matcher.matches(host.as_ref().map(Authority::host), request.uri().path(), request.headers()))
+        .map_or(&routes.default, |(_, router)| router)
+        .clone();
+
+    match router.call(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Method, Request};
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::gcra;
+use crate::store::LimitStore;
+use crate::strategy::{Bucket, SafeRequest};
+
+// 2023-11-14T22:13:20Z, any fixed time works as long as it's far enough from the epoch for GCRA
+const START_US: u64 = 1_700_000_000_000_000;
+
+
+// Deterministic building blocks to test code using the limiter without Redis or sleeps, enabled with the `testing` feature.
+// `MockStore` and managers built with `RateLimiterBuilder::clock` follow `MockClock`: windows, GCRA, quota periods and
+// schedules move with it, while the deny cache, tarpit and challenges still use the real time.
+
+// A clock that only moves when told to. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now_us: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now_us: Arc::new(AtomicU64::new(START_US)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_us.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_us(&self) -> u64 {
+        self.now_us.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[derive(Debug)]
+struct MockBucket {
+    remaining: i32,
+    // Only used by buckets with a burst
+    tat_us: Option<u64>,
+    window_ends_at_us: u64,
+    expires_at_us: u64,
+}
+
+// Same semantics as the memory store, with buckets and sets expiring on the time of a `MockClock`.
+// Every call can be made to fail to test `on_store_error`.
+#[derive(Debug)]
+pub struct MockStore {
+    clock: MockClock,
+    buckets: Mutex<HashMap<String, MockBucket>>,
+    // Values and expiry of the sets of `count_distinct`
+    sets: Mutex<HashMap<String, (HashSet<String>, u64)>>,
+    failing: AtomicBool,
+    calls: AtomicU64,
+}
+
+impl MockStore {
+    pub fn new(clock: MockClock) -> Arc<Self> {
+        Arc::new(Self {
+            clock,
+            buckets: Mutex::new(HashMap::new()),
+            sets: Mutex::new(HashMap::new()),
+            failing: AtomicBool::new(false),
+            calls: AtomicU64::new(0),
+        })
+    }
+
+    // While failing, every consume returns an error as if the store was unreachable
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+
+    // Tokens left in the bucket of `key`, None if it doesn't exist or expired
+    pub fn remaining(&self, key: &str) -> Option<i32> {
+        let now_us = self.clock.now_us();
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner()).get(key)
+            .filter(|bucket| bucket.expires_at_us > now_us)
+            .map(|bucket| bucket.remaining)
+    }
+
+    // Keys of the buckets that were not dropped yet, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self.buckets.lock().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    // Number of calls made to the store so far, including failed ones
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl LimitStore for MockStore {
+    async fn consume(&self, key: &str, bucket: &Bucket, tokens: u32) -> Result<i32, RateLimiterError> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(RateLimiterError::Store("MockStore is failing".to_string()));
+        }
+
+        let now_us = self.clock.now_us();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if !buckets.contains_key(key) {
+            buckets.insert(key.to_string(), new_bucket(bucket, bucket.tokens_count as i32, now_us));
+        }
+        let entry = buckets.get_mut(key).expect("the bucket was just inserted");
+
+        if let Some(burst) = bucket.burst {
+            let tat_us = entry.tat_us.filter(|_| entry.expires_at_us > now_us);
+            let (tat_us, remaining) = gcra::consume(tat_us, now_us, bucket, burst, tokens, 0);
+            let expires_at_us = now_us + gcra::ttl_us(tat_us, now_us);
+            *entry = MockBucket { remaining, tat_us: Some(tat_us), window_ends_at_us: expires_at_us, expires_at_us };
+            return Ok(remaining);
+        }
+
+        if entry.window_ends_at_us <= now_us {
+            let remaining = match entry.expires_at_us > now_us {
+                true => bucket.refill(entry.remaining),
+                false => bucket.tokens_count as i32,
+            };
+            *entry = new_bucket(bucket, remaining, now_us);
+        }
+        entry.remaining -= tokens as i32;
+        Ok(entry.remaining)
+    }
+
+    async fn peek(&self, key: &str, bucket: &Bucket) -> Result<i32, RateLimiterError> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(RateLimiterError::Store("MockStore is failing".to_string()));
+        }
+
+        let now_us = self.clock.now_us();
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = buckets.get(key).filter(|entry| entry.expires_at_us > now_us);
+        match bucket.burst {
+            Some(burst) => Ok(gcra::consume(entry.and_then(|entry| entry.tat_us), now_us, bucket, burst, 0, 0).1),
+            None => Ok(entry.map_or(bucket.tokens_count as i32, |entry| match entry.window_ends_at_us > now_us {
+                true => entry.remaining,
+                false => bucket.refill(entry.remaining),
+            })),
+        }
+    }
+
+    async fn window_ends_in_ms(&self, key: &str, _bucket: &Bucket) -> Result<Option<u64>, RateLimiterError> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(RateLimiterError::Store("MockStore is failing".to_string()));
+        }
+
+        let now_us = self.clock.now_us();
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let window_ends_at_us = buckets.get(key).map_or(now_us, |entry| entry.window_ends_at_us);
+        Ok(Some(window_ends_at_us.saturating_sub(now_us).div_ceil(1000)))
+    }
+
+    async fn count_distinct(&self, key: &str, value: &str, ttl_secs: u32) -> Result<u64, RateLimiterError> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(RateLimiterError::Store("MockStore is failing".to_string()));
+        }
+
+        let now_us = self.clock.now_us();
+        let mut sets = self.sets.lock().unwrap_or_else(|e| e.into_inner());
+        let (values, expires_at_us) = sets.entry(key.to_string()).or_insert_with(|| (HashSet::new(), now_us));
+        if *expires_at_us <= now_us {
+            values.clear();
+        }
+        *expires_at_us = now_us + ttl_secs as u64 * 1_000_000;
+        values.insert(value.to_string());
+        Ok(values.len() as u64)
+    }
+}
+
+fn new_bucket(bucket: &Bucket, remaining: i32, now_us: u64) -> MockBucket {
+    let window_ends_at_us = now_us + bucket.add_tokens_every as u64 * 1_000_000;
+    MockBucket {
+        remaining,
+        tat_us: None,
+        window_ends_at_us,
+        expires_at_us: window_ends_at_us + bucket.debt_secs() as u64 * 1_000_000,
+    }
+}
+
+
+// Builds requests for `RateLimiterManager::handle`, `check` and the tower layer, e.g.
+// `TestRequest::get("/api").header("X-Api-Key", "abc").ip("1.2.3.4".parse().unwrap())`
+#[derive(Debug)]
+pub struct TestRequest {
+    method: Method,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: String,
+    addr: SocketAddr,
+}
+
+impl TestRequest {
+    pub fn new(method: Method, uri: impl Into<String>) -> Self {
+        Self {
+            method,
+            uri: uri.into(),
+            headers: Vec::new(),
+            body: String::new(),
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 40000),
+        }
+    }
+
+    pub fn get(uri: impl Into<String>) -> Self {
+        Self::new(Method::GET, uri)
+    }
+
+    pub fn post(uri: impl Into<String>) -> Self {
+        Self::new(Method::POST, uri)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Client address, 192.0.2.1 by default
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.addr.set_ip(ip);
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // The request carries ConnectInfo, as the tower layer expects. Panics on invalid methods, URIs or headers.
+    pub fn into_request(self) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(self.method)
+            .uri(self.uri)
+            .extension(ConnectInfo(self.addr));
+        for (name, value) in self.headers {
+            request = request.header(name, value);
+        }
+        request.body(Body::from(self.body)).expect("Invalid test request")
+    }
+
+    pub fn into_safe_request(mut self) -> SafeRequest {
+        let body = std::mem::take(&mut self.body);
+        let (parts, _) = self.into_request().into_parts();
+        SafeRequest::new(parts, body.into())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum::http::HeaderName;
+use serde::Serialize;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::metrics;
+use crate::settings::UniqueClientsSettings;
+use crate::store::LimitStore;
+use crate::strategy::{header_value, SafeRequest};
+
+
+// Distinct clients of a route, as last counted by this instance
+#[derive(Serialize, Debug, Clone)]
+pub struct UniqueClientsStatus {
+    pub route: String,
+    pub window_secs: u32,
+    pub clients: u64,
+    // Count of the window before the current one, if this instance saw both
+    pub previous_window_clients: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct RouteCount {
+    window: u64,
+    clients: u64,
+    previous_window_clients: Option<u64>,
+}
+
+
+// Adds every client to a set per route and window of the store, a HyperLogLog with Redis, so all instances count
+// the same clients. The count returned by the store is kept for the admin server and the `rate_limiter_unique_clients` gauge.
+#[derive(Debug)]
+pub struct UniqueClients {
+    routes: Vec<String>,
+    header: Option<HeaderName>,
+    window_secs: u32,
+    counts: Mutex<HashMap<String, RouteCount>>,
+}
+
+impl UniqueClients {
+    pub fn new(settings: &UniqueClientsSettings) -> Result<Self, RateLimiterError> {
+        if settings.routes.is_empty() || settings.window_secs == 0 {
+            return Err(RateLimiterError::config("unique_clients needs routes and window_secs greater than 0"));
+        }
+        if let Some(route) = settings.routes.iter().find(|route| !route.starts_with('/')) {
+            return Err(RateLimiterError::config(format!("Route {} of unique_clients must start with /", route)));
+        }
+        let header = settings.header.as_deref()
+            .map(HeaderName::try_from)
+            .transpose()
+            .map_err(|e| RateLimiterError::config(format!("Invalid header of unique_clients: {}", e)))?;
+
+        Ok(Self {
+            routes: settings.routes.iter().map(|route| route.trim_end_matches('/').to_string()).collect(),
+            header,
+            window_secs: settings.window_secs,
+            counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Counts the client of the request in the background, so the request doesn't wait for the store
+    pub fn record(self: &Arc<Self>, request: &SafeRequest, addr: SocketAddr, store: &Arc<dyn LimitStore>, key_builder: &KeyBuilder) {
+        let Some(route) = self.route(request.parts.uri.path()) else {
+            return;
+        };
+        let client = match &self.header {
+            Some(header) => match request.parts.headers.get(header) {
+                Some(value) => header_value(value).into_owned(),
+                None => return,
+            },
+            None => addr.ip().to_string(),
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default();
+        let window_secs = self.window_secs as u64;
+        let window = now / window_secs;
+        let key = key_builder.build("unique_clients", &format!("{}:{}", route, window));
+        let ttl_secs = (window_secs - now % window_secs) as u32;
+
+        let (unique_clients, store, route) = (self.clone(), store.clone(), route.to_string());
+        tokio::spawn(async move {
+            match store.count_distinct(&key, &client, ttl_secs).await {
+                Ok(clients) => unique_clients.update(&route, window, clients),
+                Err(e) => println!("Failed to count the unique clients of {}: {}", route, e),
+            }
+        });
+    }
+
+    pub fn statuses(&self) -> Vec<UniqueClientsStatus> {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statuses = counts.iter()
+            .map(|(route, count)| UniqueClientsStatus {
+                route: route.clone(),
+                window_secs: self.window_secs,
+                clients: count.clients,
+                previous_window_clients: count.previous_window_clients,
+            })
+            .collect::<Vec<_>>();
+        statuses.sort_unstable_by(|a, b| a.route.cmp(&b.route));
+        statuses
+    }
+
+    fn route(&self, path: &str) -> Option<&str> {
+        self.routes.iter()
+            .filter(|route| path.strip_prefix(route.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|route| route.len())
+            .map(|route| if route.is_empty() { "/" } else { route.as_str() })
+    }
+
+    // Counts only grow within a window, so an answer arriving late never lowers them
+    fn update(&self, route: &str, window: u64, clients: u64) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(route.to_string()).or_default();
+        match window.cmp(&count.window) {
+            std::cmp::Ordering::Greater => {
+                count.previous_window_clients = (count.window + 1 == window).then_some(count.clients);
+                count.window = window;
+                count.clients = clients;
+            },
+            std::cmp::Ordering::Equal => count.clients = count.clients.max(clients),
+            std::cmp::Ordering::Less => return,
+        }
+        metrics::set_gauge("rate_limiter_unique_clients", &[("route", route)], count.clients);
+    }
+}
@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::extract::ConnectInfo;
+use axum::http::Uri;
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{UnixListener, UnixStream};
+use tower_service::Service;
+
+
+// Addresses starting with `unix:` are paths of Unix domain sockets, e.g. `unix:/run/app/gunicorn.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+pub fn socket_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+
+pub fn bind(path: &Path) -> Result<UnixListener, std::io::Error> {
+    // A socket left behind by a previous run would make the bind fail, other kinds of files are never removed
+    if let Ok(metadata) = std::fs::symlink_metadata(path) && metadata.file_type().is_socket() {
+        std::fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to bind Unix socket {}: {}", path.display(), e)))
+}
+
+// Unix socket clients have no IP address, so limiters see them as coming from 127.0.0.1
+pub async fn serve(listener: UnixListener, app: Router) -> Result<(), std::io::Error> {
+    let app = app.layer(Extension(ConnectInfo(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))));
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("Unix socket connection error: {}", e);
+            }
+        });
+    }
+}
+
+
+// Connects the proxy client to an upstream listening on a Unix socket, whatever the host of the request URI
+#[derive(Clone, Debug)]
+pub struct UnixConnector {
+    path: Arc<PathBuf>,
+}
+
+impl UnixConnector {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: Arc::new(path.to_path_buf()),
+        }
+    }
+}
+
+impl Service<Uri> for UnixConnector {
+    type Response = TokioIo<UnixStream>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move {
+            Ok(TokioIo::new(UnixStream::connect(path.as_path()).await?))
+        })
+    }
+}
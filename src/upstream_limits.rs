@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use dashmap::DashMap;
+use crate::error::RateLimiterError;
+use crate::key::KeyBuilder;
+use crate::settings::{KeySettings, UpstreamLimitsSettings};
+use crate::strategy::{Bucket, BucketsPerValue, LimitForRequest, SafeRequest, Strategy};
+
+// Resets above this are unix timestamps rather than seconds left, like GitHub sends them
+const MIN_RESET_TIMESTAMP: u64 = 1_000_000_000;
+// How long a client is throttled when the upstream denied it without saying until when
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(1);
+
+
+// Reads the rate limit headers the upstream answers with. Its limit can be sent to the client instead of the gateway's,
+// and clients the upstream ran out for are denied by this instance until the upstream's reset instead of being forwarded.
+#[derive(Debug)]
+pub struct UpstreamLimits {
+    merge: bool,
+    throttle: bool,
+    max_throttle: Duration,
+    max_clients: usize,
+    strategy: Strategy,
+    // Names the strategy looks for, e.g. header names, with placeholder buckets
+    values: BucketsPerValue,
+    key_builder: KeyBuilder,
+    limit: HeaderName,
+    remaining: HeaderName,
+    reset: HeaderName,
+    throttled_until: Arc<DashMap<String, Instant>>,
+}
+
+// Limit the upstream reported for a response, in the terms of the gateway's own limits
+#[derive(Clone, Debug)]
+pub struct UpstreamLimit {
+    pub limit: LimitForRequest,
+    pub reset_secs: u32,
+}
+
+impl UpstreamLimits {
+    // Must be called inside a tokio runtime, as it spawns the cleanup of expired throttles
+    pub fn new(settings: &UpstreamLimitsSettings) -> Result<Self, RateLimiterError> {
+        let header = |setting: &str, name: &str| HeaderName::try_from(name)
+            .map_err(|_| RateLimiterError::config(format!("Invalid upstream_limits.{} {:?}", setting, name)));
+        let throttled_until = Arc::new(DashMap::new());
+        if settings.throttle {
+            tokio::spawn(remove_expired(Arc::downgrade(&throttled_until)));
+        }
+
+        Ok(Self {
+            merge: settings.merge,
+            throttle: settings.throttle,
+            max_throttle: Duration::from_secs(settings.max_throttle_secs),
+            max_clients: settings.max_clients,
+            strategy: Strategy::from_possible_strategy(&settings.strategy),
+            values: BucketsPerValue::new(settings.values.iter().map(|value| (value.clone(), Bucket::new(1, 1)))),
+            key_builder: KeyBuilder::new(&KeySettings::default())?,
+            limit: header("limit", &settings.limit)?,
+            remaining: header("remaining", &settings.remaining)?,
+            reset: header("reset", &settings.reset)?,
+            throttled_until,
+        })
+    }
+
+    pub fn needs_body(&self) -> bool {
+        self.strategy.needs_body()
+    }
+
+    pub fn client(&self, request: &SafeRequest, addr: SocketAddr) -> String {
+        let placeholder = Bucket::new(1, 1);
+        self.strategy.get_key(request, addr, Some(&placeholder), Some(&self.values), &self.key_builder)
+            .map(|limit_key| limit_key.key)
+            .unwrap_or_default()
+    }
+
+    // Time left until the upstream lets the client through again, None if it isn't throttled
+    pub fn throttled_for(&self, client: &str) -> Option<Duration> {
+        self.throttled_until.get(client)?.checked_duration_since(Instant::now())
+    }
+
+    // Throttles the client if the upstream ran out for it and, when merging, returns the upstream's limit with its headers
+    // removed from the response, so one limit is sent under the gateway's header names. `now` is in unix seconds.
+    pub fn read(&self, client: &str, status: StatusCode, headers: &mut HeaderMap, now: u64) -> Option<UpstreamLimit> {
+        let number = |headers: &HeaderMap, name: &HeaderName| headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<i64>().ok());
+        let reset_secs = number(headers, &self.reset).map(|reset| match reset as u64 >= MIN_RESET_TIMESTAMP {
+            true => (reset as u64).saturating_sub(now),
+            false => reset.max(0) as u64,
+        });
+        let retry_after = number(headers, &RETRY_AFTER).map(|secs| secs.max(0) as u64);
+        let remaining = number(headers, &self.remaining);
+        let is_denied = status == StatusCode::TOO_MANY_REQUESTS;
+
+        if self.throttle && (is_denied || remaining.is_some_and(|remaining| remaining <= 0)) {
+            self.throttle(client, retry_after.or(reset_secs).map_or(DEFAULT_THROTTLE, Duration::from_secs));
+        }
+
+        if !self.merge {
+            return None;
+        }
+        let (limit, remaining) = (number(headers, &self.limit).filter(|limit| *limit > 0)?, remaining?);
+        for name in [&self.limit, &self.remaining, &self.reset] {
+            headers.remove(name);
+        }
+        Some(UpstreamLimit {
+            limit: LimitForRequest::new(u32::try_from(limit).unwrap_or(u32::MAX), i32::try_from(remaining).unwrap_or(i32::MAX), is_denied),
+            reset_secs: u32::try_from(reset_secs.or(retry_after).unwrap_or_default()).unwrap_or(u32::MAX),
+        })
+    }
+
+    fn throttle(&self, client: &str, duration: Duration) {
+        // New clients are dropped once full, they just reach the upstream as usual
+        if self.throttled_until.len() >= self.max_clients && !self.throttled_until.contains_key(client) {
+            return;
+        }
+        self.throttled_until.insert(client.to_string(), Instant::now() + duration.min(self.max_throttle));
+    }
+}
+
+async fn remove_expired(throttled_until: Weak<DashMap<String, Instant>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let Some(throttled_until) = throttled_until.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        throttled_until.retain(|_, throttled_until| *throttled_until > now);
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::body::Body;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderName, Request};
+use dashmap::DashMap;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use crate::error::RateLimiterError;
+use crate::settings::{UsageFormat, UsageSettings};
+use crate::strategy::{header_value, SafeRequest};
+
+
+#[derive(Serialize, Debug)]
+struct UsageRecord {
+    client: String,
+    requests: u64,
+    // Unix timestamps of the counted interval
+    from: u64,
+    to: u64,
+}
+
+#[derive(Debug)]
+enum UsageSink {
+    File(String),
+    Http(String, Box<Client<HttpConnector, Body>>),
+}
+
+
+// Counts the requests every client got through, exported and reset every `export_interval_secs`
+#[derive(Debug)]
+pub struct UsageRecorder {
+    header: HeaderName,
+    counters: DashMap<String, u64>,
+    interval_started_at: Mutex<u64>,
+}
+
+impl UsageRecorder {
+    // Must be called inside a tokio runtime, as it spawns the exporter
+    pub fn new(settings: &UsageSettings) -> Result<Arc<Self>, RateLimiterError> {
+        let header = HeaderName::try_from(settings.header.as_str())
+            .map_err(|e| RateLimiterError::config(format!("Invalid usage header {}: {}", settings.header, e)))?;
+        if settings.export_interval_secs == 0 {
+            return Err(RateLimiterError::config("usage.export_interval_secs must be greater than 0"));
+        }
+
+        // A single sink keeps retries simple: records that failed to export are merged into the next export
+        let sink = match (&settings.file, &settings.http_url) {
+            (Some(file), None) => UsageSink::File(file.clone()),
+            (None, Some(http_url)) if http_url.starts_with("http://") => {
+                UsageSink::Http(http_url.clone(), Box::new(Client::builder(TokioExecutor::new()).build_http()))
+            },
+            (None, Some(http_url)) => return Err(RateLimiterError::config(format!("usage.http_url {} must be an http:// URL", http_url))),
+            _ => return Err(RateLimiterError::config("usage needs exactly one of file or http_url")),
+        };
+
+        let recorder = Arc::new(Self {
+            header,
+            counters: DashMap::new(),
+            interval_started_at: Mutex::new(unix_now()),
+        });
+        tokio::spawn(export_usage(Arc::downgrade(&recorder), sink, settings.format, Duration::from_secs(settings.export_interval_secs)));
+
+        Ok(recorder)
+    }
+
+    pub fn record(&self, request: &SafeRequest) {
+        if let Some(value) = request.parts.headers.get(&self.header) {
+            *self.counters.entry(header_value(value).into_owned()).or_insert(0) += 1;
+        }
+    }
+
+    fn take(&self) -> Vec<UsageRecord> {
+        let to = unix_now();
+        let from = std::mem::replace(&mut *self.interval_started_at.lock().unwrap_or_else(|e| e.into_inner()), to);
+
+        let clients = self.counters.iter().map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        clients.into_iter()
+            .filter_map(|client| self.counters.remove(&client))
+            .map(|(client, requests)| UsageRecord { client, requests, from, to })
+            .collect()
+    }
+
+    fn restore(&self, records: Vec<UsageRecord>) {
+        if let Some(record) = records.first() {
+            *self.interval_started_at.lock().unwrap_or_else(|e| e.into_inner()) = record.from;
+        }
+        for record in records {
+            *self.counters.entry(record.client).or_insert(0) += record.requests;
+        }
+    }
+}
+
+
+async fn export_usage(recorder: Weak<UsageRecorder>, sink: UsageSink, format: UsageFormat, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        // Stop once the recorder itself has been dropped
+        let Some(recorder) = recorder.upgrade() else {
+            return;
+        };
+        let records = recorder.take();
+        if records.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = export(&sink, format, &records).await {
+            println!("Failed to export usage of {} clients, retrying with the next export: {}", records.len(), e);
+            recorder.restore(records);
+        }
+    }
+}
+
+async fn export(sink: &UsageSink, format: UsageFormat, records: &[UsageRecord]) -> Result<(), RateLimiterError> {
+    match sink {
+        UsageSink::File(path) => {
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+            let is_empty = file.metadata().await?.len() == 0;
+            let payload = match format {
+                UsageFormat::Json => json_lines(records)?,
+                UsageFormat::Csv => csv(records, is_empty),
+            };
+            file.write_all(payload.as_bytes()).await?;
+            Ok(file.flush().await?)
+        },
+        UsageSink::Http(url, client) => {
+            let (content_type, payload) = match format {
+                UsageFormat::Json => ("application/json", serde_json::to_string(records).map_err(std::io::Error::from)?),
+                UsageFormat::Csv => ("text/csv", csv(records, true)),
+            };
+            let request = Request::post(url.as_str())
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(payload))
+                .map_err(RateLimiterError::config)?;
+
+            let response = client.request(request).await.map_err(|e| RateLimiterError::Io(std::io::Error::other(e)))?;
+            match response.status().is_success() {
+                true => Ok(()),
+                false => Err(RateLimiterError::Io(std::io::Error::other(format!("{} answered {}", url, response.status())))),
+            }
+        },
+    }
+}
+
+fn json_lines(records: &[UsageRecord]) -> Result<String, RateLimiterError> {
+    let mut payload = String::new();
+    for record in records {
+        payload.push_str(&serde_json::to_string(record).map_err(std::io::Error::from)?);
+        payload.push('\n');
+    }
+    Ok(payload)
+}
+
+fn csv(records: &[UsageRecord], with_header: bool) -> String {
+    let mut payload = String::new();
+    if with_header {
+        payload.push_str("client,requests,from,to\n");
+    }
+    for record in records {
+        payload.push_str(&format!("{},{},{},{}\n", csv_field(&record.client), record.requests, record.from, record.to));
+    }
+    payload
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
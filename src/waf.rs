@@ -0,0 +1,129 @@
+use std::borrow::Cow;
+use axum::http::header::{CONTENT_TYPE, USER_AGENT};
+use percent_encoding::percent_decode_str;
+use crate::error::RateLimiterError;
+use crate::settings::WafSettings;
+use crate::strategy::{header_value, SafeRequest};
+
+
+#[derive(Debug, Clone)]
+struct WafRoute {
+    path_prefix: String,
+    path_traversal: bool,
+    max_json_depth: Option<usize>,
+    // Lowercased, matched as substrings
+    blocked_user_agents: Vec<String>,
+    blocked_body_patterns: Vec<String>,
+}
+
+impl WafRoute {
+    fn violation(&self, request: &SafeRequest) -> Option<&'static str> {
+        let uri = &request.parts.uri;
+        if self.path_traversal && (has_traversal(uri.path(), &['/', '\\']) || uri.query().is_some_and(|query| has_traversal(query, &['/', '\\', '=', '&']))) {
+            return Some("path_traversal");
+        }
+        if !self.blocked_user_agents.is_empty() {
+            let user_agent = request.parts.headers.get(USER_AGENT).map(|value| header_value(value).to_lowercase()).unwrap_or_default();
+            if self.blocked_user_agents.iter().any(|blocked| user_agent.contains(blocked.as_str())) {
+                return Some("user_agent");
+            }
+        }
+        if let Some(max_depth) = self.max_json_depth && is_json(request) && json_depth_exceeds(&request.body, max_depth) {
+            return Some("json_depth");
+        }
+        if !self.blocked_body_patterns.is_empty() && !request.body.is_empty() {
+            let body = String::from_utf8_lossy(&request.body).to_lowercase();
+            if self.blocked_body_patterns.iter().any(|pattern| body.contains(pattern.as_str())) {
+                return Some("body_pattern");
+            }
+        }
+        None
+    }
+}
+
+
+// Rejects requests matching basic attack patterns before they are limited or proxied, using the body the limiter
+// already buffered. Only requests under a configured route are inspected, by the rules of the longest matching one.
+#[derive(Debug, Clone)]
+pub struct Waf {
+    routes: Vec<WafRoute>,
+}
+
+impl Waf {
+    pub fn new(settings: &[WafSettings]) -> Result<Self, RateLimiterError> {
+        let routes = settings.iter()
+            .map(|route| {
+                if !route.path_prefix.starts_with('/') {
+                    return Err(RateLimiterError::config(format!("waf path_prefix {} must start with /", route.path_prefix)));
+                }
+                if route.max_json_depth == Some(0) {
+                    return Err(RateLimiterError::config(format!("max_json_depth of waf route {} must be greater than 0", route.path_prefix)));
+                }
+                if !route.path_traversal && route.max_json_depth.is_none() && route.blocked_user_agents.is_empty() && route.blocked_body_patterns.is_empty() {
+                    return Err(RateLimiterError::config(format!("waf route {} has no rule", route.path_prefix)));
+                }
+
+                Ok(WafRoute {
+                    path_prefix: route.path_prefix.trim_end_matches('/').to_string(),
+                    path_traversal: route.path_traversal,
+                    max_json_depth: route.max_json_depth,
+                    blocked_user_agents: route.blocked_user_agents.iter().map(|user_agent| user_agent.to_lowercase()).collect(),
+                    blocked_body_patterns: route.blocked_body_patterns.iter().map(|pattern| pattern.to_lowercase()).collect(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            routes,
+        })
+    }
+
+    // Only JSON depth and body pattern rules read the body
+    pub fn needs_body(&self) -> bool {
+        self.routes.iter().any(|route| route.max_json_depth.is_some() || !route.blocked_body_patterns.is_empty())
+    }
+
+    // Name of the rule the request breaks, None if it may go on
+    pub fn violation(&self, request: &SafeRequest) -> Option<&'static str> {
+        let path = request.parts.uri.path();
+        self.routes.iter()
+            .filter(|route| path.strip_prefix(route.path_prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|route| route.path_prefix.len())
+            .and_then(|route| route.violation(request))
+    }
+}
+
+// Looks for `..` segments as sent, percent-decoded and double percent-decoded
+fn has_traversal(value: &str, separators: &[char]) -> bool {
+    let decoded = percent_decode_str(value).decode_utf8_lossy();
+    let double_decoded = percent_decode_str(&decoded).decode_utf8_lossy().into_owned();
+    [Cow::Borrowed(value), decoded, Cow::Owned(double_decoded)].iter()
+        .any(|value| value.split(separators).any(|segment| segment == ".."))
+}
+
+fn is_json(request: &SafeRequest) -> bool {
+    request.parts.headers.get(CONTENT_TYPE).is_some_and(|value| header_value(value).contains("json"))
+}
+
+// Counts nesting without parsing, so deeply nested documents are rejected without being built
+fn json_depth_exceeds(body: &[u8], max_depth: usize) -> bool {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for byte in body {
+        match (in_string, byte) {
+            (true, _) if escaped => escaped = false,
+            (true, b'\\') => escaped = true,
+            (true, b'"') => in_string = false,
+            (true, _) => {},
+            (false, b'"') => in_string = true,
+            (false, b'{' | b'[') => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            },
+            (false, b'}' | b']') => depth = depth.saturating_sub(1),
+            (false, _) => {},
+        }
+    }
+    false
+}
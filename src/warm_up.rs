@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use dashmap::DashMap;
+use crate::clock::Clock;
+use crate::error::RateLimiterError;
+use crate::settings::WarmUpSettings;
+use crate::strategy::Bucket;
+
+
+#[derive(Debug)]
+struct CreatedBucket {
+    // As configured, a rule change gives the key a new bucket
+    tokens_count: u32,
+    burst: Option<u32>,
+    scaled: Bucket,
+    ends_at_us: u64,
+}
+
+
+// Buckets created while warming up keep the capacity they were created with until their window ends,
+// only requests that create a bucket get the fraction of the moment.
+#[derive(Debug)]
+pub struct WarmUp {
+    started_at_us: AtomicU64,
+    duration: Duration,
+    initial_fraction: f64,
+    created: DashMap<String, CreatedBucket>,
+    last_cleanup: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl WarmUp {
+    pub fn new(settings: &WarmUpSettings, clock: Arc<dyn Clock>) -> Result<Self, RateLimiterError> {
+        if !(0.0..=1.0).contains(&settings.initial_fraction) {
+            return Err(RateLimiterError::config("warm_up.initial_fraction must be between 0 and 1"));
+        }
+
+        Ok(Self {
+            started_at_us: AtomicU64::new(clock.now_us()),
+            duration: Duration::from_secs(settings.duration_secs),
+            initial_fraction: settings.initial_fraction,
+            created: DashMap::new(),
+            last_cleanup: AtomicU64::new(0),
+            clock,
+        })
+    }
+
+    // Warms up again from `initial_fraction`, for buckets created after the rules changed
+    pub fn restart(&self) {
+        self.started_at_us.store(self.clock.now_us(), Ordering::Relaxed);
+    }
+
+    // Shrinks the capacity of the bucket of `key` if it's created while warming up
+    pub fn scale(&self, key: &str, bucket: &mut Bucket) {
+        let now_us = self.clock.now_us();
+        // Buckets whose window ended are dropped once a second
+        if !self.created.is_empty() && self.last_cleanup.swap(now_us / 1_000_000, Ordering::Relaxed) != now_us / 1_000_000 {
+            self.created.retain(|_, created| created.ends_at_us > now_us);
+        }
+
+        if let Some(created) = self.created.get(key)
+            && created.ends_at_us > now_us && created.tokens_count == bucket.tokens_count && created.burst == bucket.burst {
+            *bucket = created.scaled.clone();
+            return;
+        }
+
+        let elapsed = Duration::from_micros(now_us.saturating_sub(self.started_at_us.load(Ordering::Relaxed)));
+        if elapsed >= self.duration {
+            self.created.remove(key);
+            return;
+        }
+
+        let fraction = self.initial_fraction + (1.0 - self.initial_fraction) * elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        let scale = |tokens: u32| ((tokens as f64 * fraction).ceil() as u32).clamp(1, tokens.max(1));
+        let (tokens_count, burst) = (bucket.tokens_count, bucket.burst);
+        match bucket.burst {
+            Some(burst) => bucket.burst = Some(scale(burst)),
+            None => bucket.tokens_count = scale(bucket.tokens_count),
+        }
+        self.created.insert(key.to_string(), CreatedBucket {
+            tokens_count,
+            burst,
+            scaled: bucket.clone(),
+            ends_at_us: now_us + bucket.add_tokens_every as u64 * 1_000_000,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::MockClock;
+    use super::*;
+
+    fn warm_up(clock: &MockClock) -> WarmUp {
+        WarmUp::new(&WarmUpSettings { duration_secs: 100, initial_fraction: 0.5 }, Arc::new(clock.clone())).unwrap()
+    }
+
+    fn scaled(warm_up: &WarmUp, key: &str, bucket: &Bucket) -> Bucket {
+        let mut bucket = bucket.clone();
+        warm_up.scale(key, &mut bucket);
+        bucket
+    }
+
+    #[test]
+    fn buckets_keep_the_capacity_they_were_created_with_until_their_window_ends() {
+        let clock = MockClock::new();
+        let warm_up = warm_up(&clock);
+        let bucket = Bucket::new(100, 60);
+
+        assert_eq!(scaled(&warm_up, "a", &bucket).tokens_count, 50);
+        clock.advance(Duration::from_secs(25));
+        assert_eq!(scaled(&warm_up, "a", &bucket).tokens_count, 50);
+        assert_eq!(scaled(&warm_up, "b", &bucket).tokens_count, 63);
+
+        // The window of `a` ended, its next one is created with the fraction of the moment
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(scaled(&warm_up, "a", &bucket).tokens_count, 88);
+        assert_eq!(scaled(&warm_up, "b", &bucket).tokens_count, 63);
+    }
+
+    #[test]
+    fn buckets_created_after_the_warm_up_get_their_full_capacity() {
+        let clock = MockClock::new();
+        let warm_up = warm_up(&clock);
+        let bucket = Bucket::new(100, 60).with_burst(Some(20));
+
+        clock.advance(Duration::from_secs(75));
+        assert_eq!(scaled(&warm_up, "a", &bucket).burst, Some(18));
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(scaled(&warm_up, "a", &bucket).burst, Some(18));
+        assert_eq!(scaled(&warm_up, "b", &bucket).burst, Some(20));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(scaled(&warm_up, "a", &bucket).burst, Some(20));
+    }
+
+    #[test]
+    fn rule_changes_restart_the_warm_up() {
+        let clock = MockClock::new();
+        let warm_up = warm_up(&clock);
+        clock.advance(Duration::from_secs(200));
+        assert_eq!(scaled(&warm_up, "a", &Bucket::new(100, 60)).tokens_count, 100);
+
+        warm_up.restart();
+        assert_eq!(scaled(&warm_up, "a", &Bucket::new(100, 60)).tokens_count, 50);
+        // An override gives the key a new bucket
+        assert_eq!(scaled(&warm_up, "a", &Bucket::new(40, 60)).tokens_count, 20);
+    }
+}
@@ -0,0 +1,1419 @@
+// Runs the proxy in front of a stub upstream and sends it real HTTP requests.
+// Tests marked `#[ignore]` need Redis, start it with `docker compose up -d redis` and run them with
+// `cargo test --test end_to_end -- --ignored`. RL_TEST_REDIS_URL points them to another Redis.
+// Ignored tests stopping Redis mid-run start their own containers with testcontainers and only need Docker.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::body::{to_bytes, Body, Bytes};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::{any, get, post};
+use axum::{Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use config::FileFormat;
+use hyper::body::{Body as _, Frame};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use rate_limiter::server::ProxyServer;
+use rate_limiter::settings::Settings;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+use testcontainers_modules::redis::{Redis, REDIS_PORT};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn redis_url() -> String {
+    std::env::var("RL_TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string())
+}
+
+// Keys of every run are kept apart, so runs against the same Redis don't see each other's buckets
+fn key_prefix(test: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_nanos()).unwrap_or_default();
+    format!("e2e:{}:{}", test, now)
+}
+
+async fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Server-sent events, one frame per event sent on the channel, until the sender is dropped
+struct EventStream(mpsc::Receiver<&'static str>);
+
+impl hyper::body::Body for EventStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.0.poll_recv(cx).map(|event| event.map(|event| Ok(Frame::data(Bytes::from_static(event.as_bytes())))))
+    }
+}
+
+// Answers every path and method with the path itself, so tests can tell the upstream answered. `/slow` takes half a second,
+// `/events` streams an event right away and keeps the stream open for another second before the last one.
+// `/limited` and `/exhausted` answer with rate limit headers of the upstream itself.
+async fn start_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route("/slow", get(|| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            "upstream /slow"
+        }))
+        .route("/limited", get(|| async {
+            ([("X-RateLimit-Limit", "10"), ("X-RateLimit-Remaining", "1"), ("X-RateLimit-Reset", "30")], "upstream /limited")
+        }))
+        .route("/exhausted", get(|| async {
+            (StatusCode::TOO_MANY_REQUESTS, [("X-RateLimit-Limit", "10"), ("X-RateLimit-Remaining", "0"), ("Retry-After", "20")], "upstream /exhausted")
+        }))
+        .route("/events", get(|| async {
+            let (sender, receiver) = mpsc::channel(4);
+            tokio::spawn(async move {
+                sender.send("data: first\n\n").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                sender.send(": keep-alive\n\n").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                sender.send("data: last\n\n").await.unwrap();
+            });
+            ([(CONTENT_TYPE, "text/event-stream")], Body::new(EventStream(receiver)))
+        }))
+        // Names of the headers the upstream got
+        .route("/headers", get(|headers: HeaderMap| async move { headers.keys().map(|name| name.as_str()).collect::<Vec<_>>().join(",") }))
+        // Reads the whole body before answering, like an upstream handling uploads
+        .route("/upload", post(|body: Bytes| async move { format!("upstream /upload {}", body.len()) }))
+        .fallback(any(|request: Request<Body>| async move { format!("upstream {}", request.uri().path()) }));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+// Starts the proxy with the `[rate_limiter]` table of `rate_limiter` in front of a new upstream and returns its address
+async fn start_proxy(rate_limiter: &str) -> SocketAddr {
+    start_proxy_with(|upstream| format!("target_url = \"{}\"", upstream), rate_limiter).await
+}
+
+// Like `start_proxy`, with the upstream settings of `[api_gateway]` given by `upstream_settings`
+async fn start_proxy_with(upstream_settings: impl FnOnce(SocketAddr) -> String, rate_limiter: &str) -> SocketAddr {
+    let (upstream, proxy) = (start_upstream().await, free_addr().await);
+    let settings = format!("[api_gateway]\nproxy_server_addr = \"{}\"\n{}\n\n{}", proxy, upstream_settings(upstream), rate_limiter);
+    let settings = Settings::parse(&settings, FileFormat::Toml).unwrap();
+    // Like in main, the server runs with block_on on its own runtime
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        if let Err(e) = runtime.block_on(ProxyServer::new(settings).run()) {
+            panic!("Proxy failed: {}", e);
+        }
+    });
+
+    let started_at = tokio::time::Instant::now();
+    while TcpStream::connect(proxy).await.is_err() {
+        assert!(started_at.elapsed() < STARTUP_TIMEOUT, "Proxy didn't start listening on {}", proxy);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    proxy
+}
+
+async fn send(proxy: SocketAddr, path: &str) -> (StatusCode, HeaderMap, String) {
+    send_request(Request::get(format!("http://{}{}", proxy, path)).body(Body::empty()).unwrap()).await
+}
+
+async fn send_request(request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    let response = client.request(request).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(Body::new(body), usize::MAX).await.unwrap();
+    (parts.status, parts.headers, String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn wait_for_admin(admin: SocketAddr) {
+    let started_at = tokio::time::Instant::now();
+    while TcpStream::connect(admin).await.is_err() {
+        assert!(started_at.elapsed() < STARTUP_TIMEOUT, "Admin server didn't start listening on {}", admin);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name).map(|value| value.to_str().unwrap().to_string())
+}
+
+fn limited_by_ip(backend: &str, on_store_error: &str, test: &str) -> String {
+    format!(
+        "[rate_limiter]\n{}\nip_whitelist = []\nkeys = {{ prefix = \"{}\" }}\n\n\
+        [[rate_limiter.limiter]]\nstrategy = \"ip\"\non_store_error = \"{}\"\nlog_decisions = \"off\"\n\
+        global_bucket = {{ tokens_count = 3, add_tokens_every = 60 }}\n",
+        backend, key_prefix(test), on_store_error,
+    )
+}
+
+// Most tests don't need Redis, their buckets are kept in memory
+fn limited_in_memory(test: &str) -> String {
+    limited_by_ip("backend = \"memory\"", "deny", test)
+}
+
+async fn start_in_memory(test: &str) -> SocketAddr {
+    start_proxy(&limited_in_memory(test)).await
+}
+
+#[tokio::test]
+#[ignore]
+async fn proxies_requests_with_limit_headers() {
+    let proxy = start_proxy(&limited_by_ip(&format!("redis = {{ url = \"{}\" }}", redis_url()), "deny", "headers")).await;
+
+    let (status, headers, body) = send(proxy, "/orders").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "upstream /orders");
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("3"));
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("2"));
+}
+
+#[tokio::test]
+#[ignore]
+async fn denies_exhausted_clients_with_429() {
+    let proxy = start_proxy(&limited_by_ip(&format!("redis = {{ url = \"{}\" }}", redis_url()), "deny", "exhausted")).await;
+
+    for remaining in ["2", "1", "0"] {
+        let (status, headers, _) = send(proxy, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some(remaining));
+    }
+    let (status, _, body) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body, "Rate limit exceeded");
+}
+
+// The failover tests point the proxy to a port nothing listens on, so they run without Redis
+fn unreachable_redis() -> String {
+    "redis = { url = \"redis://127.0.0.1:1\", pool = { check_on_startup = false, create_timeout_ms = 500 } }".to_string()
+}
+
+#[tokio::test]
+async fn keeps_limiting_in_memory_while_redis_is_down() {
+    let proxy = start_proxy(&limited_by_ip(&unreachable_redis(), "fallback_memory", "fallback")).await;
+
+    for _ in 0..3 {
+        assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    }
+    assert_eq!(send(proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn fails_open_or_closed_while_redis_is_down() {
+    let open = start_proxy(&limited_by_ip(&unreachable_redis(), "allow", "allow")).await;
+    let (status, headers, body) = send(open, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "upstream /");
+    assert_eq!(header(&headers, "X-RateLimit-Remaining"), None);
+
+    let closed = start_proxy(&limited_by_ip(&unreachable_redis(), "deny", "deny")).await;
+    assert_eq!(send(closed, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+// Redis of its own in a container the test stops and starts again. The host port is fixed, so the proxy finds it at the
+// same address after a restart.
+async fn start_redis() -> (ContainerAsync<Redis>, String) {
+    let port = free_addr().await.port();
+    let redis = Redis::default().with_mapped_port(port, REDIS_PORT.tcp()).start().await.expect("Failed to start Redis, is Docker running?");
+    (redis, format!("redis = {{ url = \"redis://127.0.0.1:{}\", pool = {{ create_timeout_ms = 500 }} }}", port))
+}
+
+// Sends requests until `is_done` accepts a response, for the proxy to notice a store going away or coming back
+async fn send_until(proxy: SocketAddr, is_done: impl Fn(StatusCode, &HeaderMap) -> bool) -> (StatusCode, HeaderMap) {
+    let started_at = tokio::time::Instant::now();
+    loop {
+        let (status, headers, _) = send(proxy, "/").await;
+        if is_done(status, &headers) {
+            return (status, headers);
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(30), "Proxy kept answering {}", status);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn fails_open_or_closed_when_redis_goes_away_mid_run() {
+    let (redis, backend) = start_redis().await;
+    let open = start_proxy(&limited_by_ip(&backend, "allow", "allow-mid-run")).await;
+    let closed = start_proxy(&limited_by_ip(&backend, "deny", "deny-mid-run")).await;
+    for proxy in [open, closed] {
+        let (status, headers, _) = send(proxy, "/").await;
+        assert_eq!((status, header(&headers, "X-RateLimit-Remaining").as_deref()), (StatusCode::OK, Some("2")));
+    }
+
+    redis.stop().await.unwrap();
+    let (status, headers, body) = send(open, "/").await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /"));
+    assert_eq!(header(&headers, "X-RateLimit-Remaining"), None);
+    assert_eq!(send(closed, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // Both limit again once Redis is back
+    redis.start().await.unwrap();
+    for proxy in [open, closed] {
+        send_until(proxy, |status, headers| status == StatusCode::OK && headers.contains_key("X-RateLimit-Remaining")).await;
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn resyncs_the_fallback_buckets_when_redis_comes_back() {
+    let (redis, backend) = start_redis().await;
+    let proxy = start_proxy(&limited_by_ip(&backend, "fallback_memory", "resync")).await;
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+
+    // The fallback limiter takes over with a bucket of its own
+    redis.stop().await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    }
+    assert_eq!(send(proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // The first request Redis counts again replays the 3 requests of the outage into it, whether Redis kept
+    // the request before the outage or not, the client has no tokens left after that
+    redis.start().await.unwrap();
+    send_until(proxy, |status, _| status == StatusCode::OK).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Remaining").as_deref()), (StatusCode::TOO_MANY_REQUESTS, Some("0")));
+}
+
+// Redis server of the Sentinel test. Containers share the network of the host, so the addresses the sentinel
+// hands out are reachable from the proxy. Docker only supports that on Linux.
+async fn start_redis_node(args: &[String]) -> ContainerAsync<GenericImage> {
+    GenericImage::new("redis", "7.2")
+        .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+        .with_network("host")
+        .with_cmd([&["redis-server".to_string()], args].concat())
+        .start()
+        .await
+        .expect("Failed to start Redis, is Docker running?")
+}
+
+#[tokio::test]
+#[ignore]
+async fn re_resolves_the_master_through_sentinel_after_a_failover() {
+    let (master_port, replica_port, sentinel_port) = (free_addr().await.port(), free_addr().await.port(), free_addr().await.port());
+    let master = start_redis_node(&["--port".to_string(), master_port.to_string()]).await;
+    let _replica = start_redis_node(&[
+        "--port".to_string(), replica_port.to_string(),
+        "--replicaof".to_string(), "127.0.0.1".to_string(), master_port.to_string(),
+    ]).await;
+    let sentinel_config = format!(
+        "port {}\nsentinel monitor master 127.0.0.1 {} 1\nsentinel down-after-milliseconds master 1000\nsentinel failover-timeout master 5000\n",
+        sentinel_port, master_port,
+    );
+    let _sentinel = GenericImage::new("redis", "7.2")
+        .with_wait_for(WaitFor::message_on_stdout("+monitor master"))
+        .with_network("host")
+        .with_cmd(["sh", "-c", &format!("printf '{}' > /tmp/sentinel.conf && redis-sentinel /tmp/sentinel.conf", sentinel_config)])
+        .start()
+        .await
+        .expect("Failed to start the sentinel, is Docker running?");
+
+    let backend = format!(
+        "redis = {{ sentinel = {{ addrs = [\"127.0.0.1:{}\"], master_name = \"master\" }}, pool = {{ create_timeout_ms = 500 }} }}",
+        sentinel_port,
+    );
+    let proxy = start_proxy(&limited_by_ip(&backend, "deny", "sentinel")).await;
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Remaining").as_deref()), (StatusCode::OK, Some("2")));
+
+    // The sentinel promotes the replica, which got the request counted on the old master
+    master.stop().await.unwrap();
+    let (_, headers) = send_until(proxy, |status, _| status == StatusCode::OK).await;
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("1"));
+}
+
+#[tokio::test]
+async fn streams_events_with_limit_headers_sent_first() {
+    let proxy = start_in_memory("events").await;
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    let sent_at = tokio::time::Instant::now();
+    let response = client.request(Request::get(format!("http://{}/events", proxy)).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(header(response.headers(), "X-RateLimit-Remaining").as_deref(), Some("2"));
+    assert_eq!(header(response.headers(), "Content-Type").as_deref(), Some("text/event-stream"));
+
+    // Each event reaches the client while the upstream still holds the stream open
+    let mut body = response.into_body();
+    let mut events = Vec::new();
+    while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        if let Ok(data) = frame.unwrap().into_data() {
+            events.push((String::from_utf8_lossy(&data).into_owned(), sent_at.elapsed()));
+        }
+    }
+    assert_eq!(events.iter().map(|(event, _)| event.as_str()).collect::<String>(), "data: first\n\n: keep-alive\n\ndata: last\n\n");
+    assert_eq!(events[0].0, "data: first\n\n");
+    assert!(events[0].1 < Duration::from_millis(500), "The first event was held back for {:?}", events[0].1);
+}
+
+#[tokio::test]
+async fn names_the_constraining_limiter_in_the_policy_header() {
+    let proxy = start_proxy(&limited_by_ip("backend = \"memory\"\npolicy_header = true", "deny", "policy")).await;
+
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "X-RateLimit-Policy").as_deref(), Some("ip-0;w=60;scope=global"));
+}
+
+#[tokio::test]
+async fn sends_renamed_headers_on_429_only() {
+    let settings = "backend = \"memory\"\nheaders = { send = \"denied\", remaining = \"RateLimit-Remaining\", route = [{ path_prefix = \"/internal\", send = \"never\" }] }";
+    let proxy = start_proxy(&limited_by_ip(settings, "deny", "send")).await;
+
+    for _ in 0..3 {
+        let (status, headers, _) = send(proxy, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "X-RateLimit-Limit"), None);
+    }
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("3"));
+    assert_eq!(header(&headers, "RateLimit-Remaining").as_deref(), Some("0"));
+
+    let (status, headers, _) = send(proxy, "/internal/jobs").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "X-RateLimit-Limit"), None);
+}
+
+#[tokio::test]
+async fn rejected_requests_carry_limit_headers() {
+    let proxy = start_in_memory("rejected").await;
+
+    for _ in 0..3 {
+        assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    }
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("3"));
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("0"));
+    assert_eq!(header(&headers, "X-RateLimit-Reset").as_deref(), Some("60"));
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("60"));
+}
+
+#[tokio::test]
+async fn reports_the_remaining_budget_without_charging_it() {
+    let proxy = start_proxy(&limited_by_ip("backend = \"memory\"\nwell_known_endpoint = true", "deny", "well_known")).await;
+
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    for _ in 0..2 {
+        let (status, _, body) = send(proxy, "/.well-known/ratelimit").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"limits":[{"limiter":"ip-0","policy":"ip-0;w=60;scope=global","limit":3,"remaining":2,"reset":60}]}"#);
+    }
+    let (_, headers, _) = send(proxy, "/").await;
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("1"));
+}
+
+#[tokio::test]
+async fn serves_the_status_of_limits_and_quotas_on_the_status_path() {
+    let settings = "backend = \"memory\"\nstatus_path = \"/ratelimit/status\"\n\
+        quota = [{ name = \"daily\", header = \"X-Api-Key\", period = \"daily\", limit = 5 }]";
+    let proxy = start_proxy(&limited_by_ip(settings, "deny", "status")).await;
+    let with_key = |path: &str| Request::get(format!("http://{}{}", proxy, path)).header("X-Api-Key", "abc").body(Body::empty()).unwrap();
+
+    assert_eq!(send_request(with_key("/")).await.0, StatusCode::OK);
+    let (status, headers, body) = send_request(with_key("/ratelimit/status")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "Cache-Control").as_deref(), Some("no-store"));
+    let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+    assert_eq!(body["limits"][0]["remaining"], 2);
+    assert_eq!(body["quotas"][0]["quota"], "daily");
+    assert_eq!(body["quotas"][0]["remaining"], 4);
+
+    // Only served on the configured path
+    assert_eq!(send(proxy, "/.well-known/ratelimit").await.2, "upstream /.well-known/ratelimit");
+}
+
+#[tokio::test]
+async fn applies_overrides_set_on_the_admin_server() {
+    let admin = free_addr().await;
+    let settings = format!("{}\n[admin]\naddr = \"{}\"\n", limited_by_ip("backend = \"memory\"\noverrides = {}", "deny", "overrides"), admin);
+    let proxy = start_proxy(&settings).await;
+    wait_for_admin(admin).await;
+
+    let request = Request::post(format!("http://{}/overrides", admin))
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"limiter":"ip-0","value":"127.0.0.1","multiplier":10,"ttl_secs":60}"#))
+        .unwrap();
+    assert_eq!(send_request(request).await.0, StatusCode::OK);
+
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("30"));
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("29"));
+
+    let (_, _, body) = send(admin, "/overrides").await;
+    assert!(body.contains(r#""value":"127.0.0.1","multiplier":10"#), "{}", body);
+}
+
+#[tokio::test]
+async fn the_admin_server_requires_a_bearer_token() {
+    let admin = free_addr().await;
+    let token_hash = hex::encode(Sha256::digest(b"ops-secret"));
+    let settings = format!("{}\n[admin]\naddr = \"{}\"\ntokens = {{ ops = \"{}\" }}\n", limited_in_memory("admin_auth"), admin, token_hash);
+    let proxy = start_proxy(&settings).await;
+    wait_for_admin(admin).await;
+    let with_token = |token: &str| Request::get(format!("http://{}/maintenance", admin)).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+
+    let (status, headers, _) = send(admin, "/maintenance").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(header(&headers, "WWW-Authenticate").as_deref(), Some("Bearer"));
+    assert_eq!(send_request(with_token("wrong")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(send_request(with_token("ops-secret")).await.0, StatusCode::OK);
+
+    // The proxy listener doesn't serve the admin endpoints
+    assert_eq!(send(proxy, "/maintenance").await.2, "upstream /maintenance");
+}
+
+#[tokio::test]
+async fn admin_roles_limit_what_tokens_may_change() {
+    let admin = free_addr().await;
+    let settings = format!(
+        "{}\n[admin]\naddr = \"{}\"\ntokens = {{ oncall = \"{}\", ops = \"{}\" }}\nroles = {{ oncall = \"read_only\", ops = \"operator\" }}\n",
+        limited_by_ip("backend = \"memory\"\noverrides = {}", "deny", "admin_roles"), admin,
+        hex::encode(Sha256::digest(b"oncall-secret")), hex::encode(Sha256::digest(b"ops-secret")),
+    );
+    start_proxy(&settings).await;
+    wait_for_admin(admin).await;
+    let call = |request: axum::http::request::Builder, token: &str| request.header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+    let lockdown = || Request::post(format!("http://{}/lockdown?enabled=false", admin));
+    let remove_override = || Request::delete(format!("http://{}/overrides?limiter=ip-0&value=127.0.0.1", admin));
+
+    assert_eq!(send_request(call(Request::get(format!("http://{}/maintenance", admin)), "oncall-secret")).await.0, StatusCode::OK);
+    let (status, _, body) = send_request(call(lockdown(), "oncall-secret")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body, "Needs the operator role");
+
+    assert_eq!(send_request(call(lockdown(), "ops-secret")).await.0, StatusCode::OK);
+    assert_eq!(send_request(call(remove_override(), "ops-secret")).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn restores_snapshots_on_another_instance() {
+    // Both instances share the key prefix, like an instance moved to a new store
+    let rate_limiter = format!("{}\n[rate_limiter.ban]\nmin_denials = 1\n", limited_by_ip("backend = \"memory\"\noverrides = {}", "deny", "snapshot"));
+    let (old_admin, new_admin) = (free_addr().await, free_addr().await);
+    let old_proxy = start_proxy(&format!("{}\n[admin]\naddr = \"{}\"\n", rate_limiter, old_admin)).await;
+    let new_proxy = start_proxy(&format!("{}\n[admin]\naddr = \"{}\"\n", rate_limiter, new_admin)).await;
+    wait_for_admin(old_admin).await;
+    wait_for_admin(new_admin).await;
+
+    for _ in 0..3 {
+        assert_eq!(send(old_proxy, "/").await.0, StatusCode::OK);
+    }
+    assert_eq!(send(old_proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+    let request = Request::post(format!("http://{}/overrides", old_admin))
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"limiter":"ip-0","value":"10.0.0.1","multiplier":10,"ttl_secs":60}"#))
+        .unwrap();
+    assert_eq!(send_request(request).await.0, StatusCode::OK);
+
+    let (status, _, snapshot) = send(old_admin, "/snapshot").await;
+    assert_eq!(status, StatusCode::OK);
+    let request = Request::post(format!("http://{}/snapshot", new_admin))
+        .header("Content-Type", "application/json")
+        .body(Body::from(snapshot))
+        .unwrap();
+    let (status, _, body) = send_request(request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"counters":1,"bans":1,"overrides":1}"#);
+
+    assert_eq!(send(new_proxy, "/").await.0, StatusCode::FORBIDDEN);
+    let (_, _, body) = send(new_admin, "/limits?ip=127.0.0.1").await;
+    assert!(body.contains(r#""remaining":0"#), "{}", body);
+}
+
+#[tokio::test]
+async fn the_global_throttle_sheds_traffic_before_the_limiters() {
+    let settings = format!("{}\n[maintenance]\nthrottle_percent = 100\n", limited_in_memory("throttle"));
+    let proxy = start_proxy(&settings).await;
+
+    for _ in 0..5 {
+        let (status, headers, _) = send(proxy, "/").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header(&headers, "X-RateLimit-Remaining"), None);
+    }
+}
+
+#[tokio::test]
+async fn sheds_requests_to_an_upstream_over_its_high_water_mark() {
+    let settings = format!("{}\n[api_gateway.load_shedding]\nmax_in_flight = 1\nretry_after_secs = 2\n", limited_in_memory("shedding"));
+    let proxy = start_proxy(&settings).await;
+
+    let slow = tokio::spawn(send(proxy, "/slow"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("2"));
+
+    assert_eq!(slow.await.unwrap().2, "upstream /slow");
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn takes_turns_between_clients_queued_for_the_upstream() {
+    let settings = format!(
+        "{}\n[api_gateway.fair_queue]\nmax_concurrent = 1\nmax_queued = 4\nstrategy = \"header\"\nvalues = [\"X-Client\"]\n",
+        limited_in_memory("fair_queue").replace("tokens_count = 3", "tokens_count = 10"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    let slow = |client: &'static str| {
+        let finished = finished.clone();
+        let request = Request::get(format!("http://{}/slow", proxy)).header("X-Client", client).body(Body::empty()).unwrap();
+        tokio::spawn(async move {
+            let status = send_request(request).await.0;
+            finished.lock().unwrap().push(client);
+            status
+        })
+    };
+
+    // a sends four requests before b sends its only one, which still goes second
+    let mut requests = Vec::new();
+    for client in ["a", "a", "a", "a", "b"] {
+        requests.push(slow(client));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let (status, headers, _) = send_request(Request::get(format!("http://{}/", proxy)).header("X-Client", "c").body(Body::empty()).unwrap()).await;
+    assert_eq!((status, header(&headers, "Retry-After").as_deref()), (StatusCode::SERVICE_UNAVAILABLE, Some("1")));
+
+    for request in requests {
+        assert_eq!(request.await.unwrap(), StatusCode::OK);
+    }
+    assert_eq!(*finished.lock().unwrap(), ["a", "b", "a", "a", "a"]);
+}
+
+#[tokio::test]
+async fn coalesces_identical_requests_in_flight_into_one() {
+    let settings = format!(
+        "{}\n[api_gateway.load_shedding]\nmax_in_flight = 1\n\n[api_gateway.coalesce]\ncharge_duplicates = false\n",
+        limited_in_memory("coalesce"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    // Only one request can be in flight to the upstream, the duplicates wait for it instead of being shed
+    let mut requests = Vec::new();
+    for _ in 0..3 {
+        requests.push(tokio::spawn(send(proxy, "/slow")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for request in requests {
+        let (status, headers, body) = request.await.unwrap();
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /slow"));
+        assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("2"));
+    }
+
+    // Uncharged duplicates left the tokens to the next request
+    let (status, headers, _) = send(proxy, "/orders").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Remaining").as_deref()), (StatusCode::OK, Some("1")));
+}
+
+#[tokio::test]
+async fn denies_clients_touching_too_many_distinct_resources() {
+    let settings = format!(
+        "{}\n[[rate_limiter.distinct_limit]]\nname = \"users\"\npath_prefixes = [\"/users\"]\npath_segment = 2\nmax_distinct = 2\n",
+        limited_in_memory("distinct"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    assert_eq!(send(proxy, "/users/1").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/users/2").await.0, StatusCode::OK);
+    let (status, headers, body) = send(proxy, "/users/3").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(body.starts_with("Too many distinct resources"), "{}", body);
+    assert!(header(&headers, "Retry-After").is_some());
+}
+
+#[tokio::test]
+async fn counts_the_unique_clients_of_routes() {
+    let admin = free_addr().await;
+    let settings = format!(
+        "{}\n[rate_limiter.unique_clients]\nroutes = [\"/api\"]\nheader = \"X-Client\"\n\n[admin]\naddr = \"{}\"\n",
+        limited_in_memory("unique_clients").replace("tokens_count = 3", "tokens_count = 10"), admin,
+    );
+    let proxy = start_proxy(&settings).await;
+    wait_for_admin(admin).await;
+
+    for (path, client) in [("/api/users", "a"), ("/api/orders", "b"), ("/api/users", "a"), ("/other", "c")] {
+        let request = Request::get(format!("http://{}{}", proxy, path)).header("X-Client", client).body(Body::empty()).unwrap();
+        assert_eq!(send_request(request).await.0, StatusCode::OK);
+    }
+    // Clients are counted in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let (_, _, body) = send(admin, "/unique_clients").await;
+    assert_eq!(body, r#"[{"route":"/api","window_secs":3600,"clients":2,"previous_window_clients":null}]"#);
+    let (_, _, metrics) = send(admin, "/metrics").await;
+    assert!(metrics.contains("rate_limiter_unique_clients{route=\"/api\"} 2"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn penalizes_keys_sending_far_more_than_their_baseline() {
+    let settings = format!(
+        "{}\n[rate_limiter.anomaly_detection]\ninterval_secs = 1\nmin_requests = 5\npenalty = {{ divisor = 10 }}\n",
+        limited_in_memory("anomaly").replace("tokens_count = 3", "tokens_count = 100"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    // One request sets the baseline, the burst of the next interval goes over it
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let mut last = None;
+    for _ in 0..25 {
+        last = Some(send(proxy, "/").await);
+    }
+    let (status, headers, _) = last.unwrap();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("10"));
+}
+
+#[tokio::test]
+async fn authenticates_requests_and_limits_them_by_identity() {
+    let sha256 = |value: &str| hex::encode(Sha256::digest(value));
+    let key_pair = Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap().as_ref()).unwrap();
+    let jwks = std::env::temp_dir().join(format!("{}.json", key_prefix("jwks").replace(':', "-")));
+    let jwk = serde_json::json!({ "keys": [{ "kty": "OKP", "crv": "Ed25519", "kid": "main", "x": URL_SAFE_NO_PAD.encode(key_pair.public_key()) }] });
+    std::fs::write(&jwks, jwk.to_string()).unwrap();
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/api\"\napi_key = {{ keys = {{ alice = \"{}\" }} }}\nbasic = {{ users = {{ bob = \"{}\" }} }}\njwt = {{ jwks_path = {:?}, issuer = \"idp\" }}\n\n\
+        [[rate_limiter.auth]]\npath_prefix = \"/api/health\"\n",
+        limited_in_memory("auth").replace("strategy = \"ip\"", "strategy = \"identity\""),
+        sha256("alice-key"), sha256("bob-password"), jwks,
+    );
+    let proxy = start_proxy(&settings).await;
+    let with_header = |name: &str, value: &str| Request::get(format!("http://{}/api/orders", proxy)).header(name, value).body(Body::empty()).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let jwt = |claims: serde_json::Value| {
+        let signed = format!("{}.{}", URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA","kid":"main"}"#), URL_SAFE_NO_PAD.encode(claims.to_string()));
+        format!("Bearer {}.{}", signed, URL_SAFE_NO_PAD.encode(key_pair.sign(signed.as_bytes())))
+    };
+
+    let (status, headers, _) = send(proxy, "/api/orders").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(headers.get_all("WWW-Authenticate").iter().count(), 2);
+    assert_eq!(send_request(with_header("X-Api-Key", "mallory-key")).await.0, StatusCode::UNAUTHORIZED);
+    // Public routes and paths without auth are not limited by identity either
+    for path in ["/api/health", "/api/health", "/other", "/other"] {
+        assert_eq!(send(proxy, path).await.0, StatusCode::OK);
+    }
+
+    for expected in [StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+        assert_eq!(send_request(with_header("X-Api-Key", "alice-key")).await.0, expected);
+    }
+    let bob = format!("Basic {}", STANDARD.encode("bob:password"));
+    assert_eq!(send_request(with_header("Authorization", &bob)).await.0, StatusCode::UNAUTHORIZED);
+    let bob = format!("Basic {}", STANDARD.encode("bob:bob-password"));
+    assert_eq!(send_request(with_header("Authorization", &bob)).await.0, StatusCode::OK);
+
+    let carol = jwt(serde_json::json!({ "sub": "carol", "iss": "idp", "exp": now + 600 }));
+    assert_eq!(send_request(with_header("Authorization", &carol)).await.0, StatusCode::OK);
+    let expired = jwt(serde_json::json!({ "sub": "carol", "iss": "idp", "exp": now - 600 }));
+    assert_eq!(send_request(with_header("Authorization", &expired)).await.0, StatusCode::UNAUTHORIZED);
+    let other_issuer = jwt(serde_json::json!({ "sub": "carol", "iss": "other", "exp": now + 600 }));
+    assert_eq!(send_request(with_header("Authorization", &other_issuer)).await.0, StatusCode::UNAUTHORIZED);
+    let parts = carol.split('.').collect::<Vec<_>>();
+    let claims = serde_json::json!({ "sub": "alice", "iss": "idp", "exp": now + 600 });
+    let tampered = format!("{}.{}.{}", parts[0], URL_SAFE_NO_PAD.encode(claims.to_string()), parts[2]);
+    assert_eq!(send_request(with_header("Authorization", &tampered)).await.0, StatusCode::UNAUTHORIZED);
+    std::fs::remove_file(&jwks).unwrap();
+}
+
+#[tokio::test]
+async fn rejects_jwts_signed_by_other_keys_or_outside_their_validity() {
+    let generate = || Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap().as_ref()).unwrap();
+    let (key_pair, other_key_pair) = (generate(), generate());
+    let jwks = std::env::temp_dir().join(format!("{}.json", key_prefix("rejected_jwts").replace(':', "-")));
+    let jwk = serde_json::json!({ "keys": [{ "kty": "OKP", "crv": "Ed25519", "kid": "main", "x": URL_SAFE_NO_PAD.encode(key_pair.public_key()) }] });
+    std::fs::write(&jwks, jwk.to_string()).unwrap();
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/api\"\njwt = {{ jwks_path = {:?}, issuer = \"idp\" }}\n",
+        limited_in_memory("rejected_jwts").replace("strategy = \"ip\"", "strategy = \"identity\""), jwks,
+    );
+    let proxy = start_proxy(&settings).await;
+    let bearer = |token: &str| Request::get(format!("http://{}/api/orders", proxy)).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let jwt = |header: &str, claims: serde_json::Value, key_pair: &Ed25519KeyPair| {
+        let signed = format!("{}.{}", URL_SAFE_NO_PAD.encode(header), URL_SAFE_NO_PAD.encode(claims.to_string()));
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(key_pair.sign(signed.as_bytes())))
+    };
+    let (jwt_header, claims) = (r#"{"alg":"EdDSA","kid":"main"}"#, serde_json::json!({ "sub": "carol", "iss": "idp", "exp": now + 600 }));
+
+    assert_eq!(send_request(bearer(&jwt(jwt_header, claims.clone(), &key_pair))).await.0, StatusCode::OK);
+    let rejected = [
+        jwt(jwt_header, claims.clone(), &other_key_pair),
+        jwt(r#"{"alg":"EdDSA","kid":"rotated"}"#, claims.clone(), &key_pair),
+        // The algorithm comes from the key, not from the token
+        format!("{}.{}.", URL_SAFE_NO_PAD.encode(r#"{"alg":"none","kid":"main"}"#), URL_SAFE_NO_PAD.encode(claims.to_string())),
+        jwt(jwt_header, serde_json::json!({ "sub": "carol", "iss": "idp", "exp": now + 600, "nbf": now + 300 }), &key_pair),
+        jwt(jwt_header, serde_json::json!({ "sub": "carol", "iss": "idp" }), &key_pair),
+        jwt(jwt_header, serde_json::json!({ "iss": "idp", "exp": now + 600 }), &key_pair),
+        "not-a-jwt".to_string(),
+    ];
+    for token in rejected {
+        let (status, headers, _) = send_request(bearer(&token)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", token);
+        assert!(header(&headers, "WWW-Authenticate").is_some_and(|value| value.starts_with("Bearer")), "{}", token);
+    }
+    std::fs::remove_file(&jwks).unwrap();
+}
+
+#[tokio::test]
+async fn introspects_opaque_tokens_and_caches_the_results() {
+    let introspections = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let idp = listener.local_addr().unwrap();
+    let counter = introspections.clone();
+    let app = Router::new().route("/introspect", post(move |headers: HeaderMap, body: String| async move {
+        counter.fetch_add(1, Ordering::Relaxed);
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 600;
+        let is_proxy = header(&headers, "Authorization") == Some(format!("Basic {}", STANDARD.encode("proxy:secret")));
+        match is_proxy && body.starts_with("token=good&") {
+            true => Json(serde_json::json!({ "active": true, "sub": "dave", "exp": exp })),
+            false => Json(serde_json::json!({ "active": false })),
+        }
+    }));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/api\"\nintrospection = {{ endpoint = \"http://{}/introspect\", client_id = \"proxy\", client_secret = \"secret\" }}\n",
+        limited_in_memory("introspection").replace("strategy = \"ip\"", "strategy = \"identity\""), idp,
+    );
+    let proxy = start_proxy(&settings).await;
+    let bearer = |token: &str| Request::get(format!("http://{}/api", proxy)).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+
+    for expected in [StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+        assert_eq!(send_request(bearer("good")).await.0, expected);
+    }
+    assert_eq!(introspections.load(Ordering::Relaxed), 1);
+    for _ in 0..2 {
+        let (status, headers, _) = send_request(bearer("revoked")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(header(&headers, "WWW-Authenticate").as_deref(), Some("Bearer"));
+    }
+    assert_eq!(introspections.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn rejects_tokens_the_idp_does_not_vouch_for() {
+    let introspections = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let idp = listener.local_addr().unwrap();
+    let counter = introspections.clone();
+    let app = Router::new().route("/introspect", post(move |headers: HeaderMap, body: String| async move {
+        counter.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if header(&headers, "Authorization") != Some(format!("Basic {}", STANDARD.encode("proxy:secret"))) {
+            return Json(serde_json::json!({ "active": false })).into_response();
+        }
+        match body.split('&').next().unwrap_or_default() {
+            "token=good" => Json(serde_json::json!({ "active": true, "sub": "dave", "exp": now + 600 })).into_response(),
+            "token=expired" => Json(serde_json::json!({ "active": true, "sub": "dave", "exp": now - 60 })).into_response(),
+            "token=anonymous" => Json(serde_json::json!({ "active": true, "exp": now + 600 })).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let settings = |endpoint: &str, client_secret: &str| format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/api\"\nintrospection = {{ endpoint = \"{}\", client_id = \"proxy\", client_secret = \"{}\" }}\n",
+        limited_in_memory("rejected_introspections").replace("strategy = \"ip\"", "strategy = \"identity\""), endpoint, client_secret,
+    );
+    let bearer = |proxy: SocketAddr, token: &str| Request::get(format!("http://{}/api", proxy)).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+
+    let proxy = start_proxy(&settings(&format!("http://{}/introspect", idp), "secret")).await;
+    assert_eq!(send_request(bearer(proxy, "good")).await.0, StatusCode::OK);
+    for token in ["expired", "anonymous"] {
+        assert_eq!(send_request(bearer(proxy, token)).await.0, StatusCode::UNAUTHORIZED, "{}", token);
+    }
+    // Failed introspections are not cached
+    let before = introspections.load(Ordering::Relaxed);
+    for _ in 0..2 {
+        let (status, _, body) = send_request(bearer(proxy, "broken")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::SERVICE_UNAVAILABLE, "Authentication unavailable"));
+    }
+    assert_eq!(introspections.load(Ordering::Relaxed), before + 2);
+
+    // The IdP doesn't vouch for tokens sent by a proxy with the wrong credentials, and an unreachable IdP vouches for none
+    let proxy = start_proxy(&settings(&format!("http://{}/introspect", idp), "guessed")).await;
+    assert_eq!(send_request(bearer(proxy, "good")).await.0, StatusCode::UNAUTHORIZED);
+    let proxy = start_proxy(&settings("http://127.0.0.1:1/introspect", "secret")).await;
+    assert_eq!(send_request(bearer(proxy, "good")).await.0, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn the_admin_server_never_exports_auth_secrets() {
+    let admin = free_addr().await;
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/api\"\nintrospection = {{ endpoint = \"http://127.0.0.1:1/introspect\", client_id = \"proxy\", client_secret = \"introspection-secret\" }}\n\
+        hmac = {{ secrets = {{ billing = \"hmac-secret\" }} }}\n\n[admin]\naddr = \"{}\"\n",
+        limited_in_memory("redacted_secrets"), admin,
+    );
+    let proxy = start_proxy(&settings).await;
+    wait_for_admin(admin).await;
+
+    for path in ["/config", "/config?format=toml"] {
+        let (status, _, body) = send(admin, path).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("introspection-secret") && !body.contains("hmac-secret"), "{}", body);
+        // Key IDs are still shown
+        assert!(body.contains("billing"), "{}", body);
+    }
+    // A changed secret doesn't show up in the changes either
+    let request = Request::post(format!("http://{}/config/validate?format=toml", admin))
+        .body(Body::from(format!("[api_gateway]\nproxy_server_addr = \"{}\"\ntarget_url = \"http://127.0.0.1:1\"\n\n{}", proxy, settings.replace("introspection-secret", "rotated-secret").replace("hmac-secret", "rotated-secret"))))
+        .unwrap();
+    let (status, _, body) = send_request(request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("introspection-secret") && !body.contains("hmac-secret") && !body.contains("rotated-secret"), "{}", body);
+}
+
+#[tokio::test]
+async fn verifies_hmac_signatures_and_limits_invalid_ones() {
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/webhooks\"\n\n[rate_limiter.auth.hmac]\nsignature_prefix = \"sha256=\"\ntimestamp_header = \"X-Timestamp\"\n\
+        secrets = {{ billing = \"topsecret\" }}\nfailure_limit = {{ tokens_count = 2, add_tokens_every = 60 }}\n",
+        limited_in_memory("hmac").replace("tokens_count = 3", "tokens_count = 10"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let webhook = |timestamp: u64, secret: &str| {
+        let body = r#"{"event":"invoice.paid"}"#;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hex::encode(hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes()));
+        Request::post(format!("http://{}/webhooks/billing", proxy))
+            .header("X-Key-Id", "billing")
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Signature", format!("sha256={}", signature))
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let (status, _, body) = send_request(webhook(now, "topsecret")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /webhooks/billing"));
+    // Stale timestamps are rejected without counting as invalid signatures
+    assert_eq!(send_request(webhook(now - 3600, "topsecret")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(send_request(webhook(now, "guessed")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(send_request(webhook(now, "guessed")).await.0, StatusCode::UNAUTHORIZED);
+
+    let (status, headers, body) = send_request(webhook(now, "topsecret")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::TOO_MANY_REQUESTS, "Too many invalid signatures"));
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("60"));
+}
+
+#[tokio::test]
+async fn rejects_webhooks_with_unknown_keys_forged_signatures_or_bad_timestamps() {
+    let settings = format!(
+        "{}\n[[rate_limiter.auth]]\npath_prefix = \"/webhooks\"\n\n[rate_limiter.auth.hmac]\nsignature_prefix = \"sha256=\"\ntimestamp_header = \"X-Timestamp\"\n\
+        secrets = {{ billing = \"topsecret\" }}\nfailure_limit = {{ tokens_count = 3, add_tokens_every = 60 }}\n",
+        limited_in_memory("rejected_hmac").replace("tokens_count = 3", "tokens_count = 20"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let body = r#"{"event":"invoice.paid"}"#;
+    let sign = |timestamp: &str, body: &str| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"topsecret");
+        format!("sha256={}", hex::encode(hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes())))
+    };
+    let webhook = |key_id: &str, timestamp: Option<&str>, signature: &str| {
+        let mut request = Request::post(format!("http://{}/webhooks/billing", proxy)).header("X-Key-Id", key_id).header("X-Signature", signature);
+        if let Some(timestamp) = timestamp {
+            request = request.header("X-Timestamp", timestamp);
+        }
+        request.body(Body::from(body)).unwrap()
+    };
+    let now_str = now.to_string();
+    let future = (now + 3600).to_string();
+
+    // Missing or out of date timestamps are rejected without counting as invalid signatures
+    for request in [
+        webhook("billing", None, &sign(&now_str, body)),
+        webhook("billing", Some(&future), &sign(&future, body)),
+        webhook("billing", Some("yesterday"), &sign("yesterday", body)),
+    ] {
+        assert_eq!(send_request(request).await.0, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(send_request(webhook("billing", Some(&now_str), &sign(&now_str, body))).await.0, StatusCode::OK);
+
+    // Unknown keys, signatures without their prefix and signatures of another body count towards the failure limit
+    for request in [
+        webhook("payments", Some(&now_str), &sign(&now_str, body)),
+        webhook("billing", Some(&now_str), sign(&now_str, body).trim_start_matches("sha256=")),
+        webhook("billing", Some(&now_str), &sign(&now_str, r#"{"event":"invoice.void"}"#)),
+    ] {
+        assert_eq!(send_request(request).await.0, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _, body) = send_request(webhook("billing", Some(&now_str), &sign(&now_str, body))).await;
+    assert_eq!((status, body.as_str()), (StatusCode::TOO_MANY_REQUESTS, "Too many invalid signatures"));
+}
+
+#[tokio::test]
+async fn answers_cors_preflights_and_adds_cors_headers() {
+    let settings = format!(
+        "{}\n[[rate_limiter.cors]]\npath_prefix = \"/api\"\nallowed_origins = [\"https://app.example.com\"]\nallowed_headers = [\"X-Api-Key\"]\nexposed_headers = [\"Retry-After\"]\nmax_age_secs = 600\npreflight_bypasses_limits = true\n",
+        limited_in_memory("cors"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let preflight = |origin: &str| Request::options(format!("http://{}/api/orders", proxy))
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "POST")
+        .body(Body::empty())
+        .unwrap();
+    let get = |origin: &str| Request::get(format!("http://{}/api/orders", proxy)).header("Origin", origin).body(Body::empty()).unwrap();
+
+    for _ in 0..5 {
+        let (status, headers, _) = send_request(preflight("https://app.example.com")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin").as_deref(), Some("https://app.example.com"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Methods").as_deref(), Some("GET, HEAD, POST"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Headers").as_deref(), Some("X-Api-Key"));
+        assert_eq!(header(&headers, "Access-Control-Max-Age").as_deref(), Some("600"));
+    }
+    assert_eq!(send_request(preflight("https://evil.example.com")).await.0, StatusCode::FORBIDDEN);
+
+    // Preflights didn't charge the bucket
+    let (status, headers, body) = send_request(get("https://app.example.com")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /api/orders"));
+    assert_eq!(header(&headers, "Access-Control-Allow-Origin").as_deref(), Some("https://app.example.com"));
+    assert_eq!(header(&headers, "Access-Control-Expose-Headers").as_deref(), Some("Retry-After"));
+    assert_eq!(header(&headers, "Vary").as_deref(), Some("Origin"));
+
+    let (status, headers, _) = send_request(get("https://evil.example.com")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "Access-Control-Allow-Origin"), None);
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+
+    let (status, headers, _) = send_request(get("https://app.example.com")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "Access-Control-Allow-Origin").as_deref(), Some("https://app.example.com"));
+}
+
+#[tokio::test]
+async fn sends_the_upstream_limit_and_throttles_clients_it_ran_out_for() {
+    let settings = format!(
+        "{}\n[rate_limiter.upstream_limits]\nthrottle = true\nstrategy = \"header\"\nvalues = [\"X-Client\"]\n",
+        limited_in_memory("upstream_limits"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let get = |path: &str, client: &str| Request::get(format!("http://{}{}", proxy, path)).header("X-Client", client).body(Body::empty()).unwrap();
+
+    // 1 of 10 left upstream is less than 2 of 3 left here, and each header is sent once
+    let (status, headers, _) = send_request(get("/limited", "a")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "X-RateLimit-Limit").as_deref(), Some("10"));
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("1"));
+    assert_eq!(header(&headers, "X-RateLimit-Reset").as_deref(), Some("30"));
+    assert_eq!(headers.get_all("X-RateLimit-Remaining").iter().count(), 1);
+
+    let (status, headers, body) = send_request(get("/exhausted", "a")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::TOO_MANY_REQUESTS, "upstream /exhausted"));
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("20"));
+
+    // Only the client the upstream denied is throttled, without reaching the upstream or charging the limiter
+    let (status, headers, body) = send_request(get("/", "a")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"));
+    assert!(header(&headers, "Retry-After").is_some_and(|secs| (19..=20).contains(&secs.parse::<u32>().unwrap())));
+    let (status, headers, _) = send_request(get("/", "b")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("0"));
+}
+
+#[tokio::test]
+async fn charges_retries_to_a_budget_of_their_own() {
+    let settings = format!(
+        "{}\n[rate_limiter.retry_budget]\nretries = 1\nwindow_secs = 60\n",
+        limited_in_memory("retry_budget").replace("tokens_count = 3", "tokens_count = 10"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    // The first repeat is within the budget, the second one isn't and doesn't charge the limiter
+    assert_eq!(send(proxy, "/orders").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/orders").await.0, StatusCode::OK);
+    let (status, headers, body) = send(proxy, "/orders").await;
+    assert_eq!((status, body.as_str()), (StatusCode::TOO_MANY_REQUESTS, "Retry budget exceeded"));
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("60"));
+
+    // Fresh requests still go through, unless marked as retries
+    let (status, headers, _) = send(proxy, "/orders?page=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "X-RateLimit-Remaining").as_deref(), Some("7"));
+    let marked = Request::get(format!("http://{}/payments", proxy)).header("X-Retry-Count", "1").body(Body::empty()).unwrap();
+    assert_eq!(send_request(marked).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn blocks_requests_matching_inspection_rules_before_limiting() {
+    let settings = format!(
+        "{}
+[[rate_limiter.waf]]
+path_prefix = \"/api\"
+path_traversal = true
+max_json_depth = 3
+blocked_user_agents = [\"SQLMap\"]
+",
+        limited_in_memory("waf").replace("tokens_count = 3", "tokens_count = 4"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    for path in ["/api/files/..%2F..%2Fetc/passwd", "/api/files?name=%252e%252e/secret"] {
+        let (status, _, body) = send(proxy, path).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+        assert_eq!(body, "Request blocked");
+    }
+    let scanner = Request::get(format!("http://{}/api", proxy)).header("User-Agent", "sqlmap/1.8").body(Body::empty()).unwrap();
+    assert_eq!(send_request(scanner).await.0, StatusCode::FORBIDDEN);
+
+    let json = |body: &str| Request::post(format!("http://{}/api/orders", proxy))
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    assert_eq!(send_request(json("[[[[1]]]]")).await.0, StatusCode::FORBIDDEN);
+    // Brackets inside strings don't nest
+    assert_eq!(send_request(json(r#"{"a": [{"b": "[[[["}]}"#)).await.0, StatusCode::OK);
+
+    // Outside the route nothing is inspected, and blocked requests didn't charge the bucket
+    assert_eq!(send(proxy, "/files/..%2Fsecret").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn bans_clients_that_keep_getting_denied_and_reports_them() {
+    let log = std::env::temp_dir().join(format!("{}.log", key_prefix("bans").replace(':', "-")));
+    let settings = format!(
+        "{}\n[rate_limiter.ban]\nmin_denials = 2\n\n[[rate_limiter.ban.action]]\ntype = \"file\"\npath = {:?}\n",
+        limited_in_memory("bans"), log,
+    );
+    let proxy = start_proxy(&settings).await;
+
+    for expected in [StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS] {
+        assert_eq!(send(proxy, "/").await.0, expected);
+    }
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(header(&headers, "Retry-After").as_deref(), Some("600"));
+
+    // Actions run in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let lines = std::fs::read_to_string(&log).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    assert!(lines.ends_with(" banned 127.0.0.1 for 600s\n"), "{}", lines);
+}
+
+#[tokio::test]
+async fn blocks_or_limits_clients_on_a_reputation_list() {
+    let list = std::env::temp_dir().join(format!("{}.txt", key_prefix("reputation").replace(':', "-")));
+    std::fs::write(&list, "; Spamhaus DROP List\n10.0.0.0/8 ; SBL1\n127.0.0.0/8 ; SBL2\nnot an address\n").unwrap();
+    let proxy_with_list = |test: &str, action: &str| format!(
+        "{}\n[[rate_limiter.reputation_list]]\nname = \"drop\"\npath = {:?}\naction = \"{}\"\nbucket = {{ tokens_count = 1, add_tokens_every = 60 }}\n",
+        limited_in_memory(test), list, action,
+    );
+
+    let limited = start_proxy(&proxy_with_list("reputation_limit", "limit")).await;
+    assert_eq!(send(limited, "/").await.0, StatusCode::OK);
+    let (status, headers, _) = send(limited, "/").await;
+    assert_eq!((status, header(&headers, "Retry-After").as_deref()), (StatusCode::TOO_MANY_REQUESTS, Some("60")));
+
+    let blocked = start_proxy(&proxy_with_list("reputation_block", "block")).await;
+    std::fs::remove_file(&list).unwrap();
+    assert_eq!(send(blocked, "/").await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn gives_every_ip_class_its_own_bucket() {
+    let list = std::env::temp_dir().join(format!("{}.txt", key_prefix("ip_classes").replace(':', "-")));
+    std::fs::write(&list, "# Datacenter ranges\n127.0.0.0/8\n").unwrap();
+    let settings = format!(
+        "{}buckets_per_value = [{{ value = \"datacenter\", tokens_count = 1, add_tokens_every = 60 }}]\n\n\
+        [rate_limiter.ip_classes]\nheader = \"X-Ip-Class\"\n\n[[rate_limiter.ip_classes.list]]\nclass = \"datacenter\"\npath = {:?}\n",
+        limited_in_memory("ip_classes"), list,
+    );
+    let proxy = start_proxy(&settings).await;
+    std::fs::remove_file(&list).unwrap();
+
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("1")));
+    assert_eq!(send(proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn matches_networks_and_path_prefixes_of_buckets_per_value() {
+    // The address is more specific than the network containing it
+    let settings = format!(
+        "{}buckets_per_value = [{{ value = \"127.0.0.0/8\", tokens_count = 5, add_tokens_every = 60 }}, {{ value = \"127.0.0.1\", tokens_count = 2, add_tokens_every = 60 }}]\n",
+        limited_in_memory("ip_networks"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("2")));
+
+    let settings = format!(
+        "[rate_limiter]\nbackend = \"memory\"\nip_whitelist = []\nkeys = {{ prefix = \"{}\" }}\n\n\
+        [[rate_limiter.limiter]]\nstrategy = \"url\"\nlog_decisions = \"off\"\nglobal_bucket = {{ tokens_count = 3, add_tokens_every = 60 }}\n\
+        buckets_per_value = [{{ value = \"/api/*\", tokens_count = 1, add_tokens_every = 60 }}]\n",
+        key_prefix("path_prefixes"),
+    );
+    let proxy = start_proxy(&settings).await;
+    // Every path under the prefix gets a bucket of its own
+    for path in ["/api/orders", "/api/users/1"] {
+        let (status, headers, _) = send(proxy, path).await;
+        assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("1")));
+    }
+    assert_eq!(send(proxy, "/api/orders").await.0, StatusCode::TOO_MANY_REQUESTS);
+    let (status, headers, _) = send(proxy, "/apiary").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("3")));
+}
+
+#[tokio::test]
+async fn applies_the_default_action_to_requests_no_limiter_matches() {
+    let settings = |default_action: &str, test: &str| format!(
+        "[rate_limiter]\nbackend = \"memory\"\nip_whitelist = []\ndefault_action = \"{}\"\nkeys = {{ prefix = \"{}\" }}\n\n\
+        [[rate_limiter.limiter]]\nstrategy = \"url\"\nlog_decisions = \"off\"\n\
+        buckets_per_value = [{{ value = \"/api/*\", tokens_count = 3, add_tokens_every = 60 }}]\n",
+        default_action, key_prefix(test),
+    );
+
+    // The catch-all limiter only applies to the requests the url limiter doesn't match
+    let catch_all = "\n[[rate_limiter.limiter]]\nname = \"catch-all\"\nstrategy = \"ip\"\nlog_decisions = \"off\"\nglobal_bucket = { tokens_count = 1, add_tokens_every = 60 }\n";
+    let proxy = start_proxy(&format!("{}{}", settings("bucket:catch-all", "default_bucket"), catch_all)).await;
+    let (status, headers, _) = send(proxy, "/api/orders").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("3")));
+    let (status, headers, _) = send(proxy, "/health").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Limit").as_deref()), (StatusCode::OK, Some("1")));
+    assert_eq!(send(proxy, "/health").await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send(proxy, "/api/orders").await.0, StatusCode::OK);
+
+    let proxy = start_proxy(&settings("deny", "default_deny")).await;
+    assert_eq!(send(proxy, "/api/orders").await.0, StatusCode::OK);
+    let (status, _, body) = send(proxy, "/health").await;
+    assert_eq!((status, body.as_str()), (StatusCode::FORBIDDEN, "No rate limit applies to this request"));
+}
+
+#[tokio::test]
+async fn lets_requests_with_a_valid_bypass_token_skip_the_limits() {
+    let settings = format!(
+        "{}\n[rate_limiter.bypass]\nmax_ttl_secs = 600\nsecrets = {{ current = \"secret\" }}\n",
+        limited_in_memory("bypass").replace("tokens_count = 3", "tokens_count = 1"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let token = |key_id: &str, ttl_secs: u64| {
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + ttl_secs;
+        let message = format!("{}.{}.ci-runner", key_id, expires_at);
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"secret"), message.as_bytes()));
+        format!("{}.{}", message, signature)
+    };
+    let with_token = |path: &str, token: String| Request::get(format!("http://{}{}", proxy, path)).header("X-RateLimit-Bypass", token).body(Body::empty()).unwrap();
+
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    assert_eq!(send(proxy, "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // The token isn't forwarded, and the request gets no limit headers
+    let (status, headers, body) = send_request(with_token("/headers", token("current", 60))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("x-ratelimit-bypass"), "{}", body);
+    assert!(header(&headers, "X-RateLimit-Remaining").is_none());
+
+    // Tokens of unknown keys, or valid for longer than max_ttl_secs, are ignored
+    assert_eq!(send_request(with_token("/", token("previous", 60))).await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send_request(with_token("/", token("current", 3600))).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn ignores_forged_tampered_or_expired_bypass_tokens() {
+    let settings = format!(
+        "{}\n[rate_limiter.bypass]\nmax_ttl_secs = 600\nsecrets = {{ current = \"secret\" }}\n",
+        limited_in_memory("rejected_bypass").replace("tokens_count = 3", "tokens_count = 1"),
+    );
+    let proxy = start_proxy(&settings).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let sign = |message: &str, secret: &[u8]| URL_SAFE_NO_PAD.encode(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), message.as_bytes()));
+    let with_token = |token: &str| Request::get(format!("http://{}/", proxy)).header("X-RateLimit-Bypass", token).body(Body::empty()).unwrap();
+
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+    let valid = format!("current.{}.ci-runner", now + 60);
+    assert_eq!(send_request(with_token(&format!("{}.{}", valid, sign(&valid, b"secret")))).await.0, StatusCode::OK);
+
+    let expired = format!("current.{}.ci-runner", now - 1);
+    let rejected = [
+        format!("{}.{}", valid, sign(&valid, b"guessed")),
+        format!("{}.{}", expired, sign(&expired, b"secret")),
+        // Signed fields can't be changed
+        format!("current.{}.ci-runner.{}", now + 120, sign(&valid, b"secret")),
+        format!("current.{}.admin.{}", now + 60, sign(&valid, b"secret")),
+        format!("{}.not-base64!", valid),
+        "ci-runner".to_string(),
+    ];
+    for token in rejected {
+        assert_eq!(send_request(with_token(&token)).await.0, StatusCode::TOO_MANY_REQUESTS, "{}", token);
+    }
+}
+
+#[tokio::test]
+async fn forwards_to_upstreams_found_in_dns() {
+    let proxy = start_proxy_with(
+        |upstream| format!("probe_on_startup = true\n\n[api_gateway.discovery]\ntype = \"dns\"\nhost = \"localhost\"\nport = {}", upstream.port()),
+        &limited_in_memory("dns_discovery"),
+    ).await;
+
+    let (status, _, body) = send(proxy, "/orders").await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /orders"));
+}
+
+// Answers the list of EndpointSlices with a ready pod at `ready` and a pod at `not_ready` failing its readiness probe,
+// and holds watches open without events
+async fn start_kubernetes_api(ready: SocketAddr, not_ready: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let endpoint_slices = serde_json::json!({
+        "metadata": { "resourceVersion": "1" },
+        "items": [{
+            "metadata": { "name": "api-abc12", "resourceVersion": "1" },
+            "addressType": "IPv4",
+            "endpoints": [
+                { "addresses": [ready.ip().to_string()], "conditions": { "ready": true } },
+                { "addresses": [not_ready.ip().to_string()], "conditions": { "ready": false } },
+            ],
+            "ports": [{ "name": "http", "port": ready.port() }],
+        }],
+    });
+    let app = Router::new().route("/apis/discovery.k8s.io/v1/namespaces/shop/endpointslices", get(move |request: Request<Body>| async move {
+        assert_eq!(request.uri().query().map(|query| query.contains("labelSelector=kubernetes.io%2Fservice-name%3Dapi")), Some(true));
+        if request.uri().query().is_some_and(|query| query.contains("watch=1")) {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+        Json(endpoint_slices)
+    }));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+#[tokio::test]
+async fn forwards_to_the_ready_pods_of_a_kubernetes_service() {
+    let api = start_kubernetes_api(start_upstream().await, "127.0.0.2:1".parse().unwrap()).await;
+    let proxy = start_proxy_with(
+        |_| format!("[api_gateway.discovery]\ntype = \"kubernetes\"\nservice = \"api\"\nnamespace = \"shop\"\napi_url = \"http://{}\"", api),
+        &limited_in_memory("kubernetes_discovery"),
+    ).await;
+
+    for _ in 0..3 {
+        let (status, _, body) = send(proxy, "/orders").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /orders"));
+    }
+}
+
+#[tokio::test]
+async fn closes_connections_over_the_limit_per_ip_before_reading_them() {
+    let settings = format!(
+        "{}\n[api_gateway.connection_limit]\nmax_per_ip_per_sec = 2\n",
+        limited_in_memory("connection_limit").replace("tokens_count = 3", "tokens_count = 10"),
+    );
+    let proxy = start_proxy(&settings).await;
+    // Waiting for the proxy to listen took connections of this second
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // The connections may be spread over two seconds, which still leaves at least one over the limit
+    let mut answered = Vec::new();
+    for _ in 0..5 {
+        let mut connection = TcpStream::connect(proxy).await.unwrap();
+        connection.write_all(b"GET / HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        let _ = connection.read_to_end(&mut response).await;
+        answered.push(response.starts_with(b"HTTP/1.1 200"));
+    }
+    assert!(answered[0]);
+    assert!((2..=4).contains(&answered.iter().filter(|answered| **answered).count()), "{:?}", answered);
+}
+
+#[tokio::test]
+async fn answers_clients_sending_too_slowly_with_408() {
+    let settings = format!("{}\n[api_gateway.read_timeouts]\nheaders_ms = 300\nbody_ms = 300\n", limited_in_memory("read_timeouts"));
+    let proxy = start_proxy(&settings).await;
+
+    for request in ["GET / HTTP/1.1\r\nHost: proxy\r\n".as_bytes(), b"POST /upload HTTP/1.1\r\nHost: proxy\r\nContent-Length: 10\r\n\r\nabc"] {
+        let mut connection = TcpStream::connect(proxy).await.unwrap();
+        connection.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), connection.read_to_end(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 408"), "{}", String::from_utf8_lossy(&response));
+    }
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn closes_connections_over_the_concurrent_limit_per_ip() {
+    let settings = format!("{}\n[api_gateway.connection_limit]\nmax_concurrent_per_ip = 1\n", limited_in_memory("concurrent_connections"));
+    let proxy = start_proxy(&settings).await;
+
+    let idle = TcpStream::connect(proxy).await.unwrap();
+    let mut over_limit = TcpStream::connect(proxy).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), over_limit.read_to_end(&mut response)).await.unwrap().unwrap();
+    assert!(response.is_empty());
+
+    drop(idle);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(send(proxy, "/").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn queues_requests_over_the_listener_concurrency_limit_and_rejects_the_rest() {
+    let settings = format!(
+        "{}\n[api_gateway.concurrency_limit]\nmax_in_flight = 1\nmax_queued = 1\nretry_after_secs = 2\n",
+        limited_in_memory("concurrency_limit"),
+    );
+    let proxy = start_proxy(&settings).await;
+
+    let in_flight = tokio::spawn(send(proxy, "/slow"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let queued = tokio::spawn(send(proxy, "/slow"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "Retry-After").as_deref()), (StatusCode::SERVICE_UNAVAILABLE, Some("2")));
+
+    assert_eq!(in_flight.await.unwrap().2, "upstream /slow");
+    assert_eq!(queued.await.unwrap().2, "upstream /slow");
+    // The rejected request wasn't charged
+    let (status, headers, _) = send(proxy, "/").await;
+    assert_eq!((status, header(&headers, "X-RateLimit-Remaining").as_deref()), (StatusCode::OK, Some("0")));
+}
+
+#[tokio::test]
+async fn sheds_requests_whose_bodies_take_the_memory_budget_over() {
+    let settings = format!("{}\n[memory_budget]\nmax_bytes = 4096\nretry_after_secs = 3\n", limited_in_memory("memory_budget"));
+    let proxy = start_proxy(&settings).await;
+    let upload = |body: Vec<u8>| Request::post(format!("http://{}/upload", proxy)).body(Body::from(body)).unwrap();
+
+    let (status, headers, body) = send_request(upload(vec![b'a'; 8192])).await;
+    assert_eq!((status, header(&headers, "Retry-After").as_deref(), body.as_str()), (StatusCode::SERVICE_UNAVAILABLE, Some("3"), "Server overloaded"));
+
+    // Chunked bodies are shed once the bytes received take the budget over
+    let mut connection = TcpStream::connect(proxy).await.unwrap();
+    let chunk = "a".repeat(1024);
+    let mut request = "POST /upload HTTP/1.1\r\nHost: proxy\r\nTransfer-Encoding: chunked\r\n\r\n".to_string();
+    for _ in 0..8 {
+        request.push_str(&format!("400\r\n{}\r\n", chunk));
+    }
+    request.push_str("0\r\n\r\n");
+    connection.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![0; 1024];
+    let read = connection.read(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response[..read]).starts_with("HTTP/1.1 503"));
+
+    // The bytes of finished requests are given back
+    let (status, _, body) = send_request(upload(vec![b'a'; 1024])).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upstream /upload 1024"));
+}
@@ -0,0 +1,448 @@
+// Invariants of the limiters checked over request sequences generated by proptest. A failing case is shrunk
+// to the smallest sequence that still breaks the invariant and reported with it.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::body::Body;
+use axum::http::{Response, StatusCode};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use rate_limiter::builder::RateLimiterBuilder;
+use rate_limiter::clock::Clock;
+use rate_limiter::layer::RateLimitInfo;
+use rate_limiter::limiter::RateLimiterManager;
+use rate_limiter::settings::{DecisionLogging, LocalCacheSettings, MostRestrictive, OnStoreError, PossibleStrategies, PriorityClassSettings, QuotaPeriod, QuotaSettings, RateLimiterSettings};
+use rate_limiter::testing::{MockClock, MockStore, TestRequest};
+
+const CASES: u32 = 64;
+const REQUESTS: usize = 200;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const BATCH_SIZE: u32 = 4;
+
+// What the bucket invariants are checked against: the mock store alone, behind the local cache, or down for the whole
+// run with the fallback limiter counting requests in its place
+#[derive(Clone, Copy, Debug)]
+enum Backend {
+    Store,
+    LocalCache,
+    Fallback,
+}
+
+impl Backend {
+    // Tokens the local cache claimed before a window and can still hand out in it
+    fn carried_over(self) -> u32 {
+        match self {
+            Backend::Store | Backend::Fallback => 0,
+            Backend::LocalCache => BATCH_SIZE - 1,
+        }
+    }
+
+    fn manager(self, mut settings: RateLimiterSettings, clock: &MockClock) -> RateLimiterManager {
+        let store = MockStore::new(clock.clone());
+        match self {
+            Backend::Store => {},
+            Backend::LocalCache => {
+                settings.local_cache = Some(LocalCacheSettings { hot_key_threshold: 2, batch_size: BATCH_SIZE, max_staleness_ms: 500 });
+            },
+            // A single replica, so the fallback buckets are as large as the configured ones
+            Backend::Fallback => {
+                store.set_failing(true);
+                for limiter in &mut settings.limiters_settings {
+                    limiter.on_store_error = OnStoreError::FallbackMemory;
+                }
+            },
+        }
+        RateLimiterManager::with_clock(settings, Some(store), Arc::new(clock.clone())).unwrap()
+    }
+}
+
+fn backends() -> impl Strategy<Value = Backend> {
+    prop_oneof![Just(Backend::Store), Just(Backend::LocalCache), Just(Backend::Fallback)]
+}
+
+// proptest cases are synchronous, every case gets its own runtime
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+}
+
+fn client(last_octet: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, last_octet))
+}
+
+// Milliseconds elapsed before every request and the client sending it
+fn requests(max_elapsed_ms: u64) -> impl Strategy<Value = Vec<(u64, u8)>> {
+    vec((0..max_elapsed_ms, 0..3u8), 1..=REQUESTS)
+}
+
+// Spike arrest of `requests` per `window_ms` and the microseconds elapsed before every request,
+// drawn below the interval the arrest lets requests through at
+fn spike_arrests() -> impl Strategy<Value = (u32, u32, Vec<u64>)> {
+    (1..=10u32, 10..510u32).prop_flat_map(|(requests, window_ms)| {
+        (Just(requests), Just(window_ms), vec(0..window_ms as u64 * 1000 / requests as u64, REQUESTS))
+    })
+}
+
+// Path, client and API key of a request hitting the limiters of check_most_restrictive
+fn routed_requests() -> impl Strategy<Value = Vec<(u64, usize, u8, Option<u8>)>> {
+    vec((0..1000u64, 0..3usize, 0..3u8, proptest::option::of(0..3u8)), 1..=REQUESTS)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+    #[test]
+    fn fixed_windows_never_allow_more_than_tokens_count(backend in backends(), tokens_count in 1..=10u32, add_tokens_every in 1..=5u64, requests in requests(1500)) {
+        block_on(async {
+            let settings = RateLimiterBuilder::new()
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .global_bucket(tokens_count, &format!("{}s", add_tokens_every))
+                .into_settings()
+                .unwrap();
+            let clock = MockClock::new();
+            let manager = backend.manager(settings, &clock);
+
+            // Per client: start of the current window and the requests it allowed so far
+            let mut windows = [(None, 0); 3];
+            for (elapsed_ms, last_octet) in requests {
+                clock.advance(Duration::from_millis(elapsed_ms));
+                let request = TestRequest::get("/").ip(client(last_octet));
+                let addr = request.addr();
+                let limit = manager.check(&request.into_safe_request(), addr).await.unwrap();
+
+                let (started_at, allowed) = &mut windows[last_octet as usize];
+                let now_us = clock.now_us();
+                if started_at.is_none_or(|started_at| now_us >= started_at + add_tokens_every * 1_000_000) {
+                    (*started_at, *allowed) = (Some(now_us), 0);
+                }
+                if !limit.is_limit_exceeded {
+                    *allowed += 1;
+                }
+                let bound = tokens_count + backend.carried_over();
+                prop_assert!(*allowed <= bound, "{} requests allowed in a window of {} tokens, at most {} expected", allowed, tokens_count, bound);
+            }
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn bursts_never_allow_more_than_burst_plus_rate(backend in backends(), tokens_count in 1..=10u32, add_tokens_every in 1..=5u32, burst in 1..=10u32, elapsed in vec(0..500u64, 1..=REQUESTS)) {
+        block_on(async {
+            let mut settings = RateLimiterBuilder::new()
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .global_bucket(tokens_count, &format!("{}s", add_tokens_every))
+                .into_settings()
+                .unwrap();
+            if let Some(bucket) = settings.limiters_settings[0].global_bucket.as_mut() {
+                bucket.burst = Some(burst);
+            }
+            let clock = MockClock::new();
+            let manager = backend.manager(settings, &clock);
+
+            let mut allowed_at = Vec::new();
+            for elapsed_ms in elapsed {
+                clock.advance(Duration::from_millis(elapsed_ms));
+                let request = TestRequest::get("/");
+                let addr = request.addr();
+                let limit = manager.check(&request.into_safe_request(), addr).await.unwrap();
+                if !limit.is_limit_exceeded {
+                    allowed_at.push(clock.now_us());
+                }
+            }
+
+            // Between two allowed requests, at most the burst plus the tokens given back in the meantime are allowed
+            let interval_us = (add_tokens_every as u64 * 1_000_000 / tokens_count as u64).max(1);
+            for (first, first_at) in allowed_at.iter().enumerate() {
+                for (last, last_at) in allowed_at.iter().enumerate().skip(first) {
+                    let allowed = (last - first + 1) as u64;
+                    let bound = (burst + backend.carried_over()) as u64 + (last_at - first_at) / interval_us;
+                    prop_assert!(allowed <= bound, "{} requests allowed within {} us, at most {} expected", allowed, last_at - first_at, bound);
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn borrowed_tokens_are_repaid_by_the_next_window(backend in backends(), tokens_count in 1..=10u32, add_tokens_every in 1..=5u64, borrow in 1..=5u32, requests in requests(1500)) {
+        block_on(async {
+            let mut settings = RateLimiterBuilder::new()
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .global_bucket(tokens_count, &format!("{}s", add_tokens_every))
+                .into_settings()
+                .unwrap();
+            if let Some(bucket) = settings.limiters_settings[0].global_bucket.as_mut() {
+                bucket.borrow = borrow;
+            }
+            let clock = MockClock::new();
+            // The local cache leaves buckets that can borrow to the store, so the bounds are exact with it too
+            let manager = backend.manager(settings, &clock);
+
+            // Per client: start of the current window, windows started back to back since the client was last idle
+            // for a whole window, and the requests they allowed
+            let mut windows = [(None, 0, 0); 3];
+            for (elapsed_ms, last_octet) in requests {
+                clock.advance(Duration::from_millis(elapsed_ms));
+                let request = TestRequest::get("/").ip(client(last_octet));
+                let addr = request.addr();
+                let limit = manager.check(&request.into_safe_request(), addr).await.unwrap();
+
+                let (started_at, windows_in_a_row, allowed) = &mut windows[last_octet as usize];
+                let now_us = clock.now_us();
+                let window_us = add_tokens_every * 1_000_000;
+                match *started_at {
+                    Some(at) if now_us < at + window_us => {},
+                    Some(at) if now_us < at + 2 * window_us => (*started_at, *windows_in_a_row) = (Some(now_us), *windows_in_a_row + 1),
+                    _ => (*started_at, *windows_in_a_row, *allowed) = (Some(now_us), 1, 0),
+                }
+                if !limit.is_limit_exceeded {
+                    *allowed += 1;
+                }
+                // Only the last window can still be in debt
+                let bound = *windows_in_a_row * tokens_count + borrow;
+                prop_assert!(*allowed <= bound, "{} requests allowed in {} windows in a row, at most {} expected", allowed, windows_in_a_row, bound);
+                if *windows_in_a_row == 1 {
+                    prop_assert!(!limit.is_limit_exceeded || *allowed == tokens_count + borrow, "denied after {} requests", allowed);
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn the_lowest_ratio_left_is_applied(tokens_counts in [1..=20u32, 1..=20u32, 1..=20u32, 1..=20u32], requests in routed_requests()) {
+        block_on(check_most_restrictive(MostRestrictive::Ratio, tokens_counts, requests))?;
+    }
+
+    #[test]
+    fn the_lowest_remaining_count_is_applied(tokens_counts in [1..=20u32, 1..=20u32, 1..=20u32, 1..=20u32], requests in routed_requests()) {
+        block_on(check_most_restrictive(MostRestrictive::Remaining, tokens_counts, requests))?;
+    }
+
+    #[test]
+    fn handlers_see_the_limit_of_the_headers(ip_tokens_count in 1..=20u32, key_tokens_count in 1..=20u32, clients in vec(0..3u8, 1..=REQUESTS / 4)) {
+        block_on(async {
+            let manager = RateLimiterBuilder::new()
+                .store(MockStore::new(MockClock::new()))
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .global_bucket(ip_tokens_count, "1h")
+                .limiter(PossibleStrategies::Header)
+                .log_decisions(DecisionLogging::Off)
+                .bucket_per_value("X-Api-Key", key_tokens_count, "1h")
+                .build()
+                .unwrap();
+
+            for last_octet in clients {
+                let request = TestRequest::get("/").ip(client(last_octet)).header("X-Api-Key", "abc");
+                let addr = request.addr();
+                let info = Arc::new(Mutex::new(None));
+                let response = manager.handle(request.into_request(), addr, |request| {
+                    *info.lock().unwrap() = request.extensions().get::<RateLimitInfo>().cloned();
+                    async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                }).await.unwrap();
+
+                let info = info.lock().unwrap().take();
+                if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                    prop_assert!(info.is_none(), "denied requests reached the handler");
+                    continue;
+                }
+                let Some(info) = info else {
+                    return Err(TestCaseError::fail("no RateLimitInfo for an allowed request"));
+                };
+                let limit = info.limit.unwrap();
+                let header = |name| response.headers().get(name).map(|value| value.to_str().unwrap().parse::<i64>().unwrap());
+                prop_assert_eq!(header("X-RateLimit-Remaining"), Some(limit.requests_to_exceed_limit as i64));
+                prop_assert_eq!(header("X-RateLimit-Limit"), Some(limit.total_limit as i64));
+                prop_assert_eq!(&info.limiters, &["ip-0", "header-1"]);
+                prop_assert!(info.limiter.is_some_and(|limiter| info.limiters.contains(&limiter)));
+            }
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn lower_classes_never_take_the_share_of_the_others(tokens_count in 1..=20u32, share_percent in 1..=100u8) {
+        block_on(async {
+            let manager = RateLimiterBuilder::new()
+                .store(MockStore::new(MockClock::new()))
+                .priority_class(PriorityClassSettings {
+                    name: "free".to_string(),
+                    header: Some("X-Api-Tier".to_string()),
+                    values: vec!["free".to_string()],
+                    path_prefixes: Vec::new(),
+                    share_percent,
+                })
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .global_bucket(tokens_count, "1h")
+                .build()
+                .unwrap();
+
+            // Free requests first, then premium ones get what free requests couldn't take
+            let (mut free, mut premium) = (0, 0);
+            for _ in 0..REQUESTS / 2 {
+                let request = TestRequest::get("/").header("X-Api-Tier", "free");
+                let addr = request.addr();
+                if !manager.check(&request.into_safe_request(), addr).await.unwrap().is_limit_exceeded {
+                    free += 1;
+                }
+            }
+            for _ in 0..REQUESTS / 2 {
+                let request = TestRequest::get("/");
+                let addr = request.addr();
+                if !manager.check(&request.into_safe_request(), addr).await.unwrap().is_limit_exceeded {
+                    premium += 1;
+                }
+            }
+            prop_assert_eq!(free, tokens_count * share_percent as u32 / 100);
+            prop_assert_eq!(free + premium, tokens_count);
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn spike_arrests_smooth_bursts_without_charging_the_bucket((requests, window_ms, elapsed) in spike_arrests()) {
+        block_on(async {
+            let clock = MockClock::new();
+            let store = MockStore::new(clock.clone());
+            let manager = RateLimiterBuilder::new()
+                .store(store.clone())
+                .limiter(PossibleStrategies::IP)
+                .log_decisions(DecisionLogging::Off)
+                .spike_arrest(requests, window_ms)
+                .global_bucket(REQUESTS as u32, "1h")
+                .build()
+                .unwrap();
+
+            // Requests come twice as fast as the arrest lets them through on average
+            let interval_us = window_ms as u64 * 1000 / requests as u64;
+            let mut allowed_at = Vec::new();
+            for elapsed_us in elapsed {
+                clock.advance(Duration::from_micros(elapsed_us));
+                let request = TestRequest::get("/");
+                let addr = request.addr();
+                if !manager.check(&request.into_safe_request(), addr).await.unwrap().is_limit_exceeded {
+                    allowed_at.push(clock.now_us());
+                }
+            }
+
+            // A burst of `requests`, then one request every `window_ms / requests`
+            for (first, first_at) in allowed_at.iter().enumerate() {
+                for (last, last_at) in allowed_at.iter().enumerate().skip(first) {
+                    let allowed = (last - first + 1) as u64;
+                    let bound = requests as u64 + (last_at - first_at) / interval_us;
+                    prop_assert!(allowed <= bound, "{} requests allowed within {} us, at most {} expected", allowed, last_at - first_at, bound);
+                }
+            }
+            prop_assert!(allowed_at.len() < REQUESTS, "no request was arrested");
+
+            // Only the allowed requests were charged to the bucket of the limiter
+            let key = store.keys().into_iter().find(|key| !key.ends_with(":spike")).unwrap();
+            prop_assert_eq!(store.remaining(&key), Some((REQUESTS - allowed_at.len()) as i32));
+            Ok(())
+        })?;
+    }
+
+    #[test]
+    fn daily_quotas_follow_the_clock_of_the_manager(limit in 1..=20u32, elapsed in vec(0..2 * 60 * 60u64, 1..=REQUESTS)) {
+        block_on(async {
+            let clock = MockClock::new();
+            let mut settings = RateLimiterBuilder::new().into_settings().unwrap();
+            settings.quotas_settings.push(QuotaSettings {
+                name: "daily".to_string(),
+                header: "X-Api-Key".to_string(),
+                period: QuotaPeriod::Daily,
+                limit,
+                limits_per_value: Vec::new(),
+            });
+            let manager = RateLimiterManager::with_clock(settings, Some(MockStore::new(clock.clone())), Arc::new(clock.clone())).unwrap();
+
+            // Every day of the mock clock starts with the full quota, whatever was used the day before
+            let (mut day, mut used) = (0, 0);
+            for elapsed_secs in elapsed {
+                clock.advance(Duration::from_secs(elapsed_secs));
+                if clock.now_secs() / SECONDS_PER_DAY != day {
+                    (day, used) = (clock.now_secs() / SECONDS_PER_DAY, 0);
+                }
+                used += 1;
+
+                let usage = manager.check_quotas(&TestRequest::get("/").header("X-Api-Key", "abc").into_safe_request()).await.unwrap();
+                prop_assert_eq!(usage.resets_in, SECONDS_PER_DAY - clock.now_secs() % SECONDS_PER_DAY);
+                prop_assert_eq!(usage.remaining, limit as i32 - used);
+            }
+            Ok(())
+        })?;
+    }
+}
+
+async fn check_most_restrictive(most_restrictive: MostRestrictive, tokens_counts: [u32; 4], requests: Vec<(u64, usize, u8, Option<u8>)>) -> Result<(), TestCaseError> {
+    let build = |clock: &MockClock| RateLimiterBuilder::new()
+        .store(MockStore::new(clock.clone()))
+        .most_restrictive(most_restrictive)
+        .limiter(PossibleStrategies::IP)
+        .log_decisions(DecisionLogging::Off)
+        .global_bucket(tokens_counts[0], "10s")
+        .limiter(PossibleStrategies::URL)
+        .log_decisions(DecisionLogging::Off)
+        .bucket_per_value("/a", tokens_counts[1], "10s")
+        .bucket_per_value("/b", tokens_counts[2], "5s")
+        .limiter(PossibleStrategies::Header)
+        .log_decisions(DecisionLogging::Off)
+        .bucket_per_value("X-Api-Key", tokens_counts[3], "10s")
+        .build()
+        .map(Arc::new)
+        .unwrap();
+    // Both managers get the same limits and the same requests, one is asked for every limit and the other handles requests
+    let (clock, each_clock) = (MockClock::new(), MockClock::new());
+    let manager = build(&clock);
+    let each_manager = build(&each_clock);
+
+    for (elapsed_ms, path, last_octet, key) in requests {
+        let elapsed = Duration::from_millis(elapsed_ms);
+        clock.advance(elapsed);
+        each_clock.advance(elapsed);
+
+        let path = ["/a", "/b", "/c"][path];
+        let key = key.map(|key| format!("key-{}", key));
+        let build_request = || {
+            let request = TestRequest::get(path).ip(client(last_octet));
+            match &key {
+                Some(key) => request.header("X-Api-Key", key.as_str()),
+                None => request,
+            }
+        };
+
+        let request = build_request();
+        let addr = request.addr();
+        let limits = each_manager.check_each(&request.into_safe_request(), addr).await;
+        let response = manager.handle(build_request().into_request(), addr, |_| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }).await.unwrap();
+
+        for (name, limit) in &limits {
+            prop_assert!(limit.is_limit_exceeded || limit.requests_to_exceed_limit >= 0, "limiter {} allowed with a negative remaining", name);
+        }
+        let is_exceeded = limits.iter().any(|(_, limit)| limit.is_limit_exceeded);
+        prop_assert_eq!(response.status() == StatusCode::TOO_MANY_REQUESTS, is_exceeded, "{:?}", limits);
+        if is_exceeded {
+            continue;
+        }
+
+        let header = |name| response.headers().get(name).map(|value| value.to_str().unwrap().parse::<i64>().unwrap());
+        let lowest = limits.iter().map(|(_, limit)| limit).min_by(|limit, other| match most_restrictive {
+            MostRestrictive::Ratio => (limit.requests_to_exceed_limit as f64 / limit.total_limit as f64)
+                .total_cmp(&(other.requests_to_exceed_limit as f64 / other.total_limit as f64)),
+            MostRestrictive::Remaining => limit.requests_to_exceed_limit.cmp(&other.requests_to_exceed_limit),
+        });
+        prop_assert_eq!(header("X-RateLimit-Remaining"), lowest.map(|limit| limit.requests_to_exceed_limit as i64), "{:?}", limits);
+        prop_assert_eq!(header("X-RateLimit-Limit"), lowest.map(|limit| limit.total_limit as i64), "{:?}", limits);
+        prop_assert!(header("X-RateLimit-Remaining").is_none_or(|remaining| remaining >= 0), "negative remaining header");
+    }
+    Ok(())
+}